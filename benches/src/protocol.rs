@@ -74,10 +74,12 @@ impl Plugin for ProtocolPlugin {
         app.add_channel::<Channel1>(ChannelSettings {
             mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
             ..default()
-        });
+        })
+        .unwrap();
         app.add_channel::<Channel2>(ChannelSettings {
             mode: ChannelMode::UnorderedUnreliable,
             ..default()
-        });
+        })
+        .unwrap();
     }
 }
@@ -38,7 +38,8 @@ impl Plugin for SharedPlugin {
         app.add_channel::<Channel1>(ChannelSettings {
             mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
             ..default()
-        });
+        })
+        .unwrap();
 
         if app.is_plugin_added::<RenderPlugin>() {
             app.add_systems(Startup, init);
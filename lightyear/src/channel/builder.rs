@@ -6,6 +6,7 @@ use lightyear_macros::ChannelInternal;
 use crate::channel::receivers::ordered_reliable::OrderedReliableReceiver;
 use crate::channel::receivers::sequenced_reliable::SequencedReliableReceiver;
 use crate::channel::receivers::sequenced_unreliable::SequencedUnreliableReceiver;
+use crate::channel::receivers::tick_buffered::TickBufferedReceiver;
 use crate::channel::receivers::unordered_reliable::UnorderedReliableReceiver;
 use crate::channel::receivers::unordered_unreliable::UnorderedUnreliableReceiver;
 use crate::channel::receivers::ChannelReceiver;
@@ -13,10 +14,13 @@ use crate::channel::senders::reliable::ReliableSender;
 use crate::channel::senders::sequenced_unreliable::SequencedUnreliableSender;
 use crate::channel::senders::unordered_unreliable::UnorderedUnreliableSender;
 use crate::channel::senders::unordered_unreliable_with_acks::UnorderedUnreliableWithAcksSender;
-use crate::channel::senders::ChannelSender;
+use crate::channel::senders::{ChannelSend, ChannelSender};
 #[cfg(feature = "trace")]
 use crate::channel::stats::send::ChannelSendStats;
+use crate::connection::netcode::MAX_PACKET_SIZE;
+use crate::packet::packet::{fragment_size, MIN_FRAGMENT_MTU};
 use crate::prelude::ChannelKind;
+use crate::protocol::channel::ChannelId;
 
 /// A ChannelContainer is a struct that implements the [`Channel`] trait
 #[derive(Debug)]
@@ -46,7 +50,8 @@ pub struct ChannelContainer {
 ///     mode: ChannelMode::UnorderedUnreliable,
 ///     direction: ChannelDirection::Bidirectional,
 ///     priority: 1.0,
-/// });
+/// })
+/// .unwrap();
 /// ```
 pub trait Channel: 'static {
     fn get_builder(settings: ChannelSettings) -> ChannelBuilder {
@@ -78,7 +83,7 @@ impl ChannelBuilder {
 impl ChannelContainer {
     pub fn new(settings: ChannelSettings) -> Self {
         let receiver: ChannelReceiver;
-        let sender: ChannelSender;
+        let mut sender: ChannelSender;
         let settings_clone = settings.clone();
         match settings.mode {
             ChannelMode::UnorderedUnreliableWithAcks => {
@@ -93,6 +98,10 @@ impl ChannelContainer {
                 receiver = SequencedUnreliableReceiver::new().into();
                 sender = SequencedUnreliableSender::new(settings.send_frequency).into();
             }
+            ChannelMode::TickBuffered => {
+                receiver = TickBufferedReceiver::new().into();
+                sender = UnorderedUnreliableSender::new(settings.send_frequency).into();
+            }
             ChannelMode::UnorderedReliable(reliable_settings) => {
                 receiver = UnorderedReliableReceiver::new().into();
                 sender = ReliableSender::new(reliable_settings, settings.send_frequency).into();
@@ -106,6 +115,13 @@ impl ChannelContainer {
                 sender = ReliableSender::new(reliable_settings, settings.send_frequency).into();
             }
         }
+        if let Some(fragment_mtu) = settings.fragment_mtu {
+            // the message manager accounts for the bytes reserved by its packet options
+            sender.set_fragment_size(fragment_size(fragment_mtu.min(MAX_PACKET_SIZE), 0));
+        }
+        if let Some(max_fragments_per_message) = settings.max_fragments_per_message {
+            sender.set_max_fragments_per_message(max_fragments_per_message);
+        }
         Self {
             setting: settings_clone,
             receiver,
@@ -125,6 +141,49 @@ pub struct ChannelSettings {
     pub send_frequency: Duration,
     /// Sets the priority of the channel. The final priority of a message will be `MessagePriority * ChannelPriority`
     pub priority: f32,
+    /// Share of the packets that the channel gets when not all messages can be written in packets.
+    ///
+    /// Every time the channel has messages but none of them are written, it accumulates credit
+    /// proportional to its weight; channels with the most credit are written first. The credit is
+    /// reset once the channel is written, so a low-weight channel is never starved forever.
+    pub weight: f32,
+    /// Maximum number of bytes that the channel can send per tick.
+    ///
+    /// Messages that go over the cap stay buffered and will be sent in priority on the next tick.
+    /// The first message of each tick is always sent, even if it is bigger than the cap.
+    /// Set to `None` to not cap the channel's bandwidth.
+    pub max_bytes_per_tick: Option<usize>,
+    /// Minimum number of bytes per tick that are reserved for this channel when the bandwidth
+    /// quota is enabled.
+    ///
+    /// Messages that fit in this share are sent before the rest of the bandwidth is distributed
+    /// by priority, so that a low-priority channel is never completely starved.
+    pub min_bytes_per_tick: Option<usize>,
+    /// Packet size used to split this channel's big messages into fragments, if it should
+    /// differ from the connection's MTU.
+    ///
+    /// Smaller fragments can reduce latency (e.g. for voice), but each fragment of a message is sent in its own packet.
+    /// Set to `None` to use the connection's MTU.
+    pub fragment_mtu: Option<usize>,
+    /// Maximum number of fragments that a single message on this channel can be split into.
+    ///
+    /// Sending a message that would need more fragments returns an error, so that a huge message
+    /// cannot use up all the message ids or the receiver's memory.
+    /// Set to `None` to allow as many fragments as can be written (255, or 65535 with the `big_messages` feature).
+    pub max_fragments_per_message: Option<usize>,
+    /// If true, the messages of this channel are encrypted before being written in a packet.
+    ///
+    /// The cipher is provided to the `PacketBuilder` (and the matching decryption to the receiver);
+    /// the other channels are written in the clear. Big messages that are split into fragments
+    /// are not encrypted.
+    pub encrypted: bool,
+    /// Number of bytes kept free for this channel in every packet, for channels that must never
+    /// wait for the next packet (for example the input state).
+    ///
+    /// The other channels leave that many bytes unused while this channel still has messages
+    /// to write, even if it comes later in packing order. The reservation should cover the
+    /// channel id, the number of messages and the messages. Set to 0 to not reserve anything.
+    pub reserved_bytes: usize,
 }
 
 impl Default for ChannelSettings {
@@ -133,10 +192,88 @@ impl Default for ChannelSettings {
             mode: ChannelMode::UnorderedUnreliable,
             send_frequency: Duration::default(),
             priority: 1.0,
+            weight: 1.0,
+            max_bytes_per_tick: None,
+            min_bytes_per_tick: None,
+            fragment_mtu: None,
+            max_fragments_per_message: None,
+            encrypted: false,
+            reserved_bytes: 0,
+        }
+    }
+}
+
+impl ChannelSettings {
+    /// Check that the settings are consistent, so that the channel can always send its messages
+    pub fn validate(&self) -> Result<(), ChannelConfigError> {
+        if self.priority.is_nan() || self.priority < 0.0 {
+            return Err(ChannelConfigError::InvalidPriority(self.priority));
+        }
+        if !self.weight.is_finite() || self.weight <= 0.0 {
+            return Err(ChannelConfigError::InvalidWeight(self.weight));
+        }
+        if self.max_bytes_per_tick == Some(0) {
+            return Err(ChannelConfigError::ZeroMaxBytesPerTick);
+        }
+        if let (Some(min_bytes_per_tick), Some(max_bytes_per_tick)) =
+            (self.min_bytes_per_tick, self.max_bytes_per_tick)
+        {
+            if min_bytes_per_tick > max_bytes_per_tick {
+                return Err(ChannelConfigError::MinBytesAboveMax {
+                    min_bytes_per_tick,
+                    max_bytes_per_tick,
+                });
+            }
+        }
+        if let Some(fragment_mtu) = self.fragment_mtu {
+            if fragment_mtu < MIN_FRAGMENT_MTU {
+                return Err(ChannelConfigError::FragmentMtuTooSmall {
+                    fragment_mtu,
+                    min: MIN_FRAGMENT_MTU,
+                });
+            }
+        }
+        match &self.mode {
+            ChannelMode::UnorderedReliable(reliable_settings)
+            | ChannelMode::SequencedReliable(reliable_settings)
+            | ChannelMode::OrderedReliable(reliable_settings) => {
+                let factor = reliable_settings.rtt_resend_factor;
+                if !factor.is_finite() || factor <= 0.0 {
+                    return Err(ChannelConfigError::InvalidResendFactor(factor));
+                }
+            }
+            _ => {}
         }
+        Ok(())
     }
 }
 
+/// Contradictory [`ChannelSettings`], rejected when the channel is registered
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ChannelConfigError {
+    #[error("the channel priority must be positive, got {0}")]
+    InvalidPriority(f32),
+    #[error("the channel weight must be positive and finite, got {0}")]
+    InvalidWeight(f32),
+    #[error("the channel cannot send any message with a max_bytes_per_tick of 0")]
+    ZeroMaxBytesPerTick,
+    #[error("min_bytes_per_tick ({min_bytes_per_tick}) is bigger than max_bytes_per_tick ({max_bytes_per_tick})")]
+    MinBytesAboveMax {
+        min_bytes_per_tick: usize,
+        max_bytes_per_tick: usize,
+    },
+    #[error("the fragment_mtu ({fragment_mtu}) must be at least {min} bytes to fit a fragment")]
+    FragmentMtuTooSmall { fragment_mtu: usize, min: usize },
+    #[error("the rtt_resend_factor of a reliable channel must be positive, got {0}")]
+    InvalidResendFactor(f32),
+    #[error("the stable net id {net_id} of channel {channel} is already used by channel {other}")]
+    NetIdCollision {
+        net_id: ChannelId,
+        channel: String,
+        other: String,
+    },
+}
+
 #[derive(Clone, Debug, PartialEq)]
 /// ChannelMode specifies how messages are sent and received
 /// See more information [here](http://www.jenkinssoftware.com/raknet/manual/reliabilitytypes.html)
@@ -149,6 +286,9 @@ pub enum ChannelMode {
     /// Same as unordered unreliable, but only the newest message is ever accepted, older messages
     /// are ignored
     SequencedUnreliable,
+    /// Messages may arrive out-of-order, or not at all. The receiver holds each message until the
+    /// local tick reaches the tick at which the message was sent, then releases them in tick order
+    TickBuffered,
     /// Messages may arrive out-of-order, but we make sure (with retries, acks) that the message
     /// will arrive
     UnorderedReliable(ReliableSettings),
@@ -164,18 +304,47 @@ impl ChannelMode {
             ChannelMode::UnorderedUnreliableWithAcks => false,
             ChannelMode::UnorderedUnreliable => false,
             ChannelMode::SequencedUnreliable => false,
+            ChannelMode::TickBuffered => false,
             ChannelMode::UnorderedReliable(_) => true,
             ChannelMode::SequencedReliable(_) => true,
             ChannelMode::OrderedReliable(_) => true,
         }
     }
 
+    /// Returns true if the receiver depends on the order in which the messages were sent, so
+    /// the messages must be packed in the order in which they were queued
+    pub(crate) fn is_ordered(&self) -> bool {
+        match self {
+            ChannelMode::UnorderedUnreliableWithAcks => false,
+            ChannelMode::UnorderedUnreliable => false,
+            ChannelMode::SequencedUnreliable => true,
+            ChannelMode::TickBuffered => false,
+            ChannelMode::UnorderedReliable(_) => false,
+            ChannelMode::SequencedReliable(_) => true,
+            ChannelMode::OrderedReliable(_) => true,
+        }
+    }
+
+    /// Identifier of the mode (without its settings), used to compare the channel schemas of two peers
+    pub(crate) fn kind_id(&self) -> u8 {
+        match self {
+            ChannelMode::UnorderedUnreliableWithAcks => 0,
+            ChannelMode::UnorderedUnreliable => 1,
+            ChannelMode::SequencedUnreliable => 2,
+            ChannelMode::TickBuffered => 3,
+            ChannelMode::UnorderedReliable(_) => 4,
+            ChannelMode::SequencedReliable(_) => 5,
+            ChannelMode::OrderedReliable(_) => 6,
+        }
+    }
+
     /// Returns true if the channel cares about tracking ACKs of messages
     pub(crate) fn is_watching_acks(&self) -> bool {
         match self {
             ChannelMode::UnorderedUnreliableWithAcks => true,
             ChannelMode::UnorderedUnreliable => false,
             ChannelMode::SequencedUnreliable => false,
+            ChannelMode::TickBuffered => false,
             ChannelMode::UnorderedReliable(_) => true,
             ChannelMode::SequencedReliable(_) => true,
             ChannelMode::OrderedReliable(_) => true,
@@ -197,6 +366,17 @@ pub struct ReliableSettings {
     pub rtt_resend_factor: f32,
     /// Minimum duration to wait before resending a packet if it has not been acked
     pub rtt_resend_min_delay: Duration,
+    /// If set, maximum number of bytes of messages that are resent per tick, the oldest messages
+    /// first. The other messages that need to be resent wait for the next tick.
+    ///
+    /// The first resent message of a tick is always sent, even if it is bigger than the budget.
+    pub max_resend_bytes_per_tick: Option<usize>,
+    /// If set, a message that is still not acked after being resent this many times is dropped
+    /// instead of being resent forever, and reported by
+    /// [`ReliableSender::dropped_messages`](crate::channel::senders::reliable::ReliableSender::dropped_messages)
+    pub max_retries: Option<u32>,
+    /// Clock used to measure how long ago a message was sent, to decide if it must be resent
+    pub retransmit_clock: RetransmitClock,
 }
 
 impl Default for ReliableSettings {
@@ -204,10 +384,26 @@ impl Default for ReliableSettings {
         Self {
             rtt_resend_factor: 1.5,
             rtt_resend_min_delay: Duration::default(),
+            max_resend_bytes_per_tick: None,
+            max_retries: None,
+            retransmit_clock: RetransmitClock::default(),
         }
     }
 }
 
+/// Clock that drives the resend timers of a reliable channel
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RetransmitClock {
+    /// Use the time of the [`TimeManager`](crate::shared::time_manager::TimeManager)
+    #[default]
+    Wall,
+    /// Count the ticks elapsed since a message was sent, each tick lasting `tick_duration`.
+    ///
+    /// The resend delay is then a whole number of ticks, independent of the wall-clock time
+    /// between two updates, which makes deterministic simulations and rollback tests reproducible.
+    Ticks { tick_duration: Duration },
+}
+
 impl ReliableSettings {
     pub(crate) fn resend_delay(&self, rtt: Duration) -> Duration {
         let delay = rtt.mul_f32(self.rtt_resend_factor);
@@ -5,4 +5,17 @@ pub type Result<T> = core::result::Result<T, ChannelReceiveError>;
 pub enum ChannelReceiveError {
     #[error("A message was received without a message ID")]
     MissingMessageId,
+    #[error(transparent)]
+    Reassembly(#[from] ReassemblyError),
+}
+
+/// Errors when putting the fragments of a message back together
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ReassemblyError {
+    #[error("Fragment {fragment_id} starts at byte {offset} of the message, but the previous fragments end at byte {expected}")]
+    OffsetMismatch {
+        fragment_id: usize,
+        expected: usize,
+        offset: usize,
+    },
 }
@@ -1,48 +1,138 @@
 use std::collections::HashMap;
+use std::ops::Range;
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use tracing::trace;
 
-use crate::packet::message::{FragmentData, MessageId};
-use crate::packet::packet::FRAGMENT_SIZE;
+use crate::channel::receivers::error::ReassemblyError;
+use crate::packet::message::{FragmentData, FragmentIndex, MessageId};
 use crate::prelude::Tick;
 use crate::shared::time_manager::WrappedTime;
 
+/// Whether a fragmented message can be delivered when some of its fragments are missing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FragmentReliability {
+    /// The message is only delivered once every fragment is received
+    #[default]
+    AllOrNothing,
+    /// A message that can't be completed is still delivered (see [`FragmentReceiver::cleanup`])
+    /// if at least `min_fraction` of its fragments were received, for example for best-effort
+    /// blobs where the missing parts can be replaced by a placeholder
+    Partial { min_fraction: f32 },
+}
+
+/// A fragmented message that was delivered without some of its fragments (see
+/// [`FragmentReliability::Partial`])
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialMessage {
+    pub message_id: MessageId,
+    /// Tick at which the first fragment received was sent
+    pub tick: Tick,
+    /// The received fragments at their offset in the message. The missing fragments between two
+    /// received fragments are filled with zeros, the missing fragments at the end of the message
+    /// are left out (their size is unknown)
+    pub bytes: Bytes,
+    /// Ranges of the fragments that were not received
+    pub missing_fragments: Vec<Range<FragmentIndex>>,
+}
+
 /// `FragmentReceiver` is used to reconstruct fragmented messages
 #[derive(Debug)]
 pub struct FragmentReceiver {
     fragment_messages: HashMap<MessageId, FragmentConstructor>,
+    fragment_reliability: FragmentReliability,
 }
 
 impl FragmentReceiver {
     pub fn new() -> Self {
         Self {
             fragment_messages: HashMap::new(),
+            fragment_reliability: FragmentReliability::default(),
         }
     }
 
+    pub fn with_fragment_reliability(mut self, fragment_reliability: FragmentReliability) -> Self {
+        self.fragment_reliability = fragment_reliability;
+        self
+    }
+
     /// Discard all messages for which the latest fragment was received before the cleanup time
     /// (i.e. we probably lost some fragments and we will never complete the message)
     ///
-    /// If we don't keep track of the last received time, we will never clean up the messages.
-    pub fn cleanup(&mut self, cleanup_time: WrappedTime) {
-        self.fragment_messages.retain(|_, c| {
-            c.last_received
-                .map(|t| t > cleanup_time)
-                .unwrap_or_else(|| true)
+    /// With [`FragmentReliability::Partial`], the discarded messages that have enough fragments
+    /// are returned instead of being dropped. If we don't keep track of the last received time,
+    /// we will never clean up the messages.
+    pub fn cleanup(&mut self, cleanup_time: WrappedTime) -> Vec<PartialMessage> {
+        let mut partial_messages = vec![];
+        self.fragment_messages.retain(|message_id, c| {
+            if c.last_received.map_or(true, |t| t > cleanup_time) {
+                return true;
+            }
+            if let FragmentReliability::Partial { min_fraction } = self.fragment_reliability {
+                if c.num_received_fragments as f32 >= min_fraction * c.num_fragments as f32 {
+                    match c.partial_message(*message_id) {
+                        Ok(message) => partial_messages.push(message),
+                        Err(e) => trace!(?message_id, ?e, "Dropping partial message"),
+                    }
+                }
+            }
+            false
+        });
+        partial_messages
+    }
+
+    /// Total number of fragments of the message `message_id`, if at least one of its fragments was
+    /// received (every fragment carries the total count, so it is known from the first one received)
+    pub fn num_fragments(&self, message_id: MessageId) -> Option<usize> {
+        self.fragment_messages
+            .get(&message_id)
+            .map(|constructor| constructor.num_fragments)
+    }
+
+    /// Number of fragments of the message `message_id` that were received, and total number of
+    /// fragments, while the message is being reassembled (for example to show the progress of
+    /// a download). Returns `None` before the first fragment and once the message is complete.
+    pub fn progress(&self, message_id: MessageId) -> Option<(usize, usize)> {
+        self.fragment_messages.get(&message_id).map(|constructor| {
+            (
+                constructor.num_received_fragments,
+                constructor.num_fragments,
+            )
         })
     }
 
+    /// Fragments of the message `message_id` that have not been received yet, if at least one of
+    /// its fragments was received, so that they can be requested from the sender (see
+    /// [`ReliableSender::receive_fragment_nack`](crate::channel::senders::reliable::ReliableSender::receive_fragment_nack)).
+    /// The requests are not sent by the packet layer.
+    pub fn missing_fragments(&self, message_id: MessageId) -> Vec<FragmentIndex> {
+        self.fragment_messages
+            .get(&message_id)
+            .map(|constructor| {
+                constructor
+                    .received
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, received)| !**received)
+                    .map(|(fragment_id, _)| fragment_id as FragmentIndex)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// Receive a fragment of a FragmentData message.
     ///
     /// When we complete the final message by aggregating all fragments, we will return the
     /// `remote_sent_tick` associated with the first fragment received.
+    ///
+    /// Returns an error, and drops the fragments of the message, if the offsets of the fragments
+    /// do not match their sizes.
     pub fn receive_fragment(
         &mut self,
         fragment: FragmentData,
         remote_sent_tick: Tick,
         current_time: Option<WrappedTime>,
-    ) -> Option<(Tick, Bytes)> {
+    ) -> Result<Option<(Tick, Bytes)>, ReassemblyError> {
         let fragment_message = self
             .fragment_messages
             .entry(fragment.message_id)
@@ -50,17 +140,17 @@ impl FragmentReceiver {
                 FragmentConstructor::new(remote_sent_tick, fragment.num_fragments as usize)
             });
 
-        // completed the fragmented message!
-        if let Some(payload) = fragment_message.receive_fragment(
+        let result = fragment_message.receive_fragment(
             fragment.fragment_id as usize,
-            fragment.bytes.as_ref(),
+            fragment.offset as usize,
+            fragment.bytes,
             current_time,
-        ) {
+        );
+        // completed the fragmented message, or it can never be completed
+        if !matches!(result, Ok(None)) {
             self.fragment_messages.remove(&fragment.message_id);
-            return Some(payload);
         }
-
-        None
+        result
     }
 }
 
@@ -70,8 +160,11 @@ pub struct FragmentConstructor {
     num_fragments: usize,
     num_received_fragments: usize,
     received: Vec<bool>,
-    // bytes: Bytes,
-    bytes: Vec<u8>,
+    /// Fragments can have different sizes depending on the
+    /// [`FragmentationPolicy`](crate::channel::senders::fragment_sender::FragmentationPolicy),
+    /// so we keep them separate (along with their offset in the message) until all of them are
+    /// received
+    fragments: Vec<(usize, Bytes)>,
 
     tick: Tick,
     last_received: Option<WrappedTime>,
@@ -83,70 +176,261 @@ impl FragmentConstructor {
             num_fragments,
             num_received_fragments: 0,
             received: vec![false; num_fragments],
-            bytes: vec![0; num_fragments * FRAGMENT_SIZE],
+            fragments: vec![(0, Bytes::new()); num_fragments],
             tick,
             last_received: None,
         }
     }
 
+    /// Receive the fragment `fragment_index`, which starts at byte `offset` of the message.
+    ///
+    /// Once every fragment is received, checks that each fragment starts where the previous one
+    /// ends before returning the message.
     pub fn receive_fragment(
         &mut self,
         fragment_index: usize,
-        bytes: &[u8],
+        offset: usize,
+        bytes: Bytes,
         received_time: Option<WrappedTime>,
-    ) -> Option<(Tick, Bytes)> {
+    ) -> Result<Option<(Tick, Bytes)>, ReassemblyError> {
         self.last_received = received_time;
 
-        let is_last_fragment = fragment_index == self.num_fragments - 1;
-
-        // TODO: check sizes?
-
         if !self.received[fragment_index] {
             self.received[fragment_index] = true;
             self.num_received_fragments += 1;
-
-            if is_last_fragment {
-                let len = (self.num_fragments - 1) * FRAGMENT_SIZE + bytes.len();
-                self.bytes.resize(len, 0);
-            }
-
-            let start = fragment_index * FRAGMENT_SIZE;
-            let end = start + bytes.len();
-            self.bytes[start..end].copy_from_slice(bytes);
+            self.fragments[fragment_index] = (offset, bytes);
         }
 
         if self.num_received_fragments == self.num_fragments {
             trace!("Received all fragments!");
-            let payload = std::mem::take(&mut self.bytes);
-            return Some((self.tick, payload.into()));
+            let fragments = std::mem::take(&mut self.fragments);
+            let mut payload =
+                BytesMut::with_capacity(fragments.iter().map(|(_, bytes)| bytes.len()).sum());
+            for (fragment_id, (offset, fragment)) in fragments.into_iter().enumerate() {
+                if offset != payload.len() {
+                    return Err(ReassemblyError::OffsetMismatch {
+                        fragment_id,
+                        expected: payload.len(),
+                        offset,
+                    });
+                }
+                payload.put(fragment);
+            }
+            return Ok(Some((self.tick, payload.freeze())));
         }
 
-        None
+        Ok(None)
+    }
+
+    /// Put the received fragments together, leaving gaps for the missing ones (see
+    /// [`PartialMessage`])
+    fn partial_message(&self, message_id: MessageId) -> Result<PartialMessage, ReassemblyError> {
+        let mut payload = BytesMut::new();
+        let mut missing_fragments: Vec<Range<FragmentIndex>> = vec![];
+        for (fragment_id, (offset, fragment)) in self.fragments.iter().enumerate() {
+            if !self.received[fragment_id] {
+                let fragment_id = fragment_id as FragmentIndex;
+                match missing_fragments.last_mut() {
+                    Some(range) if range.end == fragment_id => range.end += 1,
+                    _ => missing_fragments.push(fragment_id..fragment_id + 1),
+                }
+                continue;
+            }
+            // a missing fragment before this one can have any size, but it cannot overlap
+            let follows_missing = fragment_id > 0 && !self.received[fragment_id - 1];
+            if *offset < payload.len() || (!follows_missing && *offset != payload.len()) {
+                return Err(ReassemblyError::OffsetMismatch {
+                    fragment_id,
+                    expected: payload.len(),
+                    offset: *offset,
+                });
+            }
+            payload.resize(*offset, 0);
+            payload.put(fragment.clone());
+        }
+        Ok(PartialMessage {
+            message_id,
+            tick: self.tick,
+            bytes: payload.freeze(),
+            missing_fragments,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::channel::senders::fragment_sender::FragmentSender;
+    use crate::channel::senders::fragment_sender::{FragmentSender, FRAGMENT_BYTES};
 
     use super::*;
 
     #[test]
     fn test_receiver() {
         let mut receiver = FragmentReceiver::new();
-        let num_bytes = (FRAGMENT_SIZE as f32 * 1.5) as usize;
+        let num_bytes = (FRAGMENT_BYTES as f32 * 1.5) as usize;
         let message_bytes = Bytes::from(vec![1u8; num_bytes]);
-        let fragments = FragmentSender::new()
+        let fragments = FragmentSender::default()
             .build_fragments(MessageId(0), None, message_bytes.clone())
             .unwrap();
 
         assert_eq!(
             receiver.receive_fragment(fragments[0].clone(), Tick(0), None),
-            None
+            Ok(None)
         );
         assert_eq!(
             receiver.receive_fragment(fragments[1].clone(), Tick(1), None),
-            Some((Tick(0), message_bytes.clone()))
+            Ok(Some((Tick(0), message_bytes.clone())))
+        );
+    }
+
+    /// Any fragment tells the receiver how many fragments the message has
+    #[test]
+    fn test_receiver_num_fragments_from_any_fragment() {
+        let mut receiver = FragmentReceiver::new();
+        let message_bytes = Bytes::from(vec![1u8; FRAGMENT_BYTES * 3]);
+        let fragments = FragmentSender::default()
+            .build_fragments(MessageId(0), None, message_bytes.clone())
+            .unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.num_fragments == 3));
+        assert_eq!(receiver.num_fragments(MessageId(0)), None);
+
+        // a fragment in the middle of the message is received first
+        assert_eq!(
+            receiver.receive_fragment(fragments[1].clone(), Tick(0), None),
+            Ok(None)
+        );
+        assert_eq!(receiver.num_fragments(MessageId(0)), Some(3));
+        assert_eq!(
+            receiver.receive_fragment(fragments[2].clone(), Tick(0), None),
+            Ok(None)
         );
+        assert_eq!(
+            receiver.receive_fragment(fragments[0].clone(), Tick(0), None),
+            Ok(Some((Tick(0), message_bytes)))
+        );
+        assert_eq!(receiver.num_fragments(MessageId(0)), None);
+    }
+
+    #[test]
+    fn test_receiver_progress() {
+        let mut receiver = FragmentReceiver::new();
+        let message_bytes = Bytes::from(vec![1u8; FRAGMENT_BYTES * 5]);
+        let fragments = FragmentSender::default()
+            .build_fragments(MessageId(0), None, message_bytes.clone())
+            .unwrap();
+        assert_eq!(fragments.len(), 5);
+        assert_eq!(receiver.progress(MessageId(0)), None);
+
+        for fragment in [&fragments[3], &fragments[0]] {
+            assert_eq!(
+                receiver.receive_fragment(fragment.clone(), Tick(0), None),
+                Ok(None)
+            );
+        }
+        assert_eq!(receiver.progress(MessageId(0)), Some((2, 5)));
+        // a duplicate fragment is not counted twice
+        assert_eq!(
+            receiver.receive_fragment(fragments[0].clone(), Tick(0), None),
+            Ok(None)
+        );
+        assert_eq!(receiver.progress(MessageId(0)), Some((2, 5)));
+
+        for fragment in [&fragments[1], &fragments[2]] {
+            receiver
+                .receive_fragment(fragment.clone(), Tick(0), None)
+                .unwrap();
+        }
+        assert_eq!(receiver.progress(MessageId(0)), Some((4, 5)));
+        assert_eq!(
+            receiver.receive_fragment(fragments[4].clone(), Tick(0), None),
+            Ok(Some((Tick(0), message_bytes)))
+        );
+        assert_eq!(receiver.progress(MessageId(0)), None);
+    }
+
+    /// A fragment whose offset does not follow the previous fragments is rejected
+    #[test]
+    fn test_receiver_offset_mismatch() {
+        let mut receiver = FragmentReceiver::new();
+        let message_bytes = Bytes::from(vec![1u8; FRAGMENT_BYTES * 2]);
+        let mut fragments = FragmentSender::default()
+            .build_fragments(MessageId(0), None, message_bytes)
+            .unwrap();
+        assert_eq!(fragments[1].offset, FRAGMENT_BYTES as u32);
+        fragments[1].offset -= 1;
+
+        assert_eq!(
+            receiver.receive_fragment(fragments[0].clone(), Tick(0), None),
+            Ok(None)
+        );
+        assert_eq!(
+            receiver.receive_fragment(fragments[1].clone(), Tick(0), None),
+            Err(ReassemblyError::OffsetMismatch {
+                fragment_id: 1,
+                expected: FRAGMENT_BYTES,
+                offset: FRAGMENT_BYTES - 1,
+            })
+        );
+        // the fragments of the message are dropped
+        assert_eq!(receiver.num_fragments(MessageId(0)), None);
+    }
+
+    /// A message with enough fragments is delivered with zeros in place of the missing
+    /// fragments when it is cleaned up
+    #[test]
+    fn test_receiver_partial_message() {
+        let message_bytes = Bytes::from_iter((0..10u8).flat_map(|i| vec![i + 1; FRAGMENT_BYTES]));
+        let fragments = FragmentSender::default()
+            .build_fragments(MessageId(0), None, message_bytes.clone())
+            .unwrap();
+        assert_eq!(fragments.len(), 10);
+        let receive = |receiver: &mut FragmentReceiver, lost: &[usize]| {
+            for fragment in fragments.iter() {
+                if lost.contains(&(fragment.fragment_id as usize)) {
+                    continue;
+                }
+                assert_eq!(
+                    receiver.receive_fragment(
+                        fragment.clone(),
+                        Tick(2),
+                        Some(WrappedTime::new(100))
+                    ),
+                    Ok(None)
+                );
+            }
+        };
+
+        // 8 of the 10 fragments are received
+        let mut receiver = FragmentReceiver::new()
+            .with_fragment_reliability(FragmentReliability::Partial { min_fraction: 0.7 });
+        receive(&mut receiver, &[3, 9]);
+        assert!(receiver.cleanup(WrappedTime::new(50)).is_empty());
+        let partial_messages = receiver.cleanup(WrappedTime::new(200));
+        assert_eq!(partial_messages.len(), 1);
+        let message = &partial_messages[0];
+        assert_eq!(message.message_id, MessageId(0));
+        assert_eq!(message.tick, Tick(2));
+        assert_eq!(message.missing_fragments, vec![3..4, 9..10]);
+        assert_eq!(message.bytes.len(), 9 * FRAGMENT_BYTES);
+        let gap = 3 * FRAGMENT_BYTES..4 * FRAGMENT_BYTES;
+        assert!(message.bytes[gap.clone()].iter().all(|byte| *byte == 0));
+        assert_eq!(message.bytes[..gap.start], message_bytes[..gap.start]);
+        assert_eq!(
+            message.bytes[gap.end..],
+            message_bytes[gap.end..9 * FRAGMENT_BYTES]
+        );
+        assert_eq!(receiver.num_fragments(MessageId(0)), None);
+
+        // not enough fragments
+        let mut receiver = FragmentReceiver::new()
+            .with_fragment_reliability(FragmentReliability::Partial { min_fraction: 0.7 });
+        receive(&mut receiver, &[0, 3, 4, 9]);
+        assert!(receiver.cleanup(WrappedTime::new(200)).is_empty());
+
+        // the message is dropped without partial delivery
+        let mut receiver = FragmentReceiver::new();
+        receive(&mut receiver, &[3, 9]);
+        assert!(receiver.cleanup(WrappedTime::new(200)).is_empty());
+        assert_eq!(receiver.num_fragments(MessageId(0)), None);
     }
 }
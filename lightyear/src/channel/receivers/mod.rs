@@ -20,6 +20,9 @@ pub(crate) mod sequenced_reliable;
 /// Receive messages in an Sequenced Unreliable manner
 pub(crate) mod sequenced_unreliable;
 
+/// Receive messages and hold them until the local tick reaches the tick they were sent at
+pub(crate) mod tick_buffered;
+
 /// Receive messages in an Unordered Reliable manner
 pub(crate) mod unordered_reliable;
 
@@ -49,4 +52,5 @@ pub enum ChannelReceiver {
     OrderedReliable(ordered_reliable::OrderedReliableReceiver),
     SequencedReliable(sequenced_reliable::SequencedReliableReceiver),
     UnorderedReliable(unordered_reliable::UnorderedReliableReceiver),
+    TickBuffered(tick_buffered::TickBufferedReceiver),
 }
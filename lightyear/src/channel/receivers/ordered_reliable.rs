@@ -5,7 +5,7 @@ use bytes::Bytes;
 use super::error::{ChannelReceiveError, Result};
 use crate::channel::receivers::fragment_receiver::FragmentReceiver;
 use crate::channel::receivers::ChannelReceive;
-use crate::packet::message::{MessageData, MessageId, ReceiveMessage};
+use crate::packet::message::{MessageData, MessageId, ReceiveMessage, SingleData};
 use crate::prelude::Tick;
 pub use crate::shared::tick_manager::TickManager;
 pub use crate::shared::time_manager::TimeManager;
@@ -31,6 +31,16 @@ impl OrderedReliableReceiver {
             fragment_receiver: FragmentReceiver::new(),
         }
     }
+
+    /// Pop the next message of the channel's sequence, with its [`MessageId`].
+    ///
+    /// The [`MessageId`]s are assigned by the channel's sender, so they form a sequence that is
+    /// specific to the channel. A missing message blocks the following ones until it is received.
+    pub fn pop_ordered(&mut self) -> Option<SingleData> {
+        let message_id = self.pending_recv_message_id;
+        self.read_message()
+            .map(|(_, bytes)| SingleData::with_id(message_id, bytes))
+    }
 }
 
 impl ChannelReceive for OrderedReliableReceiver {
@@ -59,7 +69,7 @@ impl ChannelReceive for OrderedReliableReceiver {
                         fragment,
                         message.remote_sent_tick,
                         None,
-                    ) {
+                    )? {
                         entry.insert(res);
                     }
                 }
@@ -143,4 +153,54 @@ mod tests {
         );
         Ok(())
     }
+
+    fn receive(receiver: &mut OrderedReliableReceiver, id: u16) -> Result<(), PacketError> {
+        receiver.buffer_recv(ReceiveMessage {
+            data: SingleData::with_id(MessageId(id), Bytes::from(id.to_be_bytes().to_vec())).into(),
+            remote_sent_tick: Tick(0),
+        })?;
+        Ok(())
+    }
+
+    /// Messages are released in the order of the sequence, a gap blocks the next messages
+    #[test]
+    fn test_pop_ordered() -> Result<(), PacketError> {
+        let mut receiver = OrderedReliableReceiver::new();
+        receive(&mut receiver, 0)?;
+        receive(&mut receiver, 2)?;
+        receive(&mut receiver, 3)?;
+        assert_eq!(receiver.pop_ordered().unwrap().id, Some(MessageId(0)));
+        // message 1 is missing
+        assert_eq!(receiver.pop_ordered(), None);
+
+        receive(&mut receiver, 1)?;
+        let released: Vec<_> = std::iter::from_fn(|| receiver.pop_ordered())
+            .map(|data| data.id.unwrap())
+            .collect();
+        assert_eq!(released, vec![MessageId(1), MessageId(2), MessageId(3)]);
+        Ok(())
+    }
+
+    /// The sequence continues across the wraparound of the message ids
+    #[test]
+    fn test_pop_ordered_wrapping() -> Result<(), PacketError> {
+        let mut receiver = OrderedReliableReceiver::new();
+        receiver.pending_recv_message_id = MessageId(u16::MAX - 1);
+        for id in [0, u16::MAX, 1, u16::MAX - 1] {
+            receive(&mut receiver, id)?;
+        }
+        let released: Vec<_> = std::iter::from_fn(|| receiver.pop_ordered())
+            .map(|data| data.id.unwrap())
+            .collect();
+        assert_eq!(
+            released,
+            vec![
+                MessageId(u16::MAX - 1),
+                MessageId(u16::MAX),
+                MessageId(0),
+                MessageId(1)
+            ]
+        );
+        Ok(())
+    }
 }
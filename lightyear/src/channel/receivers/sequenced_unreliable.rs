@@ -71,7 +71,7 @@ impl ChannelReceive for SequencedUnreliableReceiver {
                     fragment,
                     message.remote_sent_tick,
                     Some(self.current_time),
-                ) {
+                )? {
                     self.recv_message_buffer.push_back(res);
                 }
             }
@@ -152,4 +152,27 @@ mod tests {
         assert_eq!(receiver.recv_message_buffer.len(), 0);
         Ok(())
     }
+
+    #[test]
+    fn test_sequenced_unreliable_receiver_drops_stale() -> Result<(), PacketError> {
+        let mut receiver = SequencedUnreliableReceiver::new();
+        let mut received = vec![];
+        let mut receive = |receiver: &mut SequencedUnreliableReceiver, ids: &[u16]| {
+            for id in ids {
+                receiver.buffer_recv(ReceiveMessage {
+                    data: SingleData::new(Some(MessageId(*id)), Bytes::from(vec![0; 1])).into(),
+                    remote_sent_tick: Tick(*id),
+                })?;
+                while let Some((tick, _)) = receiver.read_message() {
+                    received.push(tick.0);
+                }
+            }
+            Ok::<_, PacketError>(())
+        };
+        receive(&mut receiver, &[0, 2, 1, 3])?;
+        // the ids wrap around: after 65535, the id 0 is the most recent one
+        receive(&mut receiver, &[30000, 60000, 65535, 65534, 0, 1, 65535])?;
+        assert_eq!(received, vec![0, 2, 3, 30000, 60000, 65535, 0, 1]);
+        Ok(())
+    }
 }
@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+use crate::channel::receivers::error::ChannelReceiveError;
+use crate::channel::receivers::fragment_receiver::FragmentReceiver;
+use crate::channel::receivers::ChannelReceive;
+use crate::packet::message::{MessageData, ReceiveMessage};
+use crate::prelude::Tick;
+use crate::shared::tick_manager::TickManager;
+use crate::shared::time_manager::{TimeManager, WrappedTime};
+
+const DISCARD_AFTER: chrono::Duration = chrono::Duration::milliseconds(3000);
+
+/// A message released by the [`TickBufferedReceiver`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TickBufferedMessage {
+    /// Tick at which the message should be processed (the tick of the packet that contained it)
+    pub tick: Tick,
+    pub bytes: Bytes,
+    /// True if the local tick was already past the message's tick when the message arrived
+    pub late: bool,
+}
+
+/// Tick Buffered receiver:
+/// hold each message until the local tick reaches the tick of the message, then release the
+/// messages in tick order
+#[derive(Debug)]
+pub struct TickBufferedReceiver {
+    /// Messages that are waiting for their tick, sorted by tick
+    pending_messages: VecDeque<(Tick, Bytes)>,
+    /// Messages that can be released
+    ready_messages: VecDeque<TickBufferedMessage>,
+    fragment_receiver: FragmentReceiver,
+    current_time: WrappedTime,
+    current_tick: Tick,
+}
+
+impl TickBufferedReceiver {
+    pub fn new() -> Self {
+        Self {
+            pending_messages: VecDeque::new(),
+            ready_messages: VecDeque::new(),
+            fragment_receiver: FragmentReceiver::new(),
+            current_time: WrappedTime::default(),
+            current_tick: Tick(0),
+        }
+    }
+
+    fn buffer_message(&mut self, tick: Tick, bytes: Bytes) {
+        if tick < self.current_tick {
+            // the tick is already in the past: release the message immediately
+            self.ready_messages.push_back(TickBufferedMessage {
+                tick,
+                bytes,
+                late: true,
+            });
+            return;
+        }
+        // keep messages with the same tick in the order in which they were received
+        let index = self.pending_messages.partition_point(|(t, _)| *t <= tick);
+        self.pending_messages.insert(index, (tick, bytes));
+    }
+
+    /// Release all the messages whose tick is lower or equal to `current_tick`, in tick order
+    /// (late messages come first)
+    pub fn drain_up_to(&mut self, current_tick: Tick) -> Vec<TickBufferedMessage> {
+        self.release_up_to(current_tick);
+        self.ready_messages.drain(..).collect()
+    }
+
+    fn release_up_to(&mut self, current_tick: Tick) {
+        let num_ready = self
+            .pending_messages
+            .partition_point(|(tick, _)| *tick <= current_tick);
+        self.ready_messages
+            .extend(
+                self.pending_messages
+                    .drain(..num_ready)
+                    .map(|(tick, bytes)| TickBufferedMessage {
+                        tick,
+                        bytes,
+                        late: false,
+                    }),
+            );
+    }
+}
+
+impl ChannelReceive for TickBufferedReceiver {
+    fn update(&mut self, time_manager: &TimeManager, tick_manager: &TickManager) {
+        self.current_time = time_manager.current_time();
+        self.current_tick = tick_manager.tick();
+        self.fragment_receiver
+            .cleanup(self.current_time - DISCARD_AFTER);
+    }
+
+    fn buffer_recv(&mut self, message: ReceiveMessage) -> Result<(), ChannelReceiveError> {
+        match message.data {
+            MessageData::Single(single) => {
+                self.buffer_message(message.remote_sent_tick, single.bytes)
+            }
+            MessageData::Fragment(fragment) => {
+                if let Some((tick, bytes)) = self.fragment_receiver.receive_fragment(
+                    fragment,
+                    message.remote_sent_tick,
+                    Some(self.current_time),
+                )? {
+                    self.buffer_message(tick, bytes);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_message(&mut self) -> Option<(Tick, Bytes)> {
+        self.release_up_to(self.current_tick);
+        self.ready_messages
+            .pop_front()
+            .map(|message| (message.tick, message.bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::packet::message::SingleData;
+
+    use super::*;
+
+    fn receive(receiver: &mut TickBufferedReceiver, tick: Tick, bytes: &'static str) {
+        receiver
+            .buffer_recv(ReceiveMessage {
+                data: SingleData::new(None, Bytes::from(bytes)).into(),
+                remote_sent_tick: tick,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tick_buffered_receiver_internals() {
+        let mut receiver = TickBufferedReceiver::new();
+        receiver.current_tick = Tick(10);
+
+        // messages for future ticks are held
+        receive(&mut receiver, Tick(13), "c");
+        receive(&mut receiver, Tick(11), "a");
+        receive(&mut receiver, Tick(12), "b");
+        receive(&mut receiver, Tick(11), "a2");
+        assert_eq!(receiver.read_message(), None);
+        assert!(receiver.drain_up_to(Tick(10)).is_empty());
+
+        // messages are released in tick order as the tick advances
+        let drained = receiver.drain_up_to(Tick(12));
+        assert_eq!(
+            drained
+                .iter()
+                .map(|m| (m.tick, m.bytes.clone(), m.late))
+                .collect::<Vec<_>>(),
+            vec![
+                (Tick(11), Bytes::from("a"), false),
+                (Tick(11), Bytes::from("a2"), false),
+                (Tick(12), Bytes::from("b"), false),
+            ]
+        );
+
+        // a message for a past tick is released immediately, and flagged as late
+        receive(&mut receiver, Tick(9), "late");
+        assert_eq!(
+            receiver.drain_up_to(Tick(10)),
+            vec![TickBufferedMessage {
+                tick: Tick(9),
+                bytes: Bytes::from("late"),
+                late: true,
+            }]
+        );
+
+        // read_message uses the tick of the last update
+        receiver.current_tick = Tick(13);
+        assert_eq!(receiver.read_message(), Some((Tick(13), Bytes::from("c"))));
+        assert_eq!(receiver.read_message(), None);
+    }
+
+    /// The messages are released in tick order when the tick wraps around
+    #[test]
+    fn test_tick_buffered_receiver_tick_wrapping() {
+        let mut receiver = TickBufferedReceiver::new();
+        receiver.current_tick = Tick(65534);
+
+        receive(&mut receiver, Tick(1), "d");
+        receive(&mut receiver, Tick(65535), "b");
+        receive(&mut receiver, Tick(0), "c");
+        // just before the current tick
+        receive(&mut receiver, Tick(65533), "a");
+
+        let drained = receiver.drain_up_to(Tick(0));
+        assert_eq!(
+            drained
+                .iter()
+                .map(|m| (m.tick, m.bytes.clone(), m.late))
+                .collect::<Vec<_>>(),
+            vec![
+                (Tick(65533), Bytes::from("a"), true),
+                (Tick(65535), Bytes::from("b"), false),
+                (Tick(0), Bytes::from("c"), false),
+            ]
+        );
+
+        // after the wrap-around, a message from before it is late
+        receiver.current_tick = Tick(1);
+        receive(&mut receiver, Tick(65535), "late");
+        assert_eq!(
+            receiver.drain_up_to(Tick(1)),
+            vec![
+                TickBufferedMessage {
+                    tick: Tick(65535),
+                    bytes: Bytes::from("late"),
+                    late: true,
+                },
+                TickBufferedMessage {
+                    tick: Tick(1),
+                    bytes: Bytes::from("d"),
+                    late: false,
+                },
+            ]
+        );
+    }
+}
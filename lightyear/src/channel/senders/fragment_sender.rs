@@ -1,82 +1,371 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::Arc;
+
 use bytes::Bytes;
+use tracing::trace;
 
-use crate::packet::message::{FragmentData, FragmentIndex, MessageId};
+use crate::packet::message::{FragmentData, FragmentIndex, MessageId, SingleData};
 use crate::packet::packet::FRAGMENT_SIZE;
+use crate::protocol::channel::ChannelId;
+use crate::serialize::varint::varint_len;
 use crate::serialize::SerializationError;
 use crate::shared::tick_manager::Tick;
 
-/// `FragmentReceiver` is used to reconstruct fragmented messages
+/// Decides how a message that is too big to fit in a single packet is split into fragments.
+///
+/// This can be used for example to split a media stream on frame boundaries.
+pub trait FragmentationPolicy: Debug + Send + Sync {
+    /// Returns the `(offset, len)` of each fragment of `bytes`.
+    ///
+    /// The fragments must be contiguous, cover all the bytes of the message, and each fragment
+    /// must be at most `mtu` bytes long. Otherwise building the fragments fails with
+    /// [`SerializationError::InvalidFragment`].
+    fn fragments(&self, bytes: &[u8], mtu: usize) -> Vec<(usize, usize)>;
+}
+
+/// Default [`FragmentationPolicy`]: every fragment is `mtu` bytes long, apart from the last one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FixedSizeFragmentation;
+
+impl FragmentationPolicy for FixedSizeFragmentation {
+    fn fragments(&self, bytes: &[u8], mtu: usize) -> Vec<(usize, usize)> {
+        (0..bytes.len())
+            .step_by(mtu)
+            .map(|offset| (offset, mtu.min(bytes.len() - offset)))
+            .collect()
+    }
+}
+
+/// How a message was split into fragments (see [`FragmentSender::build_fragments_with_info`])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentationInfo {
+    /// Number of fragments of the message
+    pub num_fragments: usize,
+    /// Number of bytes of each fragment, in order
+    pub fragment_bytes: Vec<usize>,
+    /// True if the last fragment uses at most half of the fragment size, so that the rest of its
+    /// packet can hold other messages (the packer fills fragment packets with small messages, or
+    /// with the last fragments of other messages)
+    pub tail_packable: bool,
+}
+
+impl FragmentationInfo {
+    /// Number of bytes of the last fragment
+    pub fn last_fragment_bytes(&self) -> usize {
+        self.fragment_bytes.last().copied().unwrap_or_default()
+    }
+}
+
+/// Maximum number of fragments that a message can be split into, limited by the size of [`FragmentIndex`]
+pub(crate) const MAX_FRAGMENTS_PER_MESSAGE: usize = FragmentIndex::MAX as usize;
+
+/// `FragmentSender` is used to split big messages into multiple fragments
 #[derive(Debug)]
 pub(crate) struct FragmentSender {
+    /// Number of bytes available in a packet for a fragment and its offset in the message
     pub(crate) fragment_size: usize,
+    /// Maximum number of fragments that a single message can be split into
+    /// (at most [`MAX_FRAGMENTS_PER_MESSAGE`])
+    pub(crate) max_fragments_per_message: usize,
+    policy: Arc<dyn FragmentationPolicy>,
+}
+
+impl Default for FragmentSender {
+    fn default() -> Self {
+        Self::new(MAX_FRAGMENTS_PER_MESSAGE)
+    }
 }
 
 impl FragmentSender {
-    pub fn new() -> Self {
+    /// Create a `FragmentSender` that returns an error instead of splitting a message into more
+    /// than `max_fragments_per_message` fragments.
+    ///
+    /// Messages that are bigger than that should be split into smaller messages by the caller.
+    pub fn new(max_fragments_per_message: usize) -> Self {
         Self {
             // TODO: make this overridable?
             fragment_size: FRAGMENT_SIZE,
+            max_fragments_per_message,
+            policy: Arc::new(FixedSizeFragmentation),
+        }
+    }
+
+    /// Use a custom [`FragmentationPolicy`] to split messages
+    pub fn with_policy(mut self, policy: Arc<dyn FragmentationPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Turn a message of `channel_id` into the input of
+    /// [`PacketBuilder::build_packets`](crate::packet::packet_builder::PacketBuilder::build_packets):
+    /// a single message if it fits in a packet, or its fragments if it does not.
+    ///
+    /// The fragments are identified by `id`, so a message that needs to be fragmented must
+    /// have one.
+    pub(crate) fn prepare_message(
+        &self,
+        channel_id: ChannelId,
+        id: Option<MessageId>,
+        bytes: Bytes,
+    ) -> Result<(VecDeque<SingleData>, VecDeque<FragmentData>), SerializationError> {
+        if bytes.len() <= self.fragment_size {
+            return Ok((
+                VecDeque::from([SingleData::new(id, bytes)]),
+                VecDeque::new(),
+            ));
         }
+        let Some(id) = id else {
+            return Err(SerializationError::MissingFragmentMessageId {
+                channel_id,
+                size: bytes.len(),
+            });
+        };
+        let fragments = self.build_fragments(id, None, bytes)?;
+        Ok((VecDeque::new(), fragments.into()))
+    }
+
+    /// Maximum number of bytes of each fragment of a message of `len` bytes: the offset of the
+    /// fragment is written as a varint, so it takes at most the length of `len`
+    pub(crate) fn max_fragment_bytes(&self, len: usize) -> usize {
+        self.fragment_size
+            .saturating_sub(varint_len(len as u64))
+            .max(1)
     }
+
     pub fn build_fragments(
         &self,
         fragment_message_id: MessageId,
         tick: Option<Tick>,
         fragment_bytes: Bytes,
     ) -> Result<Vec<FragmentData>, SerializationError> {
-        if fragment_bytes.len() <= FRAGMENT_SIZE {
+        self.build_fragments_with_info(fragment_message_id, tick, fragment_bytes)
+            .map(|(fragments, _)| fragments)
+    }
+
+    /// Same as [`Self::build_fragments`], but also returns how the message was split, so that
+    /// the caller can log or act on the fragmentation
+    pub fn build_fragments_with_info(
+        &self,
+        fragment_message_id: MessageId,
+        tick: Option<Tick>,
+        fragment_bytes: Bytes,
+    ) -> Result<(Vec<FragmentData>, FragmentationInfo), SerializationError> {
+        if fragment_bytes.len() <= self.fragment_size {
             unreachable!(
                 "Message size must be at least {} to need to be fragmented",
-                FRAGMENT_SIZE
+                self.fragment_size
             );
         }
-        let chunks = fragment_bytes.chunks(self.fragment_size);
+        let max_fragment_bytes = self.max_fragment_bytes(fragment_bytes.len());
+        let chunks = self
+            .policy
+            .fragments(fragment_bytes.as_ref(), max_fragment_bytes);
+        self.check_chunks(&chunks, fragment_bytes.len(), max_fragment_bytes)?;
         let num_fragments = chunks.len();
-        if num_fragments > u8::MAX as usize {
-            return Err(SerializationError::MessageTooBig(fragment_bytes.len()));
+        let max_fragments = self
+            .max_fragments_per_message
+            .min(MAX_FRAGMENTS_PER_MESSAGE);
+        if num_fragments > max_fragments {
+            return Err(SerializationError::MessageTooLarge {
+                size: fragment_bytes.len(),
+                max_fragments,
+            });
         }
-        Ok(chunks
+        let info = FragmentationInfo {
+            num_fragments,
+            fragment_bytes: chunks.iter().map(|(_, len)| *len).collect(),
+            tail_packable: chunks
+                .last()
+                .is_some_and(|(_, len)| *len <= max_fragment_bytes / 2),
+        };
+        trace!(?fragment_message_id, ?info, "Fragmenting message");
+        let fragments = chunks
+            .into_iter()
             .enumerate()
-            // TODO: ideally we don't clone here but we take ownership of the output of writer
-            .map(|(fragment_index, chunk)| FragmentData {
+            .map(|(fragment_index, (offset, len))| FragmentData {
                 message_id: fragment_message_id,
                 // tick,
                 fragment_id: fragment_index as FragmentIndex,
                 num_fragments: num_fragments as FragmentIndex,
-                bytes: fragment_bytes.slice_ref(chunk),
+                offset: offset as u32,
+                bytes: fragment_bytes.slice(offset..offset + len),
             })
-            .collect::<_>())
+            .collect();
+        Ok((fragments, info))
+    }
+
+    /// Check that the `chunks` returned by the policy for a message of `size` bytes are
+    /// contiguous, cover the whole message, and are at most `fragment_size` bytes long
+    fn check_chunks(
+        &self,
+        chunks: &[(usize, usize)],
+        size: usize,
+        fragment_size: usize,
+    ) -> Result<(), SerializationError> {
+        let invalid = |offset, len| SerializationError::InvalidFragment {
+            size,
+            fragment_size,
+            offset,
+            len,
+        };
+        let mut end = 0;
+        for &(offset, len) in chunks {
+            if offset != end || len > fragment_size || len > size - offset {
+                return Err(invalid(offset, len));
+            }
+            end += len;
+        }
+        if end != size {
+            // the fragments stop before the end of the message (or there are none)
+            return Err(invalid(end, size - end));
+        }
+        Ok(())
     }
 }
 
+/// Number of bytes of each fragment (apart from the last one) of the messages of the tests, whose
+/// fragment offsets are written on 2 bytes
+#[cfg(test)]
+pub(crate) const FRAGMENT_BYTES: usize = FRAGMENT_SIZE - 2;
+
 #[cfg(test)]
 mod tests {
 
     use bytes::Bytes;
 
+    use crate::channel::receivers::fragment_receiver::FragmentReceiver;
     use crate::packet::packet::FRAGMENT_SIZE;
 
     use super::*;
 
+    #[test]
+    fn test_fragmentation_info() -> Result<(), SerializationError> {
+        let sender = FragmentSender::default();
+        let num_big_bytes = (2.5 * FRAGMENT_BYTES as f32) as usize;
+        let (fragments, info) = sender.build_fragments_with_info(
+            MessageId(0),
+            None,
+            Bytes::from(vec![1u8; num_big_bytes]),
+        )?;
+        assert_eq!(info.num_fragments, 3);
+        assert_eq!(
+            info.fragment_bytes,
+            vec![
+                FRAGMENT_BYTES,
+                FRAGMENT_BYTES,
+                num_big_bytes - 2 * FRAGMENT_BYTES
+            ]
+        );
+        assert_eq!(info.last_fragment_bytes(), fragments[2].bytes.len());
+        assert!(info.tail_packable);
+
+        // a last fragment that almost fills its packet leaves no room for other messages
+        let (_, info) = sender.build_fragments_with_info(
+            MessageId(1),
+            None,
+            Bytes::from(vec![1u8; 2 * FRAGMENT_BYTES - 10]),
+        )?;
+        assert_eq!(info.num_fragments, 2);
+        assert_eq!(info.last_fragment_bytes(), FRAGMENT_BYTES - 10);
+        assert!(!info.tail_packable);
+        Ok(())
+    }
+
+    /// The fragments only leave room for the number of bytes that their offsets need
+    #[test]
+    fn test_fragment_bytes_depend_on_message_len() -> Result<(), SerializationError> {
+        let sender = FragmentSender::default();
+        for (len, offset_bytes) in [(3 * FRAGMENT_SIZE, 2), (100_000, 4)] {
+            let fragments =
+                sender.build_fragments(MessageId(0), None, Bytes::from(vec![1; len]))?;
+            assert_eq!(fragments[0].bytes.len(), FRAGMENT_SIZE - offset_bytes);
+            let last = fragments.last().unwrap();
+            assert!(varint_len(last.offset as u64) <= offset_bytes);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_prepare_message() -> Result<(), SerializationError> {
+        let sender = FragmentSender::default();
+        let (single, fragments) =
+            sender.prepare_message(0, None, Bytes::from(vec![1u8; FRAGMENT_SIZE]))?;
+        assert_eq!(single.len(), 1);
+        assert!(fragments.is_empty());
+
+        let num_big_bytes = (2.5 * FRAGMENT_BYTES as f32) as usize;
+        let (single, fragments) =
+            sender.prepare_message(0, Some(MessageId(3)), Bytes::from(vec![1u8; num_big_bytes]))?;
+        assert!(single.is_empty());
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments
+            .iter()
+            .all(|fragment| fragment.message_id == MessageId(3) && fragment.num_fragments == 3));
+
+        // fragments cannot be reassembled without a message id
+        assert!(matches!(
+            sender.prepare_message(2, None, Bytes::from(vec![1u8; num_big_bytes])),
+            Err(SerializationError::MissingFragmentMessageId { channel_id: 2, size })
+                if size == num_big_bytes
+        ));
+        Ok(())
+    }
+
     #[test]
     fn test_message_too_big() {
-        let bytes = Bytes::from(vec![0; FRAGMENT_SIZE * 300]);
-        let sender = FragmentSender::new();
+        let bytes = Bytes::from(vec![0; FRAGMENT_BYTES * (MAX_FRAGMENTS_PER_MESSAGE + 45)]);
+        let sender = FragmentSender::default();
 
         let fragments = sender.build_fragments(MessageId(0), None, bytes.clone());
         assert!(matches!(
             fragments,
-            Err(SerializationError::MessageTooBig(_))
+            Err(SerializationError::MessageTooLarge {
+                size,
+                max_fragments: MAX_FRAGMENTS_PER_MESSAGE
+            }) if size == bytes.len()
         ),);
+
+        // one fragment over the limit
+        let bytes = Bytes::from(vec![0; FRAGMENT_BYTES * MAX_FRAGMENTS_PER_MESSAGE + 1]);
+        assert!(matches!(
+            sender.build_fragments(MessageId(0), None, bytes),
+            Err(SerializationError::MessageTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_max_fragments_per_message() {
+        let sender = FragmentSender::new(4);
+
+        // 4 fragments are allowed
+        let bytes = Bytes::from(vec![0; FRAGMENT_BYTES * 4]);
+        assert_eq!(
+            sender
+                .build_fragments(MessageId(0), None, bytes)
+                .unwrap()
+                .len(),
+            4
+        );
+
+        // 5 fragments are not
+        let bytes = Bytes::from(vec![0; FRAGMENT_BYTES * 4 + 1]);
+        assert!(matches!(
+            sender.build_fragments(MessageId(0), None, bytes),
+            Err(SerializationError::MessageTooLarge {
+                size,
+                max_fragments: 4
+            }) if size == FRAGMENT_BYTES * 4 + 1
+        ));
     }
 
     #[test]
     fn test_build_fragments() {
         let message_id = MessageId(0);
-        const NUM_BYTES: usize = (FRAGMENT_SIZE as f32 * 2.5) as usize;
+        const NUM_BYTES: usize = (FRAGMENT_BYTES as f32 * 2.5) as usize;
         let bytes = Bytes::from(vec![0; NUM_BYTES]);
 
-        let sender = FragmentSender::new();
+        let sender = FragmentSender::default();
 
         let fragments = sender
             .build_fragments(message_id, None, bytes.clone())
@@ -89,7 +378,8 @@ mod tests {
                 message_id,
                 fragment_id: 0,
                 num_fragments: expected_num_fragments as FragmentIndex,
-                bytes: bytes.slice(0..FRAGMENT_SIZE),
+                offset: 0,
+                bytes: bytes.slice(0..FRAGMENT_BYTES),
             }
         );
         assert_eq!(
@@ -98,7 +388,8 @@ mod tests {
                 message_id,
                 fragment_id: 1,
                 num_fragments: expected_num_fragments as FragmentIndex,
-                bytes: bytes.slice(FRAGMENT_SIZE..2 * FRAGMENT_SIZE),
+                offset: FRAGMENT_BYTES as u32,
+                bytes: bytes.slice(FRAGMENT_BYTES..2 * FRAGMENT_BYTES),
             }
         );
         assert_eq!(
@@ -108,8 +399,126 @@ mod tests {
                 // tick: None,
                 fragment_id: 2,
                 num_fragments: expected_num_fragments as FragmentIndex,
-                bytes: bytes.slice(2 * FRAGMENT_SIZE..),
+                offset: 2 * FRAGMENT_BYTES as u32,
+                bytes: bytes.slice(2 * FRAGMENT_BYTES..),
+            }
+        );
+    }
+
+    /// Policy that only splits the message on multiples of 100 bytes
+    #[derive(Debug)]
+    struct HundredBytesFragmentation;
+
+    impl FragmentationPolicy for HundredBytesFragmentation {
+        fn fragments(&self, bytes: &[u8], mtu: usize) -> Vec<(usize, usize)> {
+            let max_len = mtu - mtu % 100;
+            (0..bytes.len())
+                .step_by(max_len)
+                .map(|offset| (offset, max_len.min(bytes.len() - offset)))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_build_fragments_custom_policy() {
+        let message_id = MessageId(0);
+        const NUM_BYTES: usize = (FRAGMENT_BYTES as f32 * 2.5) as usize;
+        let bytes = Bytes::from((0..NUM_BYTES).map(|i| i as u8).collect::<Vec<_>>());
+
+        let sender = FragmentSender::default().with_policy(Arc::new(HundredBytesFragmentation));
+        let fragments = sender
+            .build_fragments(message_id, None, bytes.clone())
+            .unwrap();
+
+        let mut offset = 0;
+        for fragment in fragments.iter() {
+            // every fragment starts on a multiple of 100 bytes
+            assert_eq!(offset % 100, 0);
+            assert!(fragment.bytes.len() <= FRAGMENT_BYTES);
+            assert_eq!(
+                fragment.bytes,
+                bytes.slice(offset..offset + fragment.bytes.len())
+            );
+            offset += fragment.bytes.len();
+        }
+        assert_eq!(offset, NUM_BYTES);
+        assert_eq!(
+            fragments.len(),
+            NUM_BYTES.div_ceil(FRAGMENT_BYTES - FRAGMENT_BYTES % 100)
+        );
+
+        // the receiver can still reconstruct the message
+        let mut receiver = FragmentReceiver::new();
+        let mut result = None;
+        for fragment in fragments {
+            result = receiver.receive_fragment(fragment, Tick(0), None).unwrap();
+        }
+        assert_eq!(result, Some((Tick(0), bytes)));
+    }
+
+    /// Policy that returns the fragments given to it, whatever the message
+    #[derive(Debug)]
+    struct FixedChunks(Vec<(usize, usize)>);
+
+    impl FragmentationPolicy for FixedChunks {
+        fn fragments(&self, _: &[u8], _: usize) -> Vec<(usize, usize)> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_build_fragments_invalid_policy() {
+        const NUM_BYTES: usize = FRAGMENT_BYTES * 2;
+        let bytes = Bytes::from(vec![0; NUM_BYTES]);
+        let build = |chunks: Vec<(usize, usize)>| {
+            FragmentSender::default()
+                .with_policy(Arc::new(FixedChunks(chunks)))
+                .build_fragments(MessageId(0), None, bytes.clone())
+        };
+        let invalid = |offset, len| {
+            move |result: Result<Vec<FragmentData>, SerializationError>| {
+                matches!(
+                    result,
+                    Err(SerializationError::InvalidFragment {
+                        size: NUM_BYTES,
+                        fragment_size: FRAGMENT_BYTES,
+                        offset: o,
+                        len: l,
+                    }) if o == offset && l == len
+                )
             }
+        };
+
+        // a valid split
+        assert_eq!(
+            build(vec![(0, FRAGMENT_BYTES), (FRAGMENT_BYTES, FRAGMENT_BYTES)])
+                .unwrap()
+                .len(),
+            2
         );
+        // no fragment
+        assert!(invalid(0, NUM_BYTES)(build(vec![])));
+        // a fragment bigger than the fragment size
+        assert!(invalid(0, NUM_BYTES)(build(vec![(0, NUM_BYTES)])));
+        // overlapping fragments
+        assert!(invalid(FRAGMENT_BYTES - 1, FRAGMENT_BYTES)(build(vec![
+            (0, FRAGMENT_BYTES),
+            (FRAGMENT_BYTES - 1, FRAGMENT_BYTES)
+        ])));
+        // a gap between the fragments
+        assert!(invalid(FRAGMENT_BYTES + 1, 10)(build(vec![
+            (0, FRAGMENT_BYTES),
+            (FRAGMENT_BYTES + 1, 10)
+        ])));
+        // a fragment past the end of the message
+        assert!(invalid(FRAGMENT_BYTES, FRAGMENT_BYTES + 1)(build(vec![
+            (0, FRAGMENT_BYTES),
+            (FRAGMENT_BYTES, FRAGMENT_BYTES + 1)
+        ])));
+        // the fragments stop before the end of the message
+        assert!(invalid(FRAGMENT_BYTES, FRAGMENT_BYTES)(build(vec![(
+            0,
+            FRAGMENT_BYTES
+        )])));
     }
 }
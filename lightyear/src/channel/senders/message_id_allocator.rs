@@ -0,0 +1,132 @@
+//! Hand out the [`MessageId`]s of a sender that keeps messages around until they are acked
+use std::collections::VecDeque;
+
+use crate::packet::message::MessageId;
+
+/// Maximum number of ids that can be waiting for an ack at the same time.
+///
+/// [`MessageId`]s are compared with a wrapping distance, so ids that are more than half of the
+/// id space apart would be ordered the wrong way.
+pub(crate) const MAX_IN_FLIGHT_MESSAGE_IDS: usize = 1 << 15;
+
+/// Allocates monotonically increasing [`MessageId`]s, wrapping around at `u16::MAX`.
+///
+/// An id stays in flight until it is released with [`Self::mark_free`]; the allocator refuses to
+/// hand out new ids rather than let two in-flight messages share an id, or let the in-flight ids
+/// span more than [`MAX_IN_FLIGHT_MESSAGE_IDS`].
+#[derive(Debug, Default)]
+pub(crate) struct MessageIdAllocator {
+    /// Id that will be returned by the next call to [`Self::allocate`]
+    next_id: MessageId,
+    /// For each id from the oldest in-flight id to `next_id` (excluded), true if the id is still
+    /// in flight
+    in_flight: VecDeque<bool>,
+}
+
+impl MessageIdAllocator {
+    /// Id that will be returned by the next successful call to [`Self::allocate`]
+    pub(crate) fn next_id(&self) -> MessageId {
+        self.next_id
+    }
+
+    /// Oldest id that is still in flight
+    fn oldest_id(&self) -> MessageId {
+        self.next_id.wrapping_sub(self.in_flight.len() as u16)
+    }
+
+    /// Returns a new id, or None if there are already [`MAX_IN_FLIGHT_MESSAGE_IDS`] ids between
+    /// the oldest in-flight id and the next id
+    pub(crate) fn allocate(&mut self) -> Option<MessageId> {
+        if self.in_flight.len() >= MAX_IN_FLIGHT_MESSAGE_IDS {
+            return None;
+        }
+        let id = self.next_id;
+        self.in_flight.push_back(true);
+        self.next_id = self.next_id.wrapping_add(1);
+        Some(id)
+    }
+
+    /// Release an id once its message has been acked (or dropped), so that the id can be reused
+    /// after the id space wraps around.
+    ///
+    /// Returns false if the id was not in flight.
+    pub(crate) fn mark_free(&mut self, id: MessageId) -> bool {
+        let offset = id.diff(self.oldest_id());
+        let Some(in_flight) = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.in_flight.get_mut(offset))
+        else {
+            return false;
+        };
+        if !*in_flight {
+            return false;
+        }
+        *in_flight = false;
+        while self.in_flight.front() == Some(&false) {
+            self.in_flight.pop_front();
+        }
+        true
+    }
+
+    /// Number of ids between the oldest in-flight id and the next id
+    pub(crate) fn in_flight_span(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exhaust_id_space_near_wrap() {
+        let mut allocator = MessageIdAllocator {
+            next_id: MessageId(u16::MAX - 2),
+            ..Default::default()
+        };
+        let oldest = allocator.allocate().unwrap();
+        assert_eq!(oldest, MessageId(u16::MAX - 2));
+        for _ in 1..MAX_IN_FLIGHT_MESSAGE_IDS {
+            allocator.allocate().unwrap();
+        }
+        // the ids wrapped around, but the oldest id is still in flight
+        assert_eq!(
+            allocator.next_id(),
+            MessageId((MAX_IN_FLIGHT_MESSAGE_IDS - 3) as u16)
+        );
+        assert_eq!(allocator.allocate(), None);
+
+        // acking a recent id does not release the span
+        assert!(allocator.mark_free(MessageId(5)));
+        assert!(!allocator.mark_free(MessageId(5)));
+        assert_eq!(allocator.allocate(), None);
+
+        // acking the oldest ids lets the allocator move forward
+        assert!(allocator.mark_free(oldest));
+        assert!(allocator.mark_free(MessageId(u16::MAX - 1)));
+        assert_eq!(allocator.in_flight_span(), MAX_IN_FLIGHT_MESSAGE_IDS - 2);
+        let a = allocator.allocate().unwrap();
+        let b = allocator.allocate().unwrap();
+        assert_eq!(allocator.allocate(), None);
+        assert_eq!(a, MessageId((MAX_IN_FLIGHT_MESSAGE_IDS - 3) as u16));
+        assert_eq!(b, a.wrapping_add(1));
+
+        // ids that were never allocated, or that are not in flight anymore, cannot be freed
+        assert!(!allocator.mark_free(oldest));
+        assert!(!allocator.mark_free(allocator.next_id()));
+
+        // release everything: the acked ids are reused once the id space wraps around again
+        let mut id = MessageId(u16::MAX);
+        while allocator.in_flight_span() > 0 {
+            allocator.mark_free(id);
+            id = id.wrapping_add(1);
+        }
+        let mut reused = false;
+        for _ in 0..=u16::MAX {
+            let id = allocator.allocate().unwrap();
+            reused |= id == oldest;
+            assert!(allocator.mark_free(id));
+        }
+        assert!(reused);
+    }
+}
@@ -5,6 +5,7 @@ use crossbeam_channel::Receiver;
 use enum_dispatch::enum_dispatch;
 
 use crate::packet::message::{MessageAck, MessageId, SendMessage};
+use crate::packet::priority_manager::DropReason;
 use crate::serialize::SerializationError;
 use crate::shared::ping::manager::PingManager;
 use crate::shared::tick_manager::TickManager;
@@ -12,6 +13,7 @@ use crate::shared::time_manager::TimeManager;
 
 pub(crate) mod fragment_ack_receiver;
 pub(crate) mod fragment_sender;
+pub(crate) mod message_id_allocator;
 pub(crate) mod reliable;
 pub(crate) mod sequenced_unreliable;
 pub(crate) mod unordered_unreliable;
@@ -58,10 +60,27 @@ pub trait ChannelSend {
 
     /// Send nacks to the subscribers of nacks
     fn send_nacks(&mut self, nack: MessageId);
+
+    /// Set the maximum size of a message before it gets split into fragments
+    fn set_fragment_size(&mut self, fragment_size: usize);
+
+    /// Set the maximum number of fragments that a message can be split into
+    fn set_max_fragments_per_message(&mut self, max_fragments_per_message: usize);
+
+    /// Returns true if some fragments of messages were built but not sent yet (or, for reliable
+    /// channels, not acked yet), so they still need packets that fit the current fragment size
+    fn has_queued_fragments(&self) -> bool;
+
+    /// Returns the messages that the channel stopped sending since the last call, along with the
+    /// reason why
+    fn take_dropped_messages(&mut self) -> Vec<(MessageId, DropReason)> {
+        vec![]
+    }
 }
 
 /// Enum dispatch lets us derive ChannelSend on each enum variant
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 #[enum_dispatch(ChannelSend)]
 pub enum ChannelSender {
     UnorderedUnreliableWithAcks(unordered_unreliable_with_acks::UnorderedUnreliableWithAcksSender),
@@ -5,24 +5,90 @@ use std::collections::{BTreeMap, HashSet};
 use bevy::utils::Duration;
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
-use tracing::trace;
+use tracing::{error, trace};
 
-use crate::channel::builder::ReliableSettings;
+use crate::channel::builder::{ReliableSettings, RetransmitClock};
 use crate::channel::senders::fragment_sender::FragmentSender;
+use crate::channel::senders::message_id_allocator::MessageIdAllocator;
 use crate::channel::senders::ChannelSend;
-use crate::packet::message::{FragmentData, MessageAck, MessageId, SendMessage, SingleData};
+use crate::packet::message::{
+    FragmentData, FragmentIndex, MessageAck, MessageId, SendMessage, SingleData,
+};
+use crate::packet::priority_manager::DropReason;
 use crate::serialize::SerializationError;
 use crate::shared::ping::manager::PingManager;
-use crate::shared::tick_manager::TickManager;
+use crate::shared::tick_manager::{Tick, TickManager};
 use crate::shared::time_manager::{TimeManager, WrappedTime};
 
 #[derive(Debug)]
 pub struct FragmentAck {
     data: FragmentData,
-    acked: bool,
     last_sent: Option<WrappedTime>,
 }
 
+/// Fragments of a message that are not all acked yet.
+///
+/// A bitset keeps track of the fragments that are still waiting for an ack, so that only those
+/// fragments are resent.
+#[derive(Debug)]
+pub struct FragmentTracker {
+    fragments: Vec<FragmentAck>,
+    /// Bit `i` is set while the fragment `i` has not been acked
+    unacked: Vec<u64>,
+}
+
+impl FragmentTracker {
+    fn new(fragments: Vec<FragmentData>) -> Self {
+        let mut unacked = vec![u64::MAX; fragments.len().div_ceil(64)];
+        if let Some(last) = unacked.last_mut() {
+            // clear the bits past the last fragment
+            *last >>= (64 - fragments.len() % 64) % 64;
+        }
+        Self {
+            fragments: fragments
+                .into_iter()
+                .map(|data| FragmentAck {
+                    data,
+                    last_sent: None,
+                })
+                .collect(),
+            unacked,
+        }
+    }
+
+    fn is_unacked(unacked: &[u64], fragment_id: usize) -> bool {
+        unacked[fragment_id / 64] & (1 << (fragment_id % 64)) != 0
+    }
+
+    /// Mark the fragment as acked.
+    ///
+    /// Returns None if the fragment does not exist, otherwise true if it was not acked before
+    fn process_ack(&mut self, fragment_id: FragmentIndex) -> Option<bool> {
+        let fragment_id = fragment_id as usize;
+        if fragment_id >= self.fragments.len() {
+            return None;
+        }
+        let newly_acked = Self::is_unacked(&self.unacked, fragment_id);
+        self.unacked[fragment_id / 64] &= !(1 << (fragment_id % 64));
+        Some(newly_acked)
+    }
+
+    /// Returns true once every fragment has been acked
+    fn is_complete(&self) -> bool {
+        self.unacked.iter().all(|bits| *bits == 0)
+    }
+
+    /// Fragments that have not been acked yet
+    fn unacked_fragments_mut(&mut self) -> impl Iterator<Item = &mut FragmentAck> {
+        let unacked = &self.unacked;
+        self.fragments
+            .iter_mut()
+            .enumerate()
+            .filter(|(fragment_id, _)| Self::is_unacked(unacked, *fragment_id))
+            .map(|(_, fragment_ack)| fragment_ack)
+    }
+}
+
 /// A message that has not been acked yet
 #[derive(Debug)]
 pub enum UnackedMessage {
@@ -32,7 +98,7 @@ pub enum UnackedMessage {
         /// else: the last instant when this packet was sent
         last_sent: Option<WrappedTime>,
     },
-    Fragmented(Vec<FragmentAck>),
+    Fragmented(FragmentTracker),
 }
 
 #[derive(Debug)]
@@ -40,6 +106,44 @@ pub struct UnackedMessageWithPriority {
     pub unacked_message: UnackedMessage,
     pub base_priority: f32,
     pub accumulated_priority: f32,
+    /// Last tick at which the message can be sent, for messages with a
+    /// [`SingleData::ticks_to_live`]
+    pub expires_at: Option<Tick>,
+    /// Number of times the message (or some of its fragments) was resent
+    pub retries: u32,
+}
+
+/// Bytes of messages that can still be resent in the current tick
+/// (see [`ReliableSettings::max_resend_bytes_per_tick`])
+struct ResendBudget {
+    max_bytes: Option<usize>,
+    spent: usize,
+    exhausted: bool,
+}
+
+impl ResendBudget {
+    fn new(max_bytes: Option<usize>) -> Self {
+        Self {
+            max_bytes,
+            spent: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Returns true if `len` bytes can be resent. Once a message doesn't fit, the next ones are not
+    /// resent either, so that the oldest messages always go first.
+    fn try_spend(&mut self, len: usize) -> bool {
+        let Some(max_bytes) = self.max_bytes else {
+            return true;
+        };
+        // the first message always fits, otherwise a message bigger than the budget is never resent
+        if self.exhausted || (self.spent > 0 && self.spent + len > max_bytes) {
+            self.exhausted = true;
+            return false;
+        }
+        self.spent += len;
+        true
+    }
 }
 
 /// A sender that makes sure to resend messages until it receives an ack
@@ -50,8 +154,8 @@ pub struct ReliableSender {
     // TODO: maybe optimize by using a RingBuffer
     /// Ordered map of the messages that haven't been acked yet
     unacked_messages: BTreeMap<MessageId, UnackedMessageWithPriority>,
-    /// Message id to use for the next message to be sent
-    next_send_message_id: MessageId,
+    /// Allocates the ids of the messages; an id is released once its message is acked or dropped
+    message_ids: MessageIdAllocator,
 
     /// list of single messages that we want to fit into packets and send
     single_messages_to_send: VecDeque<SendMessage>,
@@ -71,6 +175,13 @@ pub struct ReliableSender {
     nack_senders: Vec<Sender<MessageId>>,
     current_rtt: Duration,
     current_time: WrappedTime,
+    current_tick: Tick,
+    /// Messages that were dropped after [`ReliableSettings::max_retries`] resends, since the last
+    /// call to [`Self::dropped_messages`]
+    dropped_messages: Vec<MessageId>,
+    /// Messages that were dropped (expired or after their last retry) since the last call to
+    /// [`ChannelSend::take_dropped_messages`]
+    drop_reasons: Vec<(MessageId, DropReason)>,
     /// Internal timer to determine if the channel is ready to send messages
     timer: Option<Timer>,
     /// Factor that makes sure that the priority accumulates at the same right even the channel
@@ -88,121 +199,101 @@ impl ReliableSender {
         Self {
             reliable_settings,
             unacked_messages: Default::default(),
-            next_send_message_id: MessageId(0),
+            message_ids: MessageIdAllocator::default(),
             single_messages_to_send: Default::default(),
             fragmented_messages_to_send: Default::default(),
             message_ids_to_send: Default::default(),
-            fragment_sender: FragmentSender::new(),
+            fragment_sender: FragmentSender::default(),
             ack_senders: vec![],
             nack_senders: vec![],
             current_rtt: Duration::default(),
             current_time: WrappedTime::default(),
+            current_tick: Tick(0),
+            dropped_messages: vec![],
+            drop_reasons: vec![],
             timer,
             priority_multiplier: 1.0,
         }
     }
-}
 
-impl ChannelSend for ReliableSender {
-    fn update(&mut self, time_manager: &TimeManager, ping_manager: &PingManager, _: &TickManager) {
-        self.current_time = time_manager.current_time();
-        self.current_rtt = ping_manager.rtt();
-        if let Some(timer) = &mut self.timer {
-            timer.tick(time_manager.delta());
-            self.priority_multiplier =
-                timer.duration().as_nanos() as f32 / time_manager.delta().as_nanos() as f32;
-            trace!(
-                ?timer,
-                "Priority multiplier for reliable sender channel: {:?}",
-                self.priority_multiplier
-            );
-        }
+    /// Returns the ids of the messages that were sent but not acked within the resend delay
+    /// (`rtt * rtt_resend_factor`), and queues them to be returned by the next call to
+    /// [`ChannelSend::send_packet`].
+    ///
+    /// The returned messages are resent exactly like the resends of `send_packet`: their
+    /// last-sent time is set to `now`, and the resend counts towards
+    /// [`ReliableSettings::max_retries`] (the messages that already reached it are dropped
+    /// instead). Messages that were never sent or that have been acked are not returned, and the
+    /// messages over the [`ReliableSettings::max_resend_bytes_per_tick`] budget are kept for the
+    /// next call.
+    pub fn messages_to_resend(&mut self, now: WrappedTime, rtt: Duration) -> Vec<MessageId> {
+        let mut message_ids = self
+            .fragments_to_resend(now, rtt)
+            .into_iter()
+            .map(|message_ack| message_ack.message_id)
+            .collect::<Vec<_>>();
+        message_ids.dedup();
+        message_ids
     }
 
-    /// Add a new message to the buffer of messages to be sent.
-    /// This is a client-facing function, to be called when you want to send a message
-    fn buffer_send(
-        &mut self,
-        message: Bytes,
-        priority: f32,
-    ) -> Result<Option<MessageId>, SerializationError> {
-        let message_id = self.next_send_message_id;
-        let unacked_message = if message.len() > self.fragment_sender.fragment_size {
-            let fragments = self
-                .fragment_sender
-                .build_fragments(message_id, None, message)?;
-            UnackedMessage::Fragmented(
-                fragments
-                    .into_iter()
-                    .map(|fragment| FragmentAck {
-                        data: fragment,
-                        acked: false,
-                        last_sent: None,
-                    })
-                    .collect(),
-            )
-        } else {
-            UnackedMessage::Single {
-                bytes: message,
-                last_sent: None,
-            }
-        };
-        let unacked_message_with_priority = UnackedMessageWithPriority {
-            unacked_message,
-            base_priority: priority,
-            // store with 0.0 accumulated priority because priority gets accumulated when we collect the messages
-            // for sending (even the first time the message is sent)
-            accumulated_priority: 0.0,
-        };
-        self.unacked_messages
-            .insert(message_id, unacked_message_with_priority);
-        self.next_send_message_id += 1;
-        Ok(Some(message_id))
+    /// Same as [`Self::messages_to_resend`], but a fragmented message is returned once for each
+    /// of its fragments that must be resent: the fragments that were already acked are not
+    /// returned.
+    pub fn fragments_to_resend(&mut self, now: WrappedTime, rtt: Duration) -> Vec<MessageAck> {
+        let resend_delay =
+            chrono::Duration::from_std(self.reliable_settings.resend_delay(rtt)).unwrap();
+        self.queue_messages(now, resend_delay, false)
     }
 
-    /// Take messages from the buffer of messages to be sent, and build a list of packets
-    /// to be sent
-    /// The messages to be sent need to have been collected prior to this point.
-    fn send_packet(&mut self) -> (VecDeque<SendMessage>, VecDeque<SendMessage>) {
-        if self.timer.as_ref().is_some_and(|t| !t.finished()) {
-            return (VecDeque::new(), VecDeque::new());
-        }
-
-        // Collect the list of messages that need to be sent
-        // Either because they have never been sent, or because they need to be resent
-
-        // resend delay is based on the rtt
-        let resend_delay =
-            chrono::Duration::from_std(self.reliable_settings.resend_delay(self.current_rtt))
-                .unwrap();
+    /// Queue the messages (or fragments) that must be sent at `now`: the messages that were sent
+    /// more than `resend_delay` ago and were not acked, and if `send_new` is true the messages
+    /// that were never sent. The priority of every message is accumulated if `send_new` is true,
+    /// which is only the case once per call to [`ChannelSend::send_packet`].
+    ///
+    /// Returns the messages (or fragments) that were queued to be resent.
+    fn queue_messages(
+        &mut self,
+        now: WrappedTime,
+        resend_delay: chrono::Duration,
+        send_new: bool,
+    ) -> Vec<MessageAck> {
+        self.evict_expired_messages();
+        self.drop_exhausted_messages(now, resend_delay);
         let should_send = |last_sent: &Option<WrappedTime>| -> bool {
             match last_sent {
                 // send if the message has never been sent
-                None => true,
+                None => send_new,
                 // or if we sent it a while back but didn't get an ack
-                Some(last_sent) => self.current_time - *last_sent > resend_delay,
+                Some(last_sent) => now - *last_sent > resend_delay,
             }
         };
+        // only the resent messages count towards the budget
+        let mut budget = ResendBudget::new(self.reliable_settings.max_resend_bytes_per_tick);
+        let mut resent_messages = vec![];
 
         // Iterate through all unacked messages, oldest message ids first
         for (message_id, unacked_message_with_priority) in self.unacked_messages.iter_mut() {
-            // accumulate the priority for all messages (including the ones that were just added, since we set the accumulated priority to 0.0)
-            unacked_message_with_priority.accumulated_priority +=
-                unacked_message_with_priority.base_priority * self.priority_multiplier;
-            trace!(
-                "Accumulating priority for reliable message {:?} to {:?}. Base priority: {:?}, Multiplier: {:?}",
-                message_id,
-                unacked_message_with_priority.accumulated_priority,
-                unacked_message_with_priority.base_priority,
-                self.priority_multiplier
-            );
+            if send_new {
+                // accumulate the priority for all messages (including the ones that were just added, since we set the accumulated priority to 0.0)
+                unacked_message_with_priority.accumulated_priority +=
+                    unacked_message_with_priority.base_priority * self.priority_multiplier;
+                trace!(
+                    "Accumulating priority for reliable message {:?} to {:?}. Base priority: {:?}, Multiplier: {:?}",
+                    message_id,
+                    unacked_message_with_priority.accumulated_priority,
+                    unacked_message_with_priority.base_priority,
+                    self.priority_multiplier
+                );
+            }
 
             match &mut unacked_message_with_priority.unacked_message {
                 UnackedMessage::Single {
                     bytes,
                     ref mut last_sent,
                 } => {
-                    if should_send(last_sent) {
+                    if should_send(last_sent)
+                        && (last_sent.is_none() || budget.try_spend(bytes.len()))
+                    {
                         trace!("Should send message {:?}", message_id);
                         let message_info = MessageAck {
                             message_id: *message_id,
@@ -215,15 +306,23 @@ impl ChannelSend for ReliableSender {
                                 priority: unacked_message_with_priority.accumulated_priority,
                             });
                             self.message_ids_to_send.insert(message_info);
-                            *last_sent = Some(self.current_time);
+                            if last_sent.is_some() {
+                                unacked_message_with_priority.retries += 1;
+                                resent_messages.push(message_info);
+                            }
+                            *last_sent = Some(now);
                         }
                     }
                 }
-                UnackedMessage::Fragmented(fragment_acks) => {
+                UnackedMessage::Fragmented(fragment_tracker) => {
+                    let mut resent = false;
                     // only send the fragments that haven't been acked and should be resent
-                    fragment_acks
-                        .iter_mut()
-                        .filter(|f| !f.acked && should_send(&f.last_sent))
+                    fragment_tracker
+                        .unacked_fragments_mut()
+                        .filter(|f| {
+                            should_send(&f.last_sent)
+                                && (f.last_sent.is_none() || budget.try_spend(f.data.bytes.len()))
+                        })
                         .for_each(|f| {
                             let message_info = MessageAck {
                                 message_id: *message_id,
@@ -236,14 +335,227 @@ impl ChannelSend for ReliableSender {
                                     priority: unacked_message_with_priority.accumulated_priority,
                                 });
                                 self.message_ids_to_send.insert(message_info);
-                                f.last_sent = Some(self.current_time);
+                                if f.last_sent.is_some() {
+                                    resent = true;
+                                    resent_messages.push(message_info);
+                                }
+                                f.last_sent = Some(now);
                             }
-                        })
+                        });
+                    if resent {
+                        unacked_message_with_priority.retries += 1;
+                    }
                 }
             }
         }
+        resent_messages
+    }
+
+    /// The receiver is missing the fragments `fragment_ids` of the message `message_id`: send
+    /// exactly those fragments again with the next packet, without waiting for the resend delay
+    pub fn receive_fragment_nack(&mut self, message_id: MessageId, fragment_ids: &[FragmentIndex]) {
+        let Some(UnackedMessage::Fragmented(fragment_tracker)) = self
+            .unacked_messages
+            .get_mut(&message_id)
+            .map(|message| &mut message.unacked_message)
+        else {
+            return;
+        };
+        for fragment_ack in fragment_tracker.unacked_fragments_mut() {
+            if fragment_ids.contains(&fragment_ack.data.fragment_id) {
+                fragment_ack.last_sent = None;
+            }
+        }
+    }
+
+    /// Number of messages that are buffered and waiting for an ack
+    pub fn pending_count(&self) -> usize {
+        self.unacked_messages.len()
+    }
+
+    /// Buffer a message that is only resent until its [`SingleData::ticks_to_live`] has elapsed
+    /// (partial reliability), for messages that are useless once they are too stale.
+    ///
+    /// The id of the message is assigned by the sender.
+    pub fn buffer_send_single(
+        &mut self,
+        message: SingleData,
+        priority: f32,
+    ) -> Result<Option<MessageId>, SerializationError> {
+        let expires_at = message
+            .ticks_to_live
+            .map(|ticks_to_live| self.current_tick.wrapping_add(ticks_to_live));
+        self.buffer_send_with_expiry(message.bytes, priority, expires_at)
+    }
+
+    fn buffer_send_with_expiry(
+        &mut self,
+        message: Bytes,
+        priority: f32,
+        expires_at: Option<Tick>,
+    ) -> Result<Option<MessageId>, SerializationError> {
+        let message_id =
+            self.message_ids
+                .allocate()
+                .ok_or(SerializationError::MessageIdsExhausted {
+                    in_flight: self.message_ids.in_flight_span(),
+                })?;
+        let unacked_message = if message.len() > self.fragment_sender.fragment_size {
+            let fragments = self
+                .fragment_sender
+                .build_fragments(message_id, None, message)?;
+            UnackedMessage::Fragmented(FragmentTracker::new(fragments))
+        } else {
+            UnackedMessage::Single {
+                bytes: message,
+                last_sent: None,
+            }
+        };
+        let unacked_message_with_priority = UnackedMessageWithPriority {
+            unacked_message,
+            base_priority: priority,
+            // store with 0.0 accumulated priority because priority gets accumulated when we collect the messages
+            // for sending (even the first time the message is sent)
+            accumulated_priority: 0.0,
+            expires_at,
+            retries: 0,
+        };
+        self.unacked_messages
+            .insert(message_id, unacked_message_with_priority);
+        Ok(Some(message_id))
+    }
+
+    /// Advance the time used for the resend timers (see [`RetransmitClock`])
+    pub(crate) fn update_clock(&mut self, wall_time: WrappedTime, tick: Tick) {
+        match self.reliable_settings.retransmit_clock {
+            RetransmitClock::Wall => self.current_time = wall_time,
+            RetransmitClock::Ticks { tick_duration } => {
+                // the time only moves forward, by a whole number of ticks
+                let elapsed_ticks = (tick - self.current_tick).max(0) as u32;
+                self.current_time += tick_duration * elapsed_ticks;
+            }
+        }
+        self.current_tick = tick;
+    }
+
+    /// Returns the messages that were dropped because they were still not acked after
+    /// [`ReliableSettings::max_retries`] resends (for example to disconnect a peer that stopped
+    /// responding), and forgets them. They were also reported to the subscribers of nacks.
+    pub fn dropped_messages(&mut self) -> Vec<MessageId> {
+        std::mem::take(&mut self.dropped_messages)
+    }
+
+    /// Drop the messages that are due for a resend at `now` but were already resent
+    /// [`ReliableSettings::max_retries`] times
+    fn drop_exhausted_messages(&mut self, now: WrappedTime, resend_delay: chrono::Duration) {
+        let Some(max_retries) = self.reliable_settings.max_retries else {
+            return;
+        };
+        let is_due = |last_sent: &Option<WrappedTime>| {
+            last_sent.is_some_and(|last_sent| now - last_sent > resend_delay)
+        };
+        let mut exhausted = vec![];
+        self.unacked_messages.retain(|message_id, message| {
+            let is_exhausted = message.retries >= max_retries
+                && match &mut message.unacked_message {
+                    UnackedMessage::Single { last_sent, .. } => is_due(last_sent),
+                    UnackedMessage::Fragmented(fragment_tracker) => fragment_tracker
+                        .unacked_fragments_mut()
+                        .any(|f| is_due(&f.last_sent)),
+                };
+            if is_exhausted {
+                exhausted.push(*message_id);
+            }
+            !is_exhausted
+        });
+        for message_id in exhausted {
+            trace!(
+                ?message_id,
+                "Dropping a reliable message after its last retry"
+            );
+            self.message_ids.mark_free(message_id);
+            self.send_nacks(message_id);
+            self.dropped_messages.push(message_id);
+            self.drop_reasons.push((message_id, DropReason::MaxRetries));
+        }
+    }
+
+    /// Stop sending the messages whose ticks to live have elapsed. They are reported as lost to
+    /// the subscribers of nacks.
+    fn evict_expired_messages(&mut self) {
+        let current_tick = self.current_tick;
+        let mut expired = vec![];
+        self.unacked_messages.retain(|message_id, message| {
+            let is_expired = message
+                .expires_at
+                .is_some_and(|expires_at| current_tick > expires_at);
+            if is_expired {
+                expired.push(*message_id);
+            }
+            !is_expired
+        });
+        for message_id in expired {
+            trace!(
+                ?message_id,
+                "Evicting a reliable message past its ticks to live"
+            );
+            self.message_ids.mark_free(message_id);
+            self.send_nacks(message_id);
+            self.drop_reasons.push((message_id, DropReason::Expired));
+        }
+    }
+}
+
+impl ChannelSend for ReliableSender {
+    fn update(
+        &mut self,
+        time_manager: &TimeManager,
+        ping_manager: &PingManager,
+        tick_manager: &TickManager,
+    ) {
+        self.update_clock(time_manager.current_time(), tick_manager.tick());
+        self.current_rtt = ping_manager.rtt();
+        if let Some(timer) = &mut self.timer {
+            timer.tick(time_manager.delta());
+            self.priority_multiplier =
+                timer.duration().as_nanos() as f32 / time_manager.delta().as_nanos() as f32;
+            trace!(
+                ?timer,
+                "Priority multiplier for reliable sender channel: {:?}",
+                self.priority_multiplier
+            );
+        }
+    }
+
+    /// Add a new message to the buffer of messages to be sent.
+    /// This is a client-facing function, to be called when you want to send a message
+    fn buffer_send(
+        &mut self,
+        message: Bytes,
+        priority: f32,
+    ) -> Result<Option<MessageId>, SerializationError> {
+        self.buffer_send_with_expiry(message, priority, None)
+    }
+
+    /// Take messages from the buffer of messages to be sent, and build a list of packets
+    /// to be sent
+    /// The messages to be sent need to have been collected prior to this point.
+    fn send_packet(&mut self) -> (VecDeque<SendMessage>, VecDeque<SendMessage>) {
+        if self.timer.as_ref().is_some_and(|t| !t.finished()) {
+            return (VecDeque::new(), VecDeque::new());
+        }
 
-        // TODO: is this message_ids_to_send even useful? in which situation would we send the same message twice?
+        // Collect the list of messages that need to be sent
+        // Either because they have never been sent, or because they need to be resent
+        // (the resends queued by `messages_to_resend` are already in the queues)
+
+        // resend delay is based on the rtt
+        let resend_delay =
+            chrono::Duration::from_std(self.reliable_settings.resend_delay(self.current_rtt))
+                .unwrap();
+        self.queue_messages(self.current_time, resend_delay, true);
+
+        // message_ids_to_send prevents queueing a message twice between two calls;
         // right now, we send everything; so we can reset
         self.message_ids_to_send.clear();
         if !self.single_messages_to_send.is_empty() {
@@ -291,20 +603,25 @@ impl ChannelSend for ReliableSender {
                         sender.send(message_ack.message_id).unwrap();
                     }
                     self.unacked_messages.remove(&message_ack.message_id);
+                    self.message_ids.mark_free(message_ack.message_id);
                 }
-                UnackedMessage::Fragmented(fragment_acks) => {
+                UnackedMessage::Fragmented(fragment_tracker) => {
                     let Some(fragment_id) = message_ack.fragment_id else {
                         panic!("Received a message ack for a single message but message is a fragmented message")
                     };
-                    if !fragment_acks[fragment_id as usize].acked {
-                        fragment_acks[fragment_id as usize].acked = true;
-                        // TODO: use a variable to keep track of this?
-                        // all fragments were acked
-                        if fragment_acks.iter().all(|f| f.acked) {
-                            self.unacked_messages.remove(&message_ack.message_id);
-                            for sender in &self.ack_senders {
-                                sender.send(message_ack.message_id).unwrap();
-                            }
+                    let Some(newly_acked) = fragment_tracker.process_ack(fragment_id) else {
+                        error!(
+                            ?fragment_id,
+                            "Received a message ack for a fragment that does not exist"
+                        );
+                        return;
+                    };
+                    // all fragments were acked
+                    if newly_acked && fragment_tracker.is_complete() {
+                        self.unacked_messages.remove(&message_ack.message_id);
+                        self.message_ids.mark_free(message_ack.message_id);
+                        for sender in &self.ack_senders {
+                            sender.send(message_ack.message_id).unwrap();
                         }
                     }
                 }
@@ -333,24 +650,55 @@ impl ChannelSend for ReliableSender {
             sender.send(nack).unwrap();
         }
     }
+
+    fn set_fragment_size(&mut self, fragment_size: usize) {
+        self.fragment_sender.fragment_size = fragment_size;
+    }
+
+    fn set_max_fragments_per_message(&mut self, max_fragments_per_message: usize) {
+        self.fragment_sender.max_fragments_per_message = max_fragments_per_message;
+    }
+
+    fn has_queued_fragments(&self) -> bool {
+        self.unacked_messages
+            .values()
+            .any(|message| matches!(message.unacked_message, UnackedMessage::Fragmented(_)))
+    }
+
+    fn take_dropped_messages(&mut self) -> Vec<(MessageId, DropReason)> {
+        std::mem::take(&mut self.drop_reasons)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use bevy::prelude::default;
     use bevy::utils::Duration;
     use bytes::Bytes;
 
     use crate::channel::builder::ReliableSettings;
-    use crate::packet::message::SingleData;
+    use crate::packet::message::{MessageData, SingleData};
 
     use super::*;
 
+    /// Ids of the single messages returned by `send_packet`
+    fn sent_ids(single: VecDeque<SendMessage>) -> Vec<MessageId> {
+        single
+            .into_iter()
+            .map(|message| match message.data {
+                MessageData::Single(data) => data.id.unwrap(),
+                MessageData::Fragment(_) => unreachable!(),
+            })
+            .collect()
+    }
+
     #[test]
     fn test_reliable_sender_internals() {
         let mut sender = ReliableSender::new(
             ReliableSettings {
                 rtt_resend_factor: 1.5,
                 rtt_resend_min_delay: Duration::from_millis(100),
+                ..default()
             },
             Duration::default(),
         );
@@ -361,7 +709,7 @@ mod tests {
         let message1 = Bytes::from("hello");
         sender.buffer_send(message1.clone(), 1.0).unwrap();
         assert_eq!(sender.unacked_messages.len(), 1);
-        assert_eq!(sender.next_send_message_id, MessageId(1));
+        assert_eq!(sender.message_ids.next_id(), MessageId(1));
         // Collect the messages to be sent
         let (single, _) = sender.send_packet();
         assert_eq!(single.len(), 1);
@@ -396,4 +744,411 @@ mod tests {
         let (single, _) = sender.send_packet();
         assert_eq!(single.len(), 0);
     }
+
+    /// With the tick clock, a message is resent after a whole number of ticks, whatever the
+    /// wall-clock time
+    #[test]
+    fn test_retransmit_tick_clock() {
+        let mut sender = ReliableSender::new(
+            ReliableSettings {
+                rtt_resend_factor: 1.5,
+                retransmit_clock: RetransmitClock::Ticks {
+                    tick_duration: Duration::from_millis(20),
+                },
+                ..default()
+            },
+            Duration::default(),
+        );
+        // resend delay of 150ms: the message is resent 8 ticks (160ms) after it was sent
+        sender.current_rtt = Duration::from_millis(100);
+        sender.update_clock(WrappedTime::new(0), Tick(10));
+        sender.buffer_send(Bytes::from("hello"), 1.0).unwrap();
+        let (single, _) = sender.send_packet();
+        assert_eq!(single.len(), 1);
+
+        for tick in 11..=18 {
+            // the wall-clock time is ignored
+            sender.update_clock(WrappedTime::new(u32::from(tick) * 1000), Tick(tick));
+            let (single, _) = sender.send_packet();
+            assert_eq!(single.len(), usize::from(tick == 18), "tick {tick}");
+        }
+        // the next resend is 8 ticks later
+        for tick in 19..=26 {
+            sender.update_clock(WrappedTime::new(0), Tick(tick));
+            let (single, _) = sender.send_packet();
+            assert_eq!(single.len(), usize::from(tick == 26), "tick {tick}");
+        }
+    }
+
+    /// A message that is never acked is dropped after `max_retries` resends
+    #[test]
+    fn test_max_retries() {
+        let mut sender = ReliableSender::new(
+            ReliableSettings {
+                rtt_resend_factor: 1.5,
+                max_retries: Some(2),
+                ..default()
+            },
+            Duration::default(),
+        );
+        let nacks = sender.subscribe_nacks();
+        sender.current_rtt = Duration::from_millis(100);
+        sender.current_time = WrappedTime::new(0);
+        let message_id = sender
+            .buffer_send(Bytes::from("hello"), 1.0)
+            .unwrap()
+            .unwrap();
+        let (single, _) = sender.send_packet();
+        assert_eq!(single.len(), 1);
+
+        // the message is sent once, then resent twice
+        for _ in 0..2 {
+            sender.current_time += Duration::from_millis(200);
+            let (single, _) = sender.send_packet();
+            assert_eq!(single.len(), 1);
+            assert!(sender.dropped_messages().is_empty());
+        }
+        assert_eq!(sender.unacked_messages[&message_id].retries, 2);
+
+        // not acked after the last retry: the message is dropped instead of being resent
+        sender.current_time += Duration::from_millis(200);
+        let (single, _) = sender.send_packet();
+        assert!(single.is_empty());
+        assert_eq!(sender.dropped_messages(), vec![message_id]);
+        assert!(sender.dropped_messages().is_empty());
+        assert_eq!(
+            sender.take_dropped_messages(),
+            vec![(message_id, DropReason::MaxRetries)]
+        );
+        assert_eq!(sender.pending_count(), 0);
+        assert_eq!(nacks.try_recv(), Ok(message_id));
+    }
+
+    #[test]
+    fn test_messages_to_resend() {
+        let mut sender = ReliableSender::new(
+            ReliableSettings {
+                rtt_resend_factor: 1.5,
+                rtt_resend_min_delay: Duration::default(),
+                ..default()
+            },
+            Duration::default(),
+        );
+        let rtt = Duration::from_millis(100);
+        sender.current_time = WrappedTime::new(0);
+        sender.buffer_send(Bytes::from("hello"), 1.0).unwrap();
+        sender.buffer_send(Bytes::from("world"), 1.0).unwrap();
+
+        // messages that were never sent are not resent
+        assert!(sender
+            .messages_to_resend(WrappedTime::new(1000), rtt)
+            .is_empty());
+        sender.send_packet();
+
+        // the resend delay is 150ms
+        assert!(sender
+            .messages_to_resend(WrappedTime::new(150), rtt)
+            .is_empty());
+        assert_eq!(
+            sender.messages_to_resend(WrappedTime::new(151), rtt),
+            vec![MessageId(0), MessageId(1)]
+        );
+        // the messages are resent by the next call to `send_packet`, and only once
+        let (single, _) = sender.send_packet();
+        assert_eq!(sent_ids(single), vec![MessageId(0), MessageId(1)]);
+        assert!(sender.send_packet().0.is_empty());
+        // the last-sent time was updated
+        assert!(sender
+            .messages_to_resend(WrappedTime::new(301), rtt)
+            .is_empty());
+
+        // acked messages are not resent
+        sender.receive_ack(&MessageAck {
+            message_id: MessageId(0),
+            fragment_id: None,
+        });
+        assert_eq!(
+            sender.messages_to_resend(WrappedTime::new(302), rtt),
+            vec![MessageId(1)]
+        );
+    }
+
+    /// The resends of `messages_to_resend` count towards the maximum number of retries
+    #[test]
+    fn test_messages_to_resend_max_retries() {
+        let mut sender = ReliableSender::new(
+            ReliableSettings {
+                rtt_resend_factor: 1.5,
+                rtt_resend_min_delay: Duration::default(),
+                max_retries: Some(1),
+                ..default()
+            },
+            Duration::default(),
+        );
+        let rtt = Duration::from_millis(100);
+        sender.current_time = WrappedTime::new(0);
+        let message_id = sender
+            .buffer_send(Bytes::from("hello"), 1.0)
+            .unwrap()
+            .unwrap();
+        sender.send_packet();
+
+        assert_eq!(
+            sender.messages_to_resend(WrappedTime::new(151), rtt),
+            vec![message_id]
+        );
+        sender.send_packet();
+        // the message was already resent once: it is dropped instead of being resent again
+        assert!(sender
+            .messages_to_resend(WrappedTime::new(302), rtt)
+            .is_empty());
+        assert_eq!(sender.dropped_messages(), vec![message_id]);
+        assert_eq!(sender.pending_count(), 0);
+    }
+
+    /// A message past its ticks to live is not resent anymore, even if it was not acked
+    #[test]
+    fn test_ticks_to_live() {
+        let mut sender = ReliableSender::new(
+            ReliableSettings {
+                rtt_resend_factor: 1.5,
+                rtt_resend_min_delay: Duration::default(),
+                ..default()
+            },
+            Duration::default(),
+        );
+        let rtt = Duration::from_millis(100);
+        let nacks = sender.subscribe_nacks();
+        sender.current_time = WrappedTime::new(0);
+        sender.current_tick = Tick(10);
+        let stale = sender
+            .buffer_send_single(
+                SingleData::new(None, Bytes::from("voice")).with_ticks_to_live(5),
+                1.0,
+            )
+            .unwrap()
+            .unwrap();
+        let reliable = sender
+            .buffer_send(Bytes::from("chat"), 1.0)
+            .unwrap()
+            .unwrap();
+        sender.send_packet();
+
+        // the message can still be resent at its last tick
+        sender.current_tick = Tick(15);
+        assert_eq!(
+            sender.messages_to_resend(WrappedTime::new(151), rtt),
+            vec![stale, reliable]
+        );
+        assert!(nacks.try_recv().is_err());
+        sender.send_packet();
+
+        // after that, it is evicted and reported as lost
+        sender.current_tick = Tick(16);
+        assert_eq!(
+            sender.messages_to_resend(WrappedTime::new(302), rtt),
+            vec![reliable]
+        );
+        assert_eq!(sender.pending_count(), 1);
+        assert_eq!(nacks.try_recv(), Ok(stale));
+        assert_eq!(
+            sender.take_dropped_messages(),
+            vec![(stale, DropReason::Expired)]
+        );
+    }
+
+    #[test]
+    fn test_resend_budget() {
+        let mut sender = ReliableSender::new(
+            ReliableSettings {
+                max_resend_bytes_per_tick: Some(25),
+                ..default()
+            },
+            Duration::default(),
+        );
+        sender.current_rtt = Duration::from_millis(100);
+        sender.current_time = WrappedTime::new(0);
+        for i in 0..10 {
+            sender.buffer_send(Bytes::from(vec![i; 10]), 1.0).unwrap();
+        }
+        // the messages that were never sent are not limited by the budget
+        let (single, _) = sender.send_packet();
+        assert_eq!(single.len(), 10);
+
+        // only 2 messages of 10 bytes fit in the budget, the oldest ones
+        sender.current_time += Duration::from_millis(200);
+        let (single, _) = sender.send_packet();
+        assert_eq!(sent_ids(single), vec![MessageId(0), MessageId(1)]);
+        // the other messages are resent on the next ticks
+        sender.current_time += Duration::from_millis(1);
+        let (single, _) = sender.send_packet();
+        assert_eq!(sent_ids(single), vec![MessageId(2), MessageId(3)]);
+
+        // same for the retransmit API
+        assert_eq!(
+            sender.messages_to_resend(WrappedTime::new(300), Duration::from_millis(100)),
+            vec![MessageId(4), MessageId(5)]
+        );
+    }
+
+    #[test]
+    fn test_receive_ack_fragments() {
+        let mut sender = ReliableSender::new(ReliableSettings::default(), Duration::default());
+        let message = Bytes::from(vec![1; 2 * sender.fragment_sender.fragment_size + 1]);
+        let message_id = sender.buffer_send(message, 1.0).unwrap().unwrap();
+        sender.buffer_send(Bytes::from("hello"), 1.0).unwrap();
+        assert_eq!(sender.pending_count(), 2);
+        let acks = sender.subscribe_acks();
+
+        // the fragments are acked one by one, out of order
+        for fragment_id in [2, 0] {
+            sender.receive_ack(&MessageAck {
+                message_id,
+                fragment_id: Some(fragment_id),
+            });
+            assert_eq!(sender.pending_count(), 2);
+        }
+        assert!(acks.try_recv().is_err());
+        sender.receive_ack(&MessageAck {
+            message_id,
+            fragment_id: Some(1),
+        });
+        assert_eq!(sender.pending_count(), 1);
+        assert_eq!(acks.try_recv(), Ok(message_id));
+    }
+
+    /// Only the fragments that were not acked are resent
+    #[test]
+    fn test_resend_missing_fragments() {
+        let mut sender = ReliableSender::new(
+            ReliableSettings {
+                rtt_resend_factor: 1.5,
+                rtt_resend_min_delay: Duration::default(),
+                ..default()
+            },
+            Duration::default(),
+        );
+        let rtt = Duration::from_millis(100);
+        sender.current_rtt = rtt;
+        sender.current_time = WrappedTime::new(0);
+        let message = Bytes::from(vec![1; 2 * sender.fragment_sender.fragment_size + 1]);
+        let message_id = sender.buffer_send(message, 1.0).unwrap().unwrap();
+        let (_, fragments) = sender.send_packet();
+        assert_eq!(fragments.len(), 3);
+
+        for fragment_id in [0, 2] {
+            sender.receive_ack(&MessageAck {
+                message_id,
+                fragment_id: Some(fragment_id),
+            });
+        }
+        assert_eq!(
+            sender.fragments_to_resend(WrappedTime::new(151), rtt),
+            vec![MessageAck {
+                message_id,
+                fragment_id: Some(1),
+            }]
+        );
+        sender.send_packet();
+        assert_eq!(
+            sender.messages_to_resend(WrappedTime::new(302), rtt),
+            vec![message_id]
+        );
+
+        // the packets only contain the missing fragment
+        sender.current_time = WrappedTime::new(500);
+        let (single, fragments) = sender.send_packet();
+        assert!(single.is_empty());
+        let fragment_ids = fragments
+            .into_iter()
+            .map(|message| match message.data {
+                MessageData::Fragment(data) => data.fragment_id,
+                MessageData::Single(_) => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fragment_ids, vec![1]);
+    }
+
+    /// The receiver requests the fragment that was lost, and only that fragment is resent right
+    /// away
+    #[test]
+    fn test_fragment_nack() {
+        use crate::channel::receivers::fragment_receiver::FragmentReceiver;
+
+        let mut sender = ReliableSender::new(ReliableSettings::default(), Duration::default());
+        sender.current_rtt = Duration::from_millis(100);
+        sender.current_time = WrappedTime::new(0);
+        let message = Bytes::from(vec![1; 2 * sender.fragment_sender.fragment_size + 1]);
+        let message_id = sender.buffer_send(message, 1.0).unwrap().unwrap();
+        let fragment_ids = |messages: VecDeque<SendMessage>| {
+            messages
+                .into_iter()
+                .map(|message| match message.data {
+                    MessageData::Fragment(data) => data,
+                    MessageData::Single(_) => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let (_, fragments) = sender.send_packet();
+        let fragments = fragment_ids(fragments);
+        assert_eq!(fragments.len(), 3);
+
+        // fragment 1 is lost
+        let mut receiver = FragmentReceiver::new();
+        for fragment in [&fragments[0], &fragments[2]] {
+            assert_eq!(
+                receiver.receive_fragment(fragment.clone(), Tick(0), None),
+                Ok(None)
+            );
+        }
+        assert_eq!(receiver.missing_fragments(message_id), vec![1]);
+        assert!(receiver.missing_fragments(MessageId(10)).is_empty());
+
+        // the sender resends fragment 1 before the resend delay
+        sender.receive_fragment_nack(message_id, &receiver.missing_fragments(message_id));
+        let (_, fragments) = sender.send_packet();
+        let fragments = fragment_ids(fragments);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].fragment_id, 1);
+        assert_eq!(
+            receiver
+                .receive_fragment(fragments[0].clone(), Tick(0), None)
+                .unwrap()
+                .map(|(_, bytes)| bytes.len()),
+            Some(2 * sender.fragment_sender.fragment_size + 1)
+        );
+    }
+
+    #[test]
+    fn test_receive_duplicate_ack() {
+        let mut sender = ReliableSender::new(ReliableSettings::default(), Duration::default());
+        let message = Bytes::from(vec![1; sender.fragment_sender.fragment_size + 1]);
+        let fragmented_id = sender.buffer_send(message, 1.0).unwrap().unwrap();
+        let single_id = sender
+            .buffer_send(Bytes::from("hello"), 1.0)
+            .unwrap()
+            .unwrap();
+        let acks = sender.subscribe_acks();
+
+        let single_ack = MessageAck {
+            message_id: single_id,
+            fragment_id: None,
+        };
+        let fragment_ack = MessageAck {
+            message_id: fragmented_id,
+            fragment_id: Some(0),
+        };
+        sender.receive_ack(&single_ack);
+        sender.receive_ack(&fragment_ack);
+        assert_eq!(sender.pending_count(), 1);
+        // re-acking a fragment or an evicted message does nothing
+        sender.receive_ack(&fragment_ack);
+        sender.receive_ack(&single_ack);
+        sender.receive_ack(&MessageAck {
+            message_id: fragmented_id,
+            fragment_id: Some(10),
+        });
+        assert_eq!(sender.pending_count(), 1);
+        assert_eq!(acks.try_iter().collect::<Vec<_>>(), vec![single_id]);
+    }
 }
@@ -43,7 +43,7 @@ impl SequencedUnreliableSender {
             single_messages_to_send: VecDeque::new(),
             fragmented_messages_to_send: VecDeque::new(),
             next_send_message_id: MessageId(0),
-            fragment_sender: FragmentSender::new(),
+            fragment_sender: FragmentSender::default(),
             nack_senders: vec![],
             timer,
         }
@@ -122,6 +122,18 @@ impl ChannelSend for SequencedUnreliableSender {
             sender.send(nack).unwrap();
         }
     }
+
+    fn set_fragment_size(&mut self, fragment_size: usize) {
+        self.fragment_sender.fragment_size = fragment_size;
+    }
+
+    fn set_max_fragments_per_message(&mut self, max_fragments_per_message: usize) {
+        self.fragment_sender.max_fragments_per_message = max_fragments_per_message;
+    }
+
+    fn has_queued_fragments(&self) -> bool {
+        !self.fragmented_messages_to_send.is_empty()
+    }
 }
 
 #[cfg(test)]
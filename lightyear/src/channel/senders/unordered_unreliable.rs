@@ -44,7 +44,7 @@ impl UnorderedUnreliableSender {
             single_messages_to_send: VecDeque::new(),
             fragmented_messages_to_send: VecDeque::new(),
             next_send_fragmented_message_id: MessageId::default(),
-            fragment_sender: FragmentSender::new(),
+            fragment_sender: FragmentSender::default(),
             nack_senders: vec![],
             timer,
         }
@@ -123,6 +123,18 @@ impl ChannelSend for UnorderedUnreliableSender {
             sender.send(nack).unwrap();
         }
     }
+
+    fn set_fragment_size(&mut self, fragment_size: usize) {
+        self.fragment_sender.fragment_size = fragment_size;
+    }
+
+    fn set_max_fragments_per_message(&mut self, max_fragments_per_message: usize) {
+        self.fragment_sender.max_fragments_per_message = max_fragments_per_message;
+    }
+
+    fn has_queued_fragments(&self) -> bool {
+        !self.fragmented_messages_to_send.is_empty()
+    }
 }
 
 #[cfg(test)]
@@ -9,6 +9,8 @@ use crate::client::interpolation::plugin::InterpolationConfig;
 use crate::client::prediction::plugin::PredictionConfig;
 use crate::client::sync::SyncConfig;
 use crate::connection::client::NetConfig;
+use crate::packet::error::PacketError;
+use crate::packet::wire_config::WireConfig;
 use crate::shared::config::SharedConfig;
 use crate::shared::ping::manager::PingConfig;
 use crate::shared::replication::plugin::ReplicationConfig;
@@ -60,6 +62,10 @@ pub struct PacketConfig {
     pub send_bandwidth_cap: Quota,
     /// If false, there is no bandwidth cap and all messages are sent as soon as possible
     pub bandwidth_cap_enabled: bool,
+    #[reflect(ignore)]
+    /// How the packets are written on the wire (MTU, checksum, compression, padding).
+    /// The client and the server must use the same config.
+    pub wire_config: WireConfig,
 }
 
 impl Default for PacketConfig {
@@ -69,6 +75,7 @@ impl Default for PacketConfig {
             // 56 KB/s bandwidth cap
             send_bandwidth_cap: Quota::per_second(nonzero!(56000u32)),
             bandwidth_cap_enabled: false,
+            wire_config: WireConfig::default(),
         }
     }
 }
@@ -89,6 +96,17 @@ impl PacketConfig {
         self.bandwidth_cap_enabled = true;
         self
     }
+
+    /// Write the packets with this [`WireConfig`].
+    ///
+    /// Returns an error if the config is invalid (for example an MTU that is too small to hold a
+    /// fragment). An invalid config that is set directly on the field is replaced by the default
+    /// config when the connection is created.
+    pub fn with_wire_config(mut self, wire_config: WireConfig) -> Result<Self, PacketError> {
+        wire_config.validate()?;
+        self.wire_config = wire_config;
+        Ok(self)
+    }
 }
 
 /// The configuration object that lets you create a `ClientPlugin` with the desired settings.
@@ -138,7 +138,10 @@ impl ConnectionManager {
             channel_registry,
             client_config.packet.nack_rtt_multiple,
             client_config.packet.into(),
-        );
+        )
+        .with_wire_config(client_config.packet.wire_config.or_default_if_invalid())
+        .expect("the wire config is valid")
+        .with_handshake(true);
         // get notified when a replication-update message gets acked/nacked
         let entity_updates_sender = &mut message_manager
             .channels
@@ -26,6 +26,10 @@ impl ToBytes for ClientMessage {
         self.target.len() + self.message.len()
     }
 
+    fn max_len() -> Option<usize> {
+        None
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         self.target.to_bytes(buffer)?;
         // NOTE: we just write the message bytes directly! We don't provide the length
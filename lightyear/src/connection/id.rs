@@ -21,6 +21,10 @@ impl ToBytes for ClientId {
         9
     }
 
+    fn max_len() -> Option<usize> {
+        Some(9)
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         match self {
             ClientId::Netcode(id) => {
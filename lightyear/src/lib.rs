@@ -185,8 +185,8 @@ pub mod prelude {
     pub use serde::{Deserialize, Serialize};
 
     pub use crate::channel::builder::{
-        Channel, ChannelBuilder, ChannelContainer, ChannelDirection, ChannelMode, ChannelSettings,
-        InputChannel, ReliableSettings,
+        Channel, ChannelBuilder, ChannelConfigError, ChannelContainer, ChannelDirection,
+        ChannelMode, ChannelSettings, InputChannel, ReliableSettings, RetransmitClock,
     };
     pub use crate::client::prediction::prespawn::PreSpawnedPlayerObject;
     pub use crate::connection::id::ClientId;
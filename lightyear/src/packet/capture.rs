@@ -0,0 +1,154 @@
+//! Capture the packets built by the [`PacketBuilder`](crate::packet::packet_builder::PacketBuilder)
+//! to a file, and read them back to replay them (for example to diagnose a desync).
+//!
+//! The file starts with [`CAPTURE_MAGIC`], followed by one record per packet:
+//! - timestamp in microseconds since the unix epoch (u64)
+//! - packet id (u16)
+//! - tick (u16)
+//! - length of the packet (u32)
+//! - the raw bytes of the packet
+//!
+//! All integers are written in network byte order.
+use std::fmt::{Debug, Formatter};
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::connection::netcode::MAX_PACKET_SIZE;
+use crate::packet::packet::{Packet, PacketId};
+use crate::serialize::SerializationError;
+use crate::shared::tick_manager::Tick;
+
+/// Bytes at the start of every capture file (the last byte is the version of the format)
+pub(crate) const CAPTURE_MAGIC: [u8; 6] = *b"LYCAP\x01";
+
+/// Callback invoked with every packet finished by the `PacketBuilder`
+pub(crate) struct PacketCapture(pub(crate) Box<dyn FnMut(&Packet) + Send + Sync>);
+
+impl Debug for PacketCapture {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketCapture").finish_non_exhaustive()
+    }
+}
+
+/// Writes captured packets, with the time at which they were captured
+#[derive(Debug)]
+pub(crate) struct PcapLikeWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapLikeWriter<W> {
+    pub(crate) fn new(mut writer: W) -> Result<Self, SerializationError> {
+        writer.write_all(&CAPTURE_MAGIC)?;
+        Ok(Self { writer })
+    }
+
+    /// Write the packet, timestamped with the current time
+    pub(crate) fn write_packet(&mut self, packet: &Packet) -> Result<(), SerializationError> {
+        let timestamp = bevy::utils::SystemTime::now()
+            .duration_since(bevy::utils::SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.write_packet_at(timestamp, packet)
+    }
+
+    pub(crate) fn write_packet_at(
+        &mut self,
+        timestamp: Duration,
+        packet: &Packet,
+    ) -> Result<(), SerializationError> {
+        let tick = Packet::peek_header(&packet.payload)?.tick;
+        self.writer
+            .write_u64::<NetworkEndian>(timestamp.as_micros() as u64)?;
+        // only the low 16 bits of the packet id are recorded, like in a 16-bit header
+        self.writer
+            .write_u16::<NetworkEndian>(packet.packet_id.0 as u16)?;
+        self.writer.write_u16::<NetworkEndian>(tick.0)?;
+        self.writer
+            .write_u32::<NetworkEndian>(packet.payload.len() as u32)?;
+        self.writer.write_all(&packet.payload)?;
+        Ok(())
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// A packet read back from a capture file
+#[derive(Debug)]
+pub(crate) struct CapturedPacket {
+    /// Time since the unix epoch at which the packet was captured
+    pub(crate) timestamp: Duration,
+    pub(crate) tick: Tick,
+    pub(crate) packet: Packet,
+}
+
+/// Read all the packets of a capture file written by [`PcapLikeWriter`]
+pub(crate) fn replay(mut reader: impl Read) -> Result<Vec<CapturedPacket>, SerializationError> {
+    let mut magic = [0; CAPTURE_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != CAPTURE_MAGIC {
+        return Err(SerializationError::InvalidValue);
+    }
+    let mut packets = vec![];
+    loop {
+        let timestamp = match reader.read_u64::<NetworkEndian>() {
+            Ok(timestamp) => Duration::from_micros(timestamp),
+            // a record can only end at the end of the file
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        let packet_id = PacketId(u32::from(reader.read_u16::<NetworkEndian>()?));
+        let tick = Tick(reader.read_u16::<NetworkEndian>()?);
+        let len = reader.read_u32::<NetworkEndian>()? as usize;
+        // the length comes from the file: only allocate for the bytes that are actually there
+        let mut payload = Vec::with_capacity(len.min(MAX_PACKET_SIZE));
+        (&mut reader).take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() < len {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        packets.push(CapturedPacket {
+            timestamp,
+            tick,
+            packet: Packet {
+                payload,
+                message_acks: vec![],
+                packet_id,
+                mtu: MAX_PACKET_SIZE.max(len),
+                prewritten_size: 0,
+                written_messages: 0,
+            },
+        });
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_invalid_magic() {
+        assert!(matches!(
+            replay(&b"PCAP\x00\x00"[..]),
+            Err(SerializationError::InvalidValue)
+        ));
+        // truncated record
+        let mut bytes = CAPTURE_MAGIC.to_vec();
+        bytes.extend_from_slice(&[0; 10]);
+        assert!(matches!(
+            replay(bytes.as_slice()),
+            Err(SerializationError::Io(_))
+        ));
+        // a record that claims to be much longer than the file
+        let mut bytes = CAPTURE_MAGIC.to_vec();
+        bytes.extend_from_slice(&[0; 12]);
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert!(matches!(
+            replay(bytes.as_slice()),
+            Err(SerializationError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
+}
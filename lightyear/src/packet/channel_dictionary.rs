@@ -0,0 +1,73 @@
+//! Dictionary to shrink the channel ids written in each packet
+use bevy::utils::HashMap;
+use byteorder::WriteBytesExt;
+use std::io::Read;
+
+use crate::protocol::channel::ChannelId;
+use crate::serialize::varint::{read_varint, varint_len, VarIntWriteExt};
+use crate::serialize::SerializationError;
+
+/// Maps frequently-used [`ChannelId`]s to small tokens, so that large channel ids can
+/// still be written with a single byte.
+///
+/// Both peers must use the same dictionary (for example by agreeing on it when connecting).
+///
+/// A channel id that is in the dictionary is written as its token. Any other channel id `c`
+/// is written as `c + num_tokens`, so that tokens and raw channel ids can be told apart.
+/// An empty dictionary writes the raw channel ids.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ChannelIdDictionary {
+    /// Channel id associated with each token
+    channel_ids: Vec<ChannelId>,
+    /// Token associated with each channel id in the dictionary
+    tokens: HashMap<ChannelId, u64>,
+}
+
+impl ChannelIdDictionary {
+    /// Create a dictionary from a list of channel ids. The first channel id gets the token 0, etc.
+    ///
+    /// Only the first 64 tokens fit in a single byte.
+    pub(crate) fn new(channel_ids: impl IntoIterator<Item = ChannelId>) -> Self {
+        let mut dictionary = Self::default();
+        for channel_id in channel_ids {
+            if dictionary.tokens.contains_key(&channel_id) {
+                continue;
+            }
+            dictionary
+                .tokens
+                .insert(channel_id, dictionary.channel_ids.len() as u64);
+            dictionary.channel_ids.push(channel_id);
+        }
+        dictionary
+    }
+
+    /// Value that will be written in the packet for this channel id
+    fn encode(&self, channel_id: ChannelId) -> u64 {
+        self.tokens
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(channel_id as u64 + self.channel_ids.len() as u64)
+    }
+
+    /// Number of bytes needed to write this channel id
+    pub(crate) fn len(&self, channel_id: ChannelId) -> usize {
+        varint_len(self.encode(channel_id))
+    }
+
+    pub(crate) fn write<T: WriteBytesExt>(
+        &self,
+        channel_id: ChannelId,
+        buffer: &mut T,
+    ) -> Result<(), SerializationError> {
+        buffer.write_varint(self.encode(channel_id))
+    }
+
+    pub(crate) fn read(&self, buffer: &mut impl Read) -> Result<ChannelId, SerializationError> {
+        let value = read_varint(buffer)?;
+        if let Some(channel_id) = self.channel_ids.get(value as usize) {
+            return Ok(*channel_id);
+        }
+        ChannelId::try_from(value - self.channel_ids.len() as u64)
+            .map_err(|_| SerializationError::UnknownChannel(value))
+    }
+}
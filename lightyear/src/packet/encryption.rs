@@ -0,0 +1,134 @@
+//! Encrypt the messages of the channels that carry sensitive data.
+//!
+//! The messages of an encrypted channel are written as one block, that is encrypted as a whole:
+//!
+//! `channel id | 0 | number of messages | length of the block | encrypted block`
+//!
+//! A channel section never contains 0 messages, so the `0` tells the receiver that the section
+//! must be decrypted. The sections of the other channels are unchanged.
+use std::fmt::{Debug, Formatter};
+
+use bevy::utils::HashSet;
+use bytes::Bytes;
+
+use crate::protocol::channel::ChannelId;
+use crate::serialize::reader::Reader;
+use crate::serialize::varint::{varint_len, VarIntWriteExt};
+use crate::serialize::SerializationError;
+
+/// Byte written instead of the number of messages at the start of an encrypted section
+pub(crate) const ENCRYPTED_SECTION_MARKER: u8 = 0;
+
+/// Encrypts, in place, the block of messages of a channel
+pub(crate) type EncryptFn = Box<dyn FnMut(ChannelId, &mut Vec<u8>) + Send + Sync>;
+
+/// Decrypts, in place, the block of messages of a channel. Returns false if the block could not
+/// be decrypted (for example if its authentication tag is invalid)
+pub(crate) type DecryptFn = Box<dyn FnMut(ChannelId, &mut Vec<u8>) -> bool + Send + Sync>;
+
+/// Encrypts the messages of the channels that have
+/// [`ChannelSettings::encrypted`](crate::prelude::ChannelSettings::encrypted) set
+#[derive(Default)]
+pub(crate) struct ChannelEncryption {
+    /// Channels whose messages are encrypted
+    channels: HashSet<ChannelId>,
+    /// Maximum number of bytes that the cipher adds to a block (for example an authentication tag)
+    tag_bytes: usize,
+    encrypt: Option<EncryptFn>,
+    /// Buffer reused to write the block of messages before it is encrypted
+    block: Vec<u8>,
+}
+
+impl Debug for ChannelEncryption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelEncryption")
+            .field("channels", &self.channels)
+            .field("tag_bytes", &self.tag_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChannelEncryption {
+    /// Set the channels whose messages are encrypted
+    pub(crate) fn set_channels(&mut self, channels: impl IntoIterator<Item = ChannelId>) {
+        self.channels = channels.into_iter().collect();
+    }
+
+    /// Encrypt the blocks with `encrypt`, which can make a block at most `tag_bytes` longer
+    pub(crate) fn set_cipher(&mut self, tag_bytes: usize, encrypt: EncryptFn) {
+        self.tag_bytes = tag_bytes;
+        self.encrypt = Some(encrypt);
+    }
+
+    pub(crate) fn is_encrypted(&self, channel_id: ChannelId) -> bool {
+        self.channels.contains(&channel_id)
+    }
+
+    /// Number of bytes that encrypting the messages of `channel_id` adds to a packet of at most
+    /// `mtu` bytes: the marker, the length of the block and the tag
+    pub(crate) fn overhead(&self, channel_id: ChannelId, mtu: usize) -> usize {
+        if !self.is_encrypted(channel_id) {
+            return 0;
+        }
+        1 + varint_len(mtu as u64) + self.tag_bytes
+    }
+
+    /// Take the buffer in which the block of messages is written before being encrypted
+    pub(crate) fn take_block(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.block);
+        block.clear();
+        block
+    }
+
+    /// Encrypt `block` and write it (preceded by its length) in `payload`.
+    ///
+    /// Returns an error if there is no cipher, or if the cipher added more than the tag bytes
+    /// that were reserved in the packet.
+    pub(crate) fn write_block(
+        &mut self,
+        channel_id: ChannelId,
+        mut block: Vec<u8>,
+        payload: &mut Vec<u8>,
+    ) -> Result<(), SerializationError> {
+        let encrypt = self
+            .encrypt
+            .as_mut()
+            .ok_or(SerializationError::MissingCipher { channel_id })?;
+        let plaintext_len = block.len();
+        encrypt(channel_id, &mut block);
+        if block.len() > plaintext_len + self.tag_bytes {
+            return Err(SerializationError::SizeAccountingMismatch {
+                expected: plaintext_len + self.tag_bytes,
+                actual: block.len(),
+            });
+        }
+        payload.write_varint(block.len() as u64)?;
+        payload.extend_from_slice(&block);
+        self.block = block;
+        Ok(())
+    }
+}
+
+/// Decrypts the encrypted sections of the packets that are received
+pub(crate) struct ChannelDecryption(pub(crate) DecryptFn);
+
+impl Debug for ChannelDecryption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelDecryption").finish_non_exhaustive()
+    }
+}
+
+impl ChannelDecryption {
+    /// Decrypt the block of messages of `channel_id`, and return a reader over the messages
+    pub(crate) fn read_block(
+        &mut self,
+        channel_id: ChannelId,
+        block: Bytes,
+    ) -> Result<Reader, SerializationError> {
+        let mut block = Vec::from(block);
+        if !(self.0)(channel_id, &mut block) {
+            return Err(SerializationError::DecryptionFailed { channel_id });
+        }
+        Ok(Reader::from(block))
+    }
+}
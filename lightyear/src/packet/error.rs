@@ -1,6 +1,8 @@
 //! Errors for building packets
 
 use crate::channel::receivers::error::ChannelReceiveError;
+use crate::packet::wire_config::WireConfig;
+use crate::protocol::channel::{ChannelId, ChannelSchemaEntry};
 use crate::serialize::SerializationError;
 
 pub type Result<T> = core::result::Result<T, PacketError>;
@@ -12,4 +14,25 @@ pub enum PacketError {
     ChannelNotFound,
     #[error("receiver channel error: {0}")]
     ChannelReceiveError(#[from] ChannelReceiveError),
+    #[error(
+        "the remote channel {channel_id} ({remote:?}) does not match the local channel ({local:?})"
+    )]
+    ChannelSchemaMismatch {
+        channel_id: ChannelId,
+        local: Option<ChannelSchemaEntry>,
+        remote: Option<ChannelSchemaEntry>,
+    },
+    #[error(
+        "the remote wire config ({remote:?}) does not match the local wire config ({local:?})"
+    )]
+    WireConfigMismatch {
+        local: WireConfig,
+        remote: WireConfig,
+    },
+    #[error("the MTU ({mtu} bytes) is too small to hold a fragment (at least {min} bytes)")]
+    MtuTooSmall { mtu: usize, min: usize },
+    #[error("the MTU can't be lowered to {mtu} bytes while fragments built for the current MTU are queued")]
+    FragmentsQueued { mtu: usize },
+    #[error("the ack window must be 32, 64 or 128 bits, got {ack_window_bits}")]
+    InvalidAckWindow { ack_window_bits: u8 },
 }
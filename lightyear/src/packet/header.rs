@@ -1,51 +1,182 @@
-use bevy::utils::HashMap;
+use bevy::utils::{Duration, HashMap, HashSet};
 use byteorder::NetworkEndian;
 use byteorder::ReadBytesExt;
-use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
+use ringbuffer::{AllocRingBuffer, RingBuffer};
 use tracing::trace;
 
-use crate::packet::packet::PacketId;
-use crate::packet::packet_type::PacketType;
+use crate::packet::error::PacketError;
+use crate::packet::packet::{PacketId, MAX_HEADER_BYTES};
+use crate::packet::packet_type::{
+    PacketType, ACK_WINDOW_MASK, ACK_WINDOW_SHIFT, COMPRESSED_PACKET_FLAG, DICTIONARY_PACKET_FLAG,
+    EXTENDED_PACKET_TYPE, PACKET_TYPE_MASK, SORTED_PACKET_FLAG,
+};
 use crate::packet::stats_manager::packet::PacketStatsManager;
 use crate::prelude::TimeManager;
 use crate::serialize::reader::Reader;
+use crate::serialize::varint::{varint_len, VarIntReadExt, VarIntWriteExt, VARINT_MAX};
 use crate::serialize::{SerializationError, ToBytes};
 use crate::shared::ping::manager::PingManager;
 use crate::shared::tick_manager::Tick;
 use crate::shared::time_manager::WrappedTime;
+use crate::utils::wrapping_id::wrapping_diff;
+
+/// Value of the ack window bits of the packet type byte for a header written with the
+/// [`HeaderLayout::Compact`] layout. The width of the ack bitfield is written after the packet id
+const COMPACT_HEADER: u8 = 3;
+
+/// Value of the low 2 bits of the varint that follows the packet id of a
+/// [`HeaderLayout::Compact`] header (the width of the ack bitfield, otherwise) for a header with
+/// [`PacketIdWidth::U32`] ids. The width of the ack bitfield is in the other bits of the varint.
+const WIDE_PACKET_IDS: u64 = 3;
+
+/// How the fields of the [`PacketHeader`] are written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HeaderLayout {
+    /// Every field has a fixed width, so that external tools can parse the header easily
+    #[default]
+    Verbose,
+    /// The last acked packet id is written as a varint offset from the packet id (along with the
+    /// width of the ack bitfield), and the ack bitfield as a varint of the packets that were *not*
+    /// received (usually 0).
+    ///
+    /// A header is still written with the verbose layout when the compact one would not be
+    /// smaller, so a compact header is never bigger than a verbose one. The remote peer detects
+    /// the layout of each header from a bit of the packet type byte.
+    Compact,
+}
+
+/// Number of bits of the packet ids written in the [`PacketHeader`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PacketIdWidth {
+    /// Only the low 16 bits of the packet ids are written. The receiver recovers the full id from
+    /// the ids it has seen recently, which only works if they are less than 32768 packets apart.
+    #[default]
+    U16,
+    /// The full 32-bit packet ids are written, for long sessions and wide ack windows.
+    ///
+    /// The other fields are written with a fixed width, whatever the [`HeaderLayout`]:
+    /// the header takes 5 more bytes than a [`HeaderLayout::Verbose`] header.
+    U32,
+}
+
+impl PacketIdWidth {
+    /// Recover the full id of a packet id read in a header with this width, as the id closest
+    /// to `reference` that has the same low 16 bits
+    fn extend(self, id: PacketId, reference: PacketId) -> PacketId {
+        match self {
+            PacketIdWidth::U16 => PacketId(
+                reference
+                    .0
+                    .wrapping_add_signed(i32::from(wrapping_diff(reference.0 as u16, id.0 as u16))),
+            ),
+            PacketIdWidth::U32 => id,
+        }
+    }
+}
+
+/// Width of the ack bitfield used by default
+pub(crate) const DEFAULT_ACK_WINDOW_BITS: u8 = 32;
 
 /// Header included at the start of all packets
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct PacketHeader {
+pub struct PacketHeader {
     // TODO: this seems useless besides Data vs DataFragment
     /// Type of the packet sent
     packet_type: PacketType,
+    /// True if the rest of the packet (after the header) is compressed
+    pub(crate) compressed: bool,
+    /// True if the packer reordered the messages of the packet (see [`SORTED_PACKET_FLAG`])
+    pub(crate) sorted: bool,
+    /// Id of the [`CompressionDictionary`](crate::packet::packet::CompressionDictionary) used to
+    /// compress the packet, if any
+    pub(crate) compression_dictionary: Option<u8>,
     /// Packet id from the sender's perspective
     pub(crate) packet_id: PacketId,
     /// Last ack-ed packet id received by the sender
     last_ack_packet_id: PacketId,
-    /// Bitfield of the last 32 packet ids before `ack_id`
-    /// (this means that in total we send acks for 33 packet-ids)
+    /// Number of bits of `ack_bitfield` that are written (32, 64 or 128)
+    ack_window_bits: u8,
+    /// Bitfield of the last `ack_window_bits` packet ids before `ack_id`
+    /// (this means that in total we send acks for `ack_window_bits + 1` packet-ids)
     /// See more information at: [GafferOnGames](https://gafferongames.com/post/reliability_ordering_and_congestion_avoidance_over_udp/)
-    ack_bitfield: u32,
+    ack_bitfield: u128,
     /// Current tick
     pub(crate) tick: Tick,
+    /// Layout used to write the header. A header that was read has the layout it was written with
+    layout: HeaderLayout,
+    /// Number of bits of the packet ids written in the header
+    packet_id_width: PacketIdWidth,
 }
 
 impl ToBytes for PacketHeader {
     fn len(&self) -> usize {
-        11
+        let extra_len = usize::from(self.compression_dictionary().is_some())
+            + usize::from(self.extended_type().is_some());
+        if self.packet_id_width == PacketIdWidth::U32 {
+            return 12 + self.ack_window_bits as usize / 8 + extra_len;
+        }
+        extra_len
+            + match self.compact_fields() {
+                Some((ack_offset, missing_acks)) => {
+                    5 + varint_len(ack_offset) + varint_len(missing_acks)
+                }
+                None => self.verbose_len(),
+            }
+    }
+
+    /// A compact header is never bigger than the verbose one
+    fn max_len() -> Option<usize> {
+        Some(MAX_HEADER_BYTES)
     }
 
     fn to_bytes<T: byteorder::WriteBytesExt>(
         &self,
         buffer: &mut T,
     ) -> Result<(), SerializationError> {
-        buffer.write_u8(self.packet_type as u8)?;
-        buffer.write_u16::<NetworkEndian>(self.packet_id.0)?;
-        buffer.write_u16::<NetworkEndian>(self.last_ack_packet_id.0)?;
-        buffer.write_u32::<NetworkEndian>(self.ack_bitfield)?;
+        let compact_fields = self.compact_fields();
+        let wide_ids = self.packet_id_width == PacketIdWidth::U32;
+        // `User` below the user range, or `Unknown` for a known type, would not be read back
+        // as the same type
+        if PacketType::from(u8::from(self.packet_type)) != self.packet_type {
+            return Err(SerializationError::InvalidValue);
+        }
+        let mut packet_type = u8::from(self.packet_type).min(EXTENDED_PACKET_TYPE);
+        if self.compressed {
+            packet_type |= COMPRESSED_PACKET_FLAG;
+        }
+        if self.sorted {
+            packet_type |= SORTED_PACKET_FLAG;
+        }
+        if self.compression_dictionary().is_some() {
+            packet_type |= DICTIONARY_PACKET_FLAG;
+        }
+        let ack_window = if compact_fields.is_some() || wide_ids {
+            COMPACT_HEADER
+        } else {
+            self.ack_window_bits / 64
+        };
+        packet_type |= ack_window << ACK_WINDOW_SHIFT;
+        buffer.write_u8(packet_type)?;
+        buffer.write_u16::<NetworkEndian>(self.packet_id.0 as u16)?;
+        if wide_ids {
+            buffer.write_varint(((self.ack_window_bits / 64) as u64) << 2 | WIDE_PACKET_IDS)?;
+            buffer.write_u16::<NetworkEndian>((self.packet_id.0 >> 16) as u16)?;
+            buffer.write_u32::<NetworkEndian>(self.last_ack_packet_id.0)?;
+            self.write_ack_bitfield(buffer)?;
+        } else if let Some((ack_offset, missing_acks)) = compact_fields {
+            buffer.write_varint(ack_offset)?;
+            buffer.write_varint(missing_acks)?;
+        } else {
+            buffer.write_u16::<NetworkEndian>(self.last_ack_packet_id.0 as u16)?;
+            self.write_ack_bitfield(buffer)?;
+        }
         buffer.write_u16::<NetworkEndian>(self.tick.0)?;
+        if let Some(value) = self.extended_type() {
+            buffer.write_u8(value)?;
+        }
+        if let Some(dictionary_id) = self.compression_dictionary() {
+            buffer.write_u8(dictionary_id)?;
+        }
         Ok(())
     }
 
@@ -55,36 +186,214 @@ impl ToBytes for PacketHeader {
     {
         let packet_type = buffer.read_u8()?;
         let packet_id = buffer.read_u16::<NetworkEndian>()?;
-        let last_ack_packet_id = buffer.read_u16::<NetworkEndian>()?;
-        let ack_bitfield = buffer.read_u32::<NetworkEndian>()?;
-        let tick = buffer.read_u16::<NetworkEndian>()?;
-        Ok(Self {
-            packet_type: PacketType::try_from(packet_type)?,
-            packet_id: PacketId(packet_id),
-            last_ack_packet_id: PacketId(last_ack_packet_id),
-            ack_bitfield,
-            tick: Tick(tick),
-        })
+        let ack_window_bits = |ack_window: u8| match ack_window {
+            0 => Ok(32),
+            1 => Ok(64),
+            2 => Ok(128),
+            _ => Err(SerializationError::InvalidValue),
+        };
+        let ack_window = (packet_type & ACK_WINDOW_MASK) >> ACK_WINDOW_SHIFT;
+        let compressed = packet_type & COMPRESSED_PACKET_FLAG != 0;
+        let has_dictionary = compressed && packet_type & DICTIONARY_PACKET_FLAG != 0;
+        let (packet_type, sorted) = Self::read_packet_type(packet_type);
+        let mut header = Self {
+            packet_type,
+            compressed,
+            sorted,
+            compression_dictionary: None,
+            packet_id: PacketId(u32::from(packet_id)),
+            last_ack_packet_id: PacketId::default(),
+            ack_window_bits: DEFAULT_ACK_WINDOW_BITS,
+            ack_bitfield: 0,
+            tick: Tick::default(),
+            layout: HeaderLayout::Verbose,
+            packet_id_width: PacketIdWidth::U16,
+        };
+        if ack_window == COMPACT_HEADER {
+            let ack_offset = buffer.read_varint()?;
+            if ack_offset & 0b11 == WIDE_PACKET_IDS {
+                let ack_window =
+                    u8::try_from(ack_offset >> 2).map_err(|_| SerializationError::InvalidValue)?;
+                header.ack_window_bits = ack_window_bits(ack_window)?;
+                header.packet_id_width = PacketIdWidth::U32;
+                let high_bits = buffer.read_u16::<NetworkEndian>()?;
+                header.packet_id = PacketId(u32::from(high_bits) << 16 | u32::from(packet_id));
+                header.last_ack_packet_id = PacketId(buffer.read_u32::<NetworkEndian>()?);
+                header.ack_bitfield = Self::read_ack_bitfield(buffer, header.ack_window_bits)?;
+            } else {
+                header.ack_window_bits = ack_window_bits((ack_offset & 0b11) as u8)?;
+                let ack_offset =
+                    u16::try_from(ack_offset >> 2).map_err(|_| SerializationError::InvalidValue)?;
+                let missing_acks = buffer.read_varint()? as u128;
+                let ack_window_mask = Self::ack_window_mask(header.ack_window_bits);
+                if missing_acks & !ack_window_mask != 0 {
+                    return Err(SerializationError::InvalidValue);
+                }
+                header.layout = HeaderLayout::Compact;
+                header.last_ack_packet_id = PacketId(u32::from(packet_id.wrapping_sub(ack_offset)));
+                header.ack_bitfield = !missing_acks & ack_window_mask;
+            }
+        } else {
+            header.ack_window_bits = ack_window_bits(ack_window)?;
+            header.last_ack_packet_id = PacketId(u32::from(buffer.read_u16::<NetworkEndian>()?));
+            header.ack_bitfield = Self::read_ack_bitfield(buffer, header.ack_window_bits)?;
+        }
+        header.tick = Tick(buffer.read_u16::<NetworkEndian>()?);
+        if header.packet_type == PacketType::Unknown(EXTENDED_PACKET_TYPE) {
+            // each packet type has a single encoding
+            let value = buffer.read_u8()?;
+            if value < EXTENDED_PACKET_TYPE {
+                return Err(SerializationError::InvalidValue);
+            }
+            header.packet_type = PacketType::from(value);
+        }
+        if has_dictionary {
+            header.compression_dictionary = Some(buffer.read_u8()?);
+        }
+        Ok(header)
     }
 }
 
 impl PacketHeader {
+    /// Write the `ack_window_bits` bits of the ack bitfield
+    fn write_ack_bitfield<T: byteorder::WriteBytesExt>(
+        &self,
+        buffer: &mut T,
+    ) -> Result<(), SerializationError> {
+        match self.ack_window_bits {
+            32 => buffer.write_u32::<NetworkEndian>(self.ack_bitfield as u32)?,
+            64 => buffer.write_u64::<NetworkEndian>(self.ack_bitfield as u64)?,
+            _ => buffer.write_u128::<NetworkEndian>(self.ack_bitfield)?,
+        }
+        Ok(())
+    }
+
+    fn read_ack_bitfield(
+        buffer: &mut Reader,
+        ack_window_bits: u8,
+    ) -> Result<u128, SerializationError> {
+        Ok(match ack_window_bits {
+            32 => buffer.read_u32::<NetworkEndian>()? as u128,
+            64 => buffer.read_u64::<NetworkEndian>()? as u128,
+            _ => buffer.read_u128::<NetworkEndian>()?,
+        })
+    }
+
+    /// Length of the header with the [`HeaderLayout::Verbose`] layout
+    fn verbose_len(&self) -> usize {
+        7 + self.ack_window_bits as usize / 8
+    }
+
+    /// Bits of the ack bitfield that are used with a window of `ack_window_bits`
+    fn ack_window_mask(ack_window_bits: u8) -> u128 {
+        u128::MAX >> (128 - ack_window_bits as u32)
+    }
+
+    /// Offset of the last acked packet id from the packet id (shifted to make room for the width
+    /// of the ack bitfield), and bitfield of the packets that were not received, if the header is
+    /// written with the compact layout (see [`HeaderLayout::Compact`])
+    fn compact_fields(&self) -> Option<(u64, u64)> {
+        if self.layout != HeaderLayout::Compact || self.packet_id_width == PacketIdWidth::U32 {
+            return None;
+        }
+        let ack_offset =
+            ((self.packet_id.0 as u16).wrapping_sub(self.last_ack_packet_id.0 as u16) as u64) << 2
+                | (self.ack_window_bits / 64) as u64;
+        let missing_acks = !self.ack_bitfield & Self::ack_window_mask(self.ack_window_bits);
+        let missing_acks = u64::try_from(missing_acks)
+            .ok()
+            .filter(|missing_acks| *missing_acks <= VARINT_MAX)?;
+        (5 + varint_len(ack_offset) + varint_len(missing_acks) < self.verbose_len())
+            .then_some((ack_offset, missing_acks))
+    }
+
     /// Get the value of the i-th bit in the bitfield (starting from the right-most bit, which is
     /// one PacketId below `last_ack_packet_id`
     ///
     /// i is 0-indexed. So 0 represents the first bit of the bitfield (starting from the right)
     fn get_bitfield_bit(&self, i: u8) -> bool {
-        debug_assert!(i < ACK_BITFIELD_SIZE);
+        debug_assert!(i < self.ack_window_bits);
         self.ack_bitfield & (1 << i) != 0
     }
 
+    /// Ids of the packets acked by this header: `last_ack_packet_id`, and the packets set in the bitfield
+    pub(crate) fn acked_packet_ids(&self) -> impl Iterator<Item = PacketId> + '_ {
+        std::iter::once(self.last_ack_packet_id).chain(
+            (1..=self.ack_window_bits)
+                .filter(|i| self.get_bitfield_bit(i - 1))
+                .map(|i| self.last_ack_packet_id.wrapping_sub(i as u32)),
+        )
+    }
+
     pub fn get_packet_type(&self) -> PacketType {
         self.packet_type
     }
+
+    /// Packet id from the sender's perspective
+    pub fn packet_id(&self) -> PacketId {
+        self.packet_id
+    }
+
+    /// Tick at which the packet was built
+    pub fn tick(&self) -> Tick {
+        self.tick
+    }
+
+    /// Mark an already serialized packet as compressed
+    pub(crate) fn set_compressed_flag(packet: &mut [u8]) {
+        packet[0] |= COMPRESSED_PACKET_FLAG;
+    }
+
+    /// Mark an already serialized packet as compressed with a dictionary. The id of the dictionary
+    /// must be appended to the header
+    pub(crate) fn set_dictionary_flag(packet: &mut [u8]) {
+        packet[0] |= DICTIONARY_PACKET_FLAG;
+    }
+
+    /// Id of the dictionary used to compress the packet (only written for compressed packets)
+    fn compression_dictionary(&self) -> Option<u8> {
+        self.compression_dictionary.filter(|_| self.compressed)
+    }
+
+    /// Position of the tick in the serialized header: only the user packet type and the
+    /// dictionary id (which are optional) are written after it
+    pub(crate) fn tick_offset(&self) -> usize {
+        self.len()
+            - 2
+            - usize::from(self.compression_dictionary().is_some())
+            - usize::from(self.extended_type().is_some())
+    }
+
+    /// Mark an already serialized data packet as containing messages that were reordered
+    pub(crate) fn set_sorted_flag(packet: &mut [u8]) {
+        packet[0] |= SORTED_PACKET_FLAG;
+    }
+
+    /// True if the serialized `packet` is marked as containing messages that were reordered
+    pub(crate) fn has_sorted_flag(packet: &[u8]) -> bool {
+        Self::read_packet_type(packet[0]).1
+    }
+
+    /// Read the packet type from the first header byte (see [`packet_type`](crate::packet::packet_type)
+    /// for the layout of the byte), and whether the sorted flag is set.
+    ///
+    /// An extended packet type is returned as `Unknown(EXTENDED_PACKET_TYPE)`: the type itself
+    /// is written after the tick.
+    fn read_packet_type(first_byte: u8) -> (PacketType, bool) {
+        let packet_type = PacketType::from(first_byte & PACKET_TYPE_MASK);
+        let sorted = first_byte & SORTED_PACKET_FLAG != 0
+            && matches!(packet_type, PacketType::Data | PacketType::DataFragment);
+        (packet_type, sorted)
+    }
+
+    /// The packet type, if it doesn't fit in the packet type bits of the first header byte and
+    /// is written in a byte after the tick
+    fn extended_type(&self) -> Option<u8> {
+        let value = u8::from(self.packet_type);
+        (value >= EXTENDED_PACKET_TYPE).then_some(value)
+    }
 }
 
-// we can only send acks for the last 32 packets ids before the last received packet
-const ACK_BITFIELD_SIZE: u8 = 32;
 // we can only buffer up to `MAX_SEND_PACKET_QUEUE_SIZE` packets for sending
 const MAX_SEND_PACKET_QUEUE_SIZE: u8 = 255;
 
@@ -97,7 +406,7 @@ const MAX_NACK_SECONDS: i64 = 3;
 
 /// Keeps track of sent and received packets to be able to write the packet headers correctly
 /// For more information: [GafferOnGames](https://gafferongames.com/post/reliability_ordering_and_congestion_avoidance_over_udp/)
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct PacketHeaderManager {
     // Local packet id which we'll bump each time we send a new packet over the network.
     // (we always increment the packet_id, even when we resend a lost packet)
@@ -113,32 +422,110 @@ pub struct PacketHeaderManager {
     // ack_notification_receiver: Receiver<PacketId>,
 
     // keep track of the packets that were received (last packet received and the
-    // `ACK_BITFIELD_SIZE` packets before that)
+    // `ack_window_bits` packets before that)
     recv_buffer: ReceiveBuffer,
+    /// Width of the ack bitfield: we can only ack the `ack_window_bits` packet ids before the
+    /// last received packet
+    ack_window_bits: u8,
     // copy of current time so that we don't pollute the function signatures to much
     current_time: WrappedTime,
     /// After how many multiples of RTT do we consider a packet to be lost?
     ///
     /// The default is 1.5; i.e. after 1.5 times the round trip time, we consider a packet lost if
     /// we haven't received an ACK for it.
+    ///
+    /// A packet can only be acked while it is in the remote's ack window, so with a high packet
+    /// rate a wider window (see [`Self::new`]) avoids packets that were received being
+    /// considered lost.
     nack_rtt_multiple: f32,
+    /// Ids of the received packets that we still have to ack.
+    /// Only tracked if ack packets are enabled (see [`Self::track_pending_acks`])
+    pending_acks: Option<HashSet<PacketId>>,
+    /// Pending acks that the last prepared header removed from `pending_acks`, to give them back
+    /// if its packet is not sent (see [`Self::release_sent_packet`])
+    last_header_acks: Vec<PacketId>,
+    /// Layout of the headers that we write
+    header_layout: HeaderLayout,
+    /// Number of bits of the packet ids written in our headers
+    packet_id_width: PacketIdWidth,
+    /// Number of sent packets that were acked by the remote peer
+    pub(crate) packets_acked: u64,
+    /// Number of sent packets that were considered lost because they were not acked in time
+    pub(crate) packets_lost: u64,
+    /// RTT estimate used in the last [`Self::update`] to detect the lost packets
+    pub(crate) rtt: Duration,
 }
 
 impl PacketHeaderManager {
-    pub(crate) fn new(nack_rtt_multiple: f32) -> Self {
+    /// Create a new manager, that acks the `ack_window_bits` (32, 64 or 128) packet ids before
+    /// the last received packet in every header.
+    ///
+    /// Returns an error if `ack_window_bits` is not 32, 64 or 128.
+    pub(crate) fn new(nack_rtt_multiple: f32, ack_window_bits: u8) -> Result<Self, PacketError> {
+        if !matches!(ack_window_bits, 32 | 64 | 128) {
+            return Err(PacketError::InvalidAckWindow { ack_window_bits });
+        }
         // let (ack_notification_sender, ack_notification_receiver) =
         //     crossbeam::channel::bounded(MAX_SEND_PACKET_QUEUE_SIZE as usize);
-        Self {
+        Ok(Self {
             next_packet_id: PacketId(0),
             stats_manager: PacketStatsManager::default(),
             // sent_packets_not_acked: HashSet::with_capacity(MAX_SEND_PACKET_QUEUE_SIZE as usize),
             sent_packets_not_acked: HashMap::new(),
-            recv_buffer: ReceiveBuffer::new(),
+            recv_buffer: ReceiveBuffer::new(ack_window_bits),
+            ack_window_bits,
             // ack_notification_sender,
             // ack_notification_receiver,
             current_time: WrappedTime::default(),
             nack_rtt_multiple,
+            pending_acks: None,
+            last_header_acks: vec![],
+            header_layout: HeaderLayout::default(),
+            packet_id_width: PacketIdWidth::default(),
+            packets_acked: 0,
+            packets_lost: 0,
+            rtt: Duration::default(),
+        })
+    }
+
+    /// Forget the packets sent and received, so that the manager can be used for a new
+    /// connection. The settings (ack window, header layout, etc.) are kept.
+    pub(crate) fn reset(&mut self) {
+        self.next_packet_id = PacketId(0);
+        self.sent_packets_not_acked.clear();
+        self.stats_manager = PacketStatsManager::default();
+        self.recv_buffer = ReceiveBuffer::new(self.ack_window_bits);
+        self.current_time = WrappedTime::default();
+        if let Some(pending_acks) = &mut self.pending_acks {
+            pending_acks.clear();
         }
+        self.last_header_acks.clear();
+        self.packets_acked = 0;
+        self.packets_lost = 0;
+        self.rtt = Duration::default();
+    }
+
+    /// Write the headers of the packets we send with this layout
+    pub(crate) fn set_header_layout(&mut self, header_layout: HeaderLayout) {
+        self.header_layout = header_layout;
+    }
+
+    /// Write the packet ids of our headers with this width. The remote peer can read both widths.
+    pub(crate) fn set_packet_id_width(&mut self, packet_id_width: PacketIdWidth) {
+        self.packet_id_width = packet_id_width;
+    }
+
+    /// Keep track of every received packet until it is acked, so that acks that don't fit in
+    /// the headers of the data packets can be sent with [`Self::prepare_ack_packet_header`]
+    pub(crate) fn track_pending_acks(&mut self) {
+        self.pending_acks.get_or_insert_with(HashSet::new);
+    }
+
+    /// Returns true if some received packets have not been acked yet
+    pub(crate) fn has_pending_acks(&self) -> bool {
+        self.pending_acks
+            .as_ref()
+            .is_some_and(|pending_acks| !pending_acks.is_empty())
     }
 
     /// Internal bookkeeping.
@@ -151,6 +538,7 @@ impl PacketHeaderManager {
         self.current_time = time_manager.current_time();
         self.stats_manager.update(time_manager);
         let rtt = ping_manager.final_stats.rtt;
+        self.rtt = rtt;
         let nack_duration = chrono::Duration::from_std(rtt.mul_f32(self.nack_rtt_multiple))
             .expect("duration should be valid")
             .min(chrono::TimeDelta::seconds(MAX_NACK_SECONDS))
@@ -161,6 +549,7 @@ impl PacketHeaderManager {
             if self.current_time - (*time_sent) > nack_duration {
                 trace!("sent packet got lost");
                 lost_packets.push(*packet_id);
+                self.packets_lost += 1;
                 self.stats_manager.sent_packet_lost();
                 return false;
             }
@@ -187,32 +576,70 @@ impl PacketHeaderManager {
 
     /// Increment the packet id of the next packet to be sent
     pub fn increment_next_packet_id(&mut self) {
-        self.next_packet_id = PacketId(self.next_packet_id.wrapping_add(1));
+        self.next_packet_id = self.next_packet_id.wrapping_add(1);
     }
 
     /// Process the header of a received packet (update ack metadata)
     ///
     /// Returns the list of packets that have been newly acked by the remote
     pub(crate) fn process_recv_packet_header(&mut self, header: &PacketHeader) -> Vec<PacketId> {
-        // update the receive buffer
+        self.process_recv_header(header);
+        self.process_recv_acks(header)
+    }
+
+    /// Check if the packet of the received header was already received, using the ids of the
+    /// recently received packets, and record it as received if it is new.
+    ///
+    /// The rest of a packet that is not [`PacketRecvOutcome::New`] should be dropped.
+    pub(crate) fn process_recv_header(&mut self, header: &PacketHeader) -> PacketRecvOutcome {
         self.stats_manager.received_packet();
-        self.recv_buffer.recv_packet(header.packet_id);
+        let packet_id = self.recv_packet_id(header);
+        let outcome = self.recv_buffer.outcome(packet_id);
+        // ack packets are not acked back
+        if outcome == PacketRecvOutcome::New && header.packet_type != PacketType::Ack {
+            self.recv_buffer.recv_packet(packet_id);
+            if let Some(pending_acks) = &mut self.pending_acks {
+                pending_acks.insert(packet_id);
+            }
+        }
+        outcome
+    }
 
+    /// Check if the packet of the received header was already received, without recording it:
+    /// the packet is only acked once [`Self::process_recv_header`] is called, after its payload
+    /// could be read.
+    pub(crate) fn recv_outcome(&self, header: &PacketHeader) -> PacketRecvOutcome {
+        self.recv_buffer.outcome(self.recv_packet_id(header))
+    }
+
+    /// The full id of the packet of a received header
+    fn recv_packet_id(&self, header: &PacketHeader) -> PacketId {
+        header.packet_id_width.extend(
+            header.packet_id,
+            self.recv_buffer
+                .last_recv_packet_id
+                .unwrap_or(header.packet_id),
+        )
+    }
+
+    /// Read the acks of a received header.
+    ///
+    /// Returns the list of packets that have been newly acked by the remote
+    pub(crate) fn process_recv_acks(&mut self, header: &PacketHeader) -> Vec<PacketId> {
         let mut newly_acked_packets = Vec::new();
 
         // read the ack information (ack id + ack bitfield) from the received header, and update
         // the list of our sent packets that have not been acked yet
-        if let Some(packet) = self.update_sent_packets_not_acked(&header.last_ack_packet_id) {
-            self.stats_manager.sent_packet_acked();
-            newly_acked_packets.push(packet);
-        }
-        for i in 1..=ACK_BITFIELD_SIZE {
-            let packet_id = PacketId(header.last_ack_packet_id.wrapping_sub(i as u16));
-            if header.get_bitfield_bit(i - 1) {
-                if let Some(packet) = self.update_sent_packets_not_acked(&packet_id) {
-                    self.stats_manager.sent_packet_acked();
-                    newly_acked_packets.push(packet)
-                }
+        // the acked packets were sent at most at our last packet id
+        let last_sent_packet_id = self.next_packet_id.wrapping_sub(1);
+        for packet_id in header.acked_packet_ids() {
+            let packet_id = header
+                .packet_id_width
+                .extend(packet_id, last_sent_packet_id);
+            if let Some(packet) = self.update_sent_packets_not_acked(&packet_id) {
+                self.packets_acked += 1;
+                self.stats_manager.sent_packet_acked();
+                newly_acked_packets.push(packet)
             }
         }
         newly_acked_packets
@@ -241,16 +668,35 @@ impl PacketHeaderManager {
         // (so that receiving 0 counts as an update)
         let last_ack_packet_id = match self.recv_buffer.last_recv_packet_id {
             Some(id) => id,
-            None => PacketId(u16::MAX),
+            None => PacketId(u32::MAX),
         };
         let outgoing_header = PacketHeader {
             packet_type,
+            compressed: false,
+            sorted: false,
+            compression_dictionary: None,
             packet_id: self.next_packet_id,
             last_ack_packet_id,
+            ack_window_bits: self.ack_window_bits,
             ack_bitfield: self.recv_buffer.get_bitfield(),
             // TODO: we send the tick, later. Seems a bit dangerous...
             tick: Tick(0),
+            layout: self.header_layout,
+            packet_id_width: self.packet_id_width,
         };
+        self.last_header_acks.clear();
+        if let Some(pending_acks) = &mut self.pending_acks {
+            let acked = std::iter::once(outgoing_header.last_ack_packet_id).chain(
+                (1..=self.ack_window_bits)
+                    .filter(|i| outgoing_header.get_bitfield_bit(i - 1))
+                    .map(|i| outgoing_header.last_ack_packet_id.wrapping_sub(i as u32)),
+            );
+            for packet_id in acked {
+                if pending_acks.remove(&packet_id) {
+                    self.last_header_acks.push(packet_id);
+                }
+            }
+        }
         // we build the header only when we actually send the packet, so computing the stats here is valid
         self.stats_manager.sent_packet();
         // keep track of when we sent the packet (so that if we don't get an ack after a certain amount of time we can consider it lost)
@@ -259,6 +705,80 @@ impl PacketHeaderManager {
         self.increment_next_packet_id();
         outgoing_header
     }
+
+    /// Forget a packet whose header was prepared but that will not be sent (for example because
+    /// it was merged into another packet), so that it is not reported as lost
+    pub(crate) fn cancel_sent_packet(&mut self, packet_id: PacketId) {
+        if self.sent_packets_not_acked.remove(&packet_id).is_some() {
+            self.stats_manager.sent_packet_cancelled();
+        }
+    }
+
+    /// Forget a packet whose header was prepared but that will not be sent, because it ended up
+    /// without any message.
+    ///
+    /// If it is the last header that was prepared, its packet id is given to the next packet and
+    /// the acks of the header are pending again; otherwise the packet is only cancelled (see
+    /// [`Self::cancel_sent_packet`]).
+    pub(crate) fn release_sent_packet(&mut self, packet_id: PacketId) {
+        self.cancel_sent_packet(packet_id);
+        if packet_id != self.next_packet_id.wrapping_sub(1) {
+            return;
+        }
+        self.next_packet_id = packet_id;
+        if let Some(pending_acks) = &mut self.pending_acks {
+            pending_acks.extend(self.last_header_acks.drain(..));
+        }
+    }
+
+    /// Prepare the header of an ack packet, which acks the oldest pending acks.
+    ///
+    /// A header can only ack `ack_window_bits + 1` consecutive packet ids, so this must be called
+    /// until it returns None to send all the pending acks.
+    pub(crate) fn prepare_ack_packet_header(&mut self) -> Option<PacketHeader> {
+        let pending_acks = self.pending_acks.as_mut()?;
+        let oldest = *pending_acks.iter().min()?;
+        // ack the oldest pending id with the bitfield, using the most recent id in range as the ack id
+        let last_ack_packet_id = *pending_acks
+            .iter()
+            .filter(|id| (0..=self.ack_window_bits as i32).contains(&id.diff(oldest)))
+            .max()?;
+        pending_acks.remove(&last_ack_packet_id);
+        let mut ack_bitfield = 0;
+        for i in 1..=self.ack_window_bits {
+            if pending_acks.remove(&last_ack_packet_id.wrapping_sub(i as u32)) {
+                ack_bitfield |= 1 << (i - 1);
+            }
+        }
+        let outgoing_header = PacketHeader {
+            packet_type: PacketType::Ack,
+            compressed: false,
+            sorted: false,
+            compression_dictionary: None,
+            packet_id: self.next_packet_id,
+            last_ack_packet_id,
+            ack_window_bits: self.ack_window_bits,
+            ack_bitfield,
+            tick: Tick(0),
+            layout: self.header_layout,
+            packet_id_width: self.packet_id_width,
+        };
+        // ack packets are not acked back, so we don't wait for their ack
+        self.last_header_acks.clear();
+        self.increment_next_packet_id();
+        Some(outgoing_header)
+    }
+}
+
+/// Result of checking the id of a received packet against the recently received packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PacketRecvOutcome {
+    /// The packet was not received before
+    New,
+    /// A packet with the same id was already received
+    Duplicate,
+    /// The packet is older than the ack window, so we cannot know if it was already received
+    TooOld,
 }
 
 /// Data structure to keep track of the ids of the received packets
@@ -266,20 +786,20 @@ impl PacketHeaderManager {
 pub struct ReceiveBuffer {
     /// The packet id of the most recent packet received
     last_recv_packet_id: Option<PacketId>,
-    /// Use a ring buffer of ACK_BITFIELD_SIZE to track if we received the last
-    /// ACK_BITFIELD_SIZE packets prior to the last received packet
-    buffer: ConstGenericRingBuffer<bool, { ACK_BITFIELD_SIZE as usize }>,
+    /// Use a ring buffer of the size of the ack window to track if we received the
+    /// packets prior to the last received packet
+    buffer: AllocRingBuffer<bool>,
 }
 
 impl Default for ReceiveBuffer {
     fn default() -> Self {
-        Self::new()
+        Self::new(DEFAULT_ACK_WINDOW_BITS)
     }
 }
 
 impl ReceiveBuffer {
-    fn new() -> Self {
-        let mut buffer = ConstGenericRingBuffer::new();
+    fn new(ack_window_bits: u8) -> Self {
+        let mut buffer = AllocRingBuffer::new(ack_window_bits as usize);
         // start with false (we haven't received any packet yet)
         buffer.fill(false);
         Self {
@@ -288,6 +808,29 @@ impl ReceiveBuffer {
         }
     }
 
+    /// Check if a packet with this id was already received
+    fn outcome(&self, id: PacketId) -> PacketRecvOutcome {
+        let Some(last_recv_packet_id) = self.last_recv_packet_id else {
+            return PacketRecvOutcome::New;
+        };
+        let diff = last_recv_packet_id.diff(id);
+        if diff < 0 {
+            PacketRecvOutcome::New
+        } else if diff == 0 {
+            PacketRecvOutcome::Duplicate
+        } else if diff > self.buffer.capacity() as i32 {
+            PacketRecvOutcome::TooOld
+        } else if *self
+            .buffer
+            .get_signed(-diff as isize)
+            .expect("ring buffer should be full")
+        {
+            PacketRecvOutcome::Duplicate
+        } else {
+            PacketRecvOutcome::New
+        }
+    }
+
     /// Receive a new packet id and update the receive buffer accordingly
     fn recv_packet(&mut self, id: PacketId) {
         // special case: this is the first packet we receive
@@ -296,8 +839,8 @@ impl ReceiveBuffer {
             return;
         }
 
-        let bitfield_size = ACK_BITFIELD_SIZE as i16;
-        let diff = self.last_recv_packet_id.unwrap() - id;
+        let bitfield_size = self.buffer.capacity() as i32;
+        let diff = self.last_recv_packet_id.unwrap().diff(id);
         if diff > bitfield_size {
             return;
         }
@@ -329,10 +872,10 @@ impl ReceiveBuffer {
     }
 
     /// Convert the Receive Buffer to the bitfield that we need to send in the PacketHeader
-    fn get_bitfield(&self) -> u32 {
-        let mut ack_bitfield: u32 = 0;
+    fn get_bitfield(&self) -> u128 {
+        let mut ack_bitfield: u128 = 0;
         // mask starting from the left
-        let mut mask = 1 << (ACK_BITFIELD_SIZE - 1);
+        let mut mask = 1 << (self.buffer.capacity() - 1);
 
         // iter goes from the item pushed the longest ago (to the left of the bitfield)
         // to the items pushed most recently (to the right of the bitfield)
@@ -349,21 +892,23 @@ impl ReceiveBuffer {
 // TODO: add test for notification of packet delivered
 #[cfg(test)]
 mod tests {
+    use crate::packet::packet::HEADER_BYTES;
+    use crate::packet::packet_type::FIRST_USER_PACKET_TYPE;
     use crate::serialize::ToBytes;
 
     use super::*;
 
     #[test]
     fn test_recv_buffer() {
-        let recv_buffer = ReceiveBuffer::new();
+        let recv_buffer = ReceiveBuffer::default();
         assert_eq!(recv_buffer.last_recv_packet_id, None);
         assert_eq!(recv_buffer.get_bitfield(), 0);
 
         // add a most recent packet, and perform some assertions
         fn add_most_recent_packet(
             mut buffer: ReceiveBuffer,
-            id: u16,
-            expected_bitfield: u32,
+            id: u32,
+            expected_bitfield: u128,
         ) -> ReceiveBuffer {
             buffer.recv_packet(PacketId(id));
             assert_eq!(buffer.last_recv_packet_id, Some(PacketId(id)));
@@ -378,16 +923,16 @@ mod tests {
         let recv_buffer = add_most_recent_packet(recv_buffer, 1, 1);
 
         // receive a packet where the ACK_BITFIELD_SIZE > diff_id > 0
-        let recv_buffer = add_most_recent_packet(recv_buffer, 3, 0b0000_0110u32);
+        let recv_buffer = add_most_recent_packet(recv_buffer, 3, 0b0000_0110);
 
         // receive another packet where the ACK_BITFIELD_SIZE > diff_id > 0
-        let mut recv_buffer = add_most_recent_packet(recv_buffer, 6, 0b0011_0100u32);
+        let mut recv_buffer = add_most_recent_packet(recv_buffer, 6, 0b0011_0100);
 
         // receive a packet which is in the past
         // -ACK_BITFIELD_SIZE < diff_id < 0
         recv_buffer.recv_packet(PacketId(2));
         assert_eq!(recv_buffer.last_recv_packet_id, Some(PacketId(6)));
-        assert_eq!(recv_buffer.get_bitfield(), 0b0011_1100u32);
+        assert_eq!(recv_buffer.get_bitfield(), 0b0011_1100);
 
         // receive a packet that is far ahead
         // diff > ACK_BITFIELD_SIZE
@@ -404,14 +949,147 @@ mod tests {
         assert_eq!(recv_buffer.get_bitfield(), 1 << (32 - 1));
     }
 
+    /// Acks that don't fit in a single header are split across several ack packets,
+    /// without losing or duplicating any ack
+    #[test]
+    fn test_ack_packets() {
+        let mut sender = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        // start close to the maximum id to check that the ids wrap around correctly
+        sender.next_packet_id = PacketId(65000);
+        let mut receiver = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        receiver.track_pending_acks();
+        for _ in 0..1000 {
+            let header = sender.prepare_send_packet_header(PacketType::Data);
+            receiver.process_recv_packet_header(&header);
+        }
+        assert!(receiver.has_pending_acks());
+
+        let mut num_ack_packets = 0;
+        let mut num_acks = 0;
+        let mut acked = HashSet::new();
+        while let Some(header) = receiver.prepare_ack_packet_header() {
+            num_ack_packets += 1;
+            num_acks += 1 + header.ack_bitfield.count_ones();
+            acked.extend(sender.process_recv_packet_header(&header));
+        }
+        assert!(num_ack_packets > 1);
+        assert!(!receiver.has_pending_acks());
+        // each packet was acked exactly once
+        assert_eq!(num_acks, 1000);
+        assert_eq!(acked.len(), 1000);
+        assert!(sender.sent_packets_not_acked().is_empty());
+        // the ack packets are not acked back
+        assert!(!sender.has_pending_acks());
+        assert_eq!(sender.recv_buffer.last_recv_packet_id, None);
+    }
+
+    /// A single outgoing header acks every packet received in the ack window
+    #[test]
+    fn test_ack_multiple_packets_in_one_header() {
+        let mut sender = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        let mut receiver = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        let headers: Vec<_> = (0..4)
+            .map(|_| sender.prepare_send_packet_header(PacketType::Data))
+            .collect();
+        // the third packet is lost
+        for i in [0, 1, 3] {
+            receiver.process_recv_packet_header(&headers[i]);
+        }
+
+        let header = receiver.prepare_send_packet_header(PacketType::Data);
+        assert_eq!(header.last_ack_packet_id, PacketId(3));
+        assert_eq!(header.ack_bitfield, 0b110);
+        let mut acked = sender.process_recv_packet_header(&header);
+        acked.sort_by_key(|id| id.0);
+        assert_eq!(acked, vec![PacketId(0), PacketId(1), PacketId(3)]);
+        assert_eq!(
+            sender.sent_packets_not_acked().keys().collect::<Vec<_>>(),
+            vec![&PacketId(2)]
+        );
+    }
+
+    #[test]
+    fn test_recv_duplicate_packet() {
+        let mut sender = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        let mut receiver = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        let headers: Vec<_> = (0..40)
+            .map(|_| sender.prepare_send_packet_header(PacketType::Data))
+            .collect();
+
+        assert_eq!(
+            receiver.process_recv_header(&headers[1]),
+            PacketRecvOutcome::New
+        );
+        assert_eq!(
+            receiver.process_recv_header(&headers[1]),
+            PacketRecvOutcome::Duplicate
+        );
+        // an older packet that was not received yet
+        assert_eq!(
+            receiver.process_recv_header(&headers[0]),
+            PacketRecvOutcome::New
+        );
+        assert_eq!(
+            receiver.process_recv_header(&headers[0]),
+            PacketRecvOutcome::Duplicate
+        );
+        // the duplicate is still detected after receiving newer packets
+        assert_eq!(
+            receiver.process_recv_header(&headers[5]),
+            PacketRecvOutcome::New
+        );
+        assert_eq!(
+            receiver.process_recv_header(&headers[1]),
+            PacketRecvOutcome::Duplicate
+        );
+    }
+
+    #[test]
+    fn test_recv_too_old_packet() {
+        let mut sender = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        let mut receiver = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        let headers: Vec<_> = (0..40)
+            .map(|_| sender.prepare_send_packet_header(PacketType::Data))
+            .collect();
+
+        assert_eq!(
+            receiver.process_recv_header(&headers[39]),
+            PacketRecvOutcome::New
+        );
+        // 32 packets before the last received packet is still in the window
+        assert_eq!(
+            receiver.process_recv_header(&headers[7]),
+            PacketRecvOutcome::New
+        );
+        assert_eq!(
+            receiver.process_recv_header(&headers[6]),
+            PacketRecvOutcome::TooOld
+        );
+        assert_eq!(
+            receiver.process_recv_header(&headers[0]),
+            PacketRecvOutcome::TooOld
+        );
+        // the packets are recorded only if they are new
+        let ack_header = receiver.prepare_send_packet_header(PacketType::Data);
+        let mut acked = sender.process_recv_acks(&ack_header);
+        acked.sort();
+        assert_eq!(acked, vec![PacketId(7), PacketId(39)]);
+    }
+
     #[test]
     fn test_serde_header() -> Result<(), SerializationError> {
         let header = PacketHeader {
             packet_type: PacketType::Data,
+            compressed: false,
+            sorted: false,
+            compression_dictionary: None,
             packet_id: PacketId(27),
             last_ack_packet_id: PacketId(13),
+            ack_window_bits: 32,
             ack_bitfield: 3,
             tick: Tick(6),
+            layout: HeaderLayout::Verbose,
+            packet_id_width: PacketIdWidth::U16,
         };
         let mut writer = Vec::new();
         header.to_bytes(&mut writer)?;
@@ -422,4 +1100,412 @@ mod tests {
         assert_eq!(header, read_header);
         Ok(())
     }
+
+    /// The sorted flag is only read for data packets, the same bit is part of the other types
+    #[test]
+    fn test_serde_header_sorted_flag() -> Result<(), SerializationError> {
+        let header = PacketHeader {
+            packet_type: PacketType::DataFragment,
+            compressed: true,
+            sorted: true,
+            compression_dictionary: None,
+            packet_id: PacketId(27),
+            last_ack_packet_id: PacketId(13),
+            ack_window_bits: 32,
+            ack_bitfield: 3,
+            tick: Tick(6),
+            layout: HeaderLayout::Verbose,
+            packet_id_width: PacketIdWidth::U16,
+        };
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer)?;
+        assert!(PacketHeader::has_sorted_flag(&writer));
+        assert_eq!(
+            PacketHeader::from_bytes(&mut writer.clone().into())?,
+            header
+        );
+
+        // the flag is ignored for the other packet types
+        writer[0] = SORTED_PACKET_FLAG | u8::from(PacketType::Ack);
+        assert!(!PacketHeader::has_sorted_flag(&writer));
+        let read_header = PacketHeader::from_bytes(&mut writer.into())?;
+        assert_eq!(read_header.get_packet_type(), PacketType::Ack);
+        assert!(!read_header.sorted);
+        Ok(())
+    }
+
+    /// The id of the compression dictionary is only written for compressed packets
+    #[test]
+    fn test_serde_header_compression_dictionary() -> Result<(), SerializationError> {
+        let mut header = PacketHeader {
+            packet_type: PacketType::Data,
+            compressed: true,
+            sorted: false,
+            compression_dictionary: Some(9),
+            packet_id: PacketId(27),
+            last_ack_packet_id: PacketId(13),
+            ack_window_bits: 32,
+            ack_bitfield: 3,
+            tick: Tick(6),
+            layout: HeaderLayout::Verbose,
+            packet_id_width: PacketIdWidth::U16,
+        };
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer)?;
+        assert_eq!(writer.len(), HEADER_BYTES + 1);
+        assert_eq!(writer.len(), header.len());
+        assert_eq!(*writer.last().unwrap(), 9);
+        assert_eq!(PacketHeader::from_bytes(&mut writer.into())?, header);
+
+        // the flag is ignored for uncompressed packets
+        header.compressed = false;
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer)?;
+        assert_eq!(writer.len(), HEADER_BYTES);
+        writer[0] |= DICTIONARY_PACKET_FLAG;
+        let read_header = PacketHeader::from_bytes(&mut writer.into())?;
+        assert_eq!(read_header.get_packet_type(), PacketType::Data);
+        assert_eq!(read_header.compression_dictionary, None);
+        Ok(())
+    }
+
+    /// Every packet type is read back as the same type, whatever the flags and the width of the
+    /// ack bitfield: the packet type bits don't overlap the flags
+    #[test]
+    fn test_serde_header_packet_types() -> Result<(), SerializationError> {
+        for value in 0..=u8::MAX {
+            let packet_type = PacketType::from(value);
+            for (compressed, ack_window_bits) in [(false, 32), (true, 64), (true, 128)] {
+                let sorted = matches!(packet_type, PacketType::Data | PacketType::DataFragment);
+                let header = PacketHeader {
+                    packet_type,
+                    compressed,
+                    sorted,
+                    compression_dictionary: compressed.then_some(3),
+                    packet_id: PacketId(27),
+                    last_ack_packet_id: PacketId(13),
+                    ack_window_bits,
+                    ack_bitfield: 3,
+                    tick: Tick(6),
+                    layout: HeaderLayout::Verbose,
+                    packet_id_width: PacketIdWidth::U16,
+                };
+                let mut writer = Vec::new();
+                header.to_bytes(&mut writer)?;
+                assert_eq!(writer.len(), header.len());
+                assert_eq!(PacketHeader::from_bytes(&mut writer.into())?, header);
+            }
+        }
+
+        // an extended packet type must not fit in the packet type bits
+        let header = PacketHeader {
+            packet_type: PacketType::Unknown(EXTENDED_PACKET_TYPE),
+            ..PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS)
+                .unwrap()
+                .prepare_send_packet_header(PacketType::Data)
+        };
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer)?;
+        let tick_end = writer.len() - 1;
+        writer[tick_end] = u8::from(PacketType::Ack);
+        assert!(matches!(
+            PacketHeader::from_bytes(&mut writer.into()),
+            Err(SerializationError::InvalidValue)
+        ));
+        // and the known types can't be written as unknown types
+        let header = PacketHeader {
+            packet_type: PacketType::Unknown(u8::from(PacketType::Ack)),
+            ..header
+        };
+        assert!(matches!(
+            header.to_bytes(&mut Vec::new()),
+            Err(SerializationError::InvalidValue)
+        ));
+        Ok(())
+    }
+
+    /// The user packet types are written in a byte after the tick
+    #[test]
+    fn test_serde_header_user_packet_type() -> Result<(), SerializationError> {
+        let mut manager = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        let mut header = manager.prepare_send_packet_header(PacketType::User(200));
+        header.tick = Tick(6);
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer)?;
+        assert_eq!(writer.len(), HEADER_BYTES + 1);
+        assert_eq!(writer.len(), header.len());
+        let read_header = PacketHeader::from_bytes(&mut writer.into())?;
+        assert_eq!(read_header.get_packet_type(), PacketType::User(200));
+        assert_eq!(read_header.tick, Tick(6));
+        assert_eq!(PacketType::from(200), PacketType::User(200));
+
+        // with a compression dictionary, whose id is written after the user packet type
+        header.compressed = true;
+        header.compression_dictionary = Some(9);
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer)?;
+        assert_eq!(writer[writer.len() - 2..], [200, 9]);
+        let read_header = PacketHeader::from_bytes(&mut writer.into())?;
+        assert_eq!(read_header.get_packet_type(), PacketType::User(200));
+        assert_eq!(read_header.compression_dictionary, Some(9));
+
+        // the values below the user range are not user packet types
+        header.packet_type = PacketType::User(FIRST_USER_PACKET_TYPE - 1);
+        assert!(matches!(
+            header.to_bytes(&mut Vec::new()),
+            Err(SerializationError::InvalidValue)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_header_wide_ack_window() -> Result<(), SerializationError> {
+        for (ack_window_bits, len) in [(64, 15), (128, 23)] {
+            let header = PacketHeader {
+                packet_type: PacketType::Ack,
+                compressed: true,
+                sorted: false,
+                compression_dictionary: None,
+                packet_id: PacketId(27),
+                last_ack_packet_id: PacketId(13),
+                ack_window_bits,
+                ack_bitfield: 1 << (ack_window_bits - 1) | 1,
+                tick: Tick(6),
+                layout: HeaderLayout::Verbose,
+                packet_id_width: PacketIdWidth::U16,
+            };
+            let mut writer = Vec::new();
+            header.to_bytes(&mut writer)?;
+            assert_eq!(writer.len(), len);
+            assert_eq!(header.len(), len);
+
+            let mut reader = writer.into();
+            assert_eq!(PacketHeader::from_bytes(&mut reader)?, header);
+        }
+        Ok(())
+    }
+
+    /// The same header round-trips through both layouts, and the compact one is smaller
+    #[test]
+    fn test_serde_header_layouts() -> Result<(), SerializationError> {
+        for ack_window_bits in [32, 64, 128] {
+            let verbose = PacketHeader {
+                packet_type: PacketType::Data,
+                compressed: false,
+                sorted: false,
+                compression_dictionary: None,
+                packet_id: PacketId(1027),
+                last_ack_packet_id: PacketId(1020),
+                ack_window_bits,
+                // every packet of the window was received, except one
+                ack_bitfield: PacketHeader::ack_window_mask(ack_window_bits) & !(1 << 5),
+                tick: Tick(6),
+                layout: HeaderLayout::Verbose,
+                packet_id_width: PacketIdWidth::U16,
+            };
+            let compact = PacketHeader {
+                layout: HeaderLayout::Compact,
+                ..verbose.clone()
+            };
+            let mut verbose_bytes = Vec::new();
+            verbose.to_bytes(&mut verbose_bytes)?;
+            let mut compact_bytes = Vec::new();
+            compact.to_bytes(&mut compact_bytes)?;
+            assert_eq!(verbose_bytes.len(), verbose.len());
+            assert_eq!(compact_bytes.len(), compact.len());
+            assert!(compact_bytes.len() < verbose_bytes.len());
+            assert_eq!(compact_bytes.len(), 7);
+
+            // the layout is detected when reading
+            assert_eq!(
+                PacketHeader::from_bytes(&mut verbose_bytes.into())?,
+                verbose
+            );
+            assert_eq!(
+                PacketHeader::from_bytes(&mut compact_bytes.into())?,
+                compact
+            );
+        }
+
+        // the verbose layout is used when the compact one would not be smaller
+        let header = PacketHeader {
+            packet_type: PacketType::Data,
+            compressed: false,
+            sorted: false,
+            compression_dictionary: None,
+            packet_id: PacketId(27),
+            last_ack_packet_id: PacketId(40000),
+            ack_window_bits: 32,
+            ack_bitfield: 0,
+            tick: Tick(6),
+            layout: HeaderLayout::Compact,
+            packet_id_width: PacketIdWidth::U16,
+        };
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer)?;
+        assert_eq!(writer.len(), header.verbose_len());
+        assert_eq!(
+            PacketHeader::from_bytes(&mut writer.into())?,
+            PacketHeader {
+                layout: HeaderLayout::Verbose,
+                ..header
+            }
+        );
+        Ok(())
+    }
+
+    /// Every header takes exactly `len()` bytes, and at most `max_len()`
+    #[test]
+    fn test_header_len_within_max_len() -> Result<(), SerializationError> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let ack_window_bits = [32, 64, 128][rng.gen_range(0..3)];
+            let packet_id: u32 = rng.gen();
+            let header = PacketHeader {
+                packet_type: PacketType::from(rng.gen_range(0..5)),
+                compressed: rng.gen(),
+                sorted: false,
+                compression_dictionary: None,
+                packet_id: PacketId(packet_id),
+                last_ack_packet_id: PacketId(packet_id.wrapping_sub(rng.gen_range(0..100_000))),
+                ack_window_bits,
+                // mostly received packets, so that the compact layout is used
+                ack_bitfield: PacketHeader::ack_window_mask(ack_window_bits)
+                    & !(rng.gen::<u128>() >> rng.gen_range(0..128)),
+                tick: Tick(rng.gen()),
+                layout: [HeaderLayout::Verbose, HeaderLayout::Compact][rng.gen_range(0..2)],
+                packet_id_width: [PacketIdWidth::U16, PacketIdWidth::U32][rng.gen_range(0..2)],
+            };
+            let mut writer = Vec::new();
+            header.to_bytes(&mut writer)?;
+            assert_eq!(writer.len(), header.len());
+            assert!(writer.len() <= PacketHeader::max_len().unwrap());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_header_wide_packet_ids() -> Result<(), SerializationError> {
+        for (ack_window_bits, len) in [(32, 16), (128, 28)] {
+            let header = PacketHeader {
+                packet_type: PacketType::Data,
+                compressed: true,
+                sorted: false,
+                compression_dictionary: None,
+                packet_id: PacketId(0x0001_0002),
+                last_ack_packet_id: PacketId(0xffff_fffe),
+                ack_window_bits,
+                ack_bitfield: 1 << (ack_window_bits - 1) | 1,
+                tick: Tick(6),
+                layout: HeaderLayout::Verbose,
+                packet_id_width: PacketIdWidth::U32,
+            };
+            let mut writer = Vec::new();
+            header.to_bytes(&mut writer)?;
+            assert_eq!(writer.len(), len);
+            assert_eq!(header.len(), len);
+            assert!(len <= MAX_HEADER_BYTES);
+            assert_eq!(PacketHeader::from_bytes(&mut writer.into())?, header);
+        }
+        Ok(())
+    }
+
+    /// Packets sent past the u16 boundary are acked with their full 32-bit id
+    #[test]
+    fn test_ack_packet_ids_past_u16() {
+        let mut sender = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        sender.set_packet_id_width(PacketIdWidth::U32);
+        sender.next_packet_id = PacketId(u16::MAX as u32 - 4);
+        let mut receiver = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS).unwrap();
+        receiver.set_packet_id_width(PacketIdWidth::U32);
+        let headers: Vec<_> = (0..10)
+            .map(|_| {
+                let header = sender.prepare_send_packet_header(PacketType::Data);
+                PacketHeader::from_bytes(&mut header_bytes(&header).into()).unwrap()
+            })
+            .collect();
+        assert_eq!(headers[9].packet_id, PacketId(0x0001_0004));
+        // the packet 0x10000 is lost
+        for header in headers
+            .iter()
+            .filter(|h| h.packet_id != PacketId(0x0001_0000))
+        {
+            receiver.process_recv_packet_header(header);
+        }
+
+        let ack_header = receiver.prepare_send_packet_header(PacketType::Data);
+        let ack_header = PacketHeader::from_bytes(&mut header_bytes(&ack_header).into()).unwrap();
+        let mut acked = sender.process_recv_packet_header(&ack_header);
+        acked.sort();
+        let expected: Vec<_> = (0xfffb..=0x0001_0004)
+            .filter(|id| *id != 0x0001_0000)
+            .map(PacketId)
+            .collect();
+        assert_eq!(acked, expected);
+        assert_eq!(
+            sender.sent_packets_not_acked().keys().collect::<Vec<_>>(),
+            vec![&PacketId(0x0001_0000)]
+        );
+    }
+
+    fn header_bytes(header: &PacketHeader) -> Vec<u8> {
+        let mut writer = Vec::new();
+        header.to_bytes(&mut writer).unwrap();
+        writer
+    }
+
+    /// Send packets 0..=70; the remote receives packets 0 and 50 and acks them,
+    /// then receives packet 70 and packet 1 (late) and acks them.
+    ///
+    /// Returns the packets acked by each of the two ack headers.
+    fn ack_with_window(ack_window_bits: u8) -> [Vec<PacketId>; 2] {
+        let mut sender = PacketHeaderManager::new(1.5, ack_window_bits).unwrap();
+        let mut receiver = PacketHeaderManager::new(1.5, ack_window_bits).unwrap();
+        let headers: Vec<_> = (0..=70)
+            .map(|_| sender.prepare_send_packet_header(PacketType::Data))
+            .collect();
+        [[0, 50], [70, 1]].map(|received| {
+            for i in received {
+                receiver.process_recv_packet_header(&headers[i]);
+            }
+            let ack_header = receiver.prepare_send_packet_header(PacketType::Data);
+            let mut acked = sender.process_recv_packet_header(&ack_header);
+            acked.sort();
+            acked
+        })
+    }
+
+    #[test]
+    fn test_wide_ack_window() {
+        // packet 0 is 50 ids before packet 50: only acked with a window wider than 32 bits.
+        // packet 1 is 69 ids before packet 70: only acked with a 128-bit window.
+        assert_eq!(
+            ack_with_window(32),
+            [vec![PacketId(50)], vec![PacketId(70)]]
+        );
+        assert_eq!(
+            ack_with_window(64),
+            [vec![PacketId(0), PacketId(50)], vec![PacketId(70)]]
+        );
+        assert_eq!(
+            ack_with_window(128),
+            [
+                vec![PacketId(0), PacketId(50)],
+                vec![PacketId(1), PacketId(70)]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_ack_window() {
+        assert!(matches!(
+            PacketHeaderManager::new(1.5, 48),
+            Err(PacketError::InvalidAckWindow {
+                ack_window_bits: 48
+            })
+        ));
+    }
 }
@@ -6,11 +6,26 @@ use bytes::Bytes;
 
 use crate::protocol::EventContext;
 use crate::serialize::reader::Reader;
-use crate::serialize::varint::varint_len;
+use crate::serialize::varint::{varint_len, VarIntReadExt, VarIntWriteExt};
 use crate::serialize::{SerializationError, ToBytes};
 use crate::shared::tick_manager::Tick;
 use crate::utils::wrapping_id::wrapping_id;
 
+/// Bit of the first byte of a [`SingleData`] that is set if the message has a [`MessageId`]
+pub(crate) const MESSAGE_ID_FLAG: u8 = 1;
+
+/// Bit of the first byte of a [`SingleData`] that is set if the message is sent on a
+/// sub-channel other than 0
+pub(crate) const SUB_CHANNEL_FLAG: u8 = 2;
+
+/// Bit of the first byte of a [`SingleData`] that is set on the first message of a channel in a
+/// packet if all the messages of the channel have the same length: the length is only written for
+/// the first message
+pub(crate) const FIXED_LENGTH_FLAG: u8 = 4;
+
+/// All the bits that can be set in the first byte of a [`SingleData`]
+const SINGLE_DATA_FLAGS: u8 = MESSAGE_ID_FLAG | SUB_CHANNEL_FLAG | FIXED_LENGTH_FLAG;
+
 // Internal id that we assign to each message sent over the network
 wrapping_id!(MessageId);
 
@@ -29,12 +44,25 @@ pub type FragmentIndex = u8;
 pub type FragmentIndex = u16;
 
 /// Struct to keep track of which messages/slices have been received by the remote
-#[derive(Hash, PartialEq, Eq, Debug, Clone, Copy)]
+///
+/// Acks are ordered by message id (with the wrapping order of [`MessageId`]), then by fragment:
+/// the ack of a whole message comes before the acks of fragments.
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub(crate) struct MessageAck {
     pub(crate) message_id: MessageId,
     pub(crate) fragment_id: Option<FragmentIndex>,
 }
 
+/// Sort `acks` and remove the duplicates, for example the acks of a message that was resent and
+/// acked in two packets.
+///
+/// The message ids must be less than half the id range apart, so that their wrapping order is
+/// consistent.
+pub(crate) fn dedup_sorted(acks: &mut Vec<MessageAck>) {
+    acks.sort_unstable();
+    acks.dedup();
+}
+
 /// A Message is a logical unit of data that should be transmitted over a network
 ///
 /// The message can be small (multiple messages can be sent in a single packet)
@@ -117,22 +145,38 @@ impl From<SingleData> for MessageData {
 pub struct SingleData {
     // TODO: MessageId is from 1 to 65535, so that we can use 0 to represent None?
     pub id: Option<MessageId>,
+    /// Logical stream of the message inside its channel, so that several streams can share the
+    /// settings (and the [`ChannelId`](crate::protocol::channel::ChannelId)) of one channel.
+    /// Sub-channel 0 takes no space in the packet, the others take 1 byte.
+    pub sub_channel: u8,
     pub bytes: Bytes,
+    /// Number of ticks after which a reliable channel stops resending the message, even if it was
+    /// not acked (see [`ReliableSender::buffer_send_single`]). None means that the message is
+    /// resent until it is acked.
+    ///
+    /// This is not written in the packet.
+    ///
+    /// [`ReliableSender::buffer_send_single`]: crate::channel::senders::reliable::ReliableSender::buffer_send_single
+    pub ticks_to_live: Option<u16>,
 }
 
 impl ToBytes for SingleData {
     // TODO: how to avoid the option taking 1 byte?
     fn len(&self) -> usize {
-        varint_len(self.bytes.len() as u64) + self.bytes.len() + self.id.map_or(1, |_| 3)
+        varint_len(self.bytes.len() as u64)
+            + self.bytes.len()
+            + self.id.map_or(1, |_| 3)
+            + usize::from(self.sub_channel != 0)
+    }
+
+    /// The bytes of the message are not bounded: a message that does not fit in a packet is
+    /// fragmented
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
-        if let Some(id) = self.id {
-            buffer.write_u8(1)?;
-            buffer.write_u16::<NetworkEndian>(id.0)?;
-        } else {
-            buffer.write_u8(0)?;
-        }
+        self.write_flags(buffer, 0)?;
         self.bytes.to_bytes(buffer)?;
         // buffer.write_varint(self.bytes.len() as u64)?;
         // buffer.write_all(self.bytes.as_ref())?;
@@ -143,21 +187,154 @@ impl ToBytes for SingleData {
     where
         Self: Sized,
     {
-        let id = if buffer.read_u8()? == 1 {
+        Self::from_bytes_in_block(buffer, &mut None)
+    }
+}
+
+impl SingleData {
+    pub fn new(id: Option<MessageId>, bytes: Bytes) -> Self {
+        Self {
+            id,
+            sub_channel: 0,
+            bytes,
+            ticks_to_live: None,
+        }
+    }
+
+    /// Stop resending the message `ticks_to_live` ticks after it was buffered (see
+    /// [`SingleData::ticks_to_live`])
+    pub fn with_ticks_to_live(mut self, ticks_to_live: u16) -> Self {
+        self.ticks_to_live = Some(ticks_to_live);
+        self
+    }
+
+    /// Send the message on a sub-channel of its channel (see [`SingleData::sub_channel`])
+    pub fn with_sub_channel(mut self, sub_channel: u8) -> Self {
+        self.sub_channel = sub_channel;
+        self
+    }
+
+    /// Write the message as part of a block of messages of the same length (see
+    /// [`FIXED_LENGTH_FLAG`]): only the `first` message of the block writes the length.
+    ///
+    /// The message then takes `varint_len(self.bytes.len())` bytes less than [`ToBytes::len`]
+    /// if it is not the first one.
+    pub(crate) fn to_bytes_in_block<T: WriteBytesExt>(
+        &self,
+        buffer: &mut T,
+        first: bool,
+    ) -> Result<(), SerializationError> {
+        if first {
+            self.write_flags(buffer, FIXED_LENGTH_FLAG)?;
+            self.bytes.to_bytes(buffer)?;
+        } else {
+            self.write_flags(buffer, 0)?;
+            buffer.write_all(&self.bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Read a message of a channel block. `block_len` must be None for the first message of the
+    /// block; it is set if the block contains messages of the same length.
+    pub(crate) fn from_bytes_in_block(
+        buffer: &mut Reader,
+        block_len: &mut Option<usize>,
+    ) -> Result<Self, SerializationError> {
+        let flags = buffer.read_u8()?;
+        if flags & !SINGLE_DATA_FLAGS != 0 {
+            return Err(SerializationError::InvalidValue);
+        }
+        let id = if flags & MESSAGE_ID_FLAG != 0 {
             Some(MessageId(buffer.read_u16::<NetworkEndian>()?))
         } else {
             None
         };
-        let bytes = Bytes::from_bytes(buffer)?;
+        let sub_channel = if flags & SUB_CHANNEL_FLAG != 0 {
+            buffer.read_u8()?
+        } else {
+            0
+        };
+        let bytes = match *block_len {
+            // only the first message of a block can start a block of messages of the same length
+            Some(_) if flags & FIXED_LENGTH_FLAG != 0 => {
+                return Err(SerializationError::InvalidValue)
+            }
+            Some(len) => {
+                let remaining = buffer.remaining();
+                if len > remaining {
+                    return Err(SerializationError::TruncatedMessage { len, remaining });
+                }
+                buffer.split_len(len)
+            }
+            None => {
+                let bytes = Bytes::from_bytes(buffer)?;
+                if flags & FIXED_LENGTH_FLAG != 0 {
+                    *block_len = Some(bytes.len());
+                }
+                bytes
+            }
+        };
         // let len = buffer.read_varint()? as usize;
         // let bytes = buffer.split_len(len);
-        Ok(Self { id, bytes })
+        Ok(Self {
+            id,
+            sub_channel,
+            bytes,
+            ticks_to_live: None,
+        })
+    }
+
+    /// Write everything that precedes the length of the bytes: the flags (along with
+    /// `extra_flags`), then the id and the sub-channel if they are set
+    fn write_flags<T: WriteBytesExt>(
+        &self,
+        buffer: &mut T,
+        extra_flags: u8,
+    ) -> Result<(), SerializationError> {
+        let mut flags = extra_flags;
+        if self.id.is_some() {
+            flags |= MESSAGE_ID_FLAG;
+        }
+        if self.sub_channel != 0 {
+            flags |= SUB_CHANNEL_FLAG;
+        }
+        buffer.write_u8(flags)?;
+        if let Some(id) = self.id {
+            buffer.write_u16::<NetworkEndian>(id.0)?;
+        }
+        if self.sub_channel != 0 {
+            buffer.write_u8(self.sub_channel)?;
+        }
+        Ok(())
+    }
+
+    /// A message with an id, so that it can be acked
+    pub fn with_id(id: MessageId, bytes: Bytes) -> Self {
+        Self::new(Some(id), bytes)
+    }
+
+    /// Set or replace the id of the message (for example when the sender assigns ids lazily).
+    ///
+    /// The id takes 2 more bytes in the packet, see [`ToBytes::len`].
+    pub fn set_id(&mut self, id: MessageId) {
+        self.id = Some(id);
     }
 }
 
-impl SingleData {
-    pub fn new(id: Option<MessageId>, bytes: Bytes) -> Self {
-        Self { id, bytes }
+/// Bytes that were already serialized elsewhere (for example a message received from another peer
+/// that is relayed), written in the packet exactly as they are.
+///
+/// If `id` is set, the message is acked like any other message with an id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawMessage {
+    pub id: Option<MessageId>,
+    pub bytes: Bytes,
+}
+
+impl From<RawMessage> for SingleData {
+    fn from(value: RawMessage) -> Self {
+        // only moves the `Bytes`, the payload is not copied or re-encoded
+        Self::new(value.id, value.bytes)
     }
 }
 
@@ -167,6 +344,9 @@ pub struct FragmentData {
     pub message_id: MessageId,
     pub fragment_id: FragmentIndex,
     pub num_fragments: FragmentIndex,
+    /// Position of the first byte of the fragment in the message, so that the receiver can
+    /// check that the fragments of the message are contiguous
+    pub offset: u32,
     /// Bytes data associated with the message that is too big
     pub bytes: Bytes,
 }
@@ -177,7 +357,14 @@ impl ToBytes for FragmentData {
         let extra_bytes = 4;
         #[cfg(feature = "big_messages")]
         let extra_bytes = 6;
-        extra_bytes + self.bytes.len() + varint_len(self.bytes.len() as u64)
+        extra_bytes
+            + varint_len(self.offset as u64)
+            + self.bytes.len()
+            + varint_len(self.bytes.len() as u64)
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -192,6 +379,7 @@ impl ToBytes for FragmentData {
         #[cfg(feature = "big_messages")]
         buffer.write_u16::<NetworkEndian>(self.num_fragments)?;
 
+        buffer.write_varint(self.offset as u64)?;
         self.bytes.to_bytes(buffer)?;
         // buffer.write_varint(self.bytes.len() as u64)?;
         // buffer.write_all(self.bytes.as_ref())?;
@@ -213,7 +401,13 @@ impl ToBytes for FragmentData {
         let fragment_id = buffer.read_u16::<NetworkEndian>()?;
         #[cfg(feature = "big_messages")]
         let num_fragments = buffer.read_u16::<NetworkEndian>()?;
+        // the receiver allocates the fragments of the message from the count of any fragment
+        if fragment_id >= num_fragments {
+            return Err(SerializationError::InvalidValue);
+        }
 
+        let offset =
+            u32::try_from(buffer.read_varint()?).map_err(|_| SerializationError::InvalidValue)?;
         let bytes = Bytes::from_bytes(buffer)?;
         // let len = buffer.read_varint()? as usize;
         // let bytes = buffer.split_len(len);
@@ -221,6 +415,7 @@ impl ToBytes for FragmentData {
             message_id,
             fragment_id,
             num_fragments,
+            offset,
             bytes,
         })
     }
@@ -262,6 +457,51 @@ mod tests {
         }
     }
 
+    /// Only the first message of a fixed-length block writes the length of the messages
+    #[test]
+    fn test_single_data_fixed_length_block() {
+        let mut buffer = vec![];
+        let first = SingleData::new(Some(MessageId(3)), Bytes::from_static(b"abc"));
+        let second = SingleData::new(None, Bytes::from_static(b"def")).with_sub_channel(2);
+        first.to_bytes_in_block(&mut buffer, true).unwrap();
+        second.to_bytes_in_block(&mut buffer, false).unwrap();
+        assert_eq!(buffer.len(), first.len() + second.len() - 1);
+
+        let mut reader = Reader::from(Bytes::from(buffer.clone()));
+        let mut block_len = None;
+        assert_eq!(
+            SingleData::from_bytes_in_block(&mut reader, &mut block_len).unwrap(),
+            first
+        );
+        assert_eq!(block_len, Some(3));
+        assert_eq!(
+            SingleData::from_bytes_in_block(&mut reader, &mut block_len).unwrap(),
+            second
+        );
+
+        // only the first message of a block can start a block
+        let mut reader = Reader::from(Bytes::from(buffer));
+        assert!(matches!(
+            SingleData::from_bytes_in_block(&mut reader, &mut Some(3)),
+            Err(SerializationError::InvalidValue)
+        ));
+    }
+
+    /// The id is only written (and counted in `len`) when the message has one
+    #[test]
+    fn test_single_data_len_with_id() {
+        let bytes = Bytes::from(vec![7u8; 10]);
+        let mut data = SingleData::new(None, bytes.clone());
+        assert_eq!(data.len(), 1 + 1 + 10);
+
+        data.set_id(MessageId(3));
+        assert_eq!(data, SingleData::with_id(MessageId(3), bytes));
+        assert_eq!(data.len(), 3 + 1 + 10);
+        let mut writer = vec![];
+        data.to_bytes(&mut writer).unwrap();
+        assert_eq!(writer.len(), data.len());
+    }
+
     #[test]
     fn test_to_bytes_fragment_data() {
         let bytes = Bytes::from(vec![0; 10]);
@@ -269,6 +509,7 @@ mod tests {
             message_id: MessageId(0),
             fragment_id: 2,
             num_fragments: 3,
+            offset: 20000,
             bytes: bytes.clone(),
         };
         let mut writer = vec![];
@@ -279,5 +520,47 @@ mod tests {
         let mut reader = writer.into();
         let decoded = FragmentData::from_bytes(&mut reader).unwrap();
         assert_eq!(decoded, data);
+
+        // a fragment index outside of the number of fragments is rejected
+        let data = FragmentData {
+            fragment_id: 3,
+            ..data
+        };
+        let mut writer = vec![];
+        data.to_bytes(&mut writer).unwrap();
+        assert!(matches!(
+            FragmentData::from_bytes(&mut writer.into()),
+            Err(SerializationError::InvalidValue)
+        ));
+    }
+
+    #[test]
+    fn test_dedup_sorted_acks() {
+        let ack = |message_id: u16, fragment_id: Option<FragmentIndex>| MessageAck {
+            message_id: MessageId(message_id),
+            fragment_id,
+        };
+        // the ids wrap around between 65535 and 0
+        let mut acks = vec![
+            ack(1, Some(2)),
+            ack(65535, None),
+            ack(1, Some(0)),
+            ack(0, None),
+            ack(1, Some(2)),
+            ack(65535, None),
+            ack(1, None),
+            ack(1, Some(0)),
+        ];
+        dedup_sorted(&mut acks);
+        assert_eq!(
+            acks,
+            vec![
+                ack(65535, None),
+                ack(0, None),
+                ack(1, None),
+                ack(1, Some(0)),
+                ack(1, Some(2)),
+            ]
+        );
     }
 }
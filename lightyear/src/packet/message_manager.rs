@@ -2,7 +2,7 @@ use std::collections::{HashMap, VecDeque};
 
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
-use tracing::trace;
+use tracing::{debug, trace};
 #[cfg(feature = "trace")]
 use tracing::{instrument, Level};
 
@@ -11,20 +11,26 @@ use crate::channel::receivers::ChannelReceive;
 use crate::channel::senders::ChannelSend;
 #[cfg(feature = "trace")]
 use crate::channel::stats::send::ChannelSendStats;
+use crate::connection::netcode::MAX_PACKET_SIZE;
 use crate::packet::error::PacketError;
-use crate::packet::header::PacketHeader;
+use crate::packet::header::{PacketHeader, PacketRecvOutcome};
 use crate::packet::message::{
-    FragmentData, MessageAck, MessageId, ReceiveMessage, SendMessage, SingleData,
+    dedup_sorted, FragmentData, MessageAck, MessageId, ReceiveMessage, SendMessage, SingleData,
 };
-use crate::packet::packet::PacketId;
-use crate::packet::packet_builder::{PacketBuilder, Payload, RecvPayload};
+#[cfg(feature = "lz4")]
+use crate::packet::packet::CompressionDictionary;
+use crate::packet::packet::{
+    decompress_packet, fragment_size, read_num_messages, strip_padding, verify_checksum, Packet,
+    PacketId, CHECKSUM_BYTES,
+};
+use crate::packet::packet_builder::{BufferSizing, PacketBuilder, Payload, RecvPayload};
 use crate::packet::packet_type::PacketType;
-use crate::packet::priority_manager::{PriorityConfig, PriorityManager};
-use crate::protocol::channel::{ChannelId, ChannelKind, ChannelRegistry};
+use crate::packet::priority_manager::{DropReport, PriorityConfig, PriorityManager};
+use crate::packet::wire_config::WireConfig;
+use crate::protocol::channel::{ChannelId, ChannelKind, ChannelRegistry, ChannelSchema};
 use crate::protocol::registry::NetId;
 use crate::serialize::reader::Reader;
-use crate::serialize::varint::VarIntReadExt;
-use crate::serialize::ToBytes;
+use crate::serialize::{SerializationError, ToBytes};
 use crate::shared::ping::manager::PingManager;
 use crate::shared::tick_manager::Tick;
 use crate::shared::tick_manager::TickManager;
@@ -37,8 +43,31 @@ use crate::utils::captures::Captures;
 
 pub const DEFAULT_MESSAGE_PRIORITY: f32 = 1.0;
 
+/// A received packet whose [`PacketType`] is not known by this version (for example a packet type
+/// that was added in a newer version of the remote peer)
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownPacket {
+    pub packet_type: u8,
+    /// The rest of the packet, after the header
+    pub raw: Bytes,
+}
+
+/// Options used to read the received packets
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParseOptions {
+    /// Skip the messages of channels that are not in our channel registry (for example if the remote
+    /// peer uses a newer version of the protocol) instead of rejecting the whole packet.
+    ///
+    /// The skipped channels of the last received packet are available with
+    /// [`MessageManager::skipped_channels`].
+    pub skip_unknown_channels: bool,
+}
+
 /// Wrapper to: send/receive messages via channels to a remote address
 /// By splitting the data into packets and sending them through a given transport
+///
+/// The connections set the [`WireConfig`] from the `PacketConfig`; the other `pub(crate)`
+/// `with_*` options are internal and keep their default outside of the tests.
 #[derive(Debug)]
 pub struct MessageManager {
     /// Handles sending/receiving packets (including acks)
@@ -48,9 +77,28 @@ pub struct MessageManager {
     pub(crate) channel_registry: ChannelRegistry,
     // TODO: can use Vec<ChannelKind, Vec<MessageId>> to be more efficient?
     /// Map to keep track of which messages have been sent in which packets, so that
-    /// reliable senders can stop trying to send a message that has already been received
-    packet_to_message_ack_map: HashMap<PacketId, Vec<(ChannelKind, MessageAck)>>,
+    /// reliable senders can stop trying to send a message that has already been received.
+    /// The acks are grouped by channel, so that each channel is only looked up once per packet
+    packet_to_message_ack_map: HashMap<PacketId, Vec<(ChannelKind, Vec<MessageAck>)>>,
     nack_senders: Vec<Sender<MessageId>>,
+    /// Last received packet, if its type was unknown
+    unknown_packet: Option<UnknownPacket>,
+    /// True if the last received packet was a keep-alive packet
+    keepalive_received: bool,
+    /// Number of bytes left free in each fragment packet for small messages
+    fragment_headroom: usize,
+    /// If true, the ack packets are sent before the data packets
+    ack_packets_first: bool,
+    /// If true, the handshake packet is sent with the next packets
+    handshake_pending: bool,
+    /// MTU set with [`Self::with_mtu`] or [`Self::set_mtu`]. It is not used while large packets
+    /// are enabled, but is restored when they are disabled
+    mtu: usize,
+    /// If true, packets are not limited in size (see [`Self::with_large_packets`])
+    large_packets: bool,
+    parse_options: ParseOptions,
+    /// Channels of the last received packet that were skipped because they are unknown
+    skipped_channels: Vec<ChannelId>,
 }
 
 impl MessageManager {
@@ -60,15 +108,234 @@ impl MessageManager {
         priority_config: PriorityConfig,
     ) -> Self {
         Self {
-            packet_manager: PacketBuilder::new(nack_rtt_multiple),
+            packet_manager: PacketBuilder::new(nack_rtt_multiple)
+                .with_channel_registry(channel_registry),
             priority_manager: PriorityManager::new(priority_config),
             channels: channel_registry.channels(),
             channel_registry: channel_registry.clone(),
             packet_to_message_ack_map: HashMap::new(),
             nack_senders: vec![],
+            unknown_packet: None,
+            keepalive_received: false,
+            fragment_headroom: 0,
+            ack_packets_first: false,
+            handshake_pending: false,
+            mtu: MAX_PACKET_SIZE,
+            large_packets: false,
+            parse_options: ParseOptions::default(),
+            skipped_channels: vec![],
         }
     }
 
+    /// Set the options used to read the received packets
+    pub(crate) fn with_parse_options(mut self, parse_options: ParseOptions) -> Self {
+        self.parse_options = parse_options;
+        self
+    }
+
+    /// Write a checksum at the end of each packet, and check it on each received packet.
+    /// Both peers must enable the checksum.
+    pub(crate) fn with_checksum(mut self, checksum: bool) -> Self {
+        self.packet_manager = self.packet_manager.with_checksum(checksum);
+        self.update_fragment_sizes();
+        self
+    }
+
+    /// Set the maximum number of bytes in a packet (by default [`MAX_PACKET_SIZE`]), depending on
+    /// what the transport supports. Messages are fragmented so that each fragment fits in a packet.
+    ///
+    /// Returns an error if the packets would be too small to hold a fragment.
+    pub(crate) fn with_mtu(mut self, mtu: usize) -> Result<Self, PacketError> {
+        self.wire_config().with_mtu(mtu)?;
+        self.mtu = mtu;
+        let packet_mtu = self.packet_mtu();
+        self.packet_manager = self.packet_manager.with_mtu(packet_mtu)?;
+        self.update_fragment_sizes();
+        Ok(self)
+    }
+
+    /// Change the maximum number of bytes in a packet during the connection (see
+    /// [`PacketBuilder::set_mtu`]). The messages queued from now on are fragmented to fit in it.
+    ///
+    /// Returns an error, and keeps the current MTU, if the MTU is lowered while some messages
+    /// that were fragmented for the current MTU are still queued (or not acked yet): their
+    /// fragments would not fit in the smaller packets.
+    pub(crate) fn set_mtu(&mut self, mtu: usize) -> Result<(), PacketError> {
+        if self.large_packets {
+            // nothing was fragmented, the MTU is only used once large packets are disabled
+            self.wire_config().with_mtu(mtu)?;
+            self.mtu = mtu;
+            return Ok(());
+        }
+        if mtu < self.packet_manager.mtu()
+            && self
+                .channels
+                .values()
+                .any(|channel| channel.sender.has_queued_fragments())
+        {
+            return Err(PacketError::FragmentsQueued { mtu });
+        }
+        self.packet_manager.set_mtu(mtu)?;
+        self.mtu = mtu;
+        self.update_fragment_sizes();
+        Ok(())
+    }
+
+    /// Size the buffers used to build the packets of this connection (see [`BufferSizing`])
+    pub(crate) fn with_buffer_sizing(mut self, buffer_sizing: BufferSizing) -> Self {
+        self.packet_manager = self.packet_manager.with_buffer_sizing(buffer_sizing);
+        self
+    }
+
+    /// Compress the packets we send, and decompress the packets we receive, with the same
+    /// [`CompressionDictionary`] as the remote peer
+    #[cfg(feature = "lz4")]
+    pub(crate) fn with_compression_dictionary(
+        mut self,
+        compression_dictionary: CompressionDictionary,
+    ) -> Self {
+        self.packet_manager = self
+            .packet_manager
+            .with_compression_dictionary(compression_dictionary);
+        self
+    }
+
+    /// Use the same [`WireConfig`] to build the packets we send and to read the packets we receive
+    pub fn with_wire_config(mut self, wire_config: WireConfig) -> Result<Self, PacketError> {
+        self.packet_manager = self.packet_manager.with_wire_config(wire_config)?;
+        self.mtu = wire_config.mtu;
+        let packet_mtu = self.packet_mtu();
+        self.packet_manager = self.packet_manager.with_mtu(packet_mtu)?;
+        self.update_fragment_sizes();
+        Ok(self)
+    }
+
+    /// The [`WireConfig`] currently used to build and read packets
+    ///
+    /// It can be sent to the remote peer to check that both peers use the same config.
+    pub fn wire_config(&self) -> WireConfig {
+        self.packet_manager.wire_config()
+    }
+
+    /// Make fragments `fragment_headroom` bytes smaller than what would fit in a packet, so that
+    /// every fragment packet has room left for some small messages
+    pub(crate) fn with_fragment_headroom(mut self, fragment_headroom: usize) -> Self {
+        self.fragment_headroom = fragment_headroom;
+        self.update_fragment_sizes();
+        self
+    }
+
+    /// Set the fragment size of each channel from the MTU, the fragment headroom and the bytes
+    /// reserved in each packet (for the checksum and the content length of padded packets)
+    fn update_fragment_sizes(&mut self) {
+        let mtu = self.packet_manager.mtu();
+        let reserved_len = self.packet_manager.reserved_len();
+        for channel in self.channels.values_mut() {
+            let fragment_mtu = channel.setting.fragment_mtu.map_or(mtu, |m| m.min(mtu));
+            let size =
+                fragment_size(fragment_mtu, reserved_len).saturating_sub(self.fragment_headroom);
+            channel.sender.set_fragment_size(size.max(1));
+        }
+    }
+
+    /// Write each message whole, in a single packet, instead of splitting big messages into fragments.
+    ///
+    /// Packets are then no longer limited in size, so this must only be used with transports
+    /// that can carry frames of any size (for example a stream-based transport).
+    ///
+    /// Disabling it goes back to the MTU set with [`Self::with_mtu`].
+    pub(crate) fn with_large_packets(mut self, large_packets: bool) -> Result<Self, PacketError> {
+        self.large_packets = large_packets;
+        let packet_mtu = self.packet_mtu();
+        self.packet_manager = self.packet_manager.with_mtu(packet_mtu)?;
+        self.update_fragment_sizes();
+        Ok(self)
+    }
+
+    /// Maximum number of bytes in the packets we build
+    fn packet_mtu(&self) -> usize {
+        if self.large_packets {
+            usize::MAX
+        } else {
+            self.mtu
+        }
+    }
+
+    /// Send the acks that did not fit in the headers of the data packets in extra header-only packets,
+    /// so that no received packet is left un-acked
+    pub(crate) fn with_ack_packets(mut self, ack_packets: bool) -> Self {
+        if ack_packets {
+            self.packet_manager.header_manager.track_pending_acks();
+        }
+        self
+    }
+
+    /// Send the handshake packet (see [`Self::send_handshake`]) before the first packets of the
+    /// connection, so that the remote peer returns an error if its channels don't match ours.
+    ///
+    /// The handshake packet is sent once and is not resent if it is lost, so the check is best-effort.
+    pub(crate) fn with_handshake(mut self, handshake: bool) -> Self {
+        self.handshake_pending = handshake;
+        self
+    }
+
+    /// Build the handshake packet, that lets the remote peer check that it registered the same channels
+    pub(crate) fn send_handshake(&mut self, current_tick: Tick) -> Result<Payload, PacketError> {
+        let schema = self.channel_registry.schema();
+        let packet = self
+            .packet_manager
+            .build_handshake_packet(current_tick, &schema)?;
+        Ok(packet.payload)
+    }
+
+    /// Build a keep-alive packet, to send when the connection is idle.
+    /// It contains the acks of the received packets but no messages.
+    ///
+    /// This is internal: the client and server connections don't send it, because their
+    /// transports already keep idle connections alive (for example the keep-alive packets of
+    /// netcode). It is meant for transports that don't.
+    pub(crate) fn send_keepalive(&mut self, current_tick: Tick) -> Result<Payload, PacketError> {
+        let packet = self.packet_manager.build_keepalive(current_tick)?;
+        Ok(packet.payload)
+    }
+
+    /// Keep the last packet of each `send_packets` call open, so that the messages of the next call
+    /// for the same tick can fill it (see [`PacketBuilder::with_incremental_packets`]). The open
+    /// packet is sent by the first call for a later tick, or by [`Self::flush_packets`].
+    pub(crate) fn with_incremental_packets(mut self, incremental_packets: bool) -> Self {
+        self.packet_manager = self
+            .packet_manager
+            .with_incremental_packets(incremental_packets);
+        self
+    }
+
+    /// Send the ack packets (see [`Self::with_ack_packets`]) before the data packets, so that the
+    /// remote peer receives the acks as early as possible
+    ///
+    /// Only the ack packets are moved, the data packets keep their order: the header of every
+    /// data packet carries the same ack window (the latest received packets), so sending one data
+    /// packet before another would not deliver any ack earlier.
+    pub(crate) fn with_ack_packets_first(mut self, ack_packets_first: bool) -> Self {
+        self.ack_packets_first = ack_packets_first;
+        self
+    }
+
+    /// The last packet received by `recv_packet`, if its [`PacketType`] is unknown
+    pub(crate) fn unknown_packet(&self) -> Option<&UnknownPacket> {
+        self.unknown_packet.as_ref()
+    }
+
+    /// Channels of the last packet received by `recv_packet` whose messages were skipped because
+    /// the channel is unknown (see [`ParseOptions::skip_unknown_channels`])
+    pub fn skipped_channels(&self) -> &[ChannelId] {
+        &self.skipped_channels
+    }
+
+    /// True if the last packet received by `recv_packet` was a keep-alive packet
+    pub(crate) fn keepalive_received(&self) -> bool {
+        self.keepalive_received
+    }
+
     pub(crate) fn get_replication_update_send_receiver(&mut self) -> Receiver<MessageId> {
         self.priority_manager
             .subscribe_replication_update_sent_messages()
@@ -90,19 +357,20 @@ impl MessageManager {
         // notify that some messages have been lost
         for lost_packet in lost_packets {
             if let Some(message_map) = self.packet_to_message_ack_map.remove(&lost_packet) {
-                for (channel_kind, message_ack) in message_map {
+                for (channel_kind, message_acks) in message_map {
                     let channel = self
                         .channels
                         .get_mut(&channel_kind)
                         .expect("Channel not found");
-                    // TODO: batch the messages?
-                    trace!(
-                        ?lost_packet,
-                        ?channel_kind,
-                        "message lost: {:?}",
-                        message_ack.message_id
-                    );
-                    channel.sender.send_nacks(message_ack.message_id);
+                    for message_ack in message_acks {
+                        trace!(
+                            ?lost_packet,
+                            ?channel_kind,
+                            "message lost: {:?}",
+                            message_ack.message_id
+                        );
+                        channel.sender.send_nacks(message_ack.message_id);
+                    }
                 }
             }
         }
@@ -153,8 +421,26 @@ impl MessageManager {
     // TODO: maybe pass TickManager instead of Tick? Find a more elegant way to pass extra data that might not be used?
     //  (ticks are not purely necessary without client prediction)
     //  maybe be generic over a Context ?
-    #[cfg_attr(feature = "trace", instrument(level = Level::INFO, skip_all))]
     pub fn send_packets(&mut self, current_tick: Tick) -> Result<Vec<Payload>, PacketError> {
+        self.send_packets_with_drop_report(current_tick)
+            .map(|(bytes, _)| bytes)
+    }
+
+    /// Same as [`Self::send_packets`], but also return the messages that were dropped instead of
+    /// being sent, with the reason why: the messages over the bandwidth quota, and the reliable
+    /// messages that expired or reached their maximum number of resends.
+    #[cfg_attr(feature = "trace", instrument(level = Level::INFO, skip_all))]
+    pub fn send_packets_with_drop_report(
+        &mut self,
+        current_tick: Tick,
+    ) -> Result<(Vec<Payload>, DropReport), PacketError> {
+        let mut drop_report = DropReport::default();
+        let mut bytes = Vec::new();
+        // the first packets of the connection start with the handshake packet
+        if std::mem::take(&mut self.handshake_pending) {
+            bytes.push(self.send_handshake(current_tick)?);
+        }
+
         // Step 1. Get the list of packets to send from all channels
         // for each channel, prepare packets using the buffered messages that are ready to be sent
         // TODO: iterate through the channels in order of channel priority? (with accumulation)
@@ -166,6 +452,7 @@ impl MessageManager {
                 .get_net_from_kind(channel_kind)
                 .ok_or(PacketError::ChannelNotFound)?;
             let (single_data, fragment_data) = channel.sender.send_packet();
+            drop_report.add_channel_drops(*channel_id, channel.sender.take_dropped_messages());
 
             if !single_data.is_empty() || !fragment_data.is_empty() {
                 trace!(?channel_id, "send message with channel_id");
@@ -174,15 +461,24 @@ impl MessageManager {
             }
         }
         // return early if there are no messages to send
-        if !has_data_to_send {
-            return Ok(vec![]);
+        // (messages deferred by the channel byte caps and pending acks still need to be sent)
+        if !has_data_to_send
+            && !self.packet_manager.has_deferred_messages()
+            && !self.packet_manager.has_open_packet()
+            && !self.packet_manager.header_manager.has_pending_acks()
+        {
+            return Ok((bytes, drop_report));
         }
 
         // priority manager: get the list of messages we can send according to the rate limiter
         //  (the other messages are stored in an internal buffer)
-        let (single_data, fragment_data, num_bytes_added_to_limiter) = self
-            .priority_manager
-            .priority_filter(data_to_send, &self.channel_registry, current_tick);
+        let (single_data, fragment_data, num_bytes_added_to_limiter) =
+            self.priority_manager.priority_filter(
+                data_to_send,
+                &self.channel_registry,
+                current_tick,
+                &mut drop_report,
+            );
 
         #[cfg(feature = "trace")]
         {
@@ -216,17 +512,68 @@ impl MessageManager {
             }
         }
 
-        let packets =
+        let (mut packets, deferred) =
             self.packet_manager
-                .build_packets(current_tick, single_data, fragment_data)?;
+                .build_capped_packets(current_tick, single_data, fragment_data)?;
+        // send the acks that were not included in the headers of the data packets
+        // (they are built last, because the headers of the data packets already include some acks)
+        let ack_packets = self.packet_manager.build_ack_packets(current_tick)?;
+        if self.ack_packets_first {
+            packets.splice(0..0, ack_packets);
+        } else {
+            packets.extend(ack_packets);
+        }
+        for (channel_id, num_deferred) in deferred {
+            trace!(
+                ?channel_id,
+                ?num_deferred,
+                "messages deferred to the next tick because of the channel byte cap"
+            );
+        }
+
+        self.register_sent_packets(packets, &mut bytes)?;
+
+        // adjust the real amount of bytes that we sent through the limiter (to account for the actual packet size)
+        if self.priority_manager.config.enabled {
+            let total_bytes_sent = bytes.iter().map(|b| b.len() as u32).sum::<u32>();
+            if let Ok(remaining_bytes_to_add) =
+                (total_bytes_sent - num_bytes_added_to_limiter).try_into()
+            {
+                let _ = self
+                    .priority_manager
+                    .limiter
+                    .check_n(remaining_bytes_to_add);
+            }
+        }
+
+        Ok((bytes, drop_report))
+    }
 
+    /// Send the packet kept open by the last call to `send_packets`, if any (see
+    /// [`Self::with_incremental_packets`])
+    pub(crate) fn flush_packets(&mut self) -> Result<Vec<Payload>, PacketError> {
         let mut bytes = Vec::new();
+        let packets = self.packet_manager.flush_current()?.into_iter().collect();
+        self.register_sent_packets(packets, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Remember which messages were sent in each packet (for the channels that watch the acks),
+    /// and push the payloads of the packets to `bytes`
+    fn register_sent_packets(
+        &mut self,
+        packets: Vec<Packet>,
+        bytes: &mut Vec<Payload>,
+    ) -> Result<(), PacketError> {
+        // reused for every packet
+        let mut message_acks = Vec::new();
         for mut packet in packets {
             trace!(packet_id = ?packet.packet_id, num_messages = ?packet.num_messages(), "sending packet");
             // TODO: should we update this to include fragment info as well?
             // Step 2. Update the packet_to_message_id_map (only for channels that care about acks)
-            std::mem::take(&mut packet.message_acks)
-                .into_iter()
+            packet.extract_acks_into(&mut message_acks);
+            message_acks
+                .drain(..)
                 .try_for_each(|(channel_id, message_ack)| {
                     let channel_kind = self
                         .channel_registry
@@ -243,116 +590,149 @@ impl MessageManager {
                             message_ack,
                             packet.packet_id
                         );
-                        self.packet_to_message_ack_map
+                        let channel_acks = self
+                            .packet_to_message_ack_map
                             .entry(packet.packet_id)
-                            .or_default()
-                            .push((*channel_kind, message_ack));
+                            .or_default();
+                        match channel_acks
+                            .iter_mut()
+                            .find(|(kind, _)| kind == channel_kind)
+                        {
+                            Some((_, acks)) => acks.push(message_ack),
+                            None => channel_acks.push((*channel_kind, vec![message_ack])),
+                        }
                     }
                     Ok::<(), PacketError>(())
                 })?;
 
             // Step 3. Get the packets to send over the network
             bytes.push(packet.payload);
+            self.packet_manager.recycle_acks(packet.message_acks);
         }
-
-        // adjust the real amount of bytes that we sent through the limiter (to account for the actual packet size)
-        if self.priority_manager.config.enabled {
-            let total_bytes_sent = bytes.iter().map(|b| b.len() as u32).sum::<u32>();
-            if let Ok(remaining_bytes_to_add) =
-                (total_bytes_sent - num_bytes_added_to_limiter).try_into()
-            {
-                let _ = self
-                    .priority_manager
-                    .limiter
-                    .check_n(remaining_bytes_to_add);
-            }
-        }
-
-        Ok(bytes)
+        Ok(())
     }
 
     /// Process packet received over the network as raw bytes
     /// Update the acks, and put the messages from the packets in internal buffers
     /// Returns the tick of the packet
     #[cfg_attr(feature = "trace", instrument(level = Level::INFO, skip_all))]
-    pub fn recv_packet(&mut self, packet: RecvPayload) -> Result<Tick, PacketError> {
+    pub fn recv_packet(&mut self, mut packet: RecvPayload) -> Result<Tick, PacketError> {
         trace!(?packet, "Received packet");
+        if self.packet_manager.checksum {
+            if !verify_checksum(&packet) {
+                return Err(SerializationError::ChecksumMismatch.into());
+            }
+            packet.truncate(packet.len() - CHECKSUM_BYTES);
+        }
         let mut cursor = Reader::from(packet);
 
         // Step 1. Parse the packet
         let header = PacketHeader::from_bytes(&mut cursor)?;
+        if self.packet_manager.pad_to.is_some() {
+            cursor = strip_padding(cursor)?;
+        }
+        self.unknown_packet = None;
+        self.keepalive_received = false;
+        self.skipped_channels.clear();
+        if let PacketType::Unknown(packet_type) = header.get_packet_type() {
+            // skip packets that we don't know how to read, instead of failing
+            debug!(
+                ?packet_type,
+                "Received a packet with an unknown packet type"
+            );
+            self.unknown_packet = Some(UnknownPacket {
+                packet_type,
+                raw: cursor.split_len(cursor.remaining()),
+            });
+            return Ok(header.tick);
+        }
+        // drop the packets that we already received (for example if they were duplicated by the
+        // network), so that their messages are not processed twice
+        let header_manager = &mut self.packet_manager.header_manager;
+        let outcome = header_manager.recv_outcome(&header);
+        if outcome != PacketRecvOutcome::New {
+            header_manager.process_recv_header(&header);
+            debug!(?outcome, packet_id = ?header.packet_id, "Dropping received packet");
+            return Ok(header.tick);
+        }
+        let mut cursor = decompress_packet(
+            &header,
+            cursor,
+            self.packet_manager.compression_dictionary.as_ref(),
+            self.packet_manager.mtu(),
+        )?;
         let tick = header.tick;
 
-        // TODO: if it's fragmented, put it in a buffer? while we wait for all the parts to be ready?
-        //  maybe the channel can handle the fragmentation?
-
-        // TODO: an option is to have an async task that is on the receiving side of the
-        //  cross-beam channel which tell which packets have been received
+        // Step 2. Read the whole payload before recording the packet as received: a packet that
+        // can't be read is not acked, so that the remote peer sends its messages again
+        let received = match header.get_packet_type() {
+            // the handshake packet only contains the remote channel schema
+            PacketType::Handshake => {
+                let schema = ChannelSchema::from_bytes(&mut cursor)?;
+                self.channel_registry.check_schema(&schema)?;
+                vec![]
+            }
+            // the keep-alive packet has no messages, and the user packets are read by the layer
+            // that defined them, not by the channels
+            PacketType::KeepAlive | PacketType::User(_) => vec![],
+            packet_type => self.read_payload(&mut cursor, packet_type, tick)?,
+        };
 
-        // Step 2. Update the packet acks (which packets have we received, and which of our packets
+        // Step 3. Update the packet acks (which packets have we received, and which of our packets
         // have been acked)
-        let acked_packets = self
-            .packet_manager
-            .header_manager
-            .process_recv_packet_header(&header);
+        let header_manager = &mut self.packet_manager.header_manager;
+        header_manager.process_recv_header(&header);
+        let acked_packets = header_manager.process_recv_acks(&header);
 
-        // Step 3. Update the list of messages that have been acked
+        // Step 4. Update the list of messages that have been acked. The acks of all the acked
+        // packets are grouped by channel, so that a message acked in several packets is only
+        // acked once
+        let mut channel_acks: HashMap<ChannelKind, Vec<MessageAck>> = HashMap::new();
         for acked_packet in acked_packets {
             trace!("Acked packet {:?}", acked_packet);
             if let Some(message_acks) = self.packet_to_message_ack_map.remove(&acked_packet) {
-                for (channel_kind, message_ack) in message_acks {
-                    let channel_name = self
-                        .channel_registry
-                        .name(&channel_kind)
-                        .ok_or(PacketError::ChannelNotFound)?;
-                    trace!(
-                        "Acked message in packet: channel={:?},message_ack={:?}",
-                        channel_name,
-                        message_ack
-                    );
-                    let channel = self
-                        .channels
-                        .get_mut(&channel_kind)
-                        .ok_or(PacketError::ChannelNotFound)?;
-                    channel.sender.receive_ack(&message_ack);
+                for (channel_kind, message_acks) in message_acks {
+                    channel_acks
+                        .entry(channel_kind)
+                        .or_default()
+                        .extend(message_acks);
                 }
             }
         }
+        for (channel_kind, mut message_acks) in channel_acks {
+            dedup_sorted(&mut message_acks);
+            let channel_name = self
+                .channel_registry
+                .name(&channel_kind)
+                .ok_or(PacketError::ChannelNotFound)?;
+            trace!(
+                "Acked messages: channel={:?},message_acks={:?}",
+                channel_name,
+                message_acks
+            );
+            let channel = self
+                .channels
+                .get_mut(&channel_kind)
+                .ok_or(PacketError::ChannelNotFound)?;
+            for message_ack in message_acks.iter() {
+                channel.sender.receive_ack(message_ack);
+            }
+        }
 
-        // Step 4. Parse the payload into messages, put them in the internal buffers for each channel
-        // we read directly from the packet and don't create intermediary datastructures to avoid allocations
-        // TODO: maybe do this in a helper function?
-        if header.get_packet_type() == PacketType::DataFragment {
-            // read the fragment data
-            let channel_id = ChannelId::from_bytes(&mut cursor)?;
-            let fragment_data = FragmentData::from_bytes(&mut cursor)?;
+        if header.get_packet_type() == PacketType::KeepAlive {
+            self.keepalive_received = true;
+        }
+        // Step 5. Put the messages in the internal buffers for each channel
+        for (channel_id, message) in received {
             self.get_channel_mut(channel_id)?
                 .receiver
-                .buffer_recv(ReceiveMessage {
-                    data: fragment_data.into(),
-                    remote_sent_tick: tick,
-                })?;
-        }
-        // read single message data
-        while cursor.has_remaining() {
-            let channel_id = ChannelId::from_bytes(&mut cursor)?;
-            let num_messages = cursor.read_varint()?;
-            for i in 0..num_messages {
-                let single_data = SingleData::from_bytes(&mut cursor)?;
-                self.get_channel_mut(channel_id)?
-                    .receiver
-                    .buffer_recv(ReceiveMessage {
-                        data: single_data.into(),
-                        remote_sent_tick: tick,
-                    })?;
-            }
+                .buffer_recv(message)?;
         }
         // trace!(
         //         "received {:?} messages from channel: {:?}",
         //         messages,
         //         channel_kind
         //     );
-        // TODO: use channel_id 0 as end of packet or just check that we are at the end of the packet?
         Ok(tick)
     }
 
@@ -414,6 +794,79 @@ impl MessageManager {
             .ok_or(PacketError::ChannelNotFound)
     }
 
+    /// Read the messages of a data packet, along with the channel that receives each of them.
+    ///
+    /// The messages of the channels that are skipped (see [`Self::recv_channel_mut`]) are dropped.
+    fn read_payload(
+        &mut self,
+        cursor: &mut Reader,
+        packet_type: PacketType,
+        tick: Tick,
+    ) -> Result<Vec<(ChannelId, ReceiveMessage)>, PacketError> {
+        let max_messages = self.packet_manager.max_messages_per_packet();
+        let mut received = vec![];
+        let mut num_read = 0;
+        if packet_type == PacketType::DataFragment {
+            // read the fragment data
+            let channel_id = self.packet_manager.channel_dictionary.read(cursor)?;
+            let num_fragments = read_num_messages(cursor, num_read, max_messages)?;
+            num_read += num_fragments;
+            for _ in 0..num_fragments {
+                let fragment_data = FragmentData::from_bytes(cursor)?;
+                if self.recv_channel_mut(channel_id)?.is_some() {
+                    received.push((
+                        channel_id,
+                        ReceiveMessage {
+                            data: fragment_data.into(),
+                            remote_sent_tick: tick,
+                        },
+                    ));
+                }
+            }
+        }
+        // read single message data
+        while cursor.has_remaining() {
+            let channel_id = self.packet_manager.channel_dictionary.read(cursor)?;
+            let num_messages = read_num_messages(cursor, num_read, max_messages)?;
+            num_read += num_messages;
+            let mut block_len = None;
+            for _ in 0..num_messages {
+                let single_data = SingleData::from_bytes_in_block(cursor, &mut block_len)?;
+                if self.recv_channel_mut(channel_id)?.is_some() {
+                    received.push((
+                        channel_id,
+                        ReceiveMessage {
+                            data: single_data.into(),
+                            remote_sent_tick: tick,
+                        },
+                    ));
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    /// The channel that receives the messages of `channel_id`, or None if the channel is unknown
+    /// and [`ParseOptions::skip_unknown_channels`] is enabled (the channel is then recorded in
+    /// the skipped channels)
+    fn recv_channel_mut(
+        &mut self,
+        channel_id: ChannelId,
+    ) -> Result<Option<&mut ChannelContainer>, PacketError> {
+        let is_known = self
+            .channel_registry
+            .get_kind_from_net_id(channel_id)
+            .is_some_and(|channel_kind| self.channels.contains_key(channel_kind));
+        if !is_known && self.parse_options.skip_unknown_channels {
+            if !self.skipped_channels.contains(&channel_id) {
+                debug!(?channel_id, "Skipping the messages of an unknown channel");
+                self.skipped_channels.push(channel_id);
+            }
+            return Ok(None);
+        }
+        self.get_channel_mut(channel_id).map(Some)
+    }
+
     /// Get the ChannelSendStats of a given channel
     #[cfg(feature = "trace")]
     pub fn channel_send_stats<C: crate::prelude::Channel>(&self) -> Option<&ChannelSendStats> {
@@ -429,12 +882,19 @@ impl MessageManager {
 mod tests {
     use std::collections::HashMap;
 
-    use bevy::prelude::default;
+    use bevy::prelude::{default, Reflect};
+    use lightyear_macros::ChannelInternal;
 
     use crate::packet::message::MessageId;
     use crate::packet::packet::FRAGMENT_SIZE;
-    use crate::packet::priority_manager::PriorityConfig;
+    use governor::Quota;
+    use nonzero_ext::nonzero;
+
+    use crate::channel::senders::ChannelSender;
+    use crate::packet::message::SingleData;
+    use crate::packet::priority_manager::{DropReason, PriorityConfig};
     use crate::prelude::*;
+    use crate::shared::time_manager::WrappedTime;
 
     use crate::tests::protocol::*;
 
@@ -442,14 +902,18 @@ mod tests {
 
     fn setup() -> (MessageManager, MessageManager) {
         let mut channel_registry = ChannelRegistry::default();
-        channel_registry.add_channel::<Channel1>(ChannelSettings {
-            mode: ChannelMode::UnorderedUnreliable,
-            ..default()
-        });
-        channel_registry.add_channel::<Channel2>(ChannelSettings {
-            mode: ChannelMode::UnorderedUnreliableWithAcks,
-            ..default()
-        });
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliableWithAcks,
+                ..default()
+            })
+            .unwrap();
 
         // Create message managers
         let client_message_manager =
@@ -481,10 +945,10 @@ mod tests {
                 PacketId(0),
                 vec![(
                     channel_kind_2,
-                    MessageAck {
+                    vec![MessageAck {
                         message_id: MessageId(0),
                         fragment_id: None,
-                    }
+                    }]
                 )]
             )])
         );
@@ -541,6 +1005,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    /// A packet whose payload can't be read is not recorded as received, so it is not acked and
+    /// its messages can still be received when the packet arrives intact
+    fn test_message_manager_unreadable_packet_not_acked() -> Result<(), PacketError> {
+        let (mut client_message_manager, mut server_message_manager) = setup();
+        let message: Bytes = vec![1; 10].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let payload = client_message_manager.send_packets(Tick(0))?.pop().unwrap();
+
+        // the packet is truncated in the middle of the message
+        let truncated = payload[..payload.len() - 2].to_vec();
+        assert!(server_message_manager
+            .recv_packet(truncated.into())
+            .is_err());
+        let header = Packet::peek_header(&payload)?;
+        assert_eq!(
+            server_message_manager
+                .packet_manager
+                .header_manager
+                .recv_outcome(&header),
+            PacketRecvOutcome::New
+        );
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert!(data.is_empty());
+
+        server_message_manager.recv_packet(payload.into())?;
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_1).unwrap(),
+            &vec![(Tick(0), message)]
+        );
+        Ok(())
+    }
+
     #[test]
     /// We want to test that we can send/receive messages over a connection
     fn test_message_manager_fragment_message() -> Result<(), PacketError> {
@@ -569,17 +1068,17 @@ mod tests {
             .collect();
         assert!(acks.contains(&vec![(
             channel_kind_2,
-            MessageAck {
+            vec![MessageAck {
                 message_id: MessageId(0),
                 fragment_id: Some(0),
-            }
+            }]
         )]));
         assert!(acks.contains(&vec![(
             channel_kind_2,
-            MessageAck {
+            vec![MessageAck {
                 message_id: MessageId(0),
                 fragment_id: Some(1),
-            }
+            }]
         )]));
 
         // server: receive bytes from the sent messages, then process them into messages
@@ -635,6 +1134,87 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    /// The acks of the messages written in a packet are grouped by channel, and each channel
+    /// receives the acks of its own messages when the packet is acked
+    fn test_message_manager_grouped_acks() -> Result<(), PacketError> {
+        #[derive(ChannelInternal, Reflect)]
+        struct Channel3;
+
+        let mut channel_registry = ChannelRegistry::default();
+        let settings = ChannelSettings {
+            mode: ChannelMode::UnorderedUnreliableWithAcks,
+            ..default()
+        };
+        channel_registry
+            .add_channel::<Channel1>(settings.clone())
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(settings.clone())
+            .unwrap();
+        channel_registry.add_channel::<Channel3>(settings).unwrap();
+        let mut client_message_manager =
+            MessageManager::new(&channel_registry, 1.5, PriorityConfig::default());
+        let mut server_message_manager =
+            MessageManager::new(&channel_registry, 1.5, PriorityConfig::default());
+
+        let channel_kinds = [Channel1::kind(), Channel2::kind(), Channel3::kind()];
+        let mut ack_trackers = vec![];
+        for (channel_kind, num_messages) in channel_kinds.iter().zip([2, 1, 3]) {
+            ack_trackers.push(
+                client_message_manager
+                    .channels
+                    .get_mut(channel_kind)
+                    .unwrap()
+                    .sender
+                    .subscribe_acks(),
+            );
+            for _ in 0..num_messages {
+                client_message_manager.buffer_send(vec![0].into(), *channel_kind)?;
+            }
+        }
+        let payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(payloads.len(), 1);
+        let grouped_acks = client_message_manager
+            .packet_to_message_ack_map
+            .get(&PacketId(0))
+            .unwrap()
+            .iter()
+            .map(|(channel_kind, acks)| {
+                let mut ids = acks.iter().map(|ack| ack.message_id).collect::<Vec<_>>();
+                ids.sort();
+                (*channel_kind, ids)
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            grouped_acks,
+            HashMap::from([
+                (channel_kinds[0], vec![MessageId(0), MessageId(1)]),
+                (channel_kinds[1], vec![MessageId(0)]),
+                (
+                    channel_kinds[2],
+                    vec![MessageId(0), MessageId(1), MessageId(2)]
+                ),
+            ])
+        );
+
+        // the server acks the packet
+        for payload in payloads {
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        server_message_manager.buffer_send(vec![1].into(), Channel1::kind())?;
+        for payload in server_message_manager.send_packets(Tick(0))? {
+            client_message_manager.recv_packet(payload.into())?;
+        }
+        assert!(client_message_manager.packet_to_message_ack_map.is_empty());
+        for (tracker, num_messages) in ack_trackers.iter().zip([2, 1, 3]) {
+            let mut acked = tracker.try_iter().collect::<Vec<_>>();
+            acked.sort();
+            assert_eq!(acked, (0..num_messages).map(MessageId).collect::<Vec<_>>());
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_notify_ack() -> Result<(), PacketError> {
         let (mut client_message_manager, mut server_message_manager) = setup();
@@ -657,10 +1237,10 @@ mod tests {
                 PacketId(0),
                 vec![(
                     Channel2::kind(),
-                    MessageAck {
+                    vec![MessageAck {
                         message_id,
                         fragment_id: None,
-                    }
+                    }]
                 )]
             )])
         );
@@ -682,4 +1262,638 @@ mod tests {
         assert_eq!(update_acks_tracker.try_recv().unwrap(), message_id);
         Ok(())
     }
+
+    #[test]
+    /// Corrupted packets are detected when the checksum is enabled
+    fn test_message_manager_checksum() -> Result<(), PacketError> {
+        let (client_message_manager, server_message_manager) = setup();
+        let mut client_message_manager = client_message_manager.with_checksum(true);
+        let mut server_message_manager = server_message_manager.with_checksum(true);
+
+        let message: Bytes = vec![0, 1].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let mut payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(payloads.len(), 1);
+        let payload = payloads.pop().unwrap();
+
+        // flip a byte in the packet
+        let mut corrupted_payload = payload.clone();
+        corrupted_payload[12] ^= 1;
+        assert!(matches!(
+            server_message_manager.recv_packet(corrupted_payload.into()),
+            Err(PacketError::Serialization(
+                SerializationError::ChecksumMismatch
+            ))
+        ));
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert!(data.is_empty());
+
+        server_message_manager.recv_packet(payload.into())?;
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_1).unwrap(),
+            &vec![(Tick(0), message)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Both peers build and read packets with the same `WireConfig`
+    fn test_message_manager_wire_config() -> Result<(), PacketError> {
+        const MTU: usize = 300;
+        let wire_config = WireConfig::default()
+            .with_mtu(MTU)?
+            .with_packet_compression(cfg!(feature = "lz4"))
+            .with_checksum(true);
+        let (client_message_manager, server_message_manager) = setup();
+        let mut client_message_manager = client_message_manager.with_wire_config(wire_config)?;
+        let mut server_message_manager = server_message_manager.with_wire_config(wire_config)?;
+        server_message_manager
+            .wire_config()
+            .check_remote(&client_message_manager.wire_config())?;
+
+        let message: Bytes = vec![1; 500].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let payloads = client_message_manager.send_packets(Tick(0))?;
+        assert!(payloads.len() > 1);
+        for payload in payloads {
+            assert!(payload.len() <= MTU);
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_1).unwrap(),
+            &vec![(Tick(0), message)]
+        );
+
+        // a peer that doesn't write checksums is detected
+        let (other_message_manager, _) = setup();
+        assert!(matches!(
+            server_message_manager
+                .wire_config()
+                .check_remote(&other_message_manager.wire_config()),
+            Err(PacketError::WireConfigMismatch { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    /// Padded packets all have the same size, and their messages are read back
+    fn test_message_manager_pad_to() -> Result<(), PacketError> {
+        const MTU: usize = 300;
+        let wire_config = WireConfig::default()
+            .with_mtu(MTU)?
+            .with_packet_compression(cfg!(feature = "lz4"))
+            .with_checksum(true)
+            .with_pad_to(Some(MTU));
+        let (client_message_manager, server_message_manager) = setup();
+        let mut client_message_manager = client_message_manager.with_wire_config(wire_config)?;
+        let mut server_message_manager = server_message_manager.with_wire_config(wire_config)?;
+
+        let big_message: Bytes = vec![1; 700].into();
+        let small_message: Bytes = vec![2; 10].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        client_message_manager.buffer_send(big_message.clone(), channel_kind_1)?;
+        client_message_manager.buffer_send(small_message.clone(), channel_kind_1)?;
+        let payloads = client_message_manager.send_packets(Tick(0))?;
+        assert!(payloads.len() > 2);
+        for payload in payloads {
+            assert_eq!(payload.len(), MTU);
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        // `read_messages` reads one message per channel
+        let mut received = vec![];
+        for _ in 0..2 {
+            let data = MessageManager::collect_messages(server_message_manager.read_messages());
+            received.extend(data.get(&channel_kind_1).unwrap().clone());
+        }
+        received.sort_by_key(|(_, bytes)| bytes.len());
+        assert_eq!(
+            received,
+            vec![(Tick(0), small_message), (Tick(0), big_message)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// The messages of a channel that the receiver doesn't know are skipped, and the rest of the
+    /// packet is still read
+    fn test_message_manager_skip_unknown_channels() -> Result<(), PacketError> {
+        let (mut client_message_manager, _) = setup();
+        let mut server_channel_registry = ChannelRegistry::default();
+        server_channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        let server_message_manager =
+            || MessageManager::new(&server_channel_registry, 1.5, PriorityConfig::default());
+
+        let message: Bytes = vec![1, 2].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        let channel_kind_2 = ChannelKind::of::<Channel2>();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        client_message_manager.buffer_send(message.clone(), channel_kind_2)?;
+        client_message_manager.buffer_send(message.clone(), channel_kind_2)?;
+        let mut payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(payloads.len(), 1);
+        let payload: Bytes = payloads.pop().unwrap().into();
+
+        // by default the packet is rejected
+        assert!(matches!(
+            server_message_manager().recv_packet(payload.clone()),
+            Err(PacketError::ChannelNotFound)
+        ));
+
+        let mut server_message_manager =
+            server_message_manager().with_parse_options(ParseOptions {
+                skip_unknown_channels: true,
+            });
+        server_message_manager.recv_packet(payload)?;
+        let channel_id_2 = *client_message_manager
+            .channel_registry
+            .get_net_from_kind(&channel_kind_2)
+            .unwrap();
+        assert_eq!(server_message_manager.skipped_channels(), &[channel_id_2]);
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data,
+            HashMap::from([(channel_kind_1, vec![(Tick(0), message)])])
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// With a smaller MTU, messages are fragmented earlier, and every packet fits in the MTU
+    fn test_message_manager_custom_mtu() -> Result<(), PacketError> {
+        const MTU: usize = 300;
+        let (client_message_manager, mut server_message_manager) = setup();
+        let mut client_message_manager = client_message_manager.with_mtu(MTU)?;
+        let (mut default_message_manager, _) = setup();
+
+        let message: Bytes = vec![1; 500].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        default_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        assert_eq!(default_message_manager.send_packets(Tick(0))?.len(), 1);
+
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(payloads.len(), 500usize.div_ceil(fragment_size(MTU, 0)));
+        for payload in payloads {
+            assert!(payload.len() <= MTU);
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_1).unwrap(),
+            &vec![(Tick(0), message)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// The MTU can be lowered during the connection, once the fragments built for the previous
+    /// MTU are sent
+    fn test_message_manager_set_mtu() -> Result<(), PacketError> {
+        const MTU: usize = 300;
+        let (mut client_message_manager, mut server_message_manager) = setup();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        client_message_manager.buffer_send(vec![1; 2000].into(), channel_kind_1)?;
+        assert!(matches!(
+            client_message_manager.set_mtu(MTU),
+            Err(PacketError::FragmentsQueued { mtu: MTU })
+        ));
+        assert_eq!(client_message_manager.packet_manager.mtu(), MAX_PACKET_SIZE);
+        client_message_manager.send_packets(Tick(0))?;
+
+        client_message_manager.set_mtu(MTU)?;
+        let message: Bytes = vec![2; 500].into();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let payloads = client_message_manager.send_packets(Tick(1))?;
+        assert!(payloads.len() > 1);
+        for payload in payloads {
+            assert!(payload.len() <= MTU);
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_1).unwrap(),
+            &vec![(Tick(1), message)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// A channel with a smaller fragment MTU splits messages into smaller fragments
+    fn test_message_manager_channel_fragment_mtu() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                fragment_mtu: Some(300),
+                ..default()
+            })
+            .unwrap();
+        let mut client_message_manager =
+            MessageManager::new(&channel_registry, 1.5, PriorityConfig::default());
+        let mut server_message_manager =
+            MessageManager::new(&channel_registry, 1.5, PriorityConfig::default());
+
+        let message: Bytes = vec![1; 2000].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        let channel_kind_2 = ChannelKind::of::<Channel2>();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        assert_eq!(client_message_manager.send_packets(Tick(0))?.len(), 2);
+
+        client_message_manager.buffer_send(message.clone(), channel_kind_2)?;
+        let payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(payloads.len(), 2000usize.div_ceil(fragment_size(300, 0)));
+        for payload in payloads {
+            assert!(payload.len() <= 300);
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_2).unwrap(),
+            &vec![(Tick(0), message)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// With large packets, a big message is written in a single packet without fragmentation
+    fn test_message_manager_large_packets() -> Result<(), PacketError> {
+        const MTU: usize = 300;
+        let (client_message_manager, mut server_message_manager) = setup();
+        let mut client_message_manager = client_message_manager
+            .with_mtu(MTU)?
+            .with_large_packets(true)?;
+
+        let message: Bytes = (0..200_000).map(|i| i as u8).collect::<Vec<_>>().into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let mut payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(payloads.len(), 1);
+
+        server_message_manager.recv_packet(payloads.pop().unwrap().into())?;
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_1).unwrap(),
+            &vec![(Tick(0), message)]
+        );
+
+        // the MTU set before is kept when large packets are disabled
+        let client_message_manager = client_message_manager.with_large_packets(false)?;
+        assert_eq!(client_message_manager.packet_manager.mtu(), MTU);
+        Ok(())
+    }
+
+    #[test]
+    /// The send call returns the messages dropped by the bandwidth quota and by the channels,
+    /// with the reason why
+    fn test_message_manager_drop_report() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::OrderedReliable(default()),
+                ..default()
+            })
+            .unwrap();
+        let channel_id_1 = *channel_registry
+            .get_net_from_kind(&Channel1::kind())
+            .unwrap();
+        let channel_id_2 = *channel_registry
+            .get_net_from_kind(&Channel2::kind())
+            .unwrap();
+        let mut message_manager = MessageManager::new(
+            &channel_registry,
+            1.5,
+            PriorityConfig {
+                bandwidth_quota: Quota::per_second(nonzero!(1000u32)),
+                enabled: true,
+            },
+        );
+
+        // a reliable message that can only be sent during the tick it was buffered
+        let ChannelSender::Reliable(sender) = &mut message_manager
+            .channels
+            .get_mut(&Channel2::kind())
+            .unwrap()
+            .sender
+        else {
+            unreachable!()
+        };
+        let stale = sender
+            .buffer_send_single(
+                SingleData::new(None, vec![0].into()).with_ticks_to_live(0),
+                1.0,
+            )?
+            .unwrap();
+        sender.update_clock(WrappedTime::new(0), Tick(1));
+
+        // only one of the two messages fits in the bandwidth quota
+        message_manager.buffer_send(vec![1; 600].into(), Channel1::kind())?;
+        message_manager.buffer_send(vec![2; 600].into(), Channel1::kind())?;
+        let (payloads, drop_report) = message_manager.send_packets_with_drop_report(Tick(1))?;
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(
+            drop_report.dropped,
+            vec![
+                (channel_id_2, Some(stale), DropReason::Expired),
+                (channel_id_1, None, DropReason::BandwidthQuota),
+            ]
+        );
+
+        // the report only contains the drops of its own call
+        let (_, drop_report) = message_manager.send_packets_with_drop_report(Tick(2))?;
+        assert!(drop_report.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    /// A keep-alive packet acks the received packets, without containing any messages
+    fn test_message_manager_keepalive() -> Result<(), PacketError> {
+        let (mut client_message_manager, mut server_message_manager) = setup();
+        client_message_manager.buffer_send(vec![0].into(), Channel2::kind())?;
+        for payload in client_message_manager.send_packets(Tick(0))? {
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        assert!(!server_message_manager.keepalive_received());
+        MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(client_message_manager.packet_to_message_ack_map.len(), 1);
+
+        // the server has no messages to send
+        let payload = server_message_manager.send_keepalive(Tick(1))?;
+        assert_eq!(client_message_manager.recv_packet(payload.into())?, Tick(1));
+        assert!(client_message_manager.keepalive_received());
+        assert!(client_message_manager.packet_to_message_ack_map.is_empty());
+        let data = MessageManager::collect_messages(client_message_manager.read_messages());
+        assert!(data.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    /// A packet duplicated by the network is dropped, so that the messages on unreliable
+    /// channels are not received twice
+    fn test_message_manager_duplicate_packet() -> Result<(), PacketError> {
+        let (mut client_message_manager, mut server_message_manager) = setup();
+        let message: Bytes = vec![0, 1].into();
+        client_message_manager.buffer_send(message.clone(), Channel1::kind())?;
+        let payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(payloads.len(), 1);
+        for _ in 0..2 {
+            server_message_manager.recv_packet(payloads[0].clone().into())?;
+        }
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&Channel1::kind()).unwrap(),
+            &vec![(Tick(0), message)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// A packet with a packet type from a newer version is skipped instead of failing
+    fn test_message_manager_unknown_packet_type() -> Result<(), PacketError> {
+        let (mut client_message_manager, mut server_message_manager) = setup();
+        let message: Bytes = vec![0, 1].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let mut payload = client_message_manager.send_packets(Tick(3))?.pop().unwrap();
+        // a packet type that fits in the packet type bits, but that this version doesn't know
+        payload[0] = 5;
+
+        assert_eq!(
+            server_message_manager.recv_packet(payload.clone().into())?,
+            Tick(3)
+        );
+        assert_eq!(
+            server_message_manager.unknown_packet(),
+            Some(&UnknownPacket {
+                packet_type: 5,
+                raw: Bytes::copy_from_slice(&payload[11..]),
+            })
+        );
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert!(data.is_empty());
+
+        // the next packet is read normally
+        client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+        let payload = client_message_manager.send_packets(Tick(4))?.pop().unwrap();
+        server_message_manager.recv_packet(payload.into())?;
+        assert_eq!(server_message_manager.unknown_packet(), None);
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data.get(&channel_kind_1).unwrap(),
+            &vec![(Tick(4), message)]
+        );
+        Ok(())
+    }
+
+    /// The handshake packet lets the receiver check that both peers registered the same channels
+    #[test]
+    fn test_message_manager_handshake() -> Result<(), PacketError> {
+        let (mut client_message_manager, mut server_message_manager) = setup();
+        let payload = client_message_manager.send_handshake(Tick(0))?;
+        assert_eq!(server_message_manager.recv_packet(payload.into())?, Tick(0));
+
+        // the receiver registered Channel2 with a different mode
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::OrderedReliable(default()),
+                ..default()
+            })
+            .unwrap();
+        let mut server_message_manager =
+            MessageManager::new(&channel_registry, 1.5, PriorityConfig::default());
+        let payload = client_message_manager.send_handshake(Tick(1))?;
+        let Err(PacketError::ChannelSchemaMismatch {
+            channel_id,
+            local,
+            remote,
+        }) = server_message_manager.recv_packet(payload.into())
+        else {
+            panic!("expected a channel schema mismatch");
+        };
+        assert_eq!(channel_id, 1);
+        assert_eq!(
+            local.unwrap().mode,
+            ChannelMode::OrderedReliable(default()).kind_id()
+        );
+        assert_eq!(
+            remote.unwrap().mode,
+            ChannelMode::UnorderedUnreliableWithAcks.kind_id()
+        );
+        Ok(())
+    }
+
+    /// With incremental packets, the messages sent during a tick share a packet, which is sent by
+    /// the first call for a later tick
+    #[test]
+    fn test_message_manager_incremental_packets() -> Result<(), PacketError> {
+        let (client_message_manager, mut server_message_manager) = setup();
+        let mut client_message_manager = client_message_manager.with_incremental_packets(true);
+        client_message_manager.buffer_send(vec![0].into(), Channel2::kind())?;
+        assert!(client_message_manager.send_packets(Tick(0))?.is_empty());
+        client_message_manager.buffer_send(vec![1].into(), Channel2::kind())?;
+        assert!(client_message_manager.send_packets(Tick(0))?.is_empty());
+
+        let payloads = client_message_manager.send_packets(Tick(1))?;
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(client_message_manager.packet_to_message_ack_map.len(), 1);
+        for payload in payloads {
+            assert_eq!(server_message_manager.recv_packet(payload.into())?, Tick(0));
+        }
+        // (read_messages only reads one message per channel)
+        let data: Vec<_> =
+            std::iter::from_fn(|| server_message_manager.read_messages().next()).collect();
+        assert_eq!(data.len(), 2);
+
+        // the packet can also be sent right away
+        client_message_manager.buffer_send(vec![2].into(), Channel2::kind())?;
+        assert!(client_message_manager.send_packets(Tick(1))?.is_empty());
+        assert_eq!(client_message_manager.flush_packets()?.len(), 1);
+        assert!(client_message_manager.flush_packets()?.is_empty());
+        assert_eq!(client_message_manager.packet_to_message_ack_map.len(), 2);
+        Ok(())
+    }
+
+    /// With `with_handshake`, the first packets start with the handshake packet, which is only
+    /// sent once
+    #[test]
+    fn test_message_manager_with_handshake() -> Result<(), PacketError> {
+        let (client_message_manager, mut server_message_manager) = setup();
+        let mut client_message_manager = client_message_manager.with_handshake(true);
+        client_message_manager.buffer_send(vec![0].into(), Channel2::kind())?;
+        let payloads = client_message_manager.send_packets(Tick(0))?;
+        assert_eq!(
+            payloads
+                .iter()
+                .map(|payload| PacketType::from(payload[0]))
+                .collect::<Vec<_>>(),
+            vec![PacketType::Handshake, PacketType::Data]
+        );
+        for payload in payloads {
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(data.get(&Channel2::kind()).map(Vec::len), Some(1));
+
+        client_message_manager.buffer_send(vec![1].into(), Channel2::kind())?;
+        let payloads = client_message_manager.send_packets(Tick(1))?;
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(PacketType::from(payloads[0][0]), PacketType::Data);
+        Ok(())
+    }
+
+    /// The ack packets can be sent before the data packets
+    #[test]
+    fn test_message_manager_ack_packets_first() -> Result<(), PacketError> {
+        let (mut client_message_manager, server_message_manager) = setup();
+        let mut server_message_manager = server_message_manager.with_ack_packets(true);
+        let message: Bytes = vec![0, 1].into();
+        let channel_kind_1 = ChannelKind::of::<Channel1>();
+        // receive more packets than can be acked in a single header
+        let mut receive_packets = |server_message_manager: &mut MessageManager| {
+            for _ in 0..40 {
+                client_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+                for payload in client_message_manager.send_packets(Tick(0))? {
+                    server_message_manager.recv_packet(payload.into())?;
+                }
+            }
+            server_message_manager.buffer_send(message.clone(), channel_kind_1)?;
+            server_message_manager.send_packets(Tick(0))
+        };
+        let packet_types = |payloads: &Vec<Payload>| {
+            payloads
+                .iter()
+                .map(|payload| PacketType::from(payload[0]))
+                .collect::<Vec<_>>()
+        };
+
+        let payloads = receive_packets(&mut server_message_manager)?;
+        assert_eq!(
+            packet_types(&payloads),
+            vec![PacketType::Data, PacketType::Ack]
+        );
+
+        let mut server_message_manager = server_message_manager.with_ack_packets_first(true);
+        let payloads = receive_packets(&mut server_message_manager)?;
+        assert_eq!(
+            packet_types(&payloads),
+            vec![PacketType::Ack, PacketType::Data]
+        );
+        Ok(())
+    }
+
+    /// Channel registered with a const generic, so that we can register many channels
+    struct ManyChannel<const A: u8, const B: u8>;
+
+    impl<const A: u8, const B: u8> Channel for ManyChannel<A, B> {
+        fn name() -> &'static str {
+            "ManyChannel"
+        }
+    }
+
+    macro_rules! add_many_channels {
+        ($registry:ident; $($a:literal)*) => {
+            $(add_many_channels!(@row $registry; $a; 0 1 2 3 4 5 6 7 8 9);)*
+        };
+        (@row $registry:ident; $a:literal; $($b:literal)*) => {
+            $($registry.add_channel::<ManyChannel<$a, $b>>(ChannelSettings::default()).unwrap();)*
+        };
+    }
+
+    #[test]
+    /// Channel ids that don't fit in a u8 are serialized correctly
+    fn test_message_manager_many_channels() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        add_many_channels!(channel_registry;
+            0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29
+        );
+        let channel_kind = ChannelKind::of::<ManyChannel<29, 9>>();
+        assert_eq!(
+            channel_registry.get_net_from_kind(&channel_kind),
+            Some(&299)
+        );
+
+        let mut client_message_manager =
+            MessageManager::new(&channel_registry, 1.5, PriorityConfig::default());
+        let mut server_message_manager =
+            MessageManager::new(&channel_registry, 1.5, PriorityConfig::default());
+
+        let message: Bytes = vec![0, 1].into();
+        client_message_manager.buffer_send(message.clone(), channel_kind)?;
+        for payload in client_message_manager.send_packets(Tick(0))? {
+            server_message_manager.recv_packet(payload.into())?;
+        }
+        let data = MessageManager::collect_messages(server_message_manager.read_messages());
+        assert_eq!(
+            data,
+            HashMap::from([(channel_kind, vec![(Tick(0), message)])])
+        );
+        Ok(())
+    }
 }
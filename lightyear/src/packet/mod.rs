@@ -21,6 +21,16 @@ Packets that are over the maximum packet size will be fragmented into multiple [
 
 /// Manages the [`PacketHeader`](header::PacketHeader) which includes important packet information
 pub(crate) mod header;
+pub use header::PacketHeader;
+
+/// Record the packets that are sent, to replay them later
+pub(crate) mod capture;
+
+/// Dictionary to write frequently-used channel ids in fewer bytes
+pub(crate) mod channel_dictionary;
+
+/// Encrypt the messages of some channels before they are written in a packet
+pub(crate) mod encryption;
 
 pub(crate) mod message;
 
@@ -28,11 +38,28 @@ pub(crate) mod message;
 pub mod message_manager;
 
 pub mod packet;
+pub mod packing;
 
 pub(crate) mod error;
 /// Manages building a single [`Packet`](packet::Packet) from multiple [`Messages`](message::Message)
 pub(crate) mod packet_builder;
+pub use packet_builder::{suggest_mtu, PacketFinishReason, FINISH_PACKET_TRACE_TARGET};
+/// Parse many [`Packets`](packet::Packet) in a row without reallocating
+pub(crate) mod packet_parser;
+/// Read the messages of a [`Packet`](packet::Packet) without copying them
+pub(crate) mod packet_reader;
 /// Defines the [`PacketType`](packet_type::PacketType) enum
 mod packet_type;
+pub use packet_type::PacketType;
 pub(crate) mod priority_manager;
+pub use priority_manager::{DropReason, DropReport};
 pub(crate) mod stats_manager;
+/// Split a stream of bytes back into [`Packets`](packet::Packet)
+pub(crate) mod stream_reader;
+/// Canonical [`Packets`](packet::Packet) for fixed inputs, to check other implementations of the wire format
+#[cfg(feature = "test_vectors")]
+pub mod test_vectors;
+/// Send and receive [`Packets`](packet::Packet) over a UDP socket, for the tests and tools of the crate
+pub(crate) mod udp_transport;
+/// Options that both peers must agree on to read each other's packets
+pub mod wire_config;
@@ -1,67 +1,578 @@
 /// Defines the [`Packet`] struct
+use std::fmt::Write;
+
+use byteorder::ReadBytesExt;
+use bytes::Bytes;
+
 use crate::connection::netcode::MAX_PACKET_SIZE;
-use crate::packet::message::MessageAck;
+use crate::packet::channel_dictionary::ChannelIdDictionary;
+use crate::packet::encryption::{ChannelDecryption, ENCRYPTED_SECTION_MARKER};
+use crate::packet::header::PacketHeader;
+use crate::packet::message::{FragmentData, FragmentIndex, MessageAck, SingleData};
 use crate::packet::packet_builder::Payload;
-use crate::protocol::channel::ChannelId;
-use crate::serialize::ToBytes;
-use crate::utils::wrapping_id::wrapping_id;
+use crate::packet::packet_reader::{ChannelBlocks, PacketReader};
+use crate::packet::packet_type::PacketType;
+use crate::packet::packing::{PacketSink, PackingBudget};
+use crate::protocol::channel::{ChannelId, ChannelRegistry};
+use crate::serialize::reader::Reader;
+use crate::serialize::varint::{read_varint, varint_len, VarIntReadExt, VarIntWriteExt};
+use crate::serialize::{SerializationError, ToBytes};
+use crate::shared::tick_manager::Tick;
 
 cfg_if::cfg_if!(
     if #[cfg(test)] {
-        use bytes::Bytes;
-        use crate::serialize::varint::VarIntReadExt;
         use crate::prelude::PacketError;
-        use crate::packet::header::PacketHeader;
-        use crate::packet::packet_type::PacketType;
-        use crate::packet::message::{SingleData, FragmentData};
         use bevy::utils::HashMap;
     }
 );
 
-// Internal id that we assign to each packet sent over the network
-wrapping_id!(PacketId);
+/// Internal id that we assign to each packet sent over the network.
+///
+/// The id is kept on 32 bits even if the headers only carry its low 16 bits
+/// (see [`PacketIdWidth`](crate::packet::header::PacketIdWidth)), and ids are compared with a
+/// 32-bit wrapping distance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PacketId(pub u32);
+
+impl PacketId {
+    /// Returns the id that comes `rhs` steps after this one, wrapping around at `u32::MAX`
+    pub fn wrapping_add(self, rhs: u32) -> Self {
+        Self(self.0.wrapping_add(rhs))
+    }
+
+    /// Returns the id that comes `rhs` steps before this one, wrapping around at 0
+    pub fn wrapping_sub(self, rhs: u32) -> Self {
+        Self(self.0.wrapping_sub(rhs))
+    }
+
+    /// Signed number of steps from `other` to `self`: positive if `self` is more recent
+    pub fn diff(self, other: Self) -> i32 {
+        self.0.wrapping_sub(other.0) as i32
+    }
+}
+
+impl Ord for PacketId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.diff(*other).cmp(&0)
+    }
+}
+
+impl PartialOrd for PacketId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-/// Number of bytes to write the header
-const HEADER_BYTES: usize = 11;
+/// Number of bytes to write the header, with the default ack window of 32 bits
+pub(crate) const HEADER_BYTES: usize = 11;
 
-/// The maximum number of bytes for a message before it is fragmented
-/// MAX_PACKET_SIZE - HEADER_BYTES - 1 (channel_net_id) - 6 (message_id/fragment_id/num_fragments) - 2 (num bytes in fragment)
+/// Number of bytes to write the header with the widest ack window (128 bits), 32-bit packet ids,
+/// a user packet type and a compression dictionary id
+pub(crate) const MAX_HEADER_BYTES: usize = HEADER_BYTES + 19;
+
+/// Number of bytes of the optional checksum written at the end of the packet
+pub(crate) const CHECKSUM_BYTES: usize = 4;
+
+/// Number of bytes written at the start of each transport fragment (see
+/// [`Packet::into_transport_fragments`]): the index of the fragment, the number of fragments
+/// and the id of the packet
+pub(crate) const TRANSPORT_FRAGMENT_HEADER_BYTES: usize = 2 + size_of::<u32>();
+
+/// Number of bytes written in a packet for a fragment on top of the fragment's data and of its
+/// offset in the message (a varint, see [`FragmentSender`](crate::channel::senders::fragment_sender::FragmentSender)):
+/// 1 (channel_net_id) + 1 (number of fragments in the packet)
+///
+/// Since a packet can hold fragments of several messages, `DataFragment` packets start with the
+/// number of fragments of the channel (a varint, like for the other packets), which makes them
+/// unreadable by peers running a version that expects a single fragment per packet.
+/// + 4 (message_id/fragment_id/num_fragments, 6 with `big_messages`)
+/// + 2 (num bytes in fragment)
 #[cfg(feature = "big_messages")]
-pub(crate) const FRAGMENT_SIZE: usize = MAX_PACKET_SIZE - HEADER_BYTES - 9;
+const FRAGMENT_OVERHEAD_BYTES: usize = 10;
 
 #[cfg(not(feature = "big_messages"))]
-pub(crate) const FRAGMENT_SIZE: usize = MAX_PACKET_SIZE - HEADER_BYTES - 7;
+const FRAGMENT_OVERHEAD_BYTES: usize = 8;
+
+/// Maximum number of bytes of the varint offset of a fragment in its message
+const MAX_FRAGMENT_OFFSET_BYTES: usize = 4;
+
+/// The maximum number of bytes for a message before it is fragmented, for packets of at most `mtu`
+/// bytes in which `reserved_bytes` are kept for the checksum and the padding
+/// (see [`PacketBuilder::reserved_len`](crate::packet::packet_builder::PacketBuilder::reserved_len))
+/// (0 if the packets are smaller than [`min_mtu`])
+pub(crate) const fn fragment_size(mtu: usize, reserved_bytes: usize) -> usize {
+    mtu.saturating_sub(HEADER_BYTES + reserved_bytes + FRAGMENT_OVERHEAD_BYTES)
+}
+
+/// Smallest packet size that can hold a fragment (of 1 byte), when `reserved_bytes` are kept in
+/// each packet for the checksum and the padding
+pub(crate) const fn min_mtu(reserved_bytes: usize) -> usize {
+    HEADER_BYTES + reserved_bytes + FRAGMENT_OVERHEAD_BYTES + MAX_FRAGMENT_OFFSET_BYTES + 1
+}
+
+/// Smallest packet size that can hold a fragment (of 1 byte), even with the checksum
+pub(crate) const MIN_FRAGMENT_MTU: usize = min_mtu(CHECKSUM_BYTES);
+
+/// The maximum number of bytes for a message before it is fragmented, with the default MTU
+pub(crate) const FRAGMENT_SIZE: usize = fragment_size(MAX_PACKET_SIZE, 0);
+
+/// Minimum number of bytes of a single message in a packet: 1 (message id flag) + 1 (num bytes)
+pub(crate) const MIN_SINGLE_DATA_BYTES: usize = 2;
+
+/// Default maximum number of messages (single messages and fragments) in a packet
+pub(crate) const DEFAULT_MAX_MESSAGES_PER_PACKET: usize = 1024;
+
+/// Read the number of single messages written for a channel, checking that they can
+/// fit in the rest of the packet (the count comes from the remote peer and cannot be trusted)
+///
+/// `num_read` is the number of messages already read in the packet: the packet cannot
+/// contain more than `max_messages` messages in total.
+///
+/// A channel is only written if it has messages, so a count of 0 is invalid.
+pub(crate) fn read_num_messages(
+    cursor: &mut Reader,
+    num_read: u64,
+    max_messages: usize,
+) -> Result<u64, SerializationError> {
+    let count = cursor.read_varint()?;
+    if count == 0 {
+        return Err(SerializationError::InvalidValue);
+    }
+    let claimed = num_read.saturating_add(count);
+    if claimed > max_messages as u64 {
+        return Err(SerializationError::TooManyMessages {
+            claimed,
+            max: max_messages,
+        });
+    }
+    let remaining = cursor.remaining();
+    if count > (remaining / MIN_SINGLE_DATA_BYTES) as u64 {
+        return Err(SerializationError::CountExceedsBuffer { count, remaining });
+    }
+    Ok(count)
+}
+
+/// Bytes shared by the sender and the receiver to compress the packets better than each packet
+/// on its own: the compressor can refer to the dictionary as if it preceded every packet.
+///
+/// It should contain content that looks like the packets, for example a few packets of a
+/// channel that sends similar states over and over. Both peers must use the same dictionary;
+/// its id is written in the header of the packets compressed with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionDictionary {
+    id: u8,
+    bytes: Bytes,
+}
+
+impl CompressionDictionary {
+    /// Only the last bytes of the dictionary (up to 64KB) can be referred to by the compressor
+    pub const MAX_BYTES: usize = 64 * 1024;
+
+    pub fn new(id: u8, bytes: impl Into<Bytes>) -> Self {
+        let mut bytes = bytes.into();
+        if bytes.len() > Self::MAX_BYTES {
+            bytes = bytes.slice(bytes.len() - Self::MAX_BYTES..);
+        }
+        Self { id, bytes }
+    }
+
+    /// Build a dictionary from sample payloads, the most representative last
+    pub fn from_samples<'a>(id: u8, samples: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        let bytes = samples.into_iter().flatten().copied().collect::<Vec<_>>();
+        Self::new(id, bytes)
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+/// Compress the part of the packet after the header (with the `dictionary`, if any), if it makes
+/// the packet smaller.
+///
+/// Returns true if the packet was compressed.
+#[cfg(feature = "lz4")]
+pub(crate) fn compress_packet(
+    packet: &mut Payload,
+    dictionary: Option<&CompressionDictionary>,
+) -> bool {
+    let header_len = Packet::peek_header(packet)
+        .expect("a packet always starts with its header")
+        .len();
+    let compressed = match dictionary {
+        Some(dictionary) => lz4_flex::block::compress_prepend_size_with_dict(
+            &packet[header_len..],
+            &dictionary.bytes,
+        ),
+        None => lz4_flex::compress_prepend_size(&packet[header_len..]),
+    };
+    let dictionary_len = usize::from(dictionary.is_some());
+    if header_len + dictionary_len + compressed.len() >= packet.len() {
+        return false;
+    }
+    packet.truncate(header_len);
+    if let Some(dictionary) = dictionary {
+        // the id of the dictionary is the last byte of the header
+        packet.push(dictionary.id);
+        PacketHeader::set_dictionary_flag(packet);
+    }
+    packet.extend_from_slice(&compressed);
+    PacketHeader::set_compressed_flag(packet);
+    true
+}
+
+/// Decompress the rest of the packet if the header indicates that it is compressed.
+///
+/// The decompressed size announced by the packet is checked against `max_len` (the MTU of the
+/// packets) before anything is allocated, so a peer cannot make us allocate more than a packet.
+/// Returns an error if the packet was compressed with a dictionary other than `dictionary`.
+pub(crate) fn decompress_packet(
+    header: &PacketHeader,
+    mut cursor: Reader,
+    dictionary: Option<&CompressionDictionary>,
+    max_len: usize,
+) -> Result<Reader, SerializationError> {
+    if !header.compressed {
+        return Ok(cursor);
+    }
+    let dictionary = match header.compression_dictionary {
+        Some(id) => Some(
+            dictionary
+                .filter(|dictionary| dictionary.id == id)
+                .ok_or(SerializationError::UnknownCompressionDictionary { id })?,
+        ),
+        None => None,
+    };
+    let compressed = cursor.split_len(cursor.remaining());
+    #[cfg(feature = "lz4")]
+    {
+        // the compressed bytes start with the decompressed size, as a little-endian u32
+        let (len, compressed) = lz4_flex::block::uncompressed_size(&compressed)
+            .map_err(|_| SerializationError::InvalidValue)?;
+        if len > max_len {
+            return Err(SerializationError::DecompressedPacketTooLarge { len, max: max_len });
+        }
+        let mut decompressed = vec![0; len];
+        let decompressed_len = match dictionary {
+            Some(dictionary) => lz4_flex::block::decompress_into_with_dict(
+                compressed,
+                &mut decompressed,
+                &dictionary.bytes,
+            ),
+            None => lz4_flex::block::decompress_into(compressed, &mut decompressed),
+        }
+        .map_err(|_| SerializationError::InvalidValue)?;
+        if decompressed_len != len {
+            return Err(SerializationError::InvalidValue);
+        }
+        Ok(Reader::from(decompressed))
+    }
+    // we cannot decompress the packet without the lz4 feature
+    #[cfg(not(feature = "lz4"))]
+    {
+        let _ = (compressed, dictionary, max_len);
+        Err(SerializationError::CompressionUnsupported)
+    }
+}
+
+/// Returns true if the last [`CHECKSUM_BYTES`] bytes of the packet are the checksum of the rest of the packet
+pub(crate) fn verify_checksum(bytes: &[u8]) -> bool {
+    let Some(len) = bytes.len().checked_sub(CHECKSUM_BYTES) else {
+        return false;
+    };
+    let (payload, checksum) = bytes.split_at(len);
+    crc32fast::hash(payload).to_be_bytes() == checksum
+}
+
+/// Write the length of the content of the packet right after its header, then fill the packet
+/// with zeros until it is `len` bytes long (if it is not already longer)
+pub(crate) fn pad_packet(packet: &mut Payload, len: usize) {
+    let header_len = Packet::peek_header(packet)
+        .expect("a packet always starts with its header")
+        .len();
+    let content_len = packet.len() - header_len;
+    let mut content_len_bytes = Vec::with_capacity(varint_len(content_len as u64));
+    content_len_bytes
+        .write_varint(content_len as u64)
+        .expect("writing to a vec cannot fail");
+    packet.splice(header_len..header_len, content_len_bytes);
+    if packet.len() < len {
+        packet.resize(len, 0);
+    }
+}
+
+/// Read the content length written by [`pad_packet`] at the start of `cursor` (right after the
+/// header), and return the content without the padding
+pub(crate) fn strip_padding(mut cursor: Reader) -> Result<Reader, SerializationError> {
+    let len = cursor.read_varint()? as usize;
+    let remaining = cursor.remaining();
+    if len > remaining {
+        return Err(SerializationError::TruncatedMessage { len, remaining });
+    }
+    Ok(Reader::from(cursor.split_len(len)))
+}
+
+/// Rebuild the bytes of a packet from all of its transport fragments (see
+/// [`Packet::into_transport_fragments`]), which can be given in any order.
+///
+/// Returns an error if a fragment is truncated, if the fragments belong to different packets,
+/// or if a fragment is missing or duplicated.
+pub(crate) fn reassemble_transport_fragments(
+    fragments: impl IntoIterator<Item = Bytes>,
+) -> Result<Payload, SerializationError> {
+    let mut packet_id = None;
+    let mut parts: Vec<Option<Bytes>> = vec![];
+    for mut fragment in fragments {
+        let remaining = fragment.len();
+        if remaining < TRANSPORT_FRAGMENT_HEADER_BYTES {
+            return Err(SerializationError::TruncatedMessage {
+                len: TRANSPORT_FRAGMENT_HEADER_BYTES,
+                remaining,
+            });
+        }
+        let header = fragment.split_to(TRANSPORT_FRAGMENT_HEADER_BYTES);
+        let (index, total) = (header[0] as usize, header[1] as usize);
+        let id = u32::from_be_bytes(header[2..].try_into().unwrap());
+        if *packet_id.get_or_insert(id) != id {
+            return Err(SerializationError::InvalidValue);
+        }
+        if parts.is_empty() {
+            parts.resize(total, None);
+        }
+        if total != parts.len() {
+            return Err(SerializationError::InvalidValue);
+        }
+        match parts.get_mut(index) {
+            Some(part @ None) => *part = Some(fragment),
+            _ => return Err(SerializationError::InvalidValue),
+        }
+    }
+    if parts.is_empty() {
+        return Err(SerializationError::InvalidValue);
+    }
+    let mut payload = Payload::new();
+    for part in parts {
+        payload.extend_from_slice(&part.ok_or(SerializationError::InvalidValue)?);
+    }
+    Ok(payload)
+}
+
+/// Bytes that a [`PacketBuilder`] adds around the content of the packets it finishes, needed to
+/// edit a finished packet in place (see [`Packet::set_tick`])
+///
+/// [`PacketBuilder`]: crate::packet::packet_builder::PacketBuilder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct PacketFraming {
+    /// The packets end with a checksum
+    pub(crate) checksum: bool,
+    /// The packets start with their length
+    pub(crate) stream_framing: bool,
+}
 
 /// Data structure that will help us write the packet
 #[derive(Debug)]
-pub(crate) struct Packet {
+pub struct Packet {
     pub(crate) payload: Payload,
     /// Content of the packet so we can map from channel id to message ids
     pub(crate) message_acks: Vec<(ChannelId, MessageAck)>,
     pub(crate) packet_id: PacketId,
+    /// Maximum number of bytes in the packet
+    pub(crate) mtu: usize,
     // How many bytes we know we are going to have to write in the packet, but haven't written yet
     pub(crate) prewritten_size: usize,
+    /// Number of messages (single messages and fragments) written in the packet
+    pub(crate) written_messages: usize,
+}
+
+impl PacketSink for Vec<u8> {
+    fn written_len(&self) -> usize {
+        self.len()
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> bool {
+        self.extend_from_slice(bytes);
+        true
+    }
 }
 
 impl Packet {
+    fn budget(&self) -> PackingBudget {
+        PackingBudget {
+            capacity: self.mtu,
+            reserved: self.prewritten_size,
+        }
+    }
+
     /// Check that we can still fit some data in the buffer
     pub(crate) fn can_fit(&self, size: usize) -> bool {
-        self.payload.len() + size + self.prewritten_size <= MAX_PACKET_SIZE
+        self.budget().can_fit(&self.payload, size)
+    }
+
+    /// Number of bytes that can still be written in the packet, once the bytes that are
+    /// already reserved (checksum, channel ids and message counts, etc.) are written
+    pub(crate) fn remaining_bytes(&self) -> usize {
+        self.budget().remaining_bytes(&self.payload)
+    }
+
+    /// Number of bytes left unused in the packet, for a packet of at most `mtu` bytes
+    pub(crate) fn wasted_bytes(&self, mtu: usize) -> usize {
+        mtu.saturating_sub(self.payload.len())
     }
 
-    /// Check if we can write a channel_id + the number of messages in the packet.
+    /// Check if we can write a channel_id (that takes `channel_id_len` bytes) + the number of messages in the packet.
     /// If we can, reserve some space for it
-    pub(crate) fn can_fit_channel(&mut self, channel_id: ChannelId) -> bool {
-        let size = channel_id.len() + 1;
-        // size of the channel + 1 for the number of messages
-        let can_fit = self.can_fit(channel_id.len() + 1);
-        if can_fit {
-            // reserve the space to write the channel
-            self.prewritten_size += size;
-        }
+    pub(crate) fn can_fit_channel(&mut self, channel_id_len: usize) -> bool {
+        let mut budget = self.budget();
+        let can_fit = budget.can_fit_channel(&self.payload, channel_id_len);
+        self.prewritten_size = budget.reserved;
         can_fit
     }
 
+    /// Write the checksum of the payload at the end of the packet
+    pub(crate) fn write_checksum(&mut self) {
+        let checksum = crc32fast::hash(&self.payload);
+        self.payload.extend_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// Returns true if the packet ends with a valid checksum of its payload
+    pub(crate) fn verify_checksum(&self) -> bool {
+        verify_checksum(&self.payload)
+    }
+
+    /// Group the [`MessageAck`]s of the packet by channel, keeping the order in which the channels
+    /// were written in the packet
+    pub(crate) fn grouped_acks(&self) -> Vec<(ChannelId, Vec<MessageAck>)> {
+        let mut grouped_acks: Vec<(ChannelId, Vec<MessageAck>)> = vec![];
+        for (channel_id, message_ack) in self.message_acks.iter() {
+            match grouped_acks.iter_mut().find(|(id, _)| id == channel_id) {
+                Some((_, acks)) => acks.push(*message_ack),
+                None => grouped_acks.push((*channel_id, vec![*message_ack])),
+            }
+        }
+        grouped_acks
+    }
+
+    /// Move the [`MessageAck`]s of the packet to the end of `out`, so that the caller can reuse the
+    /// same vector for every packet instead of allocating a new one.
+    ///
+    /// The acks of the packet are left empty, but keep their allocation so that it can be recycled.
+    pub(crate) fn extract_acks_into(&mut self, out: &mut Vec<(ChannelId, MessageAck)>) {
+        out.append(&mut self.message_acks);
+    }
+
+    /// Iterate over the channel blocks of a data packet without parsing their messages, for
+    /// example to only read the channels that a receiver handles (see [`ChannelBlocks`]).
+    ///
+    /// The payload must not end with a checksum.
+    pub(crate) fn channel_blocks<'a>(
+        &'a self,
+        channel_dictionary: &'a ChannelIdDictionary,
+    ) -> Result<ChannelBlocks<'a>, SerializationError> {
+        Ok(PacketReader::new(&self.payload, channel_dictionary)?.channel_blocks())
+    }
+
+    /// Parse only the header at the start of `payload`, without reading the messages
+    /// (for example to route a received packet depending on its type)
+    pub fn peek_header(payload: &[u8]) -> Result<PacketHeader, SerializationError> {
+        let header_bytes = Bytes::copy_from_slice(&payload[..payload.len().min(MAX_HEADER_BYTES)]);
+        PacketHeader::from_bytes(&mut Reader::from(header_bytes))
+    }
+
+    /// Remove the padding of a packet built with `pad_to` (see [`pad_packet`])
+    #[cfg(test)]
+    pub(crate) fn without_padding(mut self) -> Result<Self, SerializationError> {
+        let header_len = Self::peek_header(&self.payload)?.len();
+        let padded = Reader::from(self.payload.split_off(header_len));
+        self.payload
+            .extend_from_slice(&strip_padding(padded)?.consume());
+        Ok(self)
+    }
+
+    /// Split the bytes of the finished packet into fragments of at most `transport_mtu` bytes,
+    /// for transports that cannot send packets as big as the ones that were built.
+    ///
+    /// This is unrelated to the fragmentation of big messages: the packet is split as raw bytes,
+    /// and each fragment starts with a [`TRANSPORT_FRAGMENT_HEADER_BYTES`]-byte header so that
+    /// the receiver can rebuild it with [`reassemble_transport_fragments`].
+    pub(crate) fn into_transport_fragments(
+        self,
+        transport_mtu: usize,
+    ) -> Result<Vec<Bytes>, SerializationError> {
+        let Some(chunk_len) = transport_mtu
+            .checked_sub(TRANSPORT_FRAGMENT_HEADER_BYTES)
+            .filter(|len| *len > 0)
+        else {
+            return Err(SerializationError::InvalidValue);
+        };
+        let total = self.payload.len().div_ceil(chunk_len).max(1);
+        let total = u8::try_from(total).map_err(|_| SerializationError::InvalidValue)?;
+        let mut chunks = self.payload.chunks(chunk_len);
+        Ok((0..total)
+            .map(|index| {
+                let chunk = chunks.next().unwrap_or_default();
+                let mut fragment =
+                    Vec::with_capacity(TRANSPORT_FRAGMENT_HEADER_BYTES + chunk.len());
+                fragment.extend_from_slice(&[index, total]);
+                fragment.extend_from_slice(&self.packet_id.0.to_be_bytes());
+                fragment.extend_from_slice(chunk);
+                Bytes::from(fragment)
+            })
+            .collect())
+    }
+
+    /// Type of the packet, as written in its header
+    ///
+    /// Returns an error if the payload does not start with a valid header.
+    pub fn packet_type(&self) -> Result<PacketType, SerializationError> {
+        Ok(Self::peek_header(&self.payload)?.get_packet_type())
+    }
+
+    /// Returns true if the packet starts with a fragment of a message
+    ///
+    /// Returns an error if the payload does not start with a valid header.
+    pub fn is_fragment(&self) -> Result<bool, SerializationError> {
+        Ok(self.packet_type()? == PacketType::DataFragment)
+    }
+
+    /// Id of the packet, from the sender's perspective
+    pub fn packet_id(&self) -> PacketId {
+        self.packet_id
+    }
+
+    /// Tick at which the packet was built (the `current_tick` passed to the [`PacketBuilder`])
+    ///
+    /// Returns an error if the payload does not start with a valid header.
+    ///
+    /// [`PacketBuilder`]: crate::packet::packet_builder::PacketBuilder
+    pub fn tick(&self) -> Result<Tick, SerializationError> {
+        Ok(Self::peek_header(&self.payload)?.tick)
+    }
+
+    /// Overwrite the tick written in the header of the finished packet, without touching the
+    /// rest of the payload (for example to account for the delay before the packet is sent).
+    ///
+    /// `framing` is the framing of the [`PacketBuilder`] that built the packet: if the packets
+    /// end with a checksum, the checksum is updated. Packets built with stream framing start with
+    /// their length instead of the header, and return an error.
+    ///
+    /// [`PacketBuilder`]: crate::packet::packet_builder::PacketBuilder
+    pub(crate) fn set_tick(
+        &mut self,
+        tick: Tick,
+        framing: PacketFraming,
+    ) -> Result<(), SerializationError> {
+        if framing.stream_framing {
+            return Err(SerializationError::StreamFramedPacket);
+        }
+        let offset = Self::peek_header(&self.payload)?.tick_offset();
+        self.payload[offset..offset + 2].copy_from_slice(&tick.0.to_be_bytes());
+        if framing.checksum {
+            self.payload.truncate(self.payload.len() - CHECKSUM_BYTES);
+            self.write_checksum();
+        }
+        Ok(())
+    }
+
     pub(crate) fn num_messages(&self) -> usize {
         self.message_acks.len()
     }
@@ -70,6 +581,152 @@ impl Packet {
         self.message_acks.is_empty()
     }
 
+    /// Ids of the channels that have messages in the packet, in the order in which they are
+    /// written. Only the framing is read: the messages are skipped using their length, so this is
+    /// cheaper than parsing the packet.
+    ///
+    /// The packet must have been written without checksum, padding, compression or channel
+    /// dictionary. Packets without messages have no channels.
+    pub(crate) fn channels(&self) -> Result<Vec<ChannelId>, SerializationError> {
+        let header = Self::peek_header(&self.payload)?;
+        let packet_type = header.get_packet_type();
+        if !matches!(packet_type, PacketType::Data | PacketType::DataFragment) {
+            return Ok(vec![]);
+        }
+        if header.compressed {
+            return Err(SerializationError::InvalidPacketType);
+        }
+        let channel_dictionary = ChannelIdDictionary::default();
+        let mut sections = &self.payload[header.len()..];
+        let mut channels = vec![];
+        if packet_type == PacketType::DataFragment {
+            channels.push(channel_dictionary.read(&mut sections)?);
+            for _ in 0..read_varint(&mut sections)? {
+                // message id, fragment id and number of fragments
+                let ids_len = size_of::<u16>() + 2 * size_of::<FragmentIndex>();
+                let remaining = sections.len();
+                if ids_len > remaining {
+                    return Err(SerializationError::TruncatedMessage {
+                        len: ids_len,
+                        remaining,
+                    });
+                }
+                sections = &sections[ids_len..];
+                // offset of the fragment in the message
+                read_varint(&mut sections)?;
+                let len = read_varint(&mut sections)? as usize;
+                let remaining = sections.len();
+                if len > remaining {
+                    return Err(SerializationError::TruncatedMessage { len, remaining });
+                }
+                sections = &sections[len..];
+            }
+        }
+        for message in
+            PacketReader::from_channel_sections(sections, &channel_dictionary, header.tick)
+        {
+            let (channel_id, _) = message?;
+            if !channels.contains(&channel_id) {
+                channels.push(channel_id);
+            }
+        }
+        Ok(channels)
+    }
+
+    /// Human-readable description of the packet, for debugging: the header, then for each channel
+    /// (named with the `registry`) the length and [`MessageId`] of every message.
+    ///
+    /// The packet must have been written without checksum, padding or channel dictionary.
+    /// If the packet is malformed, the dump stops at the first error.
+    ///
+    /// [`MessageId`]: crate::packet::message::MessageId
+    pub(crate) fn debug_dump(&self, registry: &ChannelRegistry) -> String {
+        let mut dump = String::new();
+        if let Err(e) = self.write_dump(registry, &mut dump) {
+            let _ = writeln!(&mut dump, "error: {e}");
+        }
+        dump
+    }
+
+    fn write_dump(
+        &self,
+        registry: &ChannelRegistry,
+        dump: &mut String,
+    ) -> Result<(), SerializationError> {
+        let mut cursor = Reader::from(Bytes::copy_from_slice(&self.payload));
+        let header = PacketHeader::from_bytes(&mut cursor)?;
+        let _ = writeln!(
+            dump,
+            "{:?} packet_id={} tick={} acks={:?}",
+            header.get_packet_type(),
+            header.packet_id.0,
+            header.tick.0,
+            header.acked_packet_ids().map(|id| id.0).collect::<Vec<_>>(),
+        );
+        if !matches!(
+            header.get_packet_type(),
+            PacketType::Data | PacketType::DataFragment
+        ) {
+            return Ok(());
+        }
+        let mut cursor = decompress_packet(&header, cursor, None, self.mtu)?;
+        let channel_dictionary = ChannelIdDictionary::default();
+        let channel_name = |channel_id: ChannelId| {
+            registry
+                .get_kind_from_net_id(channel_id)
+                .and_then(|kind| registry.name(kind))
+                .unwrap_or("<unknown>")
+        };
+        let mut num_read = 0;
+        if header.get_packet_type() == PacketType::DataFragment {
+            let channel_id = channel_dictionary.read(&mut cursor)?;
+            let num_fragments =
+                read_num_messages(&mut cursor, num_read, DEFAULT_MAX_MESSAGES_PER_PACKET)?;
+            num_read += num_fragments;
+            let _ = writeln!(
+                dump,
+                "  {} (id {channel_id}): {num_fragments} fragment(s)",
+                channel_name(channel_id)
+            );
+            for _ in 0..num_fragments {
+                let fragment = FragmentData::from_bytes(&mut cursor)?;
+                let _ = writeln!(
+                    dump,
+                    "    fragment {}/{} of message {}: {} bytes",
+                    fragment.fragment_id,
+                    fragment.num_fragments,
+                    fragment.message_id.0,
+                    fragment.bytes.len()
+                );
+            }
+        }
+        while cursor.has_remaining() {
+            let channel_id = channel_dictionary.read(&mut cursor)?;
+            let num_messages =
+                read_num_messages(&mut cursor, num_read, DEFAULT_MAX_MESSAGES_PER_PACKET)?;
+            num_read += num_messages;
+            let _ = writeln!(
+                dump,
+                "  {} (id {channel_id}): {num_messages} message(s)",
+                channel_name(channel_id)
+            );
+            let mut block_len = None;
+            for _ in 0..num_messages {
+                let single_data = SingleData::from_bytes_in_block(&mut cursor, &mut block_len)?;
+                let _ = match single_data.id {
+                    Some(id) => writeln!(
+                        dump,
+                        "    {} bytes, message id {}",
+                        single_data.bytes.len(),
+                        id.0
+                    ),
+                    None => writeln!(dump, "    {} bytes", single_data.bytes.len()),
+                };
+            }
+        }
+        Ok(())
+    }
+
     /// For tests, parse the packet so that we can inspect the contents
     /// For production, parse the packets directly into messages to not allocate
     /// an intermediary data structure
@@ -77,27 +734,114 @@ impl Packet {
     pub(crate) fn parse_packet_payload(
         self,
     ) -> Result<HashMap<ChannelId, Vec<Bytes>>, PacketError> {
-        let mut cursor = self.payload.into();
+        self.parse_packet_payload_with_dictionary(&ChannelIdDictionary::default())
+    }
+
+    /// Parse the packet, using the [`ChannelIdDictionary`] that was used to write it
+    #[cfg(test)]
+    pub(crate) fn parse_packet_payload_with_dictionary(
+        self,
+        channel_dictionary: &ChannelIdDictionary,
+    ) -> Result<HashMap<ChannelId, Vec<Bytes>>, PacketError> {
+        self.parse_packet_payload_with(channel_dictionary, DEFAULT_MAX_MESSAGES_PER_PACKET)
+    }
+
+    /// Parse the packet, rejecting it if it contains more than `max_messages` messages
+    #[cfg(test)]
+    pub(crate) fn parse_packet_payload_with(
+        self,
+        channel_dictionary: &ChannelIdDictionary,
+        max_messages: usize,
+    ) -> Result<HashMap<ChannelId, Vec<Bytes>>, PacketError> {
         let mut res: HashMap<ChannelId, Vec<Bytes>> = HashMap::new();
+        self.read_payload(
+            channel_dictionary,
+            max_messages,
+            None,
+            None,
+            |channel_id, bytes| {
+                res.entry(channel_id).or_default().push(bytes);
+            },
+        )?;
+        Ok(res)
+    }
+
+    /// Read every message (or fragment) of the packet, and pass it to `on_message` along with its
+    /// channel. The messages are slices of the packet payload, they are not copied.
+    ///
+    /// Packets that carry no messages (handshakes, keep-alives and unknown packet types) are
+    /// skipped without error. The sections of encrypted channels are decrypted with `decryption`,
+    /// and are rejected as empty channels if it is not provided. Packets compressed with a
+    /// dictionary can only be read with the same `compression_dictionary`.
+    pub(crate) fn read_payload(
+        self,
+        channel_dictionary: &ChannelIdDictionary,
+        max_messages: usize,
+        mut decryption: Option<&mut ChannelDecryption>,
+        compression_dictionary: Option<&CompressionDictionary>,
+        mut on_message: impl FnMut(ChannelId, Bytes),
+    ) -> Result<(), SerializationError> {
+        let mut cursor = self.payload.into();
         let header = PacketHeader::from_bytes(&mut cursor)?;
+        if matches!(
+            header.get_packet_type(),
+            PacketType::Unknown(_)
+                | PacketType::User(_)
+                | PacketType::Handshake
+                | PacketType::KeepAlive
+        ) {
+            return Ok(());
+        }
+        let mut cursor = decompress_packet(&header, cursor, compression_dictionary, self.mtu)?;
+        let mut num_read = 0;
 
         if header.get_packet_type() == PacketType::DataFragment {
             // read the fragment data
-            let channel_id = ChannelId::from_bytes(&mut cursor)?;
-            let fragment_data = FragmentData::from_bytes(&mut cursor)?;
-            res.entry(channel_id).or_default().push(fragment_data.bytes);
+            let channel_id = channel_dictionary.read(&mut cursor)?;
+            let num_fragments = read_num_messages(&mut cursor, num_read, max_messages)?;
+            num_read += num_fragments;
+            for _ in 0..num_fragments {
+                let fragment_data = FragmentData::from_bytes(&mut cursor)?;
+                on_message(channel_id, fragment_data.bytes);
+            }
         }
         // read single message data
-        // TODO: avoid infinite loop here!
+        // (every iteration reads some bytes or returns an error, so this always terminates)
         while cursor.has_remaining() {
-            let channel_id = ChannelId::from_bytes(&mut cursor)?;
-            let num_messages = cursor.read_varint()?;
-            for i in 0..num_messages {
-                let single_data = SingleData::from_bytes(&mut cursor)?;
-                res.entry(channel_id).or_default().push(single_data.bytes);
+            let channel_id = channel_dictionary.read(&mut cursor)?;
+            // without decryption, an encrypted section is rejected like an empty channel
+            let section_decryption = decryption
+                .as_deref_mut()
+                .filter(|_| cursor.peek_u8() == Some(ENCRYPTED_SECTION_MARKER));
+            let encrypted = section_decryption.is_some();
+            if encrypted {
+                cursor.read_u8()?;
+            }
+            let num_messages = read_num_messages(&mut cursor, num_read, max_messages)?;
+            num_read += num_messages;
+            let mut block = match section_decryption {
+                Some(decryption) => {
+                    let len = cursor.read_varint()? as usize;
+                    let remaining = cursor.remaining();
+                    if len > remaining {
+                        return Err(SerializationError::TruncatedMessage { len, remaining });
+                    }
+                    Some(decryption.read_block(channel_id, cursor.split_len(len))?)
+                }
+                None => None,
+            };
+            let reader = block.as_mut().unwrap_or(&mut cursor);
+            let mut block_len = None;
+            for _ in 0..num_messages {
+                let single_data = SingleData::from_bytes_in_block(reader, &mut block_len)?;
+                on_message(channel_id, single_data.bytes);
+            }
+            // the encrypted block only contains the messages of the channel
+            if encrypted && reader.has_remaining() {
+                return Err(SerializationError::InvalidValue);
             }
         }
-        Ok(res)
+        Ok(())
     }
 }
 
@@ -121,11 +865,260 @@ mod tests {
             ..default()
         };
         let mut c = ChannelRegistry::default();
-        c.add_channel::<Channel1>(settings.clone());
-        c.add_channel::<Channel2>(settings.clone());
+        c.add_channel::<Channel1>(settings.clone()).unwrap();
+        c.add_channel::<Channel2>(settings.clone()).unwrap();
         c
     }
 
+    #[test]
+    fn test_packet_type() -> Result<(), crate::prelude::PacketError> {
+        use std::collections::VecDeque;
+
+        use crate::channel::senders::fragment_sender::FragmentSender;
+        use crate::packet::message::MessageId;
+        use crate::packet::packet::*;
+        use crate::packet::packet_builder::PacketBuilder;
+        use crate::prelude::Tick;
+
+        let mut manager = PacketBuilder::new(1.5);
+        let single_data = vec![(
+            0,
+            VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 10]))]),
+        )];
+        let fragments = FragmentSender::default().build_fragments(
+            MessageId(0),
+            None,
+            Bytes::from(vec![2; FRAGMENT_SIZE + 1]),
+        )?;
+        let fragment_data = vec![(1, VecDeque::from(fragments))];
+
+        // the fragments are packed first, and the single message fits with the last fragment
+        let packets = manager.build_packets(Tick(3), single_data, fragment_data)?;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].packet_type()?, PacketType::DataFragment);
+        assert!(packets[0].is_fragment()?);
+        assert!(packets[1].is_fragment()?);
+
+        let single_data = vec![(
+            0,
+            VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 10]))]),
+        )];
+        let packets = manager.build_packets(Tick(4), single_data, vec![])?;
+        assert_eq!(packets[0].packet_type()?, PacketType::Data);
+        assert!(!packets[0].is_fragment()?);
+
+        // only the header is parsed
+        let header = Packet::peek_header(&packets[0].payload)?;
+        assert_eq!(header.get_packet_type(), PacketType::Data);
+        assert_eq!(header.packet_id(), packets[0].packet_id());
+        assert_eq!(header.tick(), Tick(4));
+        assert!(Packet::peek_header(&packets[0].payload[..HEADER_BYTES]).is_ok());
+        assert!(matches!(
+            Packet::peek_header(&packets[0].payload[..HEADER_BYTES - 1]),
+            Err(SerializationError::Io(_))
+        ));
+
+        // a payload without a valid header returns an error instead of panicking
+        let single_data = vec![(
+            0,
+            VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 10]))]),
+        )];
+        let mut packet = manager
+            .build_packets(Tick(5), single_data, vec![])?
+            .remove(0);
+        packet.payload.truncate(HEADER_BYTES - 1);
+        assert!(packet.packet_type().is_err());
+        assert!(packet.is_fragment().is_err());
+        assert!(packet.tick().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_packet_id_and_tick() -> Result<(), crate::prelude::PacketError> {
+        use std::collections::VecDeque;
+
+        use crate::packet::packet::*;
+        use crate::packet::packet_builder::PacketBuilder;
+        use crate::prelude::Tick;
+
+        let mut manager = PacketBuilder::new(1.5);
+        for i in 0..2 {
+            let single_data = vec![(
+                0,
+                VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 10]))]),
+            )];
+            let packet = manager
+                .build_packets(Tick(42), single_data, vec![])?
+                .pop()
+                .unwrap();
+            assert_eq!(packet.packet_id(), PacketId(i));
+            assert_eq!(packet.tick()?, Tick(42));
+        }
+        let packet = manager.build_keepalive(Tick(43))?;
+        assert_eq!(packet.packet_id(), PacketId(2));
+        assert_eq!(packet.tick()?, Tick(43));
+        Ok(())
+    }
+
+    /// Only the tick of the header changes, and the checksum stays valid. Stream-framed packets
+    /// are not supported
+    #[test]
+    fn test_set_tick() -> Result<(), crate::prelude::PacketError> {
+        use std::collections::VecDeque;
+
+        use crate::packet::packet::*;
+        use crate::packet::packet_builder::PacketBuilder;
+        use crate::packet::packet_type::FIRST_USER_PACKET_TYPE;
+        use crate::prelude::Tick;
+
+        for checksum in [false, true] {
+            let mut manager = PacketBuilder::new(1.5).with_checksum(checksum);
+            let single_data = vec![(
+                0,
+                VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 10]))]),
+            )];
+            let mut packet = manager
+                .build_packets(Tick(0), single_data, vec![])?
+                .pop()
+                .unwrap();
+            let header = Packet::peek_header(&packet.payload)?;
+            let payload = packet.payload.clone();
+            packet.set_tick(Tick(5), manager.framing())?;
+            assert_eq!(Packet::peek_header(&packet.payload)?.tick, Tick(5));
+            assert_eq!(packet.verify_checksum(), checksum);
+            let changed = (0..payload.len())
+                .filter(|&i| payload[i] != packet.payload[i])
+                .collect::<Vec<_>>();
+            assert_eq!(changed.first(), Some(&(header.tick_offset() + 1)));
+            if !checksum {
+                assert_eq!(changed.len(), 1);
+            }
+            packet.set_tick(Tick(0), manager.framing())?;
+            assert_eq!(packet.payload, payload);
+        }
+
+        // a byte is written after the tick of user packets
+        let mut manager = PacketBuilder::new(1.5);
+        let mut packet = manager.build_typed(PacketType::User(FIRST_USER_PACKET_TYPE), Tick(0))?;
+        packet.set_tick(Tick(300), manager.framing())?;
+        let header = Packet::peek_header(&packet.payload)?;
+        assert_eq!(header.tick, Tick(300));
+        assert_eq!(
+            header.get_packet_type(),
+            PacketType::User(FIRST_USER_PACKET_TYPE)
+        );
+
+        // the payload starts with the length of the packet, not with the header
+        let mut manager = PacketBuilder::new(1.5).with_stream_framing(true);
+        let single_data = vec![(
+            0,
+            VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 10]))]),
+        )];
+        let mut packet = manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .remove(0);
+        let payload = packet.payload.clone();
+        assert!(matches!(
+            packet.set_tick(Tick(5), manager.framing()),
+            Err(SerializationError::StreamFramedPacket)
+        ));
+        assert_eq!(packet.payload, payload);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transport_fragments() -> Result<(), crate::prelude::PacketError> {
+        use std::collections::VecDeque;
+
+        use crate::packet::packet::*;
+        use crate::packet::packet_builder::PacketBuilder;
+        use crate::prelude::Tick;
+
+        let mut manager = PacketBuilder::new(1.5);
+        let single_data = vec![(
+            0,
+            VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 100]))]),
+        )];
+        let packet = manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .pop()
+            .unwrap();
+        let payload = packet.payload.clone();
+        let transport_mtu = TRANSPORT_FRAGMENT_HEADER_BYTES + payload.len().div_ceil(3);
+        let fragments = packet.into_transport_fragments(transport_mtu)?;
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments
+            .iter()
+            .all(|fragment| fragment.len() <= transport_mtu));
+
+        // the fragments can arrive in any order
+        let reassembled = reassemble_transport_fragments(fragments.iter().rev().cloned())?;
+        assert_eq!(reassembled, payload);
+
+        // a missing or duplicated fragment, or a fragment of another packet, is rejected
+        assert!(reassemble_transport_fragments(fragments[..2].iter().cloned()).is_err());
+        assert!(reassemble_transport_fragments(
+            [&fragments[0], &fragments[1], &fragments[1], &fragments[2]]
+                .into_iter()
+                .cloned()
+        )
+        .is_err());
+        let mut other_packet = fragments[2].to_vec();
+        other_packet[TRANSPORT_FRAGMENT_HEADER_BYTES - 1] ^= 1;
+        assert!(reassemble_transport_fragments([
+            fragments[0].clone(),
+            fragments[1].clone(),
+            other_packet.into()
+        ])
+        .is_err());
+
+        // the transport mtu must leave room for some bytes of the packet
+        let packet = manager.build_keepalive(Tick(1))?;
+        assert!(matches!(
+            packet.into_transport_fragments(TRANSPORT_FRAGMENT_HEADER_BYTES),
+            Err(SerializationError::InvalidValue)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_dump() -> Result<(), crate::prelude::PacketError> {
+        use std::collections::VecDeque;
+
+        use crate::packet::message::MessageId;
+        use crate::packet::packet::*;
+        use crate::packet::packet_builder::PacketBuilder;
+        use crate::prelude::Tick;
+
+        let registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let single_data = vec![
+            (
+                0,
+                VecDeque::from(vec![
+                    SingleData::new(None, Bytes::from(vec![1; 10])),
+                    SingleData::new(Some(MessageId(7)), Bytes::from(vec![1; 5])),
+                ]),
+            ),
+            (
+                1,
+                VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![2; 3]))]),
+            ),
+        ];
+        let packet = manager
+            .build_packets(Tick(5), single_data, vec![])?
+            .pop()
+            .unwrap();
+        let dump = packet.debug_dump(&registry);
+        assert!(dump.starts_with("Data packet_id=0 tick=5"));
+        assert!(dump.contains("Channel1 (id 0): 2 message(s)"));
+        assert!(dump.contains("    10 bytes\n"));
+        assert!(dump.contains("    5 bytes, message id 7\n"));
+        assert!(dump.contains("Channel2 (id 1): 1 message(s)"));
+        assert!(!dump.contains("error"));
+        Ok(())
+    }
+
     // #[test]
     // fn test_single_packet_add_messages() {
     //     let channel_registry = get_channel_registry();
@@ -262,4 +1255,201 @@ mod tests {
     //     assert_eq!(packet, decoded_packet);
     //     Ok(())
     // }
+
+    mod malformed {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        use crate::packet::message::MessageId;
+        use crate::packet::packet::*;
+        use crate::serialize::varint::VarIntWriteExt;
+
+        /// Header of an uncompressed packet of the given type
+        fn header(packet_type: PacketType) -> Vec<u8> {
+            let mut payload = vec![0; HEADER_BYTES];
+            payload[0] = u8::from(packet_type);
+            payload
+        }
+
+        fn parse(payload: Vec<u8>) -> Result<HashMap<ChannelId, Vec<Bytes>>, PacketError> {
+            Packet {
+                payload,
+                message_acks: vec![],
+                packet_id: PacketId(0),
+                mtu: MAX_PACKET_SIZE,
+                prewritten_size: 0,
+                written_messages: 0,
+            }
+            .parse_packet_payload()
+        }
+
+        fn serialization_error(payload: Vec<u8>) -> SerializationError {
+            match parse(payload) {
+                Err(PacketError::Serialization(e)) => e,
+                res => panic!("expected a serialization error, got {:?}", res),
+            }
+        }
+
+        #[test]
+        fn test_parse_truncated_header() {
+            let payload = header(PacketType::Data)[..5].to_vec();
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::Io(_)
+            ));
+        }
+
+        #[test]
+        fn test_parse_count_exceeds_buffer() {
+            let mut payload = header(PacketType::Data);
+            payload.write_varint(0).unwrap();
+            // 1000 messages declared, but only 3 bytes left
+            payload.write_varint(1000).unwrap();
+            payload.extend_from_slice(&[0, 1, 7]);
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::CountExceedsBuffer {
+                    count: 1000,
+                    remaining: 3
+                }
+            ));
+        }
+
+        #[test]
+        fn test_parse_too_many_messages() {
+            // at the limit: every message is read
+            let mut payload = header(PacketType::Data);
+            payload.write_varint(0).unwrap();
+            payload
+                .write_varint(DEFAULT_MAX_MESSAGES_PER_PACKET as u64)
+                .unwrap();
+            for _ in 0..DEFAULT_MAX_MESSAGES_PER_PACKET {
+                payload.extend_from_slice(&[0, 0]);
+            }
+            let contents = parse(payload).unwrap();
+            assert_eq!(contents[&0].len(), DEFAULT_MAX_MESSAGES_PER_PACKET);
+
+            // over the limit: rejected before checking the size of the rest of the packet
+            let mut payload = header(PacketType::Data);
+            payload.write_varint(0).unwrap();
+            payload.write_varint(u32::MAX as u64).unwrap();
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::TooManyMessages {
+                    claimed,
+                    max: DEFAULT_MAX_MESSAGES_PER_PACKET
+                } if claimed == u32::MAX as u64
+            ));
+
+            // the messages of all the channels are counted
+            let mut payload = header(PacketType::Data);
+            for channel_id in 0..2 {
+                payload.write_varint(channel_id).unwrap();
+                payload.write_varint(600).unwrap();
+                for _ in 0..600 {
+                    payload.extend_from_slice(&[0, 0]);
+                }
+            }
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::TooManyMessages {
+                    claimed: 1200,
+                    max: DEFAULT_MAX_MESSAGES_PER_PACKET
+                }
+            ));
+        }
+
+        #[test]
+        fn test_parse_empty_channel() {
+            let mut payload = header(PacketType::Data);
+            payload.write_varint(0).unwrap();
+            payload.write_varint(0).unwrap();
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::InvalidValue
+            ));
+        }
+
+        #[test]
+        fn test_parse_truncated_message() {
+            let mut payload = header(PacketType::Data);
+            payload.write_varint(0).unwrap();
+            payload.write_varint(1).unwrap();
+            // a message without id, which declares 50 bytes but only has 3
+            payload.push(0);
+            payload.write_varint(50).unwrap();
+            payload.extend_from_slice(&[1, 2, 3]);
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::TruncatedMessage {
+                    len: 50,
+                    remaining: 3
+                }
+            ));
+
+            // same thing for the bytes of a fragment
+            let mut payload = header(PacketType::DataFragment);
+            payload.write_varint(0).unwrap();
+            payload.write_varint(1).unwrap();
+            // message id, fragment id and number of fragments (1, in the last byte)
+            // (the overhead also counts the channel id, the number of fragments and the length of
+            // the bytes)
+            payload.extend_from_slice(&[0; FRAGMENT_OVERHEAD_BYTES - 5]);
+            payload.push(1);
+            payload.write_varint(0).unwrap();
+            payload.write_varint(1000).unwrap();
+            payload.extend_from_slice(&[1, 2, 3]);
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::TruncatedMessage {
+                    len: 1000,
+                    remaining: 3
+                }
+            ));
+        }
+
+        #[test]
+        fn test_parse_unknown_channel() {
+            let mut payload = header(PacketType::Data);
+            // does not fit in a ChannelId
+            payload.write_varint(100_000).unwrap();
+            payload.write_varint(0).unwrap();
+            assert!(matches!(
+                serialization_error(payload),
+                SerializationError::UnknownChannel(100_000)
+            ));
+        }
+
+        /// Truncating a valid packet or writing random bytes after the header never panics
+        #[test]
+        fn test_parse_random_payloads() {
+            let mut valid = header(PacketType::Data);
+            valid.write_varint(0).unwrap();
+            valid.write_varint(2).unwrap();
+            SingleData::new(None, Bytes::from("hello"))
+                .to_bytes(&mut valid)
+                .unwrap();
+            SingleData::new(Some(MessageId(3)), Bytes::from("world"))
+                .to_bytes(&mut valid)
+                .unwrap();
+            assert_eq!(parse(valid.clone()).unwrap()[&0].len(), 2);
+            // (a packet with only a header is valid)
+            for len in (0..valid.len()).filter(|len| *len != HEADER_BYTES) {
+                assert!(parse(valid[..len].to_vec()).is_err());
+            }
+
+            let mut rng = StdRng::seed_from_u64(0);
+            for _ in 0..10000 {
+                let packet_type = if rng.gen_bool(0.5) {
+                    PacketType::Data
+                } else {
+                    PacketType::DataFragment
+                };
+                let mut payload = header(packet_type);
+                let len = rng.gen_range(0..64);
+                payload.extend((0..len).map(|_| rng.gen::<u8>()));
+                let _ = parse(payload);
+            }
+        }
+    }
 }
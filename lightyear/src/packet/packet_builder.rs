@@ -1,23 +1,72 @@
 //! Module to take a buffer of messages to send and build packets
 use crate::connection::netcode::MAX_PACKET_SIZE;
-use byteorder::WriteBytesExt;
+use bevy::utils::{Duration, HashMap, HashSet};
 use bytes::Bytes;
-use std::collections::VecDeque;
+use std::borrow::Borrow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+use tracing::trace;
 #[cfg(feature = "trace")]
 use tracing::{instrument, Level};
 
-use crate::packet::header::PacketHeaderManager;
-use crate::packet::message::{FragmentData, MessageAck, SingleData};
-use crate::packet::packet::{Packet, FRAGMENT_SIZE};
+use crate::packet::capture::PacketCapture;
+use crate::packet::channel_dictionary::ChannelIdDictionary;
+use crate::packet::encryption::{ChannelEncryption, EncryptFn, ENCRYPTED_SECTION_MARKER};
+use crate::packet::error::PacketError;
+use crate::packet::header::{
+    HeaderLayout, PacketHeader, PacketHeaderManager, DEFAULT_ACK_WINDOW_BITS,
+};
+use crate::packet::message::{
+    FragmentData, FragmentIndex, MessageAck, MessageId, RawMessage, SingleData,
+};
+#[cfg(feature = "lz4")]
+use crate::packet::packet::compress_packet;
+use crate::packet::packet::{
+    fragment_size, min_mtu, pad_packet, CompressionDictionary, Packet, PacketFraming, PacketId,
+    CHECKSUM_BYTES, DEFAULT_MAX_MESSAGES_PER_PACKET, FRAGMENT_SIZE, HEADER_BYTES, MAX_HEADER_BYTES,
+    MIN_SINGLE_DATA_BYTES,
+};
+use crate::packet::packet_reader::PacketReader;
 use crate::packet::packet_type::PacketType;
+use crate::packet::stream_reader::frame_packet;
+use crate::packet::wire_config::{PacketLimits, WireConfig};
 use crate::prelude::Tick;
-use crate::protocol::channel::ChannelId;
+use crate::protocol::channel::{ChannelId, ChannelRegistry, ChannelSchema};
 use crate::protocol::registry::NetId;
-use crate::serialize::varint::varint_len;
+use crate::serialize::varint::{varint_len, VarIntWriteExt, VARINT_MAX};
 use crate::serialize::{SerializationError, ToBytes};
 
 pub type Payload = Vec<u8>;
 
+/// Seed used for the randomized packing decisions if no other seed is provided
+pub(crate) const DEFAULT_PACKING_SEED: u64 = 0;
+
+/// Maximum number of message ack buffers kept by the [`PacketBuilder`] to be reused
+const MAX_POOLED_ACK_BUFFERS: usize = 64;
+
+/// How much memory the [`PacketBuilder`] of a connection reserves for its buffers.
+///
+/// The default suits busy connections; a server with many quiet connections can use smaller
+/// values so that each connection holds on to less memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSizing {
+    /// Capacity (in bytes) allocated for the payload of each new packet. It is capped by the MTU,
+    /// and the buffer grows if the packet needs more.
+    pub initial: usize,
+    /// Maximum number of recycled buffers that are kept to be reused for the next packets
+    pub max: usize,
+}
+
+impl Default for BufferSizing {
+    fn default() -> Self {
+        Self {
+            initial: MAX_PACKET_SIZE,
+            max: MAX_POOLED_ACK_BUFFERS,
+        }
+    }
+}
+
 /// We use `Bytes` on the receive side because we want to be able to refer to sub-slices of the original
 /// packet without allocating.
 ///
@@ -25,12 +74,422 @@ pub type Payload = Vec<u8>;
 /// store subslices in receiver channels without allocating.
 pub type RecvPayload = Bytes;
 
+/// Statistics about the packets written by the last call to [`PacketBuilder::build_packets`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PackStats {
+    /// Number of bytes that each single message contributed to its packet: the message itself,
+    /// plus its share of the channel id and number of messages written before the channel's messages.
+    ///
+    /// Only messages that have a [`MessageId`] are recorded.
+    pub message_bytes: HashMap<(ChannelId, MessageId), usize>,
+    /// Number of bytes that each single message took in its packet, without the share of the
+    /// channel: [`SingleData::len`], minus the length that is not repeated inside a fixed-length
+    /// block (see [`PacketBuilder::with_fixed_length_blocks`]).
+    ///
+    /// Only messages that have a [`MessageId`] are recorded.
+    pub serialized_bytes: HashMap<(ChannelId, MessageId), usize>,
+}
+
+/// Signal that the last call to [`PacketBuilder::build_packets`] produced more packets than the
+/// caller can send in a tick (see [`PacketBuilder::with_target_packets_per_tick`]), so that the
+/// application can produce fewer messages (for example by lowering its update rate)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackPressure {
+    /// Number of packets produced by the call
+    pub produced_packets: usize,
+    /// True if more packets than the target were produced
+    pub over_budget: bool,
+}
+
+/// Target of the trace event emitted (with the `trace` feature) every time the [`PacketBuilder`]
+/// finishes a packet while packing messages.
+///
+/// The event records the [`PacketFinishReason`], the number of bytes written in the packet and the MTU.
+pub const FINISH_PACKET_TRACE_TARGET: &str = "lightyear::packet_builder::finish_packet";
+
+/// Why the [`PacketBuilder`] finished a packet and started a new one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketFinishReason {
+    /// The next message does not fit in the rest of the packet
+    MessageDoesNotFit,
+    /// The channel id and number of messages of the next channel do not fit in the rest of the packet
+    ChannelDoesNotFit,
+    /// The next channel cannot share a packet with the previous channel
+    /// (see [`PacketBuilder::with_reliability_boundary`])
+    ReliabilityBoundary,
+    /// The messages of the next channel cannot be written after the fragments of the packet
+    /// (see [`FragmentTailPacking`])
+    FragmentTailPacking,
+    /// The next channel is a different channel, and each packet only contains one channel
+    /// (see [`PacketBuilder::set_isolate_channels`])
+    ChannelIsolation,
+    /// The packet already contains the maximum number of messages
+    /// (see [`WireConfig::max_messages_per_packet`])
+    TooManyMessages,
+    /// The next message only fits in the bytes reserved for another channel
+    /// (see [`ChannelSettings::reserved_bytes`](crate::prelude::ChannelSettings::reserved_bytes))
+    ChannelReservation,
+    /// There are no more messages to write
+    NoMoreMessages,
+    /// The packet was kept open by the previous call to `build_packets`, but the new messages
+    /// belong to a different tick (see [`PacketBuilder::with_incremental_packets`])
+    TickChanged,
+    /// The packet was kept open by the previous call to `build_packets`, but the MTU changed
+    /// since (see [`PacketBuilder::set_mtu`])
+    MtuChanged,
+}
+
+/// Order in which the single messages of a channel are packed.
+///
+/// Messages of ordered channels (see [`ChannelMode::is_ordered`](crate::channel::builder::ChannelMode))
+/// are always packed in the order in which they were queued.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PackingStrategy {
+    /// Pack the smallest messages first, so that as many messages as possible share a packet
+    #[default]
+    SmallestFirst,
+    /// Pack the largest messages first
+    LargestFirst,
+    /// Pack the messages in the order in which they were queued, without sorting them
+    QueueOrder,
+}
+
+impl PackingStrategy {
+    /// Sort the messages of a channel in the order in which they are packed.
+    ///
+    /// The sort is stable: messages of the same size keep their queue order. Returns true if
+    /// the sort changed the order of the messages.
+    fn sort<T: Borrow<SingleData>>(self, messages: &mut [T]) -> bool {
+        match self {
+            PackingStrategy::SmallestFirst => {
+                let len = |message: &T| message.borrow().bytes.len();
+                let reordered = messages.windows(2).any(|w| len(&w[0]) > len(&w[1]));
+                messages.sort_by_key(len);
+                reordered
+            }
+            PackingStrategy::LargestFirst => {
+                let len = |message: &T| Reverse(message.borrow().bytes.len());
+                let reordered = messages.windows(2).any(|w| len(&w[0]) > len(&w[1]));
+                messages.sort_by_key(len);
+                reordered
+            }
+            PackingStrategy::QueueOrder => false,
+        }
+    }
+
+    /// Position of the last message of `messages` once they are sorted
+    fn last_position(self, messages: &VecDeque<SingleData>) -> usize {
+        let Some(last) = messages.back() else {
+            return 0;
+        };
+        let before = messages.iter().take(messages.len() - 1);
+        match self {
+            PackingStrategy::SmallestFirst => before
+                .filter(|message| message.bytes.len() <= last.bytes.len())
+                .count(),
+            PackingStrategy::LargestFirst => before
+                .filter(|message| message.bytes.len() >= last.bytes.len())
+                .count(),
+            PackingStrategy::QueueOrder => messages.len() - 1,
+        }
+    }
+}
+
+/// Order in which the messages of the different channels are written in the packets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScheduleMode {
+    /// Write all the messages of a channel before moving to the next channel
+    #[default]
+    Grouped,
+    /// Write one message of each channel in turn, so that a channel with many messages does not
+    /// delay the messages of the other channels to later packets.
+    ///
+    /// Each message is written in its own channel section (except when a single channel has
+    /// messages left), which costs the channel id and the number of messages for every message.
+    RoundRobin,
+}
+
+/// Order in which channels that have the same credit (see [`PacketBuilder::with_channel_weight`])
+/// are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Write the channel with the smallest [`ChannelId`] first
+    #[default]
+    ChannelId,
+    /// Write first the channel that was written the longest time ago, so that channels with the
+    /// same priority take turns being first in the packets
+    LeastRecentlyServed,
+    /// Write the channels in a random order, that changes on every call to
+    /// [`PacketBuilder::build_packets`] and is reproducible from the seed of the [`PackerConfig`]
+    Random,
+    /// Write the channels in the order in which they are given to
+    /// [`PacketBuilder::build_packets`], so that the caller chooses the processing order
+    /// independently of the channel ids
+    InputOrder,
+}
+
+/// Configuration of the randomized packing decisions (for example [`TieBreak::Random`]).
+///
+/// Packing is deterministic: two builders with the same configuration and the same settings
+/// write exactly the same bytes from the same sequence of inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackerConfig {
+    /// Seed from which every random decision is derived
+    pub seed: u64,
+}
+
+impl Default for PackerConfig {
+    fn default() -> Self {
+        Self {
+            seed: DEFAULT_PACKING_SEED,
+        }
+    }
+}
+
+/// Which single messages can be written in the space left in a fragment packet (usually after
+/// the last fragment of a message)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FragmentTailPacking {
+    /// Fragment packets only contain fragments
+    Never,
+    /// Only the messages of unreliable channels are written with the fragments, so that losing
+    /// a fragment packet never delays a reliable message that is not part of the fragmented one
+    UnreliableOnly,
+    /// The messages of any channel are written with the fragments
+    #[default]
+    Always,
+}
+
+/// What `build_packets` does when two single messages of a channel have the same [`MessageId`],
+/// which would give the reliable sender two acks for the same message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateMessageIds {
+    /// Write both messages
+    #[default]
+    Allow,
+    /// Return [`SerializationError::DuplicateMessageId`] without writing any packet
+    Error,
+    /// Only write the first message with that id
+    Drop,
+}
+
+/// Messages to pack, grouped by channel, used to build the input of [`PacketBuilder::build_packets`]
+/// one message at a time.
+///
+/// The channels are returned by [`MessageBatch::into_data`] in the order of their [`ChannelId`].
+#[derive(Debug, Default)]
+pub(crate) struct MessageBatch {
+    data: BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)>,
+}
+
+impl MessageBatch {
+    pub(crate) fn push_single(&mut self, channel_id: ChannelId, data: SingleData) -> &mut Self {
+        self.data.entry(channel_id).or_default().0.push_back(data);
+        self
+    }
+
+    pub(crate) fn push_fragment(&mut self, channel_id: ChannelId, data: FragmentData) -> &mut Self {
+        self.data.entry(channel_id).or_default().1.push_back(data);
+        self
+    }
+
+    /// Split the batch into the single messages and fragments of each channel
+    pub(crate) fn into_data(
+        self,
+    ) -> (
+        Vec<(ChannelId, VecDeque<SingleData>)>,
+        Vec<(ChannelId, VecDeque<FragmentData>)>,
+    ) {
+        let mut single_data = vec![];
+        let mut fragment_data = vec![];
+        for (channel_id, (single, fragment)) in self.data {
+            if !single.is_empty() {
+                single_data.push((channel_id, single));
+            }
+            if !fragment.is_empty() {
+                fragment_data.push((channel_id, fragment));
+            }
+        }
+        (single_data, fragment_data)
+    }
+}
+
+/// Where [`PacketBuilder::build_packets`] would write a message, see [`PacketBuilder::preview_placement`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Placement {
+    /// The message is written with the other messages of channel `channel_id` in the packet at
+    /// `packet_index`, which would be built even without the message
+    Packet {
+        packet_index: usize,
+        channel_id: ChannelId,
+    },
+    /// The message is written in the packet at `packet_index`, which is only built because of the message
+    NewPacket {
+        packet_index: usize,
+        channel_id: ChannelId,
+    },
+    /// The message goes over the channel's byte cap, so it is deferred to the next call
+    Deferred,
+}
+
+/// Counters about the packets built by a [`PacketBuilder`], accumulated until they are taken
+/// with [`PacketBuilder::take_metrics`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PacketBuilderMetrics {
+    /// Number of packets built
+    pub packets: usize,
+    /// Total number of bytes of the packets built
+    pub payload_bytes: usize,
+    /// Number of packets that start with a fragment
+    pub fragment_packets: usize,
+    /// Number of packets that only contain single messages
+    pub single_packets: usize,
+    /// Number of messages (single messages and fragments) written for each channel
+    pub channel_messages: HashMap<ChannelId, usize>,
+    /// Total number of bytes left unused in the packets built (see [`Packet::wasted_bytes`])
+    pub wasted_bytes: usize,
+    /// Largest number of bytes left unused in a single packet
+    pub max_wasted_bytes: usize,
+}
+
+impl PacketBuilderMetrics {
+    /// Average number of bytes left unused per packet
+    pub fn avg_wasted_bytes(&self) -> f32 {
+        if self.packets == 0 {
+            return 0.0;
+        }
+        self.wasted_bytes as f32 / self.packets as f32
+    }
+}
+
+/// Counters about everything sent on a connection since it started (or since
+/// [`PacketBuilder::reset`]), for example to feed a dashboard. Unlike the
+/// [`PacketBuilderMetrics`], they are never reset when they are read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Number of packets sent
+    pub packets_sent: u64,
+    /// Total number of bytes of the packets sent
+    pub bytes_sent: u64,
+    /// Number of fragments sent
+    pub fragments_sent: u64,
+    /// Number of packets that were acked by the remote peer
+    pub packets_acked: u64,
+    /// Number of packets that were not acked in time and were considered lost. The reliable
+    /// messages they contained are retransmitted
+    pub packets_lost: u64,
+    /// Current estimate of the round-trip time, used to detect the lost packets
+    pub rtt: Duration,
+}
+
 /// `PacketBuilder` handles the process of creating a packet (writing the header and packing the
 /// messages into packets)
+///
+/// Only the options of [`WireConfig`] can be set by users (with the `wire_config` of the client
+/// and server `PacketConfig`). The other `with_*` options are internal: they are set by the
+/// tests, the test vectors and the tools of the crate, and keep their default for the connections.
 #[derive(Debug)]
 pub(crate) struct PacketBuilder {
     pub(crate) header_manager: PacketHeaderManager,
     current_packet: Option<Packet>,
+    /// Maximum number of bytes in a packet
+    mtu: usize,
+    /// Maximum number of messages in a packet, so that the remote does not reject it
+    max_messages_per_packet: usize,
+    /// Maximum number of bytes that each channel can write in a single call to `build_packets`
+    channel_byte_caps: HashMap<ChannelId, usize>,
+    /// Number of bytes kept free in each packet for each channel that has a reservation, see
+    /// [`ChannelSettings::reserved_bytes`](crate::prelude::ChannelSettings::reserved_bytes)
+    channel_reservations: HashMap<ChannelId, usize>,
+    /// Weight of each channel (1.0 if not set), see [`ChannelSettings::weight`](crate::prelude::ChannelSettings::weight)
+    channel_weights: HashMap<ChannelId, f32>,
+    /// Credit accumulated by the channels that had messages but were not written in the previous
+    /// calls to `build_packets`. Channels with more credit are written first
+    channel_credits: HashMap<ChannelId, f32>,
+    /// Order of the channels that have the same credit
+    tie_break: TieBreak,
+    /// Last call to `build_packets` in which each channel was written before the other channels
+    /// that had the same credit (see [`TieBreak::LeastRecentlyServed`])
+    last_served: HashMap<ChannelId, u64>,
+    /// Number of calls to `build_packets`
+    num_builds: u64,
+    /// Messages that could not be sent because they went over their channel's byte cap.
+    /// They will be sent first on the next call to `build_packets`
+    deferred_data: BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)>,
+    /// Channels that use a reliable [`ChannelMode`](crate::channel::builder::ChannelMode)
+    reliable_channels: HashSet<ChannelId>,
+    /// If true, reliable and unreliable channels are never written in the same packet
+    reliability_boundary: bool,
+    /// Which single messages are written in the space left in the fragment packets
+    fragment_tail_packing: FragmentTailPacking,
+    /// What to do with the single messages that reuse the id of another message of their channel
+    duplicate_message_ids: DuplicateMessageIds,
+    /// If true, the messages of different channels are never written in the same packet
+    isolate_channels: bool,
+    /// Order in which the single messages of a channel are packed
+    packing_strategy: PackingStrategy,
+    /// Order in which the messages of the different channels are packed
+    schedule_mode: ScheduleMode,
+    /// Channels whose messages are always packed in queue order
+    ordered_channels: HashSet<ChannelId>,
+    /// If true, the length of the messages of a channel is only written once if they all have
+    /// the same length (see [`Self::with_fixed_length_blocks`])
+    fixed_length_blocks: bool,
+    /// If true, identical consecutive messages without id of unreliable channels are sent once
+    /// (see [`Self::with_message_coalescing`])
+    message_coalescing: bool,
+    /// Dictionary used to write channel ids in fewer bytes. Must be the same on both peers
+    pub(crate) channel_dictionary: ChannelIdDictionary,
+    /// Encrypts the messages of the encrypted channels (see [`Self::with_channel_encryption`])
+    encryption: ChannelEncryption,
+    /// If true, a checksum of the packet is written at the end of each packet
+    pub(crate) checksum: bool,
+    /// If set, each packet is padded with zeros up to this number of bytes (see [`Self::with_pad_to`])
+    pub(crate) pad_to: Option<usize>,
+    /// If true, each finished packet starts with its length (see [`Self::with_stream_framing`])
+    pub(crate) stream_framing: bool,
+    /// Number of bytes left free at the end of each packet for a footer appended after the packet
+    /// is built (see [`Self::with_reserved_footer_bytes`])
+    reserved_footer_bytes: usize,
+    /// If true, try to compress each finished packet, and keep the compressed version if it is smaller
+    #[cfg(feature = "lz4")]
+    packet_compression: bool,
+    /// Dictionary shared with the remote peer to compress the packets (see
+    /// [`Self::with_compression_dictionary`]). Also used to decompress the received packets
+    pub(crate) compression_dictionary: Option<CompressionDictionary>,
+    /// Seed of every randomized packing decision, so that packing is reproducible
+    packer_config: PackerConfig,
+    /// If enabled, statistics about the last call to `build_packets`
+    pack_stats: Option<PackStats>,
+    /// If enabled, counters about the packets built since the last call to `take_metrics`
+    metrics: Option<PacketBuilderMetrics>,
+    /// Counters about the packets sent since the connection started (see [`Self::stats`])
+    stats: ConnectionStats,
+    /// If true, check the invariants of every finished packet (see [`Self::with_invariant_checks`])
+    invariant_checks: bool,
+    /// If set, called with every finished packet (see [`Self::set_capture`])
+    capture: Option<PacketCapture>,
+    /// If true, the last packet of `build_packets` is not finished, so that the next call can
+    /// keep filling it (see [`Self::with_incremental_packets`])
+    incremental_packets: bool,
+    /// The last packet of `build_packets` is kept open while it is smaller than this number of
+    /// bytes (see [`Self::with_min_packet_bytes`])
+    min_packet_bytes: usize,
+    /// Packet kept open at the end of the last call to `build_packets`, along with the first
+    /// channel written in it. Its header is only a placeholder (see [`Self::keep_open`])
+    pending_packet: Option<(Packet, ChannelId)>,
+    /// Number of packets that the caller can send per tick (see
+    /// [`Self::with_target_packets_per_tick`])
+    target_packets_per_tick: Option<usize>,
+    /// Back-pressure of the last call to `build_packets`, if a target is set
+    back_pressure: Option<BackPressure>,
+    /// Buffers of message acks of the packets that were already sent, reused for the next
+    /// packets (see [`Self::recycle_acks`])
+    ack_buffers: Vec<Vec<(ChannelId, MessageAck)>>,
+    /// Payload buffers of the packets that were dropped before being sent, reused for the next
+    /// packets (see [`Self::discard_single_packet`])
+    payload_buffers: Vec<Payload>,
+    buffer_sizing: BufferSizing,
     // Pre-allocated buffer to encode/decode without allocation.
     // TODO: should this be associated with Packet?
     // cursor: Vec<u8>,
@@ -43,305 +502,2914 @@ pub(crate) struct PacketBuilder {
 impl PacketBuilder {
     pub fn new(nack_rtt_multiple: f32) -> Self {
         Self {
-            header_manager: PacketHeaderManager::new(nack_rtt_multiple),
+            header_manager: PacketHeaderManager::new(nack_rtt_multiple, DEFAULT_ACK_WINDOW_BITS)
+                .expect("the default ack window is valid"),
             current_packet: None,
+            mtu: MAX_PACKET_SIZE,
+            max_messages_per_packet: DEFAULT_MAX_MESSAGES_PER_PACKET,
+            channel_byte_caps: HashMap::default(),
+            channel_reservations: HashMap::default(),
+            channel_weights: HashMap::default(),
+            channel_credits: HashMap::default(),
+            tie_break: TieBreak::default(),
+            last_served: HashMap::default(),
+            num_builds: 0,
+            deferred_data: BTreeMap::new(),
+            reliable_channels: HashSet::default(),
+            reliability_boundary: false,
+            fragment_tail_packing: FragmentTailPacking::default(),
+            duplicate_message_ids: DuplicateMessageIds::default(),
+            isolate_channels: false,
+            packing_strategy: PackingStrategy::default(),
+            schedule_mode: ScheduleMode::default(),
+            ordered_channels: HashSet::default(),
+            fixed_length_blocks: false,
+            message_coalescing: false,
+            channel_dictionary: ChannelIdDictionary::default(),
+            encryption: ChannelEncryption::default(),
+            checksum: false,
+            pad_to: None,
+            stream_framing: false,
+            reserved_footer_bytes: 0,
+            #[cfg(feature = "lz4")]
+            packet_compression: false,
+            compression_dictionary: None,
+            packer_config: PackerConfig::default(),
+            pack_stats: None,
+            metrics: None,
+            stats: ConnectionStats::default(),
+            invariant_checks: false,
+            capture: None,
+            incremental_packets: false,
+            min_packet_bytes: 0,
+            pending_packet: None,
+            target_packets_per_tick: None,
+            back_pressure: None,
+            ack_buffers: vec![],
+            payload_buffers: vec![],
+            buffer_sizing: BufferSizing::default(),
             // cursor: Vec::with_capacity(PACKET_BUFFER_CAPACITY),
             // acks: Vec::new(),
-
-            // we start with 1 extra byte for the final ChannelId = 0 that marks the end of the packet
             // prewritten_size: 0,
             // are we in the middle of writing a packet?
             // mid_packet: false,
         }
     }
 
-    // TODO: get the vec from a pool of preallocated buffers
-    fn get_new_buffer(&self) -> Payload {
-        Vec::with_capacity(MAX_PACKET_SIZE)
+    /// Read the per-channel settings (byte caps, etc.) from the [`ChannelRegistry`]
+    pub(crate) fn with_channel_registry(mut self, channel_registry: &ChannelRegistry) -> Self {
+        self.channel_byte_caps = channel_registry
+            .settings()
+            .filter_map(|(channel_id, settings)| {
+                settings.max_bytes_per_tick.map(|cap| (channel_id, cap))
+            })
+            .collect();
+        self.channel_reservations = channel_registry
+            .settings()
+            .filter(|(_, settings)| settings.reserved_bytes > 0)
+            .map(|(channel_id, settings)| (channel_id, settings.reserved_bytes))
+            .collect();
+        self.channel_weights = channel_registry
+            .settings()
+            .map(|(channel_id, settings)| (channel_id, settings.weight))
+            .collect();
+        self.reliable_channels = channel_registry
+            .settings()
+            .filter(|(_, settings)| settings.mode.is_reliable())
+            .map(|(channel_id, _)| channel_id)
+            .collect();
+        self.ordered_channels = channel_registry
+            .settings()
+            .filter(|(_, settings)| settings.mode.is_ordered())
+            .map(|(channel_id, _)| channel_id)
+            .collect();
+        self.encryption.set_channels(
+            channel_registry
+                .settings()
+                .filter(|(_, settings)| settings.encrypted)
+                .map(|(channel_id, _)| channel_id),
+        );
+        self
     }
 
-    /// Start building new packet, we start with an empty packet
-    /// that can write to a given channel
-    pub(crate) fn build_new_single_packet(
-        &mut self,
-        current_tick: Tick,
-    ) -> Result<(), SerializationError> {
-        let mut cursor = self.get_new_buffer();
+    /// Encrypt the messages of the encrypted channels of the [`ChannelRegistry`] with `encrypt`,
+    /// which is called with the block of messages of a channel and encrypts it in place.
+    ///
+    /// The cipher can make a block at most `tag_bytes` longer: that space is reserved in the
+    /// packet along with the channel, so packets never exceed the MTU.
+    pub(crate) fn with_channel_encryption(
+        mut self,
+        tag_bytes: usize,
+        encrypt: impl FnMut(ChannelId, &mut Vec<u8>) + Send + Sync + 'static,
+    ) -> Self {
+        self.encryption
+            .set_cipher(tag_bytes, Box::new(encrypt) as EncryptFn);
+        self
+    }
 
-        // write the header
-        let mut header = self
-            .header_manager
-            .prepare_send_packet_header(PacketType::Data);
-        // set the tick at which the packet will be sent
-        header.tick = current_tick;
-        header.to_bytes(&mut cursor)?;
-        self.current_packet = Some(Packet {
-            payload: cursor,
-            message_acks: vec![],
-            packet_id: header.packet_id,
-            prewritten_size: 0,
-        });
-        Ok(())
+    /// Number of bytes written before the messages of `channel_id` (the channel id, and the
+    /// overhead of the encryption if the channel is encrypted), not counting the number of messages
+    fn channel_header_len(&self, channel_id: ChannelId) -> usize {
+        self.channel_dictionary.len(channel_id) + self.encryption.overhead(channel_id, self.mtu)
     }
 
-    pub(crate) fn build_new_fragment_packet(
-        &mut self,
-        channel_id: NetId,
-        fragment_data: &FragmentData,
-        current_tick: Tick,
-    ) -> Result<(), SerializationError> {
-        let mut cursor = self.get_new_buffer();
-        // writer the header
-        let mut header = self
-            .header_manager
-            .prepare_send_packet_header(PacketType::DataFragment);
-        // set the tick at which the packet will be sent
-        header.tick = current_tick;
-        header.to_bytes(&mut cursor)?;
-        channel_id.to_bytes(&mut cursor)?;
-        fragment_data.to_bytes(&mut cursor)?;
-        self.current_packet = Some(Packet {
-            payload: cursor,
-            // TODO: reuse this vec allocation instead of newly allocating!
-            message_acks: vec![(
-                ChannelId::from(channel_id),
-                MessageAck {
-                    message_id: fragment_data.message_id,
-                    fragment_id: Some(fragment_data.fragment_id),
-                },
-            )],
-            packet_id: header.packet_id,
-            prewritten_size: 0,
-        });
-        Ok(())
+    /// Choose in which order the single messages of a channel are packed (by default the smallest
+    /// messages first). The ordered channels of the [`ChannelRegistry`] (see
+    /// [`Self::with_channel_registry`]) always keep their queue order.
+    pub(crate) fn with_packing_strategy(mut self, packing_strategy: PackingStrategy) -> Self {
+        self.packing_strategy = packing_strategy;
+        self
+    }
 
-        //
-        // let is_last_fragment = fragment_data.is_last_fragment();
-        // let packet = FragmentedPacket::new(channel_id, fragment_data);
-        //
-        // debug_assert!(packet.fragment.bytes.len() <= FRAGMENT_SIZE);
-        // if is_last_fragment {
-        //     packet.encode(&mut self.try_write_buffer).unwrap();
-        //     // reserve one extra bit for the continuation bit between fragment/single packet data
-        //     self.try_write_buffer.reserve_bits(1);
-        //
-        //     // let num_bits_written = self.try_write_buffer.num_bits_written();
-        //     // no need to reserve bits, since we already just wrote in the try buffer!
-        //     // self.try_write_buffer.reserve_bits(num_bits_written);
-        //     debug_assert!(!self.try_write_buffer.overflowed())
-        // }
-        //
-        // Packet {
-        //     header,
-        //     data: PacketData::Fragmented(packet),
-        // }
+    /// Order in which the single messages of `channel_id` are packed
+    fn packing_strategy(&self, channel_id: ChannelId) -> PackingStrategy {
+        if self.ordered_channels.contains(&channel_id) {
+            PackingStrategy::QueueOrder
+        } else {
+            self.packing_strategy
+        }
     }
 
-    pub fn finish_packet(&mut self) -> Packet {
-        let mut packet = self.current_packet.take().unwrap();
-        packet.payload.shrink_to_fit();
-        // TODO: should we use bytes so this clone is cheap?
-        packet
+    /// Choose whether the messages of a channel are all written before the next channel (the
+    /// default), or if the channels take turns (see [`ScheduleMode`])
+    pub(crate) fn with_schedule_mode(mut self, schedule_mode: ScheduleMode) -> Self {
+        self.schedule_mode = schedule_mode;
+        self
     }
 
-    /// Pack messages into packets
+    /// Sort the single messages of each channel in packing order, and with
+    /// [`ScheduleMode::RoundRobin`] split the channels so that they take turns.
     ///
-    /// In general the strategy is:
-    /// - sort the single data messages from smallest to largest
-    /// - write the fragment data first. Big fragments take the entire packet. Small fragments have
-    ///   some room to spare for small messages
-    #[cfg_attr(feature = "trace", instrument(level = Level::INFO, skip_all))]
-    pub fn build_packets(
-        &mut self,
-        current_tick: Tick,
-        mut single_data: Vec<(ChannelId, VecDeque<SingleData>)>,
-        fragment_data: Vec<(ChannelId, VecDeque<FragmentData>)>,
-    ) -> Result<Vec<Packet>, SerializationError> {
-        let mut packets: Vec<Packet> = vec![];
-
-        // indices in the main vec
-        let mut single_data_idx = 0;
-
-        for (_, single_messages) in single_data.iter_mut() {
-            // sort from smallest to largest each array of small messages
-            single_messages
-                .make_contiguous()
-                .sort_by_key(|message| message.bytes.len());
+    /// With the round-robin schedule, a channel can appear several times in `single_data`. The
+    /// channels only take turns with the channels of the same reliability if the reliability
+    /// boundary is enabled, since they cannot share a packet with the other ones.
+    ///
+    /// Returns the channels whose messages are not packed in the order in which they were queued.
+    fn schedule(
+        &self,
+        single_data: &mut Vec<(ChannelId, VecDeque<SingleData>)>,
+    ) -> HashSet<ChannelId> {
+        let mut reordered = HashSet::default();
+        for (channel_id, single_messages) in single_data.iter_mut() {
+            // sort each array of small messages in the order in which they are packed
+            if self
+                .packing_strategy(*channel_id)
+                .sort(single_messages.make_contiguous())
+            {
+                reordered.insert(*channel_id);
+            }
         }
-
-        // try to fill the packet with fragment messages first
-        for (channel_id, mut fragment_messages) in fragment_data.into_iter() {
-            while let Some(fragment_data) = fragment_messages.pop_front() {
-                debug_assert!(fragment_data.bytes.len() <= FRAGMENT_SIZE);
-                self.build_new_fragment_packet(channel_id, &fragment_data, current_tick)?;
-                if !fragment_data.is_last_fragment() {
-                    // big fragment, write packet immediately
-                    packets.push(self.finish_packet());
-                } else {
-                    let mut packet = self.current_packet.take().unwrap();
-                    // it's a smaller fragment, fill it with small messages
-                    'out: while single_data_idx < single_data.len() {
-                        // if we don't even have space for a new channel, return the packet immediately
-                        if !packet.can_fit_channel(channel_id) {
-                            break;
-                        }
-
-                        let (channel_id, single_messages) = &mut single_data[single_data_idx];
-                        // number of messages for this channel that we will write
-                        // (we wait until we know the full number, because we want to write that)
-                        let mut num_messages = 0;
-                        // fill with messages from the current channel
-                        loop {
-                            // no more messages to send in this channel, try to fill with messages from the next channels
-                            if num_messages == single_messages.len() {
-                                Self::write_single_messages(
-                                    &mut packet,
-                                    single_messages,
-                                    &mut num_messages,
-                                    *channel_id,
-                                )?;
-                                single_data_idx += 1;
-                                break;
-                            }
-
-                            if packet.can_fit(single_messages[num_messages].len()) {
-                                packet.prewritten_size += single_messages[num_messages].len();
-                                num_messages += 1;
-                            } else {
-                                // can't add any more messages (since we sorted messages from smallest to largest)
-                                // finish packet and go back to trying to write fragment messages
-                                Self::write_single_messages(
-                                    &mut packet,
-                                    single_messages,
-                                    &mut num_messages,
-                                    *channel_id,
-                                )?;
-                                break 'out;
-                            }
+        if self.schedule_mode == ScheduleMode::Grouped {
+            return reordered;
+        }
+        let group = |channel_id: &ChannelId| {
+            (
+                self.reliability_boundary && self.reliable_channels.contains(channel_id),
+                self.isolate_channels.then_some(*channel_id),
+            )
+        };
+        let mut channels = std::mem::take(single_data).into_iter().peekable();
+        while let Some(first) = channels.next() {
+            // the consecutive channels that can share a packet take turns
+            let first_group = group(&first.0);
+            let mut turns = vec![first];
+            while let Some(next) =
+                channels.next_if(|(channel_id, _)| group(channel_id) == first_group)
+            {
+                turns.push(next);
+            }
+            while turns.iter().any(|(_, messages)| !messages.is_empty()) {
+                for (channel_id, messages) in turns.iter_mut() {
+                    let Some(message) = messages.pop_front() else {
+                        continue;
+                    };
+                    match single_data.last_mut() {
+                        // the channel is the only one left with messages
+                        Some((last_channel_id, last_messages)) if last_channel_id == channel_id => {
+                            last_messages.push_back(message)
                         }
+                        _ => single_data.push((*channel_id, VecDeque::from([message]))),
                     }
-                    // no more single messages to send, finish the fragment packet
-                    self.current_packet = Some(packet);
-                    packets.push(self.finish_packet());
                 }
             }
         }
+        reordered
+    }
 
-        debug_assert!(self.current_packet.is_none());
-
-        // all fragment messages have been written, now write small messages
-        'out: while single_data_idx < single_data.len() {
-            let (channel_id, single_messages) = &mut single_data[single_data_idx];
-            // start a new packet if we aren't already writing one
-            if self.current_packet.is_none() {
-                self.build_new_single_packet(current_tick)?;
-            }
+    /// When all the messages of a channel written in a packet have the same length, write the
+    /// length only once instead of before each message.
+    ///
+    /// The receiver detects these blocks on its own, so the option only needs to be enabled on the
+    /// sender.
+    pub(crate) fn with_fixed_length_blocks(mut self, fixed_length_blocks: bool) -> Self {
+        self.fixed_length_blocks = fixed_length_blocks;
+        self
+    }
 
-            let mut packet = self.current_packet.take().unwrap();
-            // we need to call this to preassign the channel_id
-            if !packet.can_fit_channel(*channel_id) {
-                // can't add any more messages (since we sorted messages from smallest to largest)
-                // finish packet and go back to trying to write fragment messages
-                self.current_packet = Some(packet);
-                packets.push(self.finish_packet());
-                continue 'out;
-            }
-            // number of messages for this channel that we will write
-            // (we wait until we know the full number, because we want to write that)
-            let mut num_messages = 0;
-            // fill with messages from the current channel
-            loop {
-                // no more messages to send in this channel, try to fill with messages from the next channels
-                if num_messages == single_messages.len() {
-                    Self::write_single_messages(
-                        &mut packet,
-                        single_messages,
-                        &mut num_messages,
-                        *channel_id,
-                    )?;
-                    // we make sure we keep writing the current packet
-                    self.current_packet = Some(packet);
-                    single_data_idx += 1;
-                    break;
-                }
+    /// Collapse the consecutive single messages of an unreliable channel that have no
+    /// [`MessageId`](crate::packet::message::MessageId) and the same bytes into one message.
+    ///
+    /// Nothing is lost for the receiver of an unreliable channel: each copy could have been dropped
+    /// anyway. Messages with an id are never merged.
+    pub(crate) fn with_message_coalescing(mut self, message_coalescing: bool) -> Self {
+        self.message_coalescing = message_coalescing;
+        self
+    }
 
-                if packet.can_fit(single_messages[num_messages].len()) {
-                    packet.prewritten_size += single_messages[num_messages].len();
-                    num_messages += 1;
-                } else {
-                    // can't add any more messages (since we sorted messages from smallest to largest)
-                    // finish packet and go back to trying to write fragment messages
-                    Self::write_single_messages(
-                        &mut packet,
-                        single_messages,
-                        &mut num_messages,
-                        *channel_id,
-                    )?;
-                    self.current_packet = Some(packet);
-                    packets.push(self.finish_packet());
-                    continue 'out;
-                }
+    /// Remove the messages that are identical to the previous message of their channel
+    /// (see [`Self::with_message_coalescing`])
+    fn coalesce_messages(&self, single_data: &mut [(ChannelId, VecDeque<SingleData>)]) {
+        if !self.message_coalescing {
+            return;
+        }
+        for (channel_id, messages) in single_data.iter_mut() {
+            if self.reliable_channels.contains(channel_id) {
+                continue;
             }
+            let mut previous: Option<(u8, Bytes)> = None;
+            messages.retain(|message| {
+                let key = (message.sub_channel, message.bytes.clone());
+                let duplicate = message.id.is_none() && previous.as_ref() == Some(&key);
+                previous = message.id.is_none().then_some(key);
+                !duplicate
+            });
         }
+    }
 
-        // if we had a packet we were working on, push it
-        if self.current_packet.is_some() {
-            packets.push(self.finish_packet());
-        }
-        Ok(packets)
+    /// Set the maximum number of bytes that a channel can write in a single call to `build_packets`
+    pub(crate) fn with_channel_byte_cap(mut self, channel_id: ChannelId, cap: usize) -> Self {
+        self.channel_byte_caps.insert(channel_id, cap);
+        self
     }
 
-    /// Helper function to fill the current packet with single data message from the current channel
-    fn write_single_messages(
-        packet: &mut Packet,
-        messages: &mut VecDeque<SingleData>,
-        num_messages: &mut usize,
-        channel_id: ChannelId,
-    ) -> Result<(), SerializationError> {
-        packet.prewritten_size = packet
-            .prewritten_size
-            .checked_sub(varint_len(channel_id as u64) + 1)
-            .ok_or(SerializationError::SubstractionOverflow)?;
-        if *num_messages > 0 {
-            channel_id.to_bytes(&mut packet.payload)?;
-            // write the number of messages for the current channel
-            packet.payload.write_u8(*num_messages as u8).unwrap();
-            // write the messages
-            for _ in 0..*num_messages {
-                // TODO: deal with error
-                let message = messages.pop_front().unwrap();
-                message.to_bytes(&mut packet.payload).unwrap();
-                packet.prewritten_size = packet
-                    .prewritten_size
-                    .checked_sub(message.len())
-                    .ok_or(SerializationError::SubstractionOverflow)?;
-                // only send a MessageAck when the message has an id (otherwise we don't expect an ack)
-                if let Some(id) = message.id {
-                    packet.message_acks.push((
-                        channel_id,
-                        MessageAck {
-                            message_id: id,
-                            fragment_id: None,
-                        },
-                    ));
-                }
-            }
-            *num_messages = 0;
-        }
-        Ok(())
+    /// Use a [`ChannelIdDictionary`] to write channel ids. The remote peer must use the same dictionary
+    pub(crate) fn with_channel_dictionary(
+        mut self,
+        channel_dictionary: ChannelIdDictionary,
+    ) -> Self {
+        self.channel_dictionary = channel_dictionary;
+        self
     }
 
-    // /// Uses multiple exponential searches to fill a packet. Has a good worst case runtime and doesn't
-    // /// create any extraneous extension packets.
-    // fn pack_multiple_exponential(mut messages: &[Message]) -> Vec<Packet> {
-    //     /// A Vec<u8> prefixed by its length as a u32. Each [`Packet`] contains 1 or more [`Section`]s.
-    //     struct Section(Vec<u8>);
-    //     impl Section {
-    //         fn len(&self) -> usize {
-    //             self.0.len() + std::mem::size_of::<u32>()
-    //         }
-    //         fn write(&self, out: &mut Vec<u8>) {
-    //             out.reserve(self.len());
+    /// Write a checksum at the end of each packet, so that the remote peer can detect corrupted
+    /// packets. The remote peer must also enable the checksum.
+    pub(crate) fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Pad each packet with zeros up to `pad_to` bytes (clamped to the MTU), so that all packets
+    /// have the same size on the wire. The length of the content is written after the header so
+    /// that the remote peer can strip the padding; the remote peer must use the same `pad_to`.
+    pub(crate) fn with_pad_to(mut self, pad_to: Option<usize>) -> Self {
+        self.pad_to = pad_to;
+        self
+    }
+
+    /// Write the length of each finished packet before it (as a varint), for transports that send
+    /// a stream of bytes instead of datagrams. The length is part of the MTU. The remote peer
+    /// splits the stream back into packets with a
+    /// [`StreamPacketReader`](crate::packet::stream_reader::StreamPacketReader).
+    ///
+    /// The payload of the returned packets starts with the length, so they can't be merged or
+    /// parsed before it is removed.
+    pub(crate) fn with_stream_framing(mut self, stream_framing: bool) -> Self {
+        self.stream_framing = stream_framing;
+        self
+    }
+
+    /// Try to compress the whole packet (after the header) once it is finished.
+    /// The compressed packet is only sent if it is smaller; a flag in the header indicates
+    /// whether the packet is compressed.
+    #[cfg(feature = "lz4")]
+    pub(crate) fn with_packet_compression(mut self, packet_compression: bool) -> Self {
+        self.packet_compression = packet_compression;
+        self
+    }
+
+    /// Compress the packets with `compression_dictionary` (when packet compression is enabled),
+    /// which the remote peer must also use to decompress them. This compresses much better
+    /// than each packet on its own when the packets look like the dictionary.
+    #[cfg(feature = "lz4")]
+    pub(crate) fn with_compression_dictionary(
+        mut self,
+        compression_dictionary: CompressionDictionary,
+    ) -> Self {
+        self.compression_dictionary = Some(compression_dictionary);
+        self
+    }
+
+    /// Set the maximum number of bytes in a packet (by default [`MAX_PACKET_SIZE`]), depending on
+    /// what the transport supports.
+    ///
+    /// The channels must fragment their messages with the matching [`fragment_size`].
+    /// Returns an error if the packets would be too small to hold a fragment.
+    pub(crate) fn with_mtu(mut self, mtu: usize) -> Result<Self, PacketError> {
+        self.set_mtu(mtu)?;
+        Ok(self)
+    }
+
+    /// Change the maximum number of bytes in a packet during the connection, for example when the
+    /// transport reports that the packets are too big for the path to the remote peer.
+    ///
+    /// The packets built from now on respect the new MTU. A packet kept open by the last call to
+    /// `build_packets` (see [`Self::with_incremental_packets`]) is finished as it is by the next
+    /// call. The channels must fragment the messages they queue from now on with the matching
+    /// [`fragment_size`] (see [`MessageManager::set_mtu`](crate::packet::message_manager::MessageManager::set_mtu)).
+    ///
+    /// Returns an error, and keeps the current MTU, if the packets would be too small to hold a
+    /// fragment, or if the MTU is lowered while fragments deferred by the last call to
+    /// `build_packets` are still queued: they were built for the current MTU and would not fit.
+    pub(crate) fn set_mtu(&mut self, mtu: usize) -> Result<(), PacketError> {
+        if mtu < self.mtu
+            && self
+                .deferred_data
+                .values()
+                .any(|(_, fragments)| !fragments.is_empty())
+        {
+            return Err(PacketError::FragmentsQueued { mtu });
+        }
+        let previous_mtu = std::mem::replace(&mut self.mtu, mtu);
+        let min = min_mtu(self.reserved_len() + self.reserved_footer_bytes);
+        if mtu < min {
+            self.mtu = previous_mtu;
+            return Err(PacketError::MtuTooSmall { mtu, min });
+        }
+        Ok(())
+    }
+
+    /// Size the buffers of the packets and the pool of recycled buffers (see [`BufferSizing`])
+    pub(crate) fn with_buffer_sizing(mut self, buffer_sizing: BufferSizing) -> Self {
+        self.buffer_sizing = buffer_sizing;
+        self.ack_buffers.truncate(buffer_sizing.max);
+        self.payload_buffers.truncate(buffer_sizing.max);
+        self
+    }
+
+    /// Write the packet headers with this layout (see [`HeaderLayout`]). The remote peer can
+    /// read both layouts.
+    pub(crate) fn with_header_layout(mut self, header_layout: HeaderLayout) -> Self {
+        self.header_manager.set_header_layout(header_layout);
+        self
+    }
+
+    /// Leave `reserved_footer_bytes` free at the end of every packet, for integrations that append
+    /// their own trailer (an encryption tag, a signature, ...) to the packet once it is built.
+    ///
+    /// Packets never exceed the MTU minus the footer. The channels must fragment their messages
+    /// with the [`fragment_size`] of that smaller MTU.
+    pub(crate) fn with_reserved_footer_bytes(mut self, reserved_footer_bytes: usize) -> Self {
+        self.reserved_footer_bytes = reserved_footer_bytes;
+        self
+    }
+
+    /// Count the packets, bytes and messages written (see [`PacketBuilderMetrics`])
+    pub(crate) fn with_metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics.then(PacketBuilderMetrics::default);
+        self
+    }
+
+    /// Return the metrics accumulated since the last call, and reset them.
+    ///
+    /// Returns empty metrics if the metrics are not enabled.
+    pub(crate) fn take_metrics(&mut self) -> PacketBuilderMetrics {
+        self.metrics
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Counters about the packets sent and acked since the connection started
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            packets_acked: self.header_manager.packets_acked,
+            packets_lost: self.header_manager.packets_lost,
+            rtt: self.header_manager.rtt,
+            ..self.stats.clone()
+        }
+    }
+
+    /// Panic if a finished packet breaks one of the packing invariants:
+    /// - the payload fits in the MTU
+    /// - all the space that was reserved in the packet has been written
+    /// - the packet doesn't contain the same message ack twice
+    ///
+    /// This is expensive, and is meant to catch regressions in tests.
+    pub(crate) fn with_invariant_checks(mut self, invariant_checks: bool) -> Self {
+        self.invariant_checks = invariant_checks;
+        self
+    }
+
+    /// Keep the last packet of each `build_packets` call open, so that the messages passed to the
+    /// next call can be written in the same packet (for senders whose messages trickle in during
+    /// a tick).
+    ///
+    /// The open packet is finished when the next call is for a different tick (a packet never
+    /// mixes ticks) or contains fragments, or when [`Self::flush_current`] is called.
+    pub(crate) fn with_incremental_packets(mut self, incremental_packets: bool) -> Self {
+        self.incremental_packets = incremental_packets;
+        self
+    }
+
+    /// Keep the last packet of a `build_packets` call open while it has fewer than
+    /// `min_packet_bytes` bytes, so that the next call for the same tick fills it instead of
+    /// sending a tiny datagram. This trades a bit of latency for less per-packet overhead.
+    ///
+    /// The open packet is finished like with [`Self::with_incremental_packets`]: at the latest
+    /// when the tick changes.
+    pub(crate) fn with_min_packet_bytes(mut self, min_packet_bytes: usize) -> Self {
+        self.min_packet_bytes = min_packet_bytes;
+        self
+    }
+
+    /// Drop the packets in progress and the state of the connection (packet ids, acks, deferred
+    /// messages, channel credits, metrics and stats), so that the builder can be reused for a new
+    /// connection. The settings of the builder are kept.
+    pub(crate) fn reset(&mut self) {
+        let packets = self
+            .current_packet
+            .take()
+            .into_iter()
+            .chain(self.pending_packet.take().map(|(packet, _)| packet));
+        for packet in packets {
+            self.recycle_acks(packet.message_acks);
+        }
+        self.header_manager.reset();
+        self.deferred_data.clear();
+        self.channel_credits.clear();
+        self.last_served.clear();
+        self.num_builds = 0;
+        if let Some(pack_stats) = &mut self.pack_stats {
+            *pack_stats = PackStats::default();
+        }
+        if let Some(metrics) = &mut self.metrics {
+            *metrics = PacketBuilderMetrics::default();
+        }
+        self.stats = ConnectionStats::default();
+        self.back_pressure = None;
+    }
+
+    /// Returns true if `message_len` more bytes fit in the packet kept open by the last call to
+    /// `build_packets` (see [`Self::with_incremental_packets`]), so that a message written now
+    /// would not start a new packet.
+    ///
+    /// `message_len` must include everything that the message adds to the packet: the message
+    /// with its id and length ([`SingleData::len`]), and the channel id and number of messages,
+    /// since the next call to `build_packets` starts a new channel section. Returns false if no
+    /// packet is open.
+    pub(crate) fn would_fit(&self, message_len: usize) -> bool {
+        self.pending_packet
+            .as_ref()
+            .is_some_and(|(packet, _)| packet.can_fit(message_len))
+    }
+
+    /// Finish the packet kept open by the last call to `build_packets`, if any
+    pub(crate) fn flush_current(&mut self) -> Result<Option<Packet>, SerializationError> {
+        let Some((packet, _)) = self.take_open_packet()? else {
+            return Ok(None);
+        };
+        self.current_packet = Some(packet);
+        Ok(Some(self.finish_packet_with_reason(
+            PacketFinishReason::NoMoreMessages,
+        )))
+    }
+
+    /// Keep `packet` open for the next call to `build_packets`.
+    ///
+    /// The packet only takes its packet id and the acks of its header when it is reopened (see
+    /// [`Self::take_open_packet`]), so its header is released now, and room is reserved for a
+    /// header of any length. Returns the packet if it has no room left for that.
+    fn keep_open(&mut self, mut packet: Packet, channel_id: ChannelId) -> Option<Packet> {
+        let header_len = Packet::peek_header(&packet.payload).map_or(MAX_HEADER_BYTES, |h| h.len());
+        let header_room = MAX_HEADER_BYTES - header_len;
+        if packet.remaining_bytes() < header_room {
+            return Some(packet);
+        }
+        packet.prewritten_size += header_room;
+        self.header_manager.release_sent_packet(packet.packet_id);
+        self.pending_packet = Some((packet, channel_id));
+        None
+    }
+
+    /// Take the packet kept open by the last call to `build_packets`, and write a new header
+    /// for it, with the next packet id and the current acks (its tick is kept)
+    fn take_open_packet(&mut self) -> Result<Option<(Packet, ChannelId)>, SerializationError> {
+        let Some((mut packet, channel_id)) = self.pending_packet.take() else {
+            return Ok(None);
+        };
+        let previous_header = Packet::peek_header(&packet.payload)?;
+        let previous_len = previous_header.len();
+        let mut header = self
+            .header_manager
+            .prepare_send_packet_header(PacketType::Data);
+        header.tick = previous_header.tick;
+        let mut header_bytes = Vec::with_capacity(MAX_HEADER_BYTES);
+        header.to_bytes(&mut header_bytes)?;
+        if PacketHeader::has_sorted_flag(&packet.payload) {
+            PacketHeader::set_sorted_flag(&mut header_bytes);
+        }
+        packet.payload.splice(..previous_len, header_bytes);
+        packet.prewritten_size -= MAX_HEADER_BYTES - previous_len;
+        packet.packet_id = header.packet_id;
+        Ok(Some((packet, channel_id)))
+    }
+
+    /// Call `capture` with every finished packet (for example to write them with a
+    /// [`PcapLikeWriter`](crate::packet::capture::PcapLikeWriter)), or stop capturing if `None`
+    pub(crate) fn set_capture(&mut self, capture: Option<Box<dyn FnMut(&Packet) + Send + Sync>>) {
+        self.capture = capture.map(PacketCapture);
+    }
+
+    fn check_invariants(&self, packet: &Packet) {
+        assert!(
+            packet.payload.len() <= self.content_mtu(),
+            "packet of {} bytes is bigger than the mtu ({}) minus the reserved footer",
+            packet.payload.len(),
+            self.mtu
+        );
+        assert_eq!(
+            packet.prewritten_size, 0,
+            "reserved bytes were not written in the packet"
+        );
+        let mut acks = HashSet::with_capacity(packet.message_acks.len());
+        for ack in packet.message_acks.iter() {
+            assert!(acks.insert(ack), "duplicate message ack {ack:?} in packet");
+        }
+    }
+
+    /// Apply the options of the [`WireConfig`]
+    ///
+    /// Packet compression is only enabled if the `lz4` feature is enabled. Returns an error if
+    /// the config is not valid (see [`WireConfig::validate`]).
+    pub(crate) fn with_wire_config(mut self, wire_config: WireConfig) -> Result<Self, PacketError> {
+        wire_config.validate()?;
+        self.mtu = wire_config.mtu;
+        self.checksum = wire_config.checksum;
+        self.max_messages_per_packet = wire_config.max_messages_per_packet.max(1);
+        self.pad_to = wire_config.pad_to;
+        #[cfg(feature = "lz4")]
+        {
+            self.packet_compression = wire_config.packet_compression;
+        }
+        Ok(self)
+    }
+
+    /// The framing of the packets finished by this builder (see [`Packet::set_tick`])
+    pub(crate) fn framing(&self) -> PacketFraming {
+        PacketFraming {
+            checksum: self.checksum,
+            stream_framing: self.stream_framing,
+        }
+    }
+
+    /// The [`WireConfig`] used to build packets
+    pub(crate) fn wire_config(&self) -> WireConfig {
+        WireConfig {
+            mtu: self.mtu,
+            #[cfg(feature = "lz4")]
+            packet_compression: self.packet_compression,
+            #[cfg(not(feature = "lz4"))]
+            packet_compression: false,
+            checksum: self.checksum,
+            max_messages_per_packet: self.max_messages_per_packet,
+            pad_to: self.pad_to,
+        }
+    }
+
+    /// Maximum number of messages (single messages and fragments) in a packet
+    pub(crate) fn max_messages_per_packet(&self) -> usize {
+        self.max_messages_per_packet
+    }
+
+    /// Maximum number of bytes in a packet
+    pub(crate) fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Maximum number of bytes written in a packet, once the reserved footer is left free
+    fn content_mtu(&self) -> usize {
+        self.mtu.saturating_sub(self.reserved_footer_bytes)
+    }
+
+    /// Size limits of the packets written by this builder
+    pub(crate) fn packet_limits(&self) -> Result<PacketLimits, PacketError> {
+        self.wire_config().packet_limits()
+    }
+
+    /// Number of bytes that still fit in the packet currently being written, or in the packet kept
+    /// open by the last call to `build_packets` (see [`Self::with_incremental_packets`]), or None
+    /// if no packet is in progress.
+    ///
+    /// The bytes that are reserved but not written yet (for the checksum, for the header of the
+    /// open packet, or for the channel id and number of messages of the current channel) are not
+    /// available.
+    pub(crate) fn current_remaining_bytes(&self) -> Option<usize> {
+        self.current_packet
+            .as_ref()
+            .or(self.pending_packet.as_ref().map(|(packet, _)| packet))
+            .map(Packet::remaining_bytes)
+    }
+
+    /// Never write messages from a reliable channel and from an unreliable channel in the same packet,
+    /// so that the receiver can dispatch each packet to a single pipeline.
+    ///
+    /// The reliable channels are read from the [`ChannelRegistry`] (see [`Self::with_channel_registry`])
+    pub(crate) fn with_reliability_boundary(mut self, reliability_boundary: bool) -> Self {
+        self.reliability_boundary = reliability_boundary;
+        self
+    }
+
+    /// Choose which single messages can fill the space left in the fragment packets (see
+    /// [`FragmentTailPacking`]). The reliable channels are read from the [`ChannelRegistry`]
+    pub(crate) fn with_fragment_tail_packing(
+        mut self,
+        fragment_tail_packing: FragmentTailPacking,
+    ) -> Self {
+        self.fragment_tail_packing = fragment_tail_packing;
+        self
+    }
+
+    /// Choose how the messages that have the same [`MessageId`] as another message of their
+    /// channel in a call to `build_packets` are handled (see [`DuplicateMessageIds`])
+    pub(crate) fn with_duplicate_message_ids(
+        mut self,
+        duplicate_message_ids: DuplicateMessageIds,
+    ) -> Self {
+        self.duplicate_message_ids = duplicate_message_ids;
+        self
+    }
+
+    /// Detect the single messages that have the same id as a previous message of their channel,
+    /// and remove them or return an error (see [`DuplicateMessageIds`])
+    fn check_message_ids(
+        &self,
+        single_data: &mut [(ChannelId, VecDeque<SingleData>)],
+    ) -> Result<(), SerializationError> {
+        if self.duplicate_message_ids == DuplicateMessageIds::Allow {
+            return Ok(());
+        }
+        let mut ids = HashSet::default();
+        for (channel_id, messages) in single_data.iter_mut() {
+            ids.clear();
+            if self.duplicate_message_ids == DuplicateMessageIds::Error {
+                if let Some(message_id) = messages
+                    .iter()
+                    .filter_map(|message| message.id)
+                    .find(|id| !ids.insert(*id))
+                {
+                    return Err(SerializationError::DuplicateMessageId {
+                        channel_id: *channel_id,
+                        message_id: message_id.0,
+                    });
+                }
+            } else {
+                messages.retain(|message| message.id.map_or(true, |id| ids.insert(id)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the messages of each channel in their own packets, so that each packet of a capture
+    /// can be attributed to a single channel. Meant for debugging: it wastes the space that other
+    /// channels could have used.
+    pub(crate) fn set_isolate_channels(&mut self, isolate_channels: bool) {
+        self.isolate_channels = isolate_channels;
+    }
+
+    /// Seed the randomized packing decisions (see [`PackerConfig`])
+    pub(crate) fn with_packer_config(mut self, packer_config: PackerConfig) -> Self {
+        self.packer_config = packer_config;
+        self
+    }
+
+    /// Seed the randomized packing decisions. Two builders with the same seed produce the same
+    /// packets from the same messages.
+    pub(crate) fn with_seed(self, seed: u64) -> Self {
+        self.with_packer_config(PackerConfig { seed })
+    }
+
+    /// Record how many bytes each message contributed to the packets (see [`PackStats`])
+    pub(crate) fn with_pack_stats(mut self, pack_stats: bool) -> Self {
+        self.pack_stats = pack_stats.then(PackStats::default);
+        self
+    }
+
+    /// Statistics about the last call to `build_packets`, if they are enabled
+    pub(crate) fn pack_stats(&self) -> Option<&PackStats> {
+        self.pack_stats.as_ref()
+    }
+
+    /// Compare the number of packets produced by each call to `build_packets` with the number of
+    /// packets that the caller can send per tick (see [`Self::back_pressure`]).
+    ///
+    /// The packets are still all produced: the signal only tells the application to slow down.
+    pub(crate) fn with_target_packets_per_tick(
+        mut self,
+        target_packets_per_tick: Option<usize>,
+    ) -> Self {
+        self.target_packets_per_tick = target_packets_per_tick;
+        self
+    }
+
+    /// Back-pressure of the last call to `build_packets`, if a target number of packets per tick
+    /// is set
+    pub(crate) fn back_pressure(&self) -> Option<BackPressure> {
+        self.back_pressure
+    }
+
+    /// Returns why the messages of these two channels cannot be written in the same packet, if
+    /// they cannot
+    fn channel_boundary(
+        &self,
+        channel_id: ChannelId,
+        other_channel_id: ChannelId,
+    ) -> Option<PacketFinishReason> {
+        if self.isolate_channels && channel_id != other_channel_id {
+            return Some(PacketFinishReason::ChannelIsolation);
+        }
+        (self.reliability_boundary
+            && self.reliable_channels.contains(&channel_id)
+                != self.reliable_channels.contains(&other_channel_id))
+        .then_some(PacketFinishReason::ReliabilityBoundary)
+    }
+
+    /// Returns why the messages of `channel_id` cannot be written in a fragment packet of
+    /// `fragment_channel_id`, if they cannot
+    fn fragment_tail_boundary(
+        &self,
+        channel_id: ChannelId,
+        fragment_channel_id: ChannelId,
+    ) -> Option<PacketFinishReason> {
+        let tail_packing = match self.fragment_tail_packing {
+            FragmentTailPacking::Never => false,
+            FragmentTailPacking::UnreliableOnly => !self.reliable_channels.contains(&channel_id),
+            FragmentTailPacking::Always => true,
+        };
+        if !tail_packing {
+            return Some(PacketFinishReason::FragmentTailPacking);
+        }
+        self.channel_boundary(channel_id, fragment_channel_id)
+    }
+
+    /// True if `channel_id` still has single messages to write
+    fn has_messages(
+        single_data: &[(ChannelId, VecDeque<SingleData>)],
+        channel_id: ChannelId,
+    ) -> bool {
+        single_data
+            .iter()
+            .any(|(id, messages)| *id == channel_id && !messages.is_empty())
+    }
+
+    /// Number of bytes that `channel_id` must leave free in a packet for the channels with a
+    /// reservation for which `has_messages` is true (the reserved channels themselves don't
+    /// leave anything free)
+    fn held_bytes(&self, channel_id: ChannelId, has_messages: impl Fn(ChannelId) -> bool) -> usize {
+        if self.channel_reservations.contains_key(&channel_id) {
+            return 0;
+        }
+        self.channel_reservations
+            .iter()
+            .filter(|(reserved_channel_id, _)| has_messages(**reserved_channel_id))
+            .map(|(_, reserved_bytes)| reserved_bytes)
+            .sum()
+    }
+
+    /// Before `packet` is finished, write in it the messages of the channels with a reservation
+    /// that come after `single_data_idx` in packing order, so that they use the bytes that the
+    /// previous channels left free for them.
+    fn fill_reserved_channels(
+        &mut self,
+        packet: &mut Packet,
+        single_data: &mut [(ChannelId, VecDeque<SingleData>)],
+        single_data_idx: usize,
+        packet_channel_id: Option<ChannelId>,
+        reordered_channels: &HashSet<ChannelId>,
+    ) -> Result<(), SerializationError> {
+        if self.channel_reservations.is_empty() {
+            return Ok(());
+        }
+        for (channel_id, single_messages) in single_data.iter_mut().skip(single_data_idx + 1) {
+            if single_messages.is_empty()
+                || !self.channel_reservations.contains_key(channel_id)
+                || packet_channel_id.is_some_and(|packet_channel_id| {
+                    self.channel_boundary(*channel_id, packet_channel_id)
+                        .is_some()
+                })
+                || !packet.can_fit_channel(self.channel_header_len(*channel_id))
+            {
+                continue;
+            }
+            let mut num_messages = 0;
+            while num_messages < single_messages.len()
+                && packet.written_messages + num_messages < self.max_messages_per_packet
+            {
+                let added_len =
+                    Self::added_message_len(single_messages[num_messages].len(), num_messages);
+                if !packet.can_fit(added_len) {
+                    break;
+                }
+                packet.prewritten_size += added_len;
+                num_messages += 1;
+            }
+            Self::write_single_messages(
+                packet,
+                single_messages,
+                &mut num_messages,
+                *channel_id,
+                &self.channel_dictionary,
+                &mut self.encryption,
+                &mut self.pack_stats,
+                &mut self.metrics,
+                self.fixed_length_blocks,
+                reordered_channels.contains(channel_id),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Set the weight of a channel (see [`ChannelSettings::weight`](crate::prelude::ChannelSettings::weight))
+    pub(crate) fn with_channel_weight(mut self, channel_id: ChannelId, weight: f32) -> Self {
+        self.channel_weights.insert(channel_id, weight);
+        self
+    }
+
+    /// Order in which the channels that have the same credit are written (see [`TieBreak`])
+    pub(crate) fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    fn channel_weight(&self, channel_id: ChannelId) -> f32 {
+        self.channel_weights
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    fn channel_credit(&self, channel_id: ChannelId) -> f32 {
+        self.channel_credits
+            .get(&channel_id)
+            .copied()
+            .unwrap_or_default()
+            + self.channel_weight(channel_id)
+    }
+
+    /// Write first the channels that accumulated the most credit, and order the channels that
+    /// have the same credit with the [`TieBreak`]
+    fn sort_by_credit(
+        &self,
+        single_data: &mut [(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &mut [(ChannelId, VecDeque<FragmentData>)],
+    ) {
+        let credit = |channel_id: &ChannelId| self.channel_credit(*channel_id);
+        let tie_key = |channel_id: &ChannelId| match self.tie_break {
+            TieBreak::ChannelId | TieBreak::InputOrder => 0,
+            // channels that were never written come first
+            TieBreak::LeastRecentlyServed => self
+                .last_served
+                .get(channel_id)
+                .map_or(0, |num_builds| num_builds + 1),
+            TieBreak::Random => splitmix64(
+                self.packer_config.seed ^ splitmix64(self.num_builds) ^ u64::from(*channel_id),
+            ),
+        };
+        let order = |a: &ChannelId, b: &ChannelId| {
+            credit(b)
+                .total_cmp(&credit(a))
+                .then_with(|| tie_key(a).cmp(&tie_key(b)))
+                .then_with(|| {
+                    // the sort is stable, so equal channels keep the input order
+                    if self.tie_break == TieBreak::InputOrder {
+                        Ordering::Equal
+                    } else {
+                        a.cmp(b)
+                    }
+                })
+        };
+        single_data.sort_by(|(a, _), (b, _)| order(a, b));
+        fragment_data.sort_by(|(a, _), (b, _)| order(a, b));
+    }
+
+    /// Remember which channel is written first among the channels that have the same credit, so
+    /// that it goes after them the next time (see [`TieBreak::LeastRecentlyServed`])
+    fn record_served(
+        &mut self,
+        single_data: &[(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &[(ChannelId, VecDeque<FragmentData>)],
+    ) {
+        if self.tie_break != TieBreak::LeastRecentlyServed {
+            return;
+        }
+        let single_channels: Vec<_> = single_data.iter().map(|(id, _)| *id).collect();
+        let fragment_channels: Vec<_> = fragment_data.iter().map(|(id, _)| *id).collect();
+        let mut served = Vec::new();
+        for channels in [single_channels, fragment_channels] {
+            // the channels are sorted by credit, so each run of equal credit is a tie
+            let mut previous_credit = None;
+            for channel_id in channels {
+                let credit = self.channel_credit(channel_id);
+                if previous_credit != Some(credit) {
+                    served.push(channel_id);
+                }
+                previous_credit = Some(credit);
+            }
+        }
+        for channel_id in served {
+            self.last_served.insert(channel_id, self.num_builds);
+        }
+    }
+
+    /// Number of messages (single messages and fragments) queued for each channel
+    fn num_queued_messages(
+        single_data: &[(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &[(ChannelId, VecDeque<FragmentData>)],
+    ) -> HashMap<ChannelId, usize> {
+        let mut queued = HashMap::default();
+        for (channel_id, num_messages) in single_data
+            .iter()
+            .map(|(channel_id, messages)| (*channel_id, messages.len()))
+            .chain(
+                fragment_data
+                    .iter()
+                    .map(|(channel_id, messages)| (*channel_id, messages.len())),
+            )
+        {
+            *queued.entry(channel_id).or_default() += num_messages;
+        }
+        queued
+    }
+
+    /// Reset the credit of the channels that were written, and increase the credit of the
+    /// channels that had messages but were not written at all
+    fn update_credits(
+        &mut self,
+        queued: HashMap<ChannelId, usize>,
+        remainder: &BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)>,
+    ) {
+        for (channel_id, num_queued) in queued {
+            if num_queued == 0 {
+                continue;
+            }
+            let num_remaining = remainder
+                .get(&channel_id)
+                .map_or(0, |(single, fragment)| single.len() + fragment.len());
+            if num_remaining < num_queued {
+                self.channel_credits.remove(&channel_id);
+            } else {
+                *self.channel_credits.entry(channel_id).or_default() +=
+                    self.channel_weight(channel_id);
+            }
+        }
+        self.num_builds += 1;
+    }
+
+    /// Group the reliable channels before the unreliable channels, so that as few packets as
+    /// possible are finished early because of the reliability boundary
+    fn sort_by_reliability(&self, single_data: &mut [(ChannelId, VecDeque<SingleData>)]) {
+        if self.reliability_boundary {
+            single_data.sort_by_key(|(channel_id, _)| !self.reliable_channels.contains(channel_id));
+        }
+    }
+
+    /// Number of bytes reserved at the end of each packet for the checksum
+    fn checksum_len(&self) -> usize {
+        if self.checksum {
+            CHECKSUM_BYTES
+        } else {
+            0
+        }
+    }
+
+    /// Number of bytes reserved in each packet for the length of its content, when packets are padded
+    pub(crate) fn padding_len(&self) -> usize {
+        if self.pad_to.is_some() {
+            varint_len(self.mtu.min(VARINT_MAX as usize) as u64)
+        } else {
+            0
+        }
+    }
+
+    /// Number of bytes reserved in each packet that are not written until the packet is finished
+    pub(crate) fn reserved_len(&self) -> usize {
+        self.checksum_len() + self.padding_len() + self.stream_prefix_len()
+    }
+
+    /// Number of bytes reserved in each packet for its length prefix, in stream mode
+    fn stream_prefix_len(&self) -> usize {
+        if self.stream_framing {
+            varint_len(self.content_mtu().min(VARINT_MAX as usize) as u64)
+        } else {
+            0
+        }
+    }
+
+    /// Returns true if some messages were deferred to the next call to `build_packets`
+    pub(crate) fn has_deferred_messages(&self) -> bool {
+        !self.deferred_data.is_empty()
+    }
+
+    /// Returns true if a packet was kept open by the last call to `build_packets` (see
+    /// [`Self::with_incremental_packets`])
+    pub(crate) fn has_open_packet(&self) -> bool {
+        self.pending_packet.is_some()
+    }
+
+    /// Number of messages per channel that are deferred to the next call to `build_packets`
+    fn num_deferred_messages(&self) -> Vec<(ChannelId, usize)> {
+        self.deferred_data
+            .iter()
+            .map(|(channel_id, (single, fragment))| (*channel_id, single.len() + fragment.len()))
+            .collect()
+    }
+
+    /// Merge the messages deferred by the previous call with the new messages.
+    /// Deferred messages are sent first.
+    ///
+    /// A new message with the same id as a deferred message of its channel is a resend (by the
+    /// reliable sender) of a message that was not written yet, so it is dropped.
+    fn merge_deferred_data(
+        deferred_data: BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)>,
+        single_data: &mut Vec<(ChannelId, VecDeque<SingleData>)>,
+        fragment_data: &mut Vec<(ChannelId, VecDeque<FragmentData>)>,
+    ) {
+        for (channel_id, (mut single, mut fragment)) in deferred_data {
+            match single_data.iter_mut().find(|(id, _)| *id == channel_id) {
+                Some((_, messages)) => {
+                    let deferred_ids: HashSet<MessageId> =
+                        single.iter().filter_map(|message| message.id).collect();
+                    single.extend(messages.drain(..).filter(|message| {
+                        message.id.map_or(true, |id| !deferred_ids.contains(&id))
+                    }));
+                    *messages = single;
+                }
+                None => single_data.push((channel_id, single)),
+            }
+            match fragment_data.iter_mut().find(|(id, _)| *id == channel_id) {
+                Some((_, messages)) => {
+                    let deferred_ids: HashSet<(MessageId, FragmentIndex)> = fragment
+                        .iter()
+                        .map(|fragment| (fragment.message_id, fragment.fragment_id))
+                        .collect();
+                    fragment.extend(messages.drain(..).filter(|fragment| {
+                        !deferred_ids.contains(&(fragment.message_id, fragment.fragment_id))
+                    }));
+                    *messages = fragment;
+                }
+                None => fragment_data.push((channel_id, fragment)),
+            }
+        }
+    }
+
+    /// Remove the messages that go over each channel's byte cap, and return them
+    fn split_channel_byte_caps(
+        &self,
+        single_data: &mut [(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &mut [(ChannelId, VecDeque<FragmentData>)],
+    ) -> BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)> {
+        let mut deferred_data = BTreeMap::new();
+        for (channel_id, cap) in self.channel_byte_caps.iter() {
+            // fragments are written first, so they also count first towards the cap
+            let mut num_bytes = 0;
+            let deferred_fragment = fragment_data
+                .iter_mut()
+                .find(|(id, _)| id == channel_id)
+                .map(|(_, messages)| {
+                    let idx = Self::num_messages_within_cap(
+                        messages.iter().map(ToBytes::len),
+                        &mut num_bytes,
+                        *cap,
+                    );
+                    messages.split_off(idx)
+                })
+                .unwrap_or_default();
+            let deferred_single = single_data
+                .iter_mut()
+                .find(|(id, _)| id == channel_id)
+                .map(|(_, messages)| {
+                    let idx = Self::num_messages_within_cap(
+                        messages.iter().map(ToBytes::len),
+                        &mut num_bytes,
+                        *cap,
+                    );
+                    messages.split_off(idx)
+                })
+                .unwrap_or_default();
+            if !deferred_single.is_empty() || !deferred_fragment.is_empty() {
+                deferred_data.insert(*channel_id, (deferred_single, deferred_fragment));
+            }
+        }
+        deferred_data
+    }
+
+    /// Returns how many messages can be pulled without the running byte total going over the cap.
+    ///
+    /// The first message of the channel is always pulled, so that a message bigger than the cap
+    /// is still sent.
+    fn num_messages_within_cap(
+        lens: impl Iterator<Item = usize>,
+        num_bytes: &mut usize,
+        cap: usize,
+    ) -> usize {
+        lens.take_while(|len| {
+            if *num_bytes > 0 && *num_bytes + len > cap {
+                return false;
+            }
+            *num_bytes += len;
+            true
+        })
+        .count()
+    }
+
+    /// Get an empty buffer for the payload of a new packet, reusing the buffer of a dropped
+    /// packet if possible
+    fn get_new_buffer(&mut self) -> Payload {
+        self.payload_buffers.pop().unwrap_or_else(|| {
+            // the mtu can be very large for transports without a packet size limit
+            Vec::with_capacity(self.mtu.min(self.buffer_sizing.initial))
+        })
+    }
+
+    /// Get an empty buffer for the message acks of a new packet, reusing a recycled one if possible
+    fn take_ack_buffer(&mut self) -> Vec<(ChannelId, MessageAck)> {
+        self.ack_buffers.pop().unwrap_or_default()
+    }
+
+    /// Give back the message acks of a packet once they have been read, so that the allocation
+    /// is reused for the next packets
+    pub(crate) fn recycle_acks(&mut self, mut message_acks: Vec<(ChannelId, MessageAck)>) {
+        if message_acks.capacity() == 0 || self.ack_buffers.len() >= self.buffer_sizing.max {
+            return;
+        }
+        message_acks.clear();
+        self.ack_buffers.push(message_acks);
+    }
+
+    /// Drop a packet started with [`Self::build_new_single_packet`] that will not be sent
+    /// because it has no message: its header is released (the packet id and the acks it carried
+    /// are used by the next packet), and its buffers are recycled.
+    fn discard_single_packet(&mut self, mut packet: Packet) {
+        trace!(packet_id = ?packet.packet_id, "Dropping a packet without messages");
+        debug_assert_eq!(packet.written_messages, 0);
+        self.header_manager.release_sent_packet(packet.packet_id);
+        if let Some(metrics) = &mut self.metrics {
+            metrics.single_packets -= 1;
+        }
+        self.recycle_acks(std::mem::take(&mut packet.message_acks));
+        if self.payload_buffers.len() < self.buffer_sizing.max {
+            packet.payload.clear();
+            self.payload_buffers.push(packet.payload);
+        }
+    }
+
+    /// Start building new packet, we start with an empty packet
+    /// that can write to a given channel
+    pub(crate) fn build_new_single_packet(
+        &mut self,
+        current_tick: Tick,
+    ) -> Result<(), SerializationError> {
+        let mut cursor = self.get_new_buffer();
+
+        // write the header
+        let mut header = self
+            .header_manager
+            .prepare_send_packet_header(PacketType::Data);
+        // set the tick at which the packet will be sent
+        header.tick = current_tick;
+        header.to_bytes(&mut cursor)?;
+        if let Some(metrics) = &mut self.metrics {
+            metrics.single_packets += 1;
+        }
+        let message_acks = self.take_ack_buffer();
+        self.current_packet = Some(Packet {
+            payload: cursor,
+            message_acks,
+            packet_id: header.packet_id,
+            mtu: self.content_mtu(),
+            // reserve space for the checksum and the content length
+            prewritten_size: self.reserved_len(),
+            written_messages: 0,
+        });
+        Ok(())
+    }
+
+    /// Start a new packet that contains the `fragments` of the channel `channel_id`
+    /// (see [`Self::group_fragments`] to find fragments that fit in the same packet)
+    pub(crate) fn build_new_fragment_packet(
+        &mut self,
+        channel_id: NetId,
+        fragments: &[FragmentData],
+        current_tick: Tick,
+    ) -> Result<(), SerializationError> {
+        let mut cursor = self.get_new_buffer();
+        // writer the header
+        let mut header = self
+            .header_manager
+            .prepare_send_packet_header(PacketType::DataFragment);
+        // set the tick at which the packet will be sent
+        header.tick = current_tick;
+        header.to_bytes(&mut cursor)?;
+        self.channel_dictionary.write(channel_id, &mut cursor)?;
+        cursor.write_varint(fragments.len() as u64)?;
+        for (i, fragment_data) in fragments.iter().enumerate() {
+            fragment_data
+                .to_bytes(&mut cursor)
+                .map_err(|e| e.in_channel(channel_id, i))?;
+        }
+        self.stats.fragments_sent += fragments.len() as u64;
+        if let Some(metrics) = &mut self.metrics {
+            metrics.fragment_packets += 1;
+            *metrics.channel_messages.entry(channel_id).or_default() += fragments.len();
+        }
+        let mut message_acks = self.take_ack_buffer();
+        message_acks.extend(fragments.iter().map(|fragment_data| {
+            (
+                ChannelId::from(channel_id),
+                MessageAck {
+                    message_id: fragment_data.message_id,
+                    fragment_id: Some(fragment_data.fragment_id),
+                },
+            )
+        }));
+        self.current_packet = Some(Packet {
+            payload: cursor,
+            message_acks,
+            packet_id: header.packet_id,
+            mtu: self.content_mtu(),
+            // reserve space for the checksum and the content length
+            prewritten_size: self.reserved_len(),
+            written_messages: fragments.len(),
+        });
+        Ok(())
+
+        //
+        // let is_last_fragment = fragment_data.is_last_fragment();
+        // let packet = FragmentedPacket::new(channel_id, fragment_data);
+        //
+        // debug_assert!(packet.fragment.bytes.len() <= FRAGMENT_SIZE);
+        // if is_last_fragment {
+        //     packet.encode(&mut self.try_write_buffer).unwrap();
+        //     // reserve one extra bit for the continuation bit between fragment/single packet data
+        //     self.try_write_buffer.reserve_bits(1);
+        //
+        //     // let num_bits_written = self.try_write_buffer.num_bits_written();
+        //     // no need to reserve bits, since we already just wrote in the try buffer!
+        //     // self.try_write_buffer.reserve_bits(num_bits_written);
+        //     debug_assert!(!self.try_write_buffer.overflowed())
+        // }
+        //
+        // Packet {
+        //     header,
+        //     data: PacketData::Fragmented(packet),
+        // }
+    }
+
+    /// Finish the current packet, recording why it was finished
+    fn finish_packet_with_reason(&mut self, reason: PacketFinishReason) -> Packet {
+        #[cfg(feature = "trace")]
+        if let Some(packet) = &self.current_packet {
+            tracing::debug!(
+                target: FINISH_PACKET_TRACE_TARGET,
+                ?reason,
+                packet_id = ?packet.packet_id,
+                payload_bytes = packet.payload.len(),
+                mtu = packet.mtu,
+                "finish packet"
+            );
+        }
+        self.finish_packet()
+    }
+
+    /// Finish the current data packet and pass it to `sink`.
+    ///
+    /// A packet without any message is pure overhead: it is dropped instead (see
+    /// [`Self::discard_single_packet`]). The acks are only sent on their own by
+    /// [`Self::build_ack_packets`] or [`Self::build_keepalive`].
+    fn emit_packet<E>(
+        &mut self,
+        reason: PacketFinishReason,
+        sink: &mut impl FnMut(Packet) -> Result<(), E>,
+        num_packets: &mut usize,
+    ) -> Result<(), E> {
+        if self
+            .current_packet
+            .as_ref()
+            .is_some_and(|packet| packet.written_messages == 0)
+        {
+            let packet = self.current_packet.take().unwrap();
+            self.discard_single_packet(packet);
+            return Ok(());
+        }
+        *num_packets += 1;
+        sink(self.finish_packet_with_reason(reason))
+    }
+
+    pub fn finish_packet(&mut self) -> Packet {
+        let mut packet = self.current_packet.take().unwrap();
+        #[cfg(feature = "lz4")]
+        if self.packet_compression {
+            compress_packet(&mut packet.payload, self.compression_dictionary.as_ref());
+        }
+        if let Some(pad_to) = self.pad_to {
+            packet.prewritten_size -= self.padding_len();
+            pad_packet(
+                &mut packet.payload,
+                pad_to
+                    .min(self.content_mtu())
+                    .saturating_sub(self.checksum_len() + self.stream_prefix_len()),
+            );
+        }
+        if self.checksum {
+            packet.prewritten_size -= CHECKSUM_BYTES;
+            packet.write_checksum();
+        }
+        if self.stream_framing {
+            packet.prewritten_size -= self.stream_prefix_len();
+            frame_packet(&mut packet.payload);
+        }
+        if self.invariant_checks {
+            self.check_invariants(&packet);
+        }
+        let content_mtu = self.content_mtu();
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += packet.payload.len() as u64;
+        if let Some(metrics) = &mut self.metrics {
+            metrics.packets += 1;
+            metrics.payload_bytes += packet.payload.len();
+            let wasted_bytes = packet.wasted_bytes(content_mtu);
+            metrics.wasted_bytes += wasted_bytes;
+            metrics.max_wasted_bytes = metrics.max_wasted_bytes.max(wasted_bytes);
+        }
+        packet.payload.shrink_to_fit();
+        if let Some(capture) = &mut self.capture {
+            (capture.0)(&packet);
+        }
+        // TODO: should we use bytes so this clone is cheap?
+        packet
+    }
+
+    /// Build header-only packets carrying the acks that were not sent in the headers of the data packets.
+    ///
+    /// Each header can only carry a limited number of acks, so the pending acks are split
+    /// across as many packets as needed.
+    pub(crate) fn build_ack_packets(
+        &mut self,
+        current_tick: Tick,
+    ) -> Result<Vec<Packet>, SerializationError> {
+        let mut packets = vec![];
+        while let Some(mut header) = self.header_manager.prepare_ack_packet_header() {
+            let mut cursor = self.get_new_buffer();
+            header.tick = current_tick;
+            header.to_bytes(&mut cursor)?;
+            self.current_packet = Some(Packet {
+                payload: cursor,
+                message_acks: vec![],
+                packet_id: header.packet_id,
+                mtu: self.content_mtu(),
+                // reserve space for the checksum and the content length
+                prewritten_size: self.reserved_len(),
+                written_messages: 0,
+            });
+            packets.push(self.finish_packet());
+        }
+        Ok(packets)
+    }
+
+    /// Build the handshake packet, which contains the [`ChannelSchema`] of the local channel registry
+    pub(crate) fn build_handshake_packet(
+        &mut self,
+        current_tick: Tick,
+        schema: &ChannelSchema,
+    ) -> Result<Packet, SerializationError> {
+        let mut cursor = self.get_new_buffer();
+        let mut header = self
+            .header_manager
+            .prepare_send_packet_header(PacketType::Handshake);
+        header.tick = current_tick;
+        header.to_bytes(&mut cursor)?;
+        schema.to_bytes(&mut cursor)?;
+        self.current_packet = Some(Packet {
+            payload: cursor,
+            message_acks: vec![],
+            packet_id: header.packet_id,
+            mtu: self.content_mtu(),
+            // reserve space for the checksum and the content length
+            prewritten_size: self.reserved_len(),
+            written_messages: 0,
+        });
+        Ok(self.finish_packet())
+    }
+
+    /// Build a [`PacketType::Data`] packet without any channel, that only carries the acks of its
+    /// header, to send the acks promptly when there are no messages to send.
+    ///
+    /// Unlike [`Self::build_ack_packets`], the packet is acked back by the remote peer.
+    ///
+    /// This is internal: `MessageManager::send_packets` does not send it, the connections only
+    /// send their acks in the headers of the packets they send anyway. It is used by the tests.
+    pub(crate) fn build_ack_packet(
+        &mut self,
+        current_tick: Tick,
+    ) -> Result<Packet, SerializationError> {
+        self.build_new_single_packet(current_tick)?;
+        Ok(self.finish_packet())
+    }
+
+    /// Build a keep-alive packet, which only contains a header (with the acks of the received packets)
+    /// and no messages
+    pub(crate) fn build_keepalive(
+        &mut self,
+        current_tick: Tick,
+    ) -> Result<Packet, SerializationError> {
+        self.build_typed(PacketType::KeepAlive, current_tick)
+    }
+
+    /// Build a packet of type `packet_type` that only contains a header, for example a
+    /// [`PacketType::User`] packet of the transport.
+    pub(crate) fn build_typed(
+        &mut self,
+        packet_type: PacketType,
+        current_tick: Tick,
+    ) -> Result<Packet, SerializationError> {
+        let mut cursor = self.get_new_buffer();
+        let mut header = self.header_manager.prepare_send_packet_header(packet_type);
+        header.tick = current_tick;
+        header.to_bytes(&mut cursor)?;
+        self.current_packet = Some(Packet {
+            payload: cursor,
+            message_acks: vec![],
+            packet_id: header.packet_id,
+            mtu: self.content_mtu(),
+            // reserve space for the checksum and the content length
+            prewritten_size: self.reserved_len(),
+            written_messages: 0,
+        });
+        Ok(self.finish_packet())
+    }
+
+    /// Build a packet that only contains `message`, to send it right away instead of waiting for
+    /// the next call to `build_packets` (for example for a latency-critical event).
+    ///
+    /// The packet is acked like any other data packet: if the message has an id, it is part of
+    /// the packet's message acks.
+    pub fn build_immediate(
+        &mut self,
+        channel_id: ChannelId,
+        message: SingleData,
+        current_tick: Tick,
+    ) -> Result<Packet, SerializationError> {
+        if message.len() > self.max_single_message_len(channel_id) {
+            return Err(SerializationError::MessageShouldBeFragmented {
+                size: message.bytes.len(),
+            });
+        }
+        self.build_new_single_packet(current_tick)?;
+        let mut packet = self.current_packet.take().unwrap();
+        // the packet is empty: the channel either fits or returns an error
+        let written = Self::reserve_channel(
+            &mut packet,
+            true,
+            channel_id,
+            &self.channel_dictionary,
+            &self.encryption,
+        )
+        .and_then(|_| {
+            packet.prewritten_size += Self::added_message_len(message.len(), 0);
+            Self::write_single_messages(
+                &mut packet,
+                &mut VecDeque::from([message]),
+                &mut 1,
+                channel_id,
+                &self.channel_dictionary,
+                &mut self.encryption,
+                // the pack stats only describe the last call to `build_packets`
+                &mut None,
+                &mut self.metrics,
+                self.fixed_length_blocks,
+                false,
+            )
+        });
+        if let Err(e) = written {
+            // the packet was rolled back, nothing is sent
+            self.discard_single_packet(packet);
+            return Err(e);
+        }
+        self.current_packet = Some(packet);
+        Ok(self.finish_packet_with_reason(PacketFinishReason::NoMoreMessages))
+    }
+
+    /// Pack as many `items` as fit in a single packet of `channel_id` (for example the component
+    /// snapshots of entities), serializing them one at a time with `serialize` until the next
+    /// one doesn't fit.
+    ///
+    /// Returns the packet (`None` if there are no items), the number of items written in it,
+    /// and the items that were not written, in order, to be packed in the next packets. An
+    /// item that doesn't fit even in an empty packet is an error, like in
+    /// [`Self::build_immediate`].
+    pub fn pack_items<T, I, F>(
+        &mut self,
+        channel_id: ChannelId,
+        items: I,
+        serialize: F,
+        current_tick: Tick,
+    ) -> Result<(Option<Packet>, usize, Vec<T>), SerializationError>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(&T) -> Bytes,
+    {
+        let mut items = items.into_iter();
+        let Some(first) = items.next() else {
+            return Ok((None, 0, vec![]));
+        };
+        // check that the first item fits before starting the packet
+        let first = SingleData::new(None, serialize(&first));
+        if first.len() > self.max_single_message_len(channel_id) {
+            return Err(SerializationError::MessageShouldBeFragmented {
+                size: first.bytes.len(),
+            });
+        }
+        self.build_new_single_packet(current_tick)?;
+        let mut packet = self.current_packet.take().unwrap();
+        if let Err(e) = Self::reserve_channel(
+            &mut packet,
+            true,
+            channel_id,
+            &self.channel_dictionary,
+            &self.encryption,
+        ) {
+            self.discard_single_packet(packet);
+            return Err(e);
+        }
+        packet.prewritten_size += Self::added_message_len(first.len(), 0);
+        let mut messages = VecDeque::from([first]);
+        let mut leftover = vec![];
+        while messages.len() < self.max_messages_per_packet {
+            let Some(item) = items.next() else {
+                break;
+            };
+            let message = SingleData::new(None, serialize(&item));
+            let added_len = Self::added_message_len(message.len(), messages.len());
+            if !packet.can_fit(added_len) {
+                leftover.push(item);
+                break;
+            }
+            packet.prewritten_size += added_len;
+            messages.push_back(message);
+        }
+        leftover.extend(items);
+        let mut num_messages = messages.len();
+        let num_items = num_messages;
+        let written = Self::write_single_messages(
+            &mut packet,
+            &mut messages,
+            &mut num_messages,
+            channel_id,
+            &self.channel_dictionary,
+            &mut self.encryption,
+            &mut None,
+            &mut self.metrics,
+            self.fixed_length_blocks,
+            false,
+        );
+        if let Err(e) = written {
+            // the packet was rolled back, nothing is sent
+            self.discard_single_packet(packet);
+            return Err(e);
+        }
+        self.current_packet = Some(packet);
+        let finish_reason = if leftover.is_empty() {
+            PacketFinishReason::NoMoreMessages
+        } else if num_items == self.max_messages_per_packet {
+            PacketFinishReason::TooManyMessages
+        } else {
+            PacketFinishReason::MessageDoesNotFit
+        };
+        Ok((
+            Some(self.finish_packet_with_reason(finish_reason)),
+            num_items,
+            leftover,
+        ))
+    }
+
+    /// Merge the inputs of several `build_packets` calls (for example coming from different systems)
+    /// so that they can be packed together.
+    ///
+    /// The queues of a same channel are concatenated in the order of the inputs, and channels
+    /// are kept in the order in which they first appear.
+    pub(crate) fn merge_build_inputs<T>(
+        inputs: Vec<Vec<(ChannelId, VecDeque<T>)>>,
+    ) -> Vec<(ChannelId, VecDeque<T>)> {
+        let mut merged: Vec<(ChannelId, VecDeque<T>)> = vec![];
+        for (channel_id, mut messages) in inputs.into_iter().flatten() {
+            match merged.iter_mut().find(|(id, _)| *id == channel_id) {
+                Some((_, merged_messages)) => merged_messages.append(&mut messages),
+                None => merged.push((channel_id, messages)),
+            }
+        }
+        merged
+    }
+
+    /// The [`MessageAck`]s of each packet returned by `build_packets`, i.e. the messages
+    /// (with a [`MessageId`]) and fragments that are in flight until the packet is acked.
+    ///
+    /// Packets without any message to ack are skipped.
+    pub(crate) fn message_acks_by_packet(
+        packets: &[Packet],
+    ) -> Vec<(PacketId, Vec<(ChannelId, MessageAck)>)> {
+        packets
+            .iter()
+            .filter(|packet| !packet.message_acks.is_empty())
+            .map(|packet| (packet.packet_id, packet.message_acks.clone()))
+            .collect()
+    }
+
+    /// Pack messages into packets
+    ///
+    /// In general the strategy is:
+    /// - sort the single data messages from smallest to largest (see [`PackingStrategy`]). The sort
+    ///   is stable, so messages of the same size are written in the order in which they were queued.
+    ///   Messages of ordered channels are not sorted
+    /// - write the fragment data first. Big fragments take the entire packet. Small fragments have
+    ///   some room to spare for small messages
+    ///
+    /// Messages that go over their channel's byte cap are not written; they are kept
+    /// and sent first on the next call (see [`Self::build_capped_packets`])
+    ///
+    /// If writing a channel fails, the error is returned and no partially written packet is left
+    /// behind: the channel is rolled back, so the packet in progress only contains the channels
+    /// written before the error and stays valid. It is finished by the next call, after which
+    /// the messages that were not written can be sent again.
+    ///
+    /// Calling it without messages (or only with empty channels) returns no packets. A packet
+    /// kept open by the previous call (see [`Self::with_incremental_packets`]) stays open, unless
+    /// the tick changed: use [`Self::flush_current`] to finish it.
+    #[cfg_attr(feature = "trace", instrument(level = Level::INFO, skip_all))]
+    pub fn build_packets(
+        &mut self,
+        current_tick: Tick,
+        single_data: Vec<(ChannelId, VecDeque<SingleData>)>,
+        fragment_data: Vec<(ChannelId, VecDeque<FragmentData>)>,
+    ) -> Result<Vec<Packet>, SerializationError> {
+        let mut packets = vec![];
+        self.build_packets_with(current_tick, single_data, fragment_data, |packet| {
+            packets.push(packet);
+            Ok::<_, SerializationError>(())
+        })?;
+        Ok(packets)
+    }
+
+    /// Pack messages like [`Self::build_packets`], and also return the number of messages per
+    /// channel that went over their channel's byte cap. They are kept and sent first on the next
+    /// call.
+    pub(crate) fn build_capped_packets(
+        &mut self,
+        current_tick: Tick,
+        single_data: Vec<(ChannelId, VecDeque<SingleData>)>,
+        fragment_data: Vec<(ChannelId, VecDeque<FragmentData>)>,
+    ) -> Result<(Vec<Packet>, Vec<(ChannelId, usize)>), SerializationError> {
+        let packets = self.build_packets(current_tick, single_data, fragment_data)?;
+        Ok((packets, self.num_deferred_messages()))
+    }
+
+    /// Pack messages like [`Self::build_packets`], but pass each packet to `sink` as soon as it
+    /// is finished instead of returning all of them at the end, so that it can be sent (and its
+    /// buffers recycled) right away.
+    ///
+    /// If `sink` returns an error, packing stops and the messages that were not written yet are lost.
+    pub fn build_packets_with<E: From<SerializationError>>(
+        &mut self,
+        current_tick: Tick,
+        single_data: Vec<(ChannelId, VecDeque<SingleData>)>,
+        fragment_data: Vec<(ChannelId, VecDeque<FragmentData>)>,
+        mut sink: impl FnMut(Packet) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let remainder =
+            self.build_packets_inner(current_tick, single_data, fragment_data, None, &mut sink)?;
+        debug_assert!(remainder.is_empty());
+        Ok(())
+    }
+
+    /// Pack messages that were already serialized, for example to relay them to another peer.
+    ///
+    /// They are packed exactly like the single messages of [`Self::build_packets`].
+    pub(crate) fn build_raw_packets(
+        &mut self,
+        current_tick: Tick,
+        raw_data: Vec<(ChannelId, VecDeque<RawMessage>)>,
+    ) -> Result<Vec<Packet>, SerializationError> {
+        let single_data = raw_data
+            .into_iter()
+            .map(|(channel_id, messages)| {
+                (
+                    channel_id,
+                    messages.into_iter().map(SingleData::from).collect(),
+                )
+            })
+            .collect();
+        self.build_packets(current_tick, single_data, vec![])
+    }
+
+    /// Merge consecutive data packets whose contents fit together in a single packet, to send
+    /// fewer datagrams (for example when separate calls to `build_packets` each left an
+    /// under-full packet).
+    ///
+    /// Fragment packets, compressed or padded packets, and packets of different ticks are never
+    /// merged, and the reliability boundary is respected. The merged packet keeps the header of
+    /// the first packet and receives the message acks of the other one, whose packet id is
+    /// released. The metrics and the capture see the packets as they were before merging, while
+    /// the [`ConnectionStats`] only count the merged packet.
+    pub(crate) fn coalesce(&mut self, packets: Vec<Packet>) -> Vec<Packet> {
+        let mut coalesced: Vec<Packet> = Vec::with_capacity(packets.len());
+        for packet in packets {
+            if let Some(last) = coalesced.last_mut() {
+                if self.can_merge(last, &packet) {
+                    self.merge_packet(last, packet);
+                    continue;
+                }
+            }
+            coalesced.push(packet);
+        }
+        coalesced
+    }
+
+    /// Header of a finished packet and its channel sections (the payload without the header and
+    /// the checksum), if the packet is an uncompressed data packet
+    fn channel_sections<'a>(&self, packet: &'a Packet) -> Option<(PacketHeader, &'a [u8])> {
+        let header = Packet::peek_header(&packet.payload).ok()?;
+        if header.compressed || header.get_packet_type() != PacketType::Data {
+            return None;
+        }
+        let end = packet.payload.len().checked_sub(self.checksum_len())?;
+        let sections = packet.payload.get(header.len()..end)?;
+        Some((header, sections))
+    }
+
+    /// Check if the channel sections of `other` can be appended to `packet`
+    fn can_merge(&self, packet: &Packet, other: &Packet) -> bool {
+        if self.pad_to.is_some() || self.stream_framing {
+            return false;
+        }
+        let (Some((header, _)), Some((other_header, other_sections))) =
+            (self.channel_sections(packet), self.channel_sections(other))
+        else {
+            return false;
+        };
+        // with the reliability boundary, all the channels of a packet have the same reliability
+        let first_channel = |packet: &Packet| {
+            let end = packet.payload.len() - self.checksum_len();
+            PacketReader::new(&packet.payload[..end], &self.channel_dictionary)
+                .ok()?
+                .next()?
+                .ok()
+                .map(|(channel_id, _)| channel_id)
+        };
+        header.tick == other_header.tick
+            && packet.written_messages + other.written_messages <= self.max_messages_per_packet
+            && packet.payload.len() + other_sections.len() <= self.content_mtu()
+            && (!(self.reliability_boundary || self.isolate_channels)
+                || first_channel(packet).zip(first_channel(other)).is_some_and(
+                    |(channel_id, other_channel_id)| {
+                        self.channel_boundary(channel_id, other_channel_id)
+                            .is_none()
+                    },
+                ))
+    }
+
+    /// Append the channel sections of `other` to `packet` (see [`Self::can_merge`])
+    fn merge_packet(&mut self, packet: &mut Packet, other: Packet) {
+        let Some((_, sections)) = self.channel_sections(&other) else {
+            return;
+        };
+        packet
+            .payload
+            .truncate(packet.payload.len() - self.checksum_len());
+        packet.payload.extend_from_slice(sections);
+        if self.checksum {
+            packet.write_checksum();
+        }
+        // the header and checksum of `other` are not sent
+        self.stats.packets_sent -= 1;
+        self.stats.bytes_sent -= (other.payload.len() - sections.len()) as u64;
+        if PacketHeader::has_sorted_flag(&other.payload) {
+            PacketHeader::set_sorted_flag(&mut packet.payload);
+        }
+        packet.message_acks.extend_from_slice(&other.message_acks);
+        packet.written_messages += other.written_messages;
+        self.header_manager.cancel_sent_packet(other.packet_id);
+        self.recycle_acks(other.message_acks);
+    }
+
+    /// Pack messages that belong to successive ticks.
+    ///
+    /// Each item of `data` starts at a tick boundary: the packet being written is finished, and
+    /// the following packets are stamped with the new tick. Messages of different ticks never
+    /// share a packet.
+    pub(crate) fn build_packets_per_tick(
+        &mut self,
+        data: Vec<(
+            Tick,
+            Vec<(ChannelId, VecDeque<SingleData>)>,
+            Vec<(ChannelId, VecDeque<FragmentData>)>,
+        )>,
+    ) -> Result<Vec<Packet>, SerializationError> {
+        let mut packets = vec![];
+        for (tick, single_data, fragment_data) in data {
+            // a packet never mixes ticks, so the next tick starts a new packet
+            packets.extend(self.build_packets(tick, single_data, fragment_data)?);
+        }
+        Ok(packets)
+    }
+
+    /// Pack messages into at most `max_packets` packets, using the same strategy as [`Self::build_packets`]
+    ///
+    /// Returns the packets, along with the single and fragment messages that could not be written
+    /// for each channel, so that the caller can buffer them again.
+    #[cfg_attr(feature = "trace", instrument(level = Level::INFO, skip_all))]
+    pub fn build_packets_limited(
+        &mut self,
+        current_tick: Tick,
+        single_data: Vec<(ChannelId, VecDeque<SingleData>)>,
+        fragment_data: Vec<(ChannelId, VecDeque<FragmentData>)>,
+        max_packets: usize,
+    ) -> Result<
+        (
+            Vec<Packet>,
+            BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)>,
+        ),
+        SerializationError,
+    > {
+        let mut packets = vec![];
+        let remainder = self.build_packets_inner(
+            current_tick,
+            single_data,
+            fragment_data,
+            Some(max_packets),
+            &mut |packet| {
+                packets.push(packet);
+                Ok::<_, SerializationError>(())
+            },
+        )?;
+        Ok((packets, remainder))
+    }
+
+    /// Build the packets of every tick of `ticks`, with the messages returned by `data_per_tick`
+    /// for that tick (for example to rebuild the sends of a range of ticks during a rollback).
+    ///
+    /// Each tick is packed on its own: a packet kept open at the end of a tick (see
+    /// [`Self::with_incremental_packets`]) is finished before the next tick is packed, so the
+    /// packets of a tick only contain that tick's messages and carry that tick in their header.
+    pub fn build_packets_for_ticks(
+        &mut self,
+        ticks: Range<Tick>,
+        mut data_per_tick: impl FnMut(
+            Tick,
+        ) -> (
+            Vec<(ChannelId, VecDeque<SingleData>)>,
+            Vec<(ChannelId, VecDeque<FragmentData>)>,
+        ),
+    ) -> Result<Vec<(Tick, Vec<Packet>)>, SerializationError> {
+        let num_ticks = (ticks.end - ticks.start).max(0) as u16;
+        (0..num_ticks)
+            .map(|i| {
+                let tick = ticks.start.wrapping_add(i);
+                let (single_data, fragment_data) = data_per_tick(tick);
+                let mut packets = self.build_packets(tick, single_data, fragment_data)?;
+                packets.extend(self.flush_current()?);
+                Ok((tick, packets))
+            })
+            .collect()
+    }
+
+    /// Pack the messages, and pass each packet to `sink` as soon as it is finished.
+    ///
+    /// Returns the messages that could not be written because of `max_packets`.
+    fn build_packets_inner<E: From<SerializationError>>(
+        &mut self,
+        current_tick: Tick,
+        mut single_data: Vec<(ChannelId, VecDeque<SingleData>)>,
+        mut fragment_data: Vec<(ChannelId, VecDeque<FragmentData>)>,
+        max_packets: Option<usize>,
+        sink: &mut impl FnMut(Packet) -> Result<(), E>,
+    ) -> Result<BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)>, E> {
+        let mut num_packets = 0;
+        let mut remainder: BTreeMap<ChannelId, (VecDeque<SingleData>, VecDeque<FragmentData>)> =
+            BTreeMap::new();
+        let is_full = |num_packets: usize| max_packets.is_some_and(|max| num_packets >= max);
+        if let Some(pack_stats) = &mut self.pack_stats {
+            *pack_stats = PackStats::default();
+        }
+        Self::merge_deferred_data(
+            std::mem::take(&mut self.deferred_data),
+            &mut single_data,
+            &mut fragment_data,
+        );
+        self.coalesce_messages(&mut single_data);
+        self.check_message_ids(&mut single_data)?;
+        // a channel that can't hold any message is reported as such, rather than as a message
+        // that is too big
+        self.validate_channels(&single_data)?;
+        // a single message that doesn't fit in an empty packet would make us start new packets forever
+        for (channel_id, single_messages) in single_data.iter() {
+            let max_len = self.max_single_message_len(*channel_id);
+            if let Some(message) = single_messages
+                .iter()
+                .find(|message| message.len() > max_len)
+            {
+                return Err(SerializationError::MessageShouldBeFragmented {
+                    size: message.bytes.len(),
+                }
+                .into());
+            }
+        }
+        self.deferred_data = self.split_channel_byte_caps(&mut single_data, &mut fragment_data);
+        self.sort_by_credit(&mut single_data, &mut fragment_data);
+        self.record_served(&single_data, &fragment_data);
+        self.sort_by_reliability(&mut single_data);
+        let queued = Self::num_queued_messages(&single_data, &fragment_data);
+
+        // indices in the main vec
+        let mut single_data_idx = 0;
+        #[cfg(feature = "trace")]
+        let metrics_before = self
+            .metrics
+            .as_ref()
+            .map(|m| (m.packets, m.payload_bytes, m.fragment_packets));
+
+        let reordered_channels = self.schedule(&mut single_data);
+
+        // a packet left in progress (by a call that returned early with an error) was started for
+        // a tick that might not be the current one: finish it so that no packet ever mixes ticks
+        if let Some(packet) = &self.current_packet {
+            let finish_reason = if packet.tick()? != current_tick {
+                PacketFinishReason::TickChanged
+            } else {
+                PacketFinishReason::NoMoreMessages
+            };
+            self.emit_packet(finish_reason, sink, &mut num_packets)?;
+        }
+
+        // the packet kept open by the previous call can only receive single messages of the same tick
+        let mut pending_packet = self.take_open_packet()?;
+        if let Some((packet, _)) = &pending_packet {
+            let finish_reason = if packet.tick()? != current_tick {
+                Some(PacketFinishReason::TickChanged)
+            } else if packet.mtu != self.content_mtu() {
+                Some(PacketFinishReason::MtuChanged)
+            } else if fragment_data
+                .iter()
+                .any(|(_, messages)| !messages.is_empty())
+            {
+                // fragments always start a new packet
+                Some(PacketFinishReason::NoMoreMessages)
+            } else {
+                None
+            };
+            if let Some(finish_reason) = finish_reason {
+                self.current_packet = pending_packet.take().map(|(packet, _)| packet);
+                self.emit_packet(finish_reason, sink, &mut num_packets)?;
+            }
+        }
+
+        // try to fill the packet with fragment messages first
+        let mut fragment_data = fragment_data.into_iter();
+        'fragments: for (fragment_channel_id, fragment_messages) in fragment_data.by_ref() {
+            debug_assert!(fragment_messages
+                .iter()
+                .all(|fragment_data| fragment_data.bytes.len()
+                    <= fragment_size(self.content_mtu(), self.reserved_len())));
+            let groups = self.group_fragments(fragment_channel_id, &fragment_messages);
+            let mut fragment_messages = fragment_messages.into_iter().map(Some).collect::<Vec<_>>();
+            for (fragment_indices, _) in groups {
+                if is_full(num_packets) {
+                    // we reached the packet limit, the remaining fragments are not sent
+                    remainder.entry(fragment_channel_id).or_default().1 =
+                        fragment_messages.into_iter().flatten().collect();
+                    break 'fragments;
+                }
+                let fragments = fragment_indices
+                    .into_iter()
+                    .map(|idx| fragment_messages[idx].take().unwrap())
+                    .collect::<Vec<_>>();
+                self.build_new_fragment_packet(fragment_channel_id, &fragments, current_tick)?;
+                let mut packet = self.current_packet.take().unwrap();
+                let mut finish_reason = PacketFinishReason::NoMoreMessages;
+                // fill the rest of the fragment packet with small messages.
+                // A channel that does not fit doesn't end the packet: the smallest messages of the
+                // next channels might still fit in the remaining space
+                for idx in single_data_idx..single_data.len() {
+                    let held = self.held_bytes(single_data[idx].0, |channel_id| {
+                        Self::has_messages(&single_data, channel_id)
+                    });
+                    let (channel_id, single_messages) = &mut single_data[idx];
+                    if single_messages.is_empty() {
+                        continue;
+                    }
+                    if let Some(reason) =
+                        self.fragment_tail_boundary(*channel_id, fragment_channel_id)
+                    {
+                        finish_reason = reason;
+                        continue;
+                    }
+                    if !packet.can_fit_channel(self.channel_header_len(*channel_id)) {
+                        finish_reason = PacketFinishReason::ChannelDoesNotFit;
+                        continue;
+                    }
+
+                    // number of messages for this channel that we will write
+                    // (we wait until we know the full number, because we want to write that)
+                    let mut num_messages = 0;
+                    // reserve as many messages as possible, in packing order
+                    while num_messages < single_messages.len() {
+                        if packet.written_messages + num_messages >= self.max_messages_per_packet {
+                            finish_reason = PacketFinishReason::TooManyMessages;
+                            break;
+                        }
+                        let added_len = Self::added_message_len(
+                            single_messages[num_messages].len(),
+                            num_messages,
+                        );
+                        if !packet.can_fit(added_len + held) {
+                            finish_reason = if packet.can_fit(added_len) {
+                                PacketFinishReason::ChannelReservation
+                            } else {
+                                PacketFinishReason::MessageDoesNotFit
+                            };
+                            break;
+                        }
+                        packet.prewritten_size += added_len;
+                        num_messages += 1;
+                    }
+                    if let Err(e) = Self::write_single_messages(
+                        &mut packet,
+                        single_messages,
+                        &mut num_messages,
+                        *channel_id,
+                        &self.channel_dictionary,
+                        &mut self.encryption,
+                        &mut self.pack_stats,
+                        &mut self.metrics,
+                        self.fixed_length_blocks,
+                        reordered_channels.contains(channel_id),
+                    ) {
+                        // the packet was rolled back, it is finished by the next call
+                        self.current_packet = Some(packet);
+                        return Err(e.into());
+                    }
+                }
+                // skip the channels that have been fully written
+                while single_data
+                    .get(single_data_idx)
+                    .is_some_and(|(_, single_messages)| single_messages.is_empty())
+                {
+                    single_data_idx += 1;
+                }
+                // no more single messages to send, finish the fragment packet
+                self.current_packet = Some(packet);
+                self.emit_packet(finish_reason, sink, &mut num_packets)?;
+            }
+        }
+
+        for (channel_id, fragment_messages) in fragment_data {
+            if !fragment_messages.is_empty() {
+                remainder.entry(channel_id).or_default().1 = fragment_messages;
+            }
+        }
+
+        debug_assert!(self.current_packet.is_none());
+
+        // first channel written in the current packet
+        let mut packet_channel_id = None;
+        if let Some((packet, channel_id)) = pending_packet {
+            self.current_packet = Some(packet);
+            packet_channel_id = Some(channel_id);
+        }
+        // all fragment messages have been written, now write small messages
+        'out: while single_data_idx < single_data.len() {
+            let held = self.held_bytes(single_data[single_data_idx].0, |channel_id| {
+                Self::has_messages(&single_data, channel_id)
+            });
+            let (channel_id, single_messages) = &mut single_data[single_data_idx];
+            // the channel might have been fully written in the fragment packets
+            if single_messages.is_empty() {
+                single_data_idx += 1;
+                continue 'out;
+            }
+            // start a new packet if we aren't already writing one
+            let is_new_packet = self.current_packet.is_none();
+            if is_new_packet {
+                if is_full(num_packets) {
+                    // we reached the packet limit, the remaining messages are not sent
+                    break 'out;
+                }
+                self.build_new_single_packet(current_tick)?;
+                packet_channel_id = Some(*channel_id);
+            } else if let Some(reason) = packet_channel_id
+                .and_then(|packet_channel_id| self.channel_boundary(*channel_id, packet_channel_id))
+            {
+                // the channel cannot share the packet with the previous channels
+                self.emit_packet(reason, sink, &mut num_packets)?;
+                continue 'out;
+            }
+
+            let mut packet = self.current_packet.take().unwrap();
+            // we need to call this to preassign the channel_id
+            if !Self::reserve_channel(
+                &mut packet,
+                is_new_packet,
+                *channel_id,
+                &self.channel_dictionary,
+                &self.encryption,
+            )? {
+                // can't add any more messages (they are written in packing order)
+                // finish packet and go back to trying to write fragment messages
+                let written = self.fill_reserved_channels(
+                    &mut packet,
+                    &mut single_data,
+                    single_data_idx,
+                    packet_channel_id,
+                    &reordered_channels,
+                );
+                self.current_packet = Some(packet);
+                written?;
+                self.emit_packet(
+                    PacketFinishReason::ChannelDoesNotFit,
+                    sink,
+                    &mut num_packets,
+                )?;
+                continue 'out;
+            }
+            // number of messages for this channel that we will write
+            // (we wait until we know the full number, because we want to write that)
+            let mut num_messages = 0;
+            // fill with messages from the current channel
+            loop {
+                // no more messages to send in this channel, try to fill with messages from the next channels
+                if num_messages == single_messages.len() {
+                    let written = Self::write_single_messages(
+                        &mut packet,
+                        single_messages,
+                        &mut num_messages,
+                        *channel_id,
+                        &self.channel_dictionary,
+                        &mut self.encryption,
+                        &mut self.pack_stats,
+                        &mut self.metrics,
+                        self.fixed_length_blocks,
+                        reordered_channels.contains(channel_id),
+                    );
+                    // we make sure we keep writing the current packet
+                    // (on error, the packet was rolled back and is finished by the next call)
+                    self.current_packet = Some(packet);
+                    written?;
+                    single_data_idx += 1;
+                    break;
+                }
+
+                let added_len =
+                    Self::added_message_len(single_messages[num_messages].len(), num_messages);
+                let finish_reason =
+                    if packet.written_messages + num_messages >= self.max_messages_per_packet {
+                        Some(PacketFinishReason::TooManyMessages)
+                    } else if !packet.can_fit(added_len) {
+                        Some(PacketFinishReason::MessageDoesNotFit)
+                    } else if packet.written_messages + num_messages > 0
+                        && !packet.can_fit(added_len + held)
+                    {
+                        // (the first message of a packet can always use the reserved bytes, so
+                        // that the channel makes progress)
+                        Some(PacketFinishReason::ChannelReservation)
+                    } else {
+                        None
+                    };
+                if let Some(finish_reason) = finish_reason {
+                    // can't add any more messages (they are written in packing order)
+                    // finish packet and go back to trying to write fragment messages
+                    let written = Self::write_single_messages(
+                        &mut packet,
+                        single_messages,
+                        &mut num_messages,
+                        *channel_id,
+                        &self.channel_dictionary,
+                        &mut self.encryption,
+                        &mut self.pack_stats,
+                        &mut self.metrics,
+                        self.fixed_length_blocks,
+                        reordered_channels.contains(channel_id),
+                    )
+                    .and_then(|_| {
+                        self.fill_reserved_channels(
+                            &mut packet,
+                            &mut single_data,
+                            single_data_idx,
+                            packet_channel_id,
+                            &reordered_channels,
+                        )
+                    });
+                    self.current_packet = Some(packet);
+                    written?;
+                    self.emit_packet(finish_reason, sink, &mut num_packets)?;
+                    continue 'out;
+                }
+                packet.prewritten_size += added_len;
+                num_messages += 1;
+            }
+        }
+
+        // if we had a packet we were working on, emit it (or keep it open for the next call)
+        if let Some(packet) = self.current_packet.take() {
+            match packet_channel_id {
+                Some(channel_id)
+                    if self.incremental_packets || packet.payload.len() < self.min_packet_bytes =>
+                {
+                    if let Some(packet) = self.keep_open(packet, channel_id) {
+                        self.current_packet = Some(packet);
+                        self.emit_packet(
+                            PacketFinishReason::NoMoreMessages,
+                            sink,
+                            &mut num_packets,
+                        )?;
+                    }
+                }
+                _ => {
+                    self.current_packet = Some(packet);
+                    self.emit_packet(PacketFinishReason::NoMoreMessages, sink, &mut num_packets)?;
+                }
+            }
+        }
+        // partially written channels only hold the messages that were not written
+        for (channel_id, single_messages) in single_data.into_iter().skip(single_data_idx) {
+            // (with the round-robin schedule, a channel can have several entries)
+            if !single_messages.is_empty() {
+                remainder
+                    .entry(channel_id)
+                    .or_default()
+                    .0
+                    .extend(single_messages);
+            }
+        }
+        #[cfg(feature = "trace")]
+        if let (Some(metrics), Some((num_packets, payload_bytes, fragment_packets))) =
+            (&self.metrics, metrics_before)
+        {
+            tracing::info!(
+                packets = metrics.packets - num_packets,
+                payload_bytes = metrics.payload_bytes - payload_bytes,
+                fragment_packets = metrics.fragment_packets - fragment_packets,
+                "Built packets"
+            );
+        }
+        self.update_credits(queued, &remainder);
+        self.back_pressure = self.target_packets_per_tick.map(|target| BackPressure {
+            produced_packets: num_packets,
+            over_budget: num_packets > target,
+        });
+        Ok(remainder)
+    }
+
+    /// Returns the number of packets that `build_packets` would produce for this data.
+    ///
+    /// This runs the same packing arithmetic as `build_packets` (including the channel byte caps
+    /// and the messages deferred by the previous call), but without writing any payload and
+    /// without modifying the state of the builder.
+    ///
+    /// It is not allocation-free: the lists of messages are copied so that the deferred messages
+    /// can be merged into them and the channels reordered. The bytes of the messages are
+    /// reference-counted [`Bytes`], so they are shared and not copied.
+    pub(crate) fn estimate_packets(
+        &self,
+        single_data: &[(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &[(ChannelId, VecDeque<FragmentData>)],
+    ) -> usize {
+        let (mut single_data, fragment_data) = self.prepare_simulation(single_data, fragment_data);
+        self.schedule(&mut single_data);
+        self.simulate_packing(&single_data, &fragment_data, |_, _, _| {})
+    }
+
+    /// Returns where `build_packets` would write the `candidate` message if it was added at the
+    /// end of the messages of channel `channel_id`, without modifying the state of the builder.
+    pub(crate) fn preview_placement(
+        &self,
+        single_data: &[(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &[(ChannelId, VecDeque<FragmentData>)],
+        channel_id: ChannelId,
+        candidate: &SingleData,
+    ) -> Placement {
+        let mut with_candidate = single_data.to_vec();
+        match with_candidate.iter_mut().find(|(id, _)| *id == channel_id) {
+            Some((_, messages)) => messages.push_back(candidate.clone()),
+            None => with_candidate.push((channel_id, VecDeque::from([candidate.clone()]))),
+        }
+        let num_messages = |single_data: &[(ChannelId, VecDeque<SingleData>)]| {
+            single_data
+                .iter()
+                .find(|(id, _)| *id == channel_id)
+                .map_or(0, |(_, messages)| messages.len())
+        };
+        // the candidate is the last message of its channel, even after adding the deferred messages
+        let mut merged = with_candidate.clone();
+        Self::merge_deferred_data(self.deferred_data.clone(), &mut merged, &mut vec![]);
+        self.coalesce_messages(&mut merged);
+        let _ = self.check_message_ids(&mut merged);
+        let num_merged = num_messages(&merged);
+        let (mut with_candidate, fragments) =
+            self.prepare_simulation(&with_candidate, fragment_data);
+        if num_messages(&with_candidate) < num_merged {
+            // the messages over the channel byte cap are deferred, starting from the last one
+            return Placement::Deferred;
+        }
+
+        // position of the candidate once the messages are sorted in packing order
+        // (the sort is stable, and the candidate is the last message of the channel)
+        let mut position = with_candidate
+            .iter()
+            .find(|(id, _)| *id == channel_id)
+            .map(|(_, messages)| self.packing_strategy(channel_id).last_position(messages))
+            .unwrap();
+        self.schedule(&mut with_candidate);
+        // with the round-robin schedule, the messages of the channel are split in several entries
+        let mut channel_idx = 0;
+        for (idx, (_, messages)) in with_candidate
+            .iter()
+            .enumerate()
+            .filter(|(_, (id, _))| *id == channel_id)
+        {
+            channel_idx = idx;
+            if position < messages.len() {
+                break;
+            }
+            position -= messages.len();
+        }
+        let mut packet_index = 0;
+        self.simulate_packing(&with_candidate, &fragments, |packet, idx, written| {
+            if idx == channel_idx && written.contains(&position) {
+                packet_index = packet;
+            }
+        });
+        if packet_index >= self.estimate_packets(single_data, fragment_data) {
+            Placement::NewPacket {
+                packet_index,
+                channel_id,
+            }
+        } else {
+            Placement::Packet {
+                packet_index,
+                channel_id,
+            }
+        }
+    }
+
+    /// Apply the same transformations to the data as `build_packets` does before packing it
+    /// (adding the deferred messages, coalescing identical messages, applying the channel byte caps
+    /// and sorting the channels), on a copy of the lists of messages
+    fn prepare_simulation(
+        &self,
+        single_data: &[(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &[(ChannelId, VecDeque<FragmentData>)],
+    ) -> (
+        Vec<(ChannelId, VecDeque<SingleData>)>,
+        Vec<(ChannelId, VecDeque<FragmentData>)>,
+    ) {
+        let mut single_data = single_data.to_vec();
+        let mut fragment_data = fragment_data.to_vec();
+        Self::merge_deferred_data(
+            self.deferred_data.clone(),
+            &mut single_data,
+            &mut fragment_data,
+        );
+        self.coalesce_messages(&mut single_data);
+        // (with `DuplicateMessageIds::Error`, `build_packets` would not write anything)
+        let _ = self.check_message_ids(&mut single_data);
+        self.split_channel_byte_caps(&mut single_data, &mut fragment_data);
+        self.sort_by_credit(&mut single_data, &mut fragment_data);
+        self.sort_by_reliability(&mut single_data);
+        (single_data, fragment_data)
+    }
+
+    /// Run the packing arithmetic of `build_packets` on data returned by [`Self::prepare_simulation`],
+    /// and return the number of packets.
+    ///
+    /// `on_write` is called with the index of the packet, the index of the channel in `single_data`
+    /// and the range of messages written, in the packing order of the messages.
+    fn simulate_packing(
+        &self,
+        single_data: &[(ChannelId, VecDeque<SingleData>)],
+        fragment_data: &[(ChannelId, VecDeque<FragmentData>)],
+        mut on_write: impl FnMut(usize, usize, Range<usize>),
+    ) -> usize {
+        // for each channel: the channel id, the number of bytes needed to write the channel id + number of messages,
+        // and the size of each message, in packing order.
+        // Also the length of the bytes of each message, in the same order, to find the
+        // fixed-length blocks
+        let (single_lens, bytes_lens): (Vec<(ChannelId, usize, Vec<usize>)>, Vec<Vec<usize>>) =
+            single_data
+                .iter()
+                .map(|(channel_id, messages)| {
+                    // sort the same way as in `build_packets`
+                    let mut messages = messages.iter().collect::<Vec<_>>();
+                    self.packing_strategy(*channel_id).sort(&mut messages);
+                    let (lens, bytes_lens) = messages
+                        .into_iter()
+                        .map(|message| (message.len(), message.bytes.len()))
+                        .unzip();
+                    (
+                        (*channel_id, self.channel_header_len(*channel_id) + 1, lens),
+                        bytes_lens,
+                    )
+                })
+                .unzip();
+        let can_fit = |payload: usize, size: usize| payload + size <= self.content_mtu();
+        // write as many messages as possible from the channel at `idx` in the packet,
+        // which already contains `count` messages, leaving `held` bytes free for the reserved
+        // channels (except for the first message of the packet)
+        // Returns the range of messages written
+        let fill_channel =
+            |payload: &mut usize, count: &mut usize, idx: usize, start: &mut usize, held: usize| {
+                let (_, channel_len, lens) = &single_lens[idx];
+                let packet_count = *count;
+                let (num_messages, size) = lens[*start..]
+                    .iter()
+                    .take(self.max_messages_per_packet.saturating_sub(*count))
+                    .enumerate()
+                    .scan(*payload + *channel_len, |size, (i, len)| {
+                        *size += Self::added_message_len(*len, i);
+                        Some((i, *size))
+                    })
+                    .take_while(|(i, size)| {
+                        *size <= self.content_mtu()
+                            && (packet_count + i == 0 || *size + held <= self.content_mtu())
+                    })
+                    .fold((0, *payload), |(num_messages, _), (_, size)| {
+                        (num_messages + 1, size)
+                    });
+                // the space is reserved for every length, the lengths that a fixed-length block does
+                // not write are released once the channel is written
+                let block_saving = Self::fixed_length_block_len(
+                    self.fixed_length_blocks,
+                    bytes_lens[idx][*start..*start + num_messages]
+                        .iter()
+                        .copied(),
+                )
+                .map_or(0, |len| (num_messages - 1) * varint_len(len as u64));
+                *payload = size - block_saving;
+                *count += num_messages;
+                *start += num_messages;
+                *start - num_messages..*start
+            };
+
+        // bytes that the channel at `idx` leaves free for the reserved channels
+        let held = |idx: usize, message_idx: &[usize]| {
+            self.held_bytes(single_lens[idx].0, |channel_id| {
+                single_lens
+                    .iter()
+                    .zip(message_idx)
+                    .any(|((id, _, lens), start)| *id == channel_id && *start < lens.len())
+            })
+        };
+        // write the reserved channels that come after `single_data_idx` in the rest of the packet
+        // (see `fill_reserved_channels`)
+        let fill_reserved =
+            |payload: &mut usize,
+             count: &mut usize,
+             packet_channel_id: ChannelId,
+             single_data_idx: usize,
+             message_idx: &mut [usize],
+             on_write: &mut dyn FnMut(usize, Range<usize>)| {
+                for idx in single_data_idx + 1..single_lens.len() {
+                    let (channel_id, channel_len, lens) = &single_lens[idx];
+                    if message_idx[idx] == lens.len()
+                        || !self.channel_reservations.contains_key(channel_id)
+                        || self
+                            .channel_boundary(*channel_id, packet_channel_id)
+                            .is_some()
+                        || !can_fit(*payload, *channel_len)
+                    {
+                        continue;
+                    }
+                    let written = fill_channel(payload, count, idx, &mut message_idx[idx], 0);
+                    on_write(idx, written);
+                }
+            };
+
+        let mut num_packets = 0;
+        let mut single_data_idx = 0;
+        // for each channel, index of the first message that hasn't been written yet
+        let mut message_idx = vec![0; single_lens.len()];
+        for (channel_id, fragment_messages) in fragment_data.iter() {
+            for (fragments, mut payload) in self.group_fragments(*channel_id, fragment_messages) {
+                num_packets += 1;
+                let mut count = fragments.len();
+                // fill the rest of the fragment packet with small messages
+                for idx in single_data_idx..single_lens.len() {
+                    let (single_channel_id, channel_len, lens) = &single_lens[idx];
+                    if message_idx[idx] == lens.len()
+                        || !can_fit(payload, *channel_len)
+                        || self
+                            .fragment_tail_boundary(*single_channel_id, *channel_id)
+                            .is_some()
+                    {
+                        continue;
+                    }
+                    let held = held(idx, &message_idx);
+                    let written =
+                        fill_channel(&mut payload, &mut count, idx, &mut message_idx[idx], held);
+                    on_write(num_packets - 1, idx, written);
+                }
+                while single_data_idx < single_lens.len()
+                    && message_idx[single_data_idx] == single_lens[single_data_idx].2.len()
+                {
+                    single_data_idx += 1;
+                }
+            }
+        }
+
+        // payload size of the current packet, first channel written in it and number of messages
+        let mut current_payload: Option<(usize, ChannelId, usize)> = None;
+        while single_data_idx < single_lens.len() {
+            let (channel_id, channel_len, lens) = &single_lens[single_data_idx];
+            if message_idx[single_data_idx] == lens.len() {
+                single_data_idx += 1;
+                continue;
+            }
+            let (payload, packet_channel_id, count) = current_payload.get_or_insert_with(|| {
+                num_packets += 1;
+                (HEADER_BYTES + self.reserved_len(), *channel_id, 0)
+            });
+            if self
+                .channel_boundary(*channel_id, *packet_channel_id)
+                .is_some()
+            {
+                current_payload = None;
+                continue;
+            }
+            let packet_idx = num_packets - 1;
+            if can_fit(*payload, *channel_len) {
+                let held = held(single_data_idx, &message_idx);
+                let written = fill_channel(
+                    payload,
+                    count,
+                    single_data_idx,
+                    &mut message_idx[single_data_idx],
+                    held,
+                );
+                on_write(packet_idx, single_data_idx, written);
+                if message_idx[single_data_idx] == lens.len() {
+                    single_data_idx += 1;
+                    continue;
+                }
+            }
+            fill_reserved(
+                payload,
+                count,
+                *packet_channel_id,
+                single_data_idx,
+                &mut message_idx,
+                &mut |idx, written| on_write(packet_idx, idx, written),
+            );
+            current_payload = None;
+        }
+        num_packets
+    }
+
+    /// Check, before calling `build_packets`, that every channel of `data` can write at least an
+    /// empty message in an empty packet.
+    ///
+    /// Returns the first channel whose id (along with the number of messages and the smallest
+    /// message) does not fit in an empty packet.
+    pub fn validate_channels<T>(
+        &self,
+        data: &[(ChannelId, VecDeque<T>)],
+    ) -> Result<(), SerializationError> {
+        for (channel_id, _) in data {
+            if self.max_single_message_len(*channel_id) < MIN_SINGLE_DATA_BYTES {
+                return Err(SerializationError::ChannelTooLargeForEmptyPacket {
+                    channel_id: *channel_id,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Maximum number of bytes of a single message of channel `channel_id` (including its id and
+    /// length) that fits in an empty packet
+    fn max_single_message_len(&self, channel_id: ChannelId) -> usize {
+        self.content_mtu().saturating_sub(
+            HEADER_BYTES
+                + self.reserved_len()
+                + self.channel_header_len(channel_id)
+                + varint_len(1),
+        )
+    }
+
+    /// Group the fragments of a channel into packets, in a single pass over the fragments.
+    ///
+    /// One packet is kept open for the fragments of other messages that fit in the rest of it.
+    /// A fragment that doesn't fit gets its own packet, which replaces the open packet if it has
+    /// more room left. Usually only the last fragments of messages are small enough to share a
+    /// packet. The fragments of a same message are always in different packets (so that a
+    /// channel's `fragment_mtu` is respected), and the packets of a message are returned in the
+    /// order of its fragments.
+    ///
+    /// Returns the indices of the fragments of each packet, and the number of bytes that the
+    /// packet uses (including the checksum).
+    fn group_fragments(
+        &self,
+        channel_id: ChannelId,
+        fragments: &VecDeque<FragmentData>,
+    ) -> Vec<(Vec<usize>, usize)> {
+        let empty_payload = HEADER_BYTES
+            + self.reserved_len()
+            + self.channel_dictionary.len(channel_id)
+            + varint_len(1);
+        let mut groups = vec![];
+        let mut open_group: Option<(Vec<usize>, usize)> = None;
+        // the messages that have a fragment in the open packet
+        let mut open_messages = HashSet::new();
+        for (idx, fragment) in fragments.iter().enumerate() {
+            let in_open_group = open_messages.contains(&fragment.message_id);
+            if let Some((indices, payload)) = &mut open_group {
+                let added_len = Self::added_message_len(fragment.len(), indices.len());
+                if !in_open_group
+                    && indices.len() < self.max_messages_per_packet
+                    && *payload + added_len <= self.content_mtu()
+                {
+                    *payload += added_len;
+                    indices.push(idx);
+                    open_messages.insert(fragment.message_id);
+                    continue;
+                }
+            }
+            let new_group = (vec![idx], empty_payload + fragment.len());
+            match open_group.take() {
+                // the open packet has more room left: the new packet is finished right away.
+                // This is not possible if the open packet has a previous fragment of the same
+                // message, which must come first
+                Some(group) if !in_open_group && group.1 <= new_group.1 => {
+                    groups.push(new_group);
+                    open_group = Some(group);
+                }
+                group => {
+                    groups.extend(group);
+                    open_messages.clear();
+                    open_messages.insert(fragment.message_id);
+                    open_group = Some(new_group);
+                }
+            }
+        }
+        groups.extend(open_group);
+        groups
+    }
+
+    /// Number of bytes needed to add a message of `message_len` bytes to a channel that already
+    /// has `num_messages` messages in the packet.
+    ///
+    /// The number of messages is written as a varint, so writing it can take more than the 1 byte
+    /// reserved by [`Packet::can_fit_channel`].
+    fn added_message_len(message_len: usize, num_messages: usize) -> usize {
+        message_len + varint_len(num_messages as u64 + 1) - varint_len(num_messages as u64)
+    }
+
+    /// Reserve some space in the packet to write the channel id and the number of messages.
+    ///
+    /// Returns false if the channel does not fit in the packet, and an error if it does not fit
+    /// even though the packet is empty, as starting another packet would not help.
+    fn reserve_channel(
+        packet: &mut Packet,
+        is_new_packet: bool,
+        channel_id: ChannelId,
+        channel_dictionary: &ChannelIdDictionary,
+        encryption: &ChannelEncryption,
+    ) -> Result<bool, SerializationError> {
+        let channel_header_len =
+            channel_dictionary.len(channel_id) + encryption.overhead(channel_id, packet.mtu);
+        if packet.can_fit_channel(channel_header_len) {
+            return Ok(true);
+        }
+        if is_new_packet {
+            return Err(SerializationError::ChannelTooLargeForEmptyPacket { channel_id });
+        }
+        Ok(false)
+    }
+
+    /// Release `written` bytes of the space reserved in the packet, now that they have been written.
+    ///
+    /// Returns an error if less than `written` bytes were reserved, which means that the
+    /// size of the data was computed incorrectly when it was reserved.
+    fn consume_prewritten(
+        packet: &mut Packet,
+        written: usize,
+        channel_id: ChannelId,
+    ) -> Result<(), SerializationError> {
+        let Some(remaining) = packet.prewritten_size.checked_sub(written) else {
+            #[cfg(debug_assertions)]
+            tracing::error!(
+                ?channel_id,
+                expected = packet.prewritten_size,
+                actual = written,
+                "wrote more bytes in the packet than were reserved"
+            );
+            return Err(SerializationError::SizeAccountingMismatch {
+                expected: packet.prewritten_size,
+                actual: written,
+            });
+        };
+        packet.prewritten_size = remaining;
+        Ok(())
+    }
+
+    /// Helper function to fill the current packet with single data message from the current channel
+    ///
+    /// The space reserved for the channel id and number of messages is always released; they are
+    /// only written if at least one message is written, so a packet never contains an empty channel.
+    /// The messages of an encrypted channel are written in a block that is encrypted as a whole.
+    ///
+    /// If an error is returned, the packet is rolled back to its state before the channel: nothing
+    /// is written, the space reserved for the channel is released, and the messages stay in
+    /// `messages`.
+    ///
+    /// `reordered` tells if the messages are not in queue order, in which case the packet is
+    /// marked as sorted (see [`PacketHeader::set_sorted_flag`]).
+    fn write_single_messages(
+        packet: &mut Packet,
+        messages: &mut VecDeque<SingleData>,
+        num_messages: &mut usize,
+        channel_id: ChannelId,
+        channel_dictionary: &ChannelIdDictionary,
+        encryption: &mut ChannelEncryption,
+        pack_stats: &mut Option<PackStats>,
+        metrics: &mut Option<PacketBuilderMetrics>,
+        fixed_length_blocks: bool,
+        reordered: bool,
+    ) -> Result<(), SerializationError> {
+        let channel_len = channel_dictionary.len(channel_id)
+            + encryption.overhead(channel_id, packet.mtu)
+            + varint_len(*num_messages as u64);
+        let payload_len = packet.payload.len();
+        let num_acks = packet.message_acks.len();
+        let prewritten_size = packet.prewritten_size;
+        let written = Self::write_channel_section(
+            packet,
+            messages,
+            *num_messages,
+            channel_id,
+            channel_len,
+            channel_dictionary,
+            encryption,
+            fixed_length_blocks,
+        );
+        let fixed_length_block = match written {
+            Ok(fixed_length_block) => fixed_length_block,
+            Err(e) => {
+                packet.payload.truncate(payload_len);
+                packet.message_acks.truncate(num_acks);
+                let reserved_len = channel_len
+                    + messages
+                        .iter()
+                        .take(*num_messages)
+                        .map(ToBytes::len)
+                        .sum::<usize>();
+                packet.prewritten_size = prewritten_size.saturating_sub(reserved_len);
+                *num_messages = 0;
+                return Err(e);
+            }
+        };
+        if *num_messages > 0 {
+            if reordered {
+                PacketHeader::set_sorted_flag(&mut packet.payload);
+            }
+            packet.written_messages += *num_messages;
+            if let Some(metrics) = metrics {
+                *metrics.channel_messages.entry(channel_id).or_default() += *num_messages;
+            }
+            for (i, message) in messages.drain(..*num_messages).enumerate() {
+                if let (Some(pack_stats), Some(id)) = (pack_stats.as_mut(), message.id) {
+                    // split the channel bytes evenly between the channel's messages
+                    let channel_share =
+                        channel_len / *num_messages + usize::from(i < channel_len % *num_messages);
+                    pack_stats
+                        .message_bytes
+                        .insert((channel_id, id), message.len() + channel_share);
+                    // in a fixed-length block, only the first message writes its length
+                    let serialized_len = if fixed_length_block && i > 0 {
+                        message.len() - varint_len(message.bytes.len() as u64)
+                    } else {
+                        message.len()
+                    };
+                    pack_stats
+                        .serialized_bytes
+                        .insert((channel_id, id), serialized_len);
+                }
+            }
+            *num_messages = 0;
+        }
+        Ok(())
+    }
+
+    /// Write the channel id, the number of messages and the first `num_messages` messages of
+    /// `messages` in the packet (see [`Self::write_single_messages`]), without removing them
+    /// from the queue.
+    ///
+    /// Returns true if the messages were written as a fixed-length block.
+    fn write_channel_section(
+        packet: &mut Packet,
+        messages: &VecDeque<SingleData>,
+        num_messages: usize,
+        channel_id: ChannelId,
+        channel_len: usize,
+        channel_dictionary: &ChannelIdDictionary,
+        encryption: &mut ChannelEncryption,
+        fixed_length_blocks: bool,
+    ) -> Result<bool, SerializationError> {
+        let fixed_length_block = Self::fixed_length_block_len(
+            fixed_length_blocks,
+            messages
+                .iter()
+                .take(num_messages)
+                .map(|message| message.bytes.len()),
+        )
+        .is_some();
+        Self::consume_prewritten(packet, channel_len, channel_id)?;
+        if num_messages == 0 {
+            return Ok(false);
+        }
+        channel_dictionary.write(channel_id, &mut packet.payload)?;
+        let encrypted = encryption.is_encrypted(channel_id);
+        if encrypted {
+            packet.payload.push(ENCRYPTED_SECTION_MARKER);
+        }
+        // write the number of messages for the current channel
+        packet.payload.write_varint(num_messages as u64)?;
+        // the messages of an encrypted channel are written in a separate block first
+        let mut block = if encrypted {
+            encryption.take_block()
+        } else {
+            vec![]
+        };
+        for (i, message) in messages.iter().take(num_messages).enumerate() {
+            let out = if encrypted {
+                &mut block
+            } else {
+                &mut packet.payload
+            };
+            let written = if fixed_length_block {
+                message.to_bytes_in_block(out, i == 0)
+            } else {
+                message.to_bytes(out)
+            };
+            // the space saved by a block is released along with the rest of the reservation
+            written
+                .and_then(|()| Self::consume_prewritten(packet, message.len(), channel_id))
+                .map_err(|e| e.in_channel(channel_id, i))?;
+            // only send a MessageAck when the message has an id (otherwise we don't expect an ack)
+            if let Some(id) = message.id {
+                packet.message_acks.push((
+                    channel_id,
+                    MessageAck {
+                        message_id: id,
+                        fragment_id: None,
+                    },
+                ));
+            }
+        }
+        if encrypted {
+            encryption.write_block(channel_id, block, &mut packet.payload)?;
+        }
+        Ok(fixed_length_block)
+    }
+
+    /// Length of the messages of a channel section, if they are written as a fixed-length block
+    /// (see [`Self::with_fixed_length_blocks`]): the section has more than one message, and all of
+    /// them have the same length.
+    ///
+    /// Empty messages are excluded, so that each message still takes at least 2 bytes.
+    fn fixed_length_block_len(
+        fixed_length_blocks: bool,
+        mut bytes_lens: impl Iterator<Item = usize>,
+    ) -> Option<usize> {
+        if !fixed_length_blocks {
+            return None;
+        }
+        let len = bytes_lens.next().filter(|len| *len > 0)?;
+        let mut num_messages = 1;
+        for other in bytes_lens {
+            if other != len {
+                return None;
+            }
+            num_messages += 1;
+        }
+        (num_messages > 1).then_some(len)
+    }
+
+    // /// Uses multiple exponential searches to fill a packet. Has a good worst case runtime and doesn't
+    // /// create any extraneous extension packets.
+    // fn pack_multiple_exponential(mut messages: &[Message]) -> Vec<Packet> {
+    //     /// A Vec<u8> prefixed by its length as a u32. Each [`Packet`] contains 1 or more [`Section`]s.
+    //     struct Section(Vec<u8>);
+    //     impl Section {
+    //         fn len(&self) -> usize {
+    //             self.0.len() + std::mem::size_of::<u32>()
+    //         }
+    //         fn write(&self, out: &mut Vec<u8>) {
+    //             out.reserve(self.len());
     //             out.extend_from_slice(&u32::try_from(self.0.len()).unwrap().to_le_bytes()); // TODO use varint.
     //             out.extend_from_slice(&self.0);
     //         }
@@ -415,157 +3483,3867 @@ impl PacketBuilder {
     // }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::VecDeque;
+/// Suggest which of the `candidate_mtus` packs messages of these `sizes` (in bytes) most efficiently.
+///
+/// The packing is simulated for each MTU: messages that don't fit in a packet are fragmented,
+/// and every packet pays for its header. The MTU with the highest fill ratio (message bytes divided
+/// by the total bytes of the packets) is returned; ties go to the first candidate.
+///
+/// # Panics
+///
+/// Panics if `candidate_mtus` is empty
+pub fn suggest_mtu(sizes: &[usize], candidate_mtus: &[usize]) -> usize {
+    assert!(!candidate_mtus.is_empty(), "no candidate MTU");
+    let total_bytes: usize = sizes.iter().sum();
+    // bytes written for each fragment on top of the fragment's data
+    let fragment_overhead = MAX_PACKET_SIZE - HEADER_BYTES - FRAGMENT_SIZE;
+    let fill_ratio = |mtu: usize| -> f32 {
+        let Some(fragment_size) = mtu.checked_sub(HEADER_BYTES + fragment_overhead) else {
+            return 0.0;
+        };
+        if fragment_size == 0 {
+            return 0.0;
+        }
+        let mut num_packets = 0;
+        // size of the messages (or last fragments) that can share a packet
+        let mut small_lens = vec![];
+        for size in sizes {
+            // single message: bytes + length + message id
+            let single_len = size + varint_len(*size as u64) + 3;
+            if single_len <= fragment_size {
+                small_lens.push(single_len);
+                continue;
+            }
+            // every fragment but the last one takes a full packet
+            let num_fragments = size.div_ceil(fragment_size);
+            num_packets += num_fragments - 1;
+            small_lens.push(size - (num_fragments - 1) * fragment_size + fragment_overhead);
+        }
+        // fill the packets from the smallest to the largest message, with a channel id and
+        // number of messages at the start of each packet
+        small_lens.sort();
+        let mut payload: Option<usize> = None;
+        for len in small_lens {
+            match payload.as_mut() {
+                Some(payload) if *payload + len <= mtu => *payload += len,
+                _ => {
+                    num_packets += 1;
+                    payload = Some(HEADER_BYTES + 2 + len);
+                }
+            }
+        }
+        if num_packets == 0 {
+            return 0.0;
+        }
+        total_bytes as f32 / (num_packets * mtu) as f32
+    };
+    candidate_mtus
+        .iter()
+        .map(|mtu| (*mtu, fill_ratio(*mtu)))
+        .fold((candidate_mtus[0], f32::MIN), |best, (mtu, ratio)| {
+            if ratio > best.1 {
+                (mtu, ratio)
+            } else {
+                best
+            }
+        })
+        .0
+}
+
+/// Mix the bits of `x`, to derive independent pseudo-random values from a seed
+/// (see [`TieBreak::Random`])
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use bevy::prelude::{default, TypePath};
+    use bytes::Bytes;
+
+    use lightyear_macros::ChannelInternal;
+
+    use crate::channel::senders::fragment_sender::{FragmentSender, FRAGMENT_BYTES};
+    use crate::packet::capture::{replay, PcapLikeWriter};
+    use crate::packet::message::{FragmentIndex, MessageId};
+    use crate::packet::packet_parser::PacketParser;
+    use crate::packet::packet_reader::PacketReader;
+    use crate::prelude::*;
+    use crate::serialize::reader::Reader;
+    use crate::serialize::varint::VarIntReadExt;
+
+    use super::*;
+
+    #[derive(ChannelInternal, TypePath)]
+    struct Channel1;
+
+    #[derive(ChannelInternal, TypePath)]
+    struct Channel2;
+
+    #[derive(ChannelInternal, TypePath)]
+    struct Channel3;
+
+    fn get_channel_registry() -> ChannelRegistry {
+        let settings = ChannelSettings {
+            mode: ChannelMode::UnorderedUnreliable,
+            ..default()
+        };
+        let mut c = ChannelRegistry::default();
+        c.add_channel::<Channel1>(settings.clone()).unwrap();
+        c.add_channel::<Channel2>(settings.clone()).unwrap();
+        c.add_channel::<Channel3>(settings.clone()).unwrap();
+        c
+    }
+
+    /// Build the packets, and check that `estimate_packets` predicted the same number of packets
+    fn build_packets(
+        manager: &mut PacketBuilder,
+        current_tick: Tick,
+        single_data: Vec<(ChannelId, VecDeque<SingleData>)>,
+        fragment_data: Vec<(ChannelId, VecDeque<FragmentData>)>,
+    ) -> Result<Vec<Packet>, SerializationError> {
+        let estimate = manager.estimate_packets(&single_data, &fragment_data);
+        let packets = manager.build_packets(current_tick, single_data, fragment_data)?;
+        assert_eq!(estimate, packets.len());
+        Ok(packets)
+    }
+
+    /// A bunch of small messages that all fit in the same packet
+    #[test]
+    fn test_pack_small_messages() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5).with_metrics(true);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+        let channel_kind3 = ChannelKind::of::<Channel3>();
+        let channel_id3 = channel_registry.get_net_from_kind(&channel_kind3).unwrap();
+
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+
+        let single_data = vec![
+            (*channel_id1, VecDeque::from(vec![small_message.clone()])),
+            (
+                *channel_id2,
+                VecDeque::from(vec![small_message.clone(), small_message.clone()]),
+            ),
+            (*channel_id3, VecDeque::from(vec![small_message.clone()])),
+        ];
+        let fragment_data = vec![];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+        assert_eq!(packets.len(), 1);
+        let packet = packets.pop().unwrap();
+        assert_eq!(packet.message_acks, vec![]);
+        assert_eq!(
+            manager.take_metrics(),
+            PacketBuilderMetrics {
+                packets: 1,
+                payload_bytes: packet.payload.len(),
+                fragment_packets: 0,
+                single_packets: 1,
+                channel_messages: HashMap::from_iter([
+                    (*channel_id1, 1),
+                    (*channel_id2, 2),
+                    (*channel_id3, 1)
+                ]),
+                wasted_bytes: MAX_PACKET_SIZE - packet.payload.len(),
+                max_wasted_bytes: MAX_PACKET_SIZE - packet.payload.len(),
+            }
+        );
+        // header, 3 channels (id + number of messages) and 4 messages (id flag + length + 10 bytes)
+        assert_eq!(
+            packet.wasted_bytes(MAX_PACKET_SIZE),
+            MAX_PACKET_SIZE - HEADER_BYTES - 3 * 2 - 4 * 12
+        );
+        assert_eq!(manager.take_metrics(), PacketBuilderMetrics::default());
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(
+            contents.get(channel_id1).unwrap(),
+            &vec![small_bytes.clone()]
+        );
+        assert_eq!(
+            contents.get(channel_id2).unwrap(),
+            &vec![small_bytes.clone(), small_bytes.clone()]
+        );
+        assert_eq!(
+            contents.get(channel_id3).unwrap(),
+            &vec![small_bytes.clone()]
+        );
+        Ok(())
+    }
+
+    /// The channels of the packet built in `test_pack_small_messages` are read without parsing
+    /// the messages
+    #[test]
+    fn test_packet_channels() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_ids = [
+            ChannelKind::of::<Channel1>(),
+            ChannelKind::of::<Channel2>(),
+            ChannelKind::of::<Channel3>(),
+        ]
+        .map(|kind| *channel_registry.get_net_from_kind(&kind).unwrap());
+
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![
+            (channel_ids[0], VecDeque::from(vec![small_message.clone()])),
+            (
+                channel_ids[1],
+                VecDeque::from(vec![small_message.clone(), small_message.clone()]),
+            ),
+            (channel_ids[2], VecDeque::from(vec![small_message.clone()])),
+        ];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        let packet = packets.pop().unwrap();
+        assert_eq!(packet.channels()?, channel_ids.to_vec());
+
+        // the fragments are skipped too
+        let fragment_sender = FragmentSender::default();
+        let fragments =
+            fragment_sender.build_fragments(MessageId(0), None, Bytes::from(vec![1; 1500]))?;
+        let single_data = vec![(channel_ids[2], VecDeque::from(vec![small_message.clone()]))];
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            single_data,
+            vec![(channel_ids[0], fragments.into())],
+        )?;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].channels()?, vec![channel_ids[0]]);
+        assert_eq!(packets[1].channels()?, vec![channel_ids[0], channel_ids[2]]);
+
+        // header-only packets have no channels
+        assert!(manager.build_keepalive(Tick(1))?.channels()?.is_empty());
+        Ok(())
+    }
+
+    /// The messages of the packet built in `test_pack_small_messages` can be read as slices
+    /// of the payload
+    #[test]
+    fn test_packet_reader() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_id1 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let channel_id2 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let channel_id3 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel3>())
+            .unwrap();
+
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+        let single_data = vec![
+            (channel_id1, VecDeque::from(vec![small_message.clone()])),
+            (
+                channel_id2,
+                VecDeque::from(vec![small_message.clone(), small_message.clone()]),
+            ),
+            (channel_id3, VecDeque::from(vec![small_message.clone()])),
+        ];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        let packet = packets.pop().unwrap();
+
+        let channel_dictionary = ChannelIdDictionary::default();
+        let payload = packet.payload.as_ptr_range();
+        let mut num_messages = HashMap::new();
+        for message in PacketReader::new(&packet.payload, &channel_dictionary)? {
+            let (channel_id, bytes) = message?;
+            assert_eq!(bytes, small_bytes.as_ref());
+            // the message was not copied out of the payload
+            assert!(payload.contains(&bytes.as_ptr()));
+            *num_messages.entry(channel_id).or_insert(0) += 1;
+        }
+        assert_eq!(
+            num_messages,
+            HashMap::from_iter([(channel_id1, 1), (channel_id2, 2), (channel_id3, 1)])
+        );
+
+        // the framing is checked: a truncated packet returns an error, and stops the iteration
+        let truncated = &packet.payload[..packet.payload.len() - 1];
+        let messages: Vec<_> = PacketReader::new(truncated, &channel_dictionary)?.collect();
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(
+            messages[3],
+            Err(SerializationError::TruncatedMessage { .. })
+        ));
+        Ok(())
+    }
+
+    /// Only the channel blocks that are needed are parsed
+    #[test]
+    fn test_channel_blocks() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let message = |channel_id: ChannelId, i: u8| {
+            SingleData::new(
+                None,
+                Bytes::from(vec![10 * channel_id as u8 + i; 10 + i as usize]),
+            )
+        };
+        let single_data = (0..3)
+            .map(|channel_id| {
+                let messages = (0..channel_id as u8 + 1)
+                    .map(|i| message(channel_id, i))
+                    .collect::<VecDeque<_>>();
+                (channel_id, messages)
+            })
+            .collect();
+        let packet = build_packets(&mut manager, Tick(4), single_data, vec![])?
+            .pop()
+            .unwrap();
+
+        let channel_dictionary = ChannelIdDictionary::default();
+        let blocks = packet
+            .channel_blocks(&channel_dictionary)?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|(channel_id, num_messages, _)| (*channel_id, *num_messages))
+                .collect::<Vec<_>>(),
+            vec![(0, 1), (1, 2), (2, 3)]
+        );
+        // the blocks are contiguous slices of the payload
+        let payload = packet.payload.as_ptr_range();
+        assert!(blocks
+            .iter()
+            .all(|(_, _, block)| payload.contains(&block.as_ptr())));
+
+        // only parse the messages of channel 2
+        let (channel_id, num_messages, block) = blocks[2];
+        let messages = PacketReader::from_channel_block(
+            channel_id,
+            num_messages,
+            block,
+            &channel_dictionary,
+            packet.tick()?,
+        )
+        .collect::<Result<Vec<_>, _>>()?;
+        let expected = (0..3).map(|i| message(2, i).bytes).collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            expected
+                .iter()
+                .map(|bytes| (2, bytes.as_ref()))
+                .collect::<Vec<_>>()
+        );
+
+        // a truncated block is an error, and ends the iteration
+        let truncated = &packet.payload[..packet.payload.len() - 1];
+        let blocks = PacketReader::new(truncated, &channel_dictionary)?
+            .channel_blocks()
+            .collect::<Vec<_>>();
+        assert_eq!(blocks.len(), 3);
+        assert!(matches!(
+            blocks[2],
+            Err(SerializationError::TruncatedMessage { .. })
+        ));
+        Ok(())
+    }
+
+    /// The messages read from a packet expose the tick at which the packet was sent
+    #[test]
+    fn test_packet_reader_tick() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![
+            (0, VecDeque::from(vec![message.clone(), message.clone()])),
+            (1, VecDeque::from(vec![message.clone()])),
+        ];
+        let packet = build_packets(&mut manager, Tick(7), single_data, vec![])?
+            .pop()
+            .unwrap();
+
+        let channel_dictionary = ChannelIdDictionary::default();
+        let reader = PacketReader::new(&packet.payload, &channel_dictionary)?;
+        assert_eq!(reader.tick(), Tick(7));
+        let messages = reader.with_tick().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(messages.len(), 3);
+        for (_, sent_tick, bytes) in messages {
+            assert_eq!(sent_tick, Tick(7));
+            assert_eq!(bytes, message.bytes.as_ref());
+            // transit delay, if the message is read at tick 10
+            assert_eq!(Tick(10) - sent_tick, 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_schedule_mode() -> Result<(), PacketError> {
+        // the messages of channel 1 start at 250, so that they can be told apart
+        let message = |channel_id: ChannelId, i: u8| {
+            SingleData::new(None, Bytes::from(vec![250 * channel_id as u8 + i; 10]))
+        };
+        let single_data = |num_messages: [u8; 2]| {
+            (0..2)
+                .map(|channel_id| {
+                    let messages = (0..num_messages[channel_id as usize])
+                        .map(|i| message(channel_id, i))
+                        .collect::<VecDeque<_>>();
+                    (channel_id, messages)
+                })
+                .collect::<Vec<_>>()
+        };
+        let channel_dictionary = ChannelIdDictionary::default();
+        let read_order = |packet: &Packet| {
+            PacketReader::new(&packet.payload, &channel_dictionary)
+                .unwrap()
+                .map(|message| message.unwrap().1[0])
+                .collect::<Vec<_>>()
+        };
+
+        // grouped: all the messages of channel 0, then all the messages of channel 1
+        let mut manager = PacketBuilder::new(1.5);
+        let packets = build_packets(&mut manager, Tick(0), single_data([3, 3]), vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(read_order(&packets[0]), vec![0, 1, 2, 250, 251, 252]);
+
+        // round-robin: the channels take turns, the last messages of channel 0 share a section
+        let mut manager = PacketBuilder::new(1.5).with_schedule_mode(ScheduleMode::RoundRobin);
+        let packets = build_packets(&mut manager, Tick(0), single_data([4, 2]), vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(read_order(&packets[0]), vec![0, 250, 1, 251, 2, 3]);
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        assert_eq!(contents[&0].len(), 4);
+        assert_eq!(contents[&1].len(), 2);
+
+        // when the messages span several packets, the message of channel 1 is only sent in the
+        // last packet with the grouped schedule, and in the first one with round-robin
+        let mut manager = PacketBuilder::new(1.5);
+        let packets = build_packets(&mut manager, Tick(0), single_data([200, 1]), vec![])?;
+        assert!(packets.len() > 1);
+        assert!(read_order(packets.last().unwrap()).contains(&250));
+        let mut manager = PacketBuilder::new(1.5).with_schedule_mode(ScheduleMode::RoundRobin);
+        let packets = build_packets(&mut manager, Tick(0), single_data([200, 1]), vec![])?;
+        assert!(packets.len() > 1);
+        assert_eq!(read_order(&packets[0])[..2], [0, 250]);
+        Ok(())
+    }
+
+    /// An empty message is written with a length of 0, and read back as an empty message
+    /// instead of being dropped
+    #[test]
+    fn test_pack_empty_message() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let empty_message = SingleData::new(None, Bytes::new());
+        assert_eq!(empty_message.len(), MIN_SINGLE_DATA_BYTES);
+
+        // alone in the packet: channel id, number of messages, no message id, length 0
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(0, VecDeque::from(vec![empty_message.clone()]))],
+            vec![],
+        )?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].payload[HEADER_BYTES..], [0, 1, 0, 0]);
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        assert_eq!(contents.get(&0).unwrap(), &vec![Bytes::new()]);
+
+        // between other messages, and followed by another channel
+        let small_message = SingleData::new(Some(MessageId(1)), Bytes::from(vec![7u8; 10]));
+        let single_data = vec![
+            (
+                0,
+                VecDeque::from(vec![
+                    empty_message.clone(),
+                    small_message.clone(),
+                    SingleData::with_id(MessageId(2), Bytes::new()),
+                ]),
+            ),
+            (1, VecDeque::from(vec![empty_message])),
+        ];
+        let packets = build_packets(&mut manager, Tick(1), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        let packet = packets.into_iter().next().unwrap();
+        assert_eq!(packet.message_acks.len(), 2);
+        let channel_dictionary = ChannelIdDictionary::default();
+        let messages = PacketReader::new(&packet.payload, &channel_dictionary)?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            messages,
+            vec![
+                (0, &[][..]),
+                (0, &[][..]),
+                (0, &[7u8; 10][..]),
+                (1, &[][..])
+            ]
+        );
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(contents.get(&0).unwrap().len(), 3);
+        assert_eq!(contents.get(&1).unwrap(), &vec![Bytes::new()]);
+        Ok(())
+    }
+
+    /// A channel with reserved bytes fits in the first packet even if an earlier channel has
+    /// enough messages to fill it
+    #[test]
+    fn test_pack_channel_reservation() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings::default())
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                reserved_bytes: 30,
+                ..default()
+            })
+            .unwrap();
+        let channel_id1 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let channel_id2 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let big_message = SingleData::new(None, Bytes::from(vec![1u8; 50]));
+        let input = SingleData::new(None, Bytes::from(vec![2u8; 20]));
+        let single_data = |inputs: usize| {
+            vec![
+                (channel_id1, VecDeque::from(vec![big_message.clone(); 30])),
+                (channel_id2, VecDeque::from(vec![input.clone(); inputs])),
+            ]
+        };
+
+        // without the reservation, the first channel fills the packet
+        let mut manager = PacketBuilder::new(1.5);
+        let (mut packets, remainder) =
+            manager.build_packets_limited(Tick(0), single_data(1), vec![], 1)?;
+        assert_eq!(remainder[&channel_id2].0.len(), 1);
+        let packet = packets.pop().unwrap();
+        let unreserved_messages = packet.written_messages;
+        assert!(!packet.parse_packet_payload()?.contains_key(&channel_id2));
+
+        let mut manager = PacketBuilder::new(1.5).with_channel_registry(&channel_registry);
+        let (mut packets, remainder) =
+            manager.build_packets_limited(Tick(0), single_data(1), vec![], 1)?;
+        assert!(!remainder.contains_key(&channel_id2));
+        assert_eq!(
+            remainder[&channel_id1].0.len(),
+            30 - (packets[0].written_messages - 1)
+        );
+        assert!(packets[0].payload.len() <= manager.mtu);
+        let contents = packets.pop().unwrap().parse_packet_payload()?;
+        assert_eq!(contents[&channel_id2], vec![input.bytes.clone()]);
+
+        // the estimate takes the reservation into account
+        let packets = build_packets(&mut manager, Tick(1), single_data(1), vec![])?;
+        assert_eq!(packets.len(), 2);
+
+        // without messages on the reserved channel, the reservation is released
+        let (packets, _) = manager.build_packets_limited(Tick(2), single_data(0), vec![], 1)?;
+        assert_eq!(packets[0].written_messages, unreserved_messages);
+        Ok(())
+    }
+
+    /// Packets never contain more messages than the remote accepts
+    #[test]
+    fn test_pack_max_messages_per_packet() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let wire_config = WireConfig::default().with_max_messages_per_packet(4);
+        let mut manager = PacketBuilder::new(1.5).with_wire_config(wire_config)?;
+        let channel_id1 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let channel_id2 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![
+            (channel_id1, VecDeque::from(vec![small_message.clone(); 6])),
+            (channel_id2, VecDeque::from(vec![small_message.clone(); 4])),
+        ];
+        let packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 3);
+        let mut num_messages = 0;
+        for (i, packet) in packets.into_iter().enumerate() {
+            let expected = if i < 2 { 4 } else { 2 };
+            assert_eq!(packet.written_messages, expected);
+            let contents = packet.parse_packet_payload_with(&ChannelIdDictionary::default(), 4)?;
+            num_messages += contents.values().map(Vec::len).sum::<usize>();
+        }
+        assert_eq!(num_messages, 10);
+
+        // a packet at the limit is rejected by a peer with a lower limit
+        let single_data = vec![(channel_id1, VecDeque::from(vec![small_message; 4]))];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(
+            packets
+                .pop()
+                .unwrap()
+                .parse_packet_payload_with(&ChannelIdDictionary::default(), 3),
+            Err(PacketError::Serialization(
+                SerializationError::TooManyMessages { claimed: 4, max: 3 }
+            ))
+        ));
+        Ok(())
+    }
+
+    /// A channel with more messages than the limit is split across packets, and every block
+    /// is accepted by the remote
+    #[test]
+    fn test_pack_channel_over_max_messages() -> Result<(), PacketError> {
+        let wire_config = WireConfig::default().with_max_messages_per_packet(3);
+        let mut manager = PacketBuilder::new(1.5).with_wire_config(wire_config)?;
+        let messages = (0..10)
+            .map(|i| SingleData::new(None, Bytes::from(vec![i; 10])))
+            .collect::<VecDeque<_>>();
+        let packets = build_packets(&mut manager, Tick(0), vec![(0, messages.clone())], vec![])?;
+        assert_eq!(packets.len(), 4);
+
+        let mut received = vec![];
+        for packet in packets {
+            assert!(packet.written_messages <= 3);
+            let mut contents =
+                packet.parse_packet_payload_with(&ChannelIdDictionary::default(), 3)?;
+            assert_eq!(contents.len(), 1);
+            received.extend(contents.remove(&0).unwrap());
+        }
+        assert_eq!(
+            received,
+            messages
+                .into_iter()
+                .map(|message| message.bytes)
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    /// Relayed bytes are written as they are, and acked if they have a message id
+    #[test]
+    fn test_pack_raw_messages() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+
+        // opaque bytes, that don't need to be a valid message
+        let blob = Bytes::from_static(b"\xff\x00opaque relayed blob\x01");
+        let raw_data = vec![(
+            channel_id,
+            VecDeque::from(vec![
+                RawMessage {
+                    id: Some(MessageId(4)),
+                    bytes: blob.clone(),
+                },
+                RawMessage {
+                    id: None,
+                    bytes: blob.clone(),
+                },
+            ]),
+        )];
+        let mut packets = manager.build_raw_packets(Tick(0), raw_data)?;
+        assert_eq!(packets.len(), 1);
+        let packet = packets.pop().unwrap();
+        assert_eq!(
+            packet.message_acks,
+            vec![(
+                channel_id,
+                MessageAck {
+                    message_id: MessageId(4),
+                    fragment_id: None,
+                }
+            )]
+        );
+        // the blob appears byte for byte in the packet, after the message id and the length
+        let expected = [
+            &[1, 0, 4, blob.len() as u8][..],
+            &blob,
+            &[0, blob.len() as u8],
+            &blob,
+        ]
+        .concat();
+        assert!(packet.payload.ends_with(&expected));
+        assert_eq!(
+            packet.parse_packet_payload()?.get(&channel_id).unwrap(),
+            &vec![blob.clone(), blob]
+        );
+        Ok(())
+    }
+
+    /// The captured packets can be replayed byte for byte
+    #[test]
+    fn test_capture_packets() -> Result<(), PacketError> {
+        let writer = Arc::new(Mutex::new(PcapLikeWriter::new(vec![])?));
+        let mut manager = PacketBuilder::new(1.5);
+        let capture_writer = writer.clone();
+        manager.set_capture(Some(Box::new(move |packet| {
+            capture_writer.lock().unwrap().write_packet(packet).unwrap();
+        })));
+
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let mut packets = vec![];
+        for tick in 0..3 {
+            let single_data = vec![
+                (0, VecDeque::from(vec![small_message.clone()])),
+                (
+                    1,
+                    VecDeque::from(vec![small_message.clone(), small_message.clone()]),
+                ),
+            ];
+            packets.extend(manager.build_packets(Tick(tick), single_data, vec![])?);
+        }
+        // packets are not captured anymore
+        manager.set_capture(None);
+        let single_data = vec![(0, VecDeque::from(vec![small_message.clone()]))];
+        manager.build_packets(Tick(3), single_data, vec![])?;
+
+        drop(manager);
+        let bytes = Arc::into_inner(writer)
+            .unwrap()
+            .into_inner()
+            .unwrap()
+            .into_inner();
+        let replayed = replay(bytes.as_slice())?;
+        assert_eq!(replayed.len(), packets.len());
+        for (tick, (captured, packet)) in replayed.iter().zip(packets.iter()).enumerate() {
+            assert_eq!(captured.tick, Tick(tick as u16));
+            assert_eq!(captured.packet.packet_id, packet.packet_id);
+            assert_eq!(captured.packet.payload, packet.payload);
+        }
+        Ok(())
+    }
+
+    /// A data packet without any channel carries the acks of the received packets
+    #[test]
+    fn test_build_ack_packet() -> Result<(), PacketError> {
+        let mut sender = PacketBuilder::new(1.5);
+        let mut receiver = PacketBuilder::new(1.5);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        for i in 0..3 {
+            let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+            let packet = sender
+                .build_packets(Tick(0), single_data, vec![])?
+                .pop()
+                .unwrap();
+            // packet 1 is lost
+            if i != 1 {
+                let header = Packet::peek_header(&packet.payload)?;
+                receiver.header_manager.process_recv_packet_header(&header);
+            }
+        }
+
+        let packet = receiver.build_ack_packet(Tick(1))?;
+        assert_eq!(packet.packet_type()?, PacketType::Data);
+        assert_ne!(packet.packet_type()?, PacketType::KeepAlive);
+        assert!(packet.is_empty());
+        assert_eq!(packet.payload.len(), HEADER_BYTES);
+        let header = Packet::peek_header(&packet.payload)?;
+        let acked = header.acked_packet_ids().collect::<Vec<_>>();
+        assert!(acked.contains(&PacketId(0)));
+        assert!(!acked.contains(&PacketId(1)));
+        assert!(acked.contains(&PacketId(2)));
+        assert!(packet.parse_packet_payload()?.is_empty());
+
+        let mut newly_acked = sender.header_manager.process_recv_packet_header(&header);
+        newly_acked.sort();
+        assert_eq!(newly_acked, vec![PacketId(0), PacketId(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_keepalive() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let packet = manager.build_keepalive(Tick(2))?;
+        assert_eq!(packet.payload.len(), HEADER_BYTES);
+        assert_eq!(packet.packet_type()?, PacketType::KeepAlive);
+        assert!(packet.is_empty());
+        let header = Packet::peek_header(&packet.payload)?;
+        assert_eq!(header.tick, Tick(2));
+        assert!(packet.parse_packet_payload()?.is_empty());
+
+        // the next packet gets the next packet id
+        assert_eq!(manager.build_keepalive(Tick(3))?.packet_id, PacketId(1));
+        Ok(())
+    }
+
+    /// The placement previewed for a message is where the message is written by the real build
+    #[test]
+    fn test_preview_placement() -> Result<(), PacketError> {
+        let message =
+            |id: u16, len: usize| SingleData::new(Some(MessageId(id)), Bytes::from(vec![7u8; len]));
+        let single_data = vec![
+            (0, VecDeque::from(vec![message(0, 10), message(1, 400)])),
+            (1, VecDeque::from(vec![message(2, 400)])),
+        ];
+        // returns the index of the packet where the candidate was written by the real build
+        let build_with_candidate = |manager: &mut PacketBuilder,
+                                    channel_id: ChannelId,
+                                    candidate: &SingleData|
+         -> Result<Option<usize>, PacketError> {
+            let mut single_data = single_data.clone();
+            single_data
+                .iter_mut()
+                .find(|(id, _)| *id == channel_id)
+                .unwrap()
+                .1
+                .push_back(candidate.clone());
+            let packets = manager.build_packets(Tick(0), single_data, vec![])?;
+            Ok(packets.iter().position(|packet| {
+                packet
+                    .message_acks
+                    .iter()
+                    .any(|(id, ack)| *id == channel_id && Some(ack.message_id) == candidate.id)
+            }))
+        };
+
+        // the candidate fits in the existing packet
+        let mut manager = PacketBuilder::new(1.5);
+        let candidate = message(10, 100);
+        let placement = manager.preview_placement(&single_data, &[], 1, &candidate);
+        assert_eq!(
+            placement,
+            Placement::Packet {
+                packet_index: 0,
+                channel_id: 1
+            }
+        );
+        assert_eq!(build_with_candidate(&mut manager, 1, &candidate)?, Some(0));
+
+        // the candidate needs a new packet
+        let mut manager = PacketBuilder::new(1.5);
+        let candidate = message(11, 800);
+        let placement = manager.preview_placement(&single_data, &[], 0, &candidate);
+        assert_eq!(
+            placement,
+            Placement::NewPacket {
+                packet_index: 1,
+                channel_id: 0
+            }
+        );
+        assert_eq!(build_with_candidate(&mut manager, 0, &candidate)?, Some(1));
+
+        // the candidate goes over the channel byte cap
+        let mut manager = PacketBuilder::new(1.5).with_channel_byte_cap(1, 300);
+        let candidate = message(12, 200);
+        let placement = manager.preview_placement(&single_data, &[], 1, &candidate);
+        assert_eq!(placement, Placement::Deferred);
+        assert_eq!(build_with_candidate(&mut manager, 1, &candidate)?, None);
+        Ok(())
+    }
+
+    /// Same as `test_pack_small_messages`, with the messages added through a `MessageBatch`
+    #[test]
+    fn test_pack_small_messages_batch() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_id1 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let channel_id2 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let channel_id3 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel3>())
+            .unwrap();
+
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+        let mut batch = MessageBatch::default();
+        batch
+            .push_single(channel_id2, small_message.clone())
+            .push_single(channel_id1, small_message.clone())
+            .push_single(channel_id2, small_message.clone())
+            .push_single(channel_id3, small_message.clone());
+
+        let (single_data, fragment_data) = batch.into_data();
+        assert!(fragment_data.is_empty());
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+        assert_eq!(packets.len(), 1);
+        let contents = packets.pop().unwrap().parse_packet_payload()?;
+        assert_eq!(
+            contents.get(&channel_id1).unwrap(),
+            &vec![small_bytes.clone()]
+        );
+        assert_eq!(
+            contents.get(&channel_id2).unwrap(),
+            &vec![small_bytes.clone(), small_bytes.clone()]
+        );
+        assert_eq!(contents.get(&channel_id3).unwrap(), &vec![small_bytes]);
+        Ok(())
+    }
+
+    /// We cannot write the channel id of the next channel in the packet, so we need to finish the current
+    /// packet and start a new one.
+    /// We have 1200 -11 (header) -1 (channel_id) - 1(num_message) = 1184 bytes per message
+    ///
+    /// Test both with different channels and same channels
+    #[test]
+    fn test_pack_cannot_write_channel_id() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+
+        let small_bytes = Bytes::from(vec![7u8; 1184]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+
+        {
+            let single_data = vec![(
+                *channel_id1,
+                VecDeque::from(vec![small_message.clone(), small_message.clone()]),
+            )];
+            let fragment_data = vec![];
+            let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+            assert_eq!(packets.len(), 2);
+        }
+        {
+            let single_data = vec![
+                (*channel_id1, VecDeque::from(vec![small_message.clone()])),
+                (*channel_id2, VecDeque::from(vec![small_message.clone()])),
+            ];
+            let fragment_data = vec![];
+            let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+            assert_eq!(packets.len(), 2);
+        }
+        Ok(())
+    }
+
+    /// With one packet per tick, the low-weight channel accumulates credit until it is written
+    /// ahead of the high-weight channel
+    #[test]
+    fn test_pack_limited_channel_weights() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5)
+            .with_channel_weight(0, 10.0)
+            .with_channel_weight(1, 1.0);
+        // each message takes a full packet
+        let big_message = SingleData::new(None, Bytes::from(vec![7u8; 1184]));
+        let mut num_packets = HashMap::<ChannelId, usize>::default();
+        for tick in 0..100 {
+            let single_data = vec![
+                (0, VecDeque::from(vec![big_message.clone()])),
+                (1, VecDeque::from(vec![big_message.clone()])),
+            ];
+            let (packets, remainder) =
+                manager.build_packets_limited(Tick(tick), single_data, vec![], 1)?;
+            assert_eq!(packets.len(), 1);
+            // the channel that was not written is the one left in the remainder
+            let (channel_id, _) = remainder.first_key_value().unwrap();
+            *num_packets.entry(1 - *channel_id).or_default() += 1;
+        }
+        // channel 1 gets about 1 packet every 11 ticks
+        assert!(num_packets[&1] >= 8, "{num_packets:?}");
+        assert!(num_packets[&0] > 8 * num_packets[&1], "{num_packets:?}");
+        Ok(())
+    }
+
+    /// Channels with the same weight take turns being written first
+    #[test]
+    fn test_pack_tie_break() -> Result<(), PacketError> {
+        // each message takes most of a packet, so each channel is written in its own packet
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 700]));
+        let first_channels = |tie_break: TieBreak| -> Result<Vec<Vec<ChannelId>>, PacketError> {
+            let mut manager = PacketBuilder::new(1.5)
+                .with_tie_break(tie_break)
+                .with_seed(3);
+            (0..4)
+                .map(|tick| {
+                    let single_data = (0..3)
+                        .map(|channel_id| (channel_id, VecDeque::from(vec![message.clone()])))
+                        .collect();
+                    let packets = manager.build_packets(Tick(tick), single_data, vec![])?;
+                    Ok(packets
+                        .into_iter()
+                        .map(|packet| {
+                            let payload = packet.parse_packet_payload().unwrap();
+                            assert_eq!(payload.len(), 1);
+                            *payload.keys().next().unwrap()
+                        })
+                        .collect())
+                })
+                .collect()
+        };
+        assert_eq!(first_channels(TieBreak::ChannelId)?, vec![vec![0, 1, 2]; 4]);
+        assert_eq!(
+            first_channels(TieBreak::LeastRecentlyServed)?,
+            vec![vec![0, 1, 2], vec![1, 2, 0], vec![2, 0, 1], vec![0, 1, 2]]
+        );
+        // the random order is reproducible from the seed
+        let random = first_channels(TieBreak::Random)?;
+        assert_eq!(random, first_channels(TieBreak::Random)?);
+        for order in &random {
+            let mut channels = order.clone();
+            channels.sort();
+            assert_eq!(channels, vec![0, 1, 2]);
+        }
+        Ok(())
+    }
+
+    /// With `TieBreak::InputOrder`, the channels are written in the order of the input
+    #[test]
+    fn test_pack_input_order() -> Result<(), PacketError> {
+        let message =
+            |channel_id: ChannelId| SingleData::new(None, vec![channel_id as u8; 10].into());
+        let single_data = || {
+            [3, 1, 2]
+                .into_iter()
+                .map(|channel_id| (channel_id, VecDeque::from(vec![message(channel_id); 2])))
+                .collect::<Vec<_>>()
+        };
+        let channel_dictionary = ChannelIdDictionary::default();
+        let channel_order = |packet: &Packet| {
+            PacketReader::new(&packet.payload, &channel_dictionary)
+                .unwrap()
+                .map(|message| message.unwrap().0)
+                .collect::<Vec<_>>()
+        };
+
+        let mut manager = PacketBuilder::new(1.5);
+        let packets = build_packets(&mut manager, Tick(0), single_data(), vec![])?;
+        assert_eq!(channel_order(&packets[0]), vec![1, 1, 2, 2, 3, 3]);
+
+        let mut manager = PacketBuilder::new(1.5).with_tie_break(TieBreak::InputOrder);
+        let packets = build_packets(&mut manager, Tick(0), single_data(), vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(channel_order(&packets[0]), vec![3, 3, 1, 1, 2, 2]);
+        Ok(())
+    }
+
+    /// The packet limit is reached while writing the messages of a channel
+    #[test]
+    fn test_pack_limited_mid_channel() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+
+        // each message takes a full packet
+        let big_message = SingleData::new(None, Bytes::from(vec![7u8; 1184]));
+        let single_data = vec![
+            (*channel_id1, VecDeque::from(vec![big_message.clone(); 3])),
+            (*channel_id2, VecDeque::from(vec![big_message.clone()])),
+        ];
+        let (packets, remainder) =
+            manager.build_packets_limited(Tick(0), single_data, vec![], 2)?;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(
+            remainder,
+            BTreeMap::from([
+                (
+                    *channel_id1,
+                    (VecDeque::from(vec![big_message.clone()]), VecDeque::new())
+                ),
+                (
+                    *channel_id2,
+                    (VecDeque::from(vec![big_message.clone()]), VecDeque::new())
+                ),
+            ])
+        );
+        Ok(())
+    }
+
+    /// The packet limit is reached right after a channel has been fully written
+    #[test]
+    fn test_pack_limited_channel_boundary() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+        let channel_kind3 = ChannelKind::of::<Channel3>();
+        let channel_id3 = channel_registry.get_net_from_kind(&channel_kind3).unwrap();
+
+        let big_message = SingleData::new(None, Bytes::from(vec![7u8; 1184]));
+        let num_big_bytes = (1.5 * FRAGMENT_SIZE as f32) as usize;
+        let fragments = FragmentSender::default().build_fragments(
+            MessageId(0),
+            None,
+            Bytes::from(vec![1u8; num_big_bytes]),
+        )?;
+        let single_data = vec![
+            (*channel_id1, VecDeque::from(vec![big_message.clone()])),
+            (*channel_id2, VecDeque::from(vec![big_message.clone()])),
+        ];
+        let fragment_data = vec![(*channel_id3, VecDeque::from(fragments.clone()))];
+        // the 2 fragments, then the message from channel 1
+        let (packets, remainder) =
+            manager.build_packets_limited(Tick(0), single_data, fragment_data, 3)?;
+        assert_eq!(packets.len(), 3);
+        assert_eq!(
+            remainder,
+            BTreeMap::from([(
+                *channel_id2,
+                (VecDeque::from(vec![big_message.clone()]), VecDeque::new())
+            )])
+        );
+
+        // the limit is reached in the middle of the fragments
+        let single_data = vec![(*channel_id1, VecDeque::from(vec![big_message.clone()]))];
+        let fragment_data = vec![(*channel_id3, VecDeque::from(fragments.clone()))];
+        let (packets, remainder) =
+            manager.build_packets_limited(Tick(0), single_data, fragment_data, 1)?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            remainder,
+            BTreeMap::from([
+                (
+                    *channel_id1,
+                    (VecDeque::from(vec![big_message.clone()]), VecDeque::new())
+                ),
+                (
+                    *channel_id3,
+                    (VecDeque::new(), VecDeque::from(vec![fragments[1].clone()]))
+                ),
+            ])
+        );
+        Ok(())
+    }
+
+    /// The remainder keeps the single and fragment messages of a channel separate
+    #[test]
+    fn test_pack_limited_remainder_split() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+
+        let big_message = SingleData::new(None, Bytes::from(vec![7u8; 1184]));
+        let num_big_bytes = (2.5 * FRAGMENT_SIZE as f32) as usize;
+        let fragments = FragmentSender::default().build_fragments(
+            MessageId(0),
+            None,
+            Bytes::from(vec![1u8; num_big_bytes]),
+        )?;
+        let single_data = vec![(*channel_id1, VecDeque::from(vec![big_message.clone(); 2]))];
+        let fragment_data = vec![(*channel_id1, VecDeque::from(fragments.clone()))];
+        let (packets, remainder) =
+            manager.build_packets_limited(Tick(0), single_data, fragment_data, 2)?;
+        assert_eq!(packets.len(), 2);
+        let (single, fragment) = remainder.get(channel_id1).unwrap();
+        assert_eq!(single, &VecDeque::from(vec![big_message.clone(); 2]));
+        assert_eq!(fragment, &VecDeque::from(vec![fragments[2].clone()]));
+        Ok(())
+    }
+
+    /// A channel that cannot fit in an empty packet returns an error instead of creating
+    /// empty packets forever
+    #[test]
+    fn test_channel_too_large_for_empty_packet() {
+        let channel_dictionary = ChannelIdDictionary::default();
+        // simulate a packet that only has room for 1 more byte
+        let mut packet = Packet {
+            payload: vec![0; MAX_PACKET_SIZE - 1],
+            message_acks: vec![],
+            packet_id: PacketId(0),
+            mtu: MAX_PACKET_SIZE,
+            prewritten_size: 0,
+            written_messages: 0,
+        };
+        assert!(!PacketBuilder::reserve_channel(
+            &mut packet,
+            false,
+            300,
+            &channel_dictionary,
+            &ChannelEncryption::default()
+        )
+        .unwrap());
+        assert!(matches!(
+            PacketBuilder::reserve_channel(
+                &mut packet,
+                true,
+                300,
+                &channel_dictionary,
+                &ChannelEncryption::default()
+            ),
+            Err(SerializationError::ChannelTooLargeForEmptyPacket { channel_id: 300 })
+        ));
+        // the space is only reserved if the channel fits
+        assert_eq!(packet.prewritten_size, 0);
+    }
+
+    /// `build_packets` returns the error for a channel that does not fit in an empty packet, and
+    /// the builder can still pack the other channels afterwards
+    #[test]
+    fn test_build_packets_channel_too_large() -> Result<(), PacketError> {
+        // room for a 1-byte channel id, the number of messages and an empty message
+        let mtu = HEADER_BYTES + 1 + 1 + MIN_SINGLE_DATA_BYTES;
+        let mut manager = PacketBuilder::new(1.5).with_reserved_footer_bytes(MAX_PACKET_SIZE - mtu);
+        let message = SingleData::new(None, Bytes::new());
+
+        // channel 300 is written with 2 bytes
+        let single_data = vec![(300, VecDeque::from(vec![message.clone()]))];
+        assert!(matches!(
+            manager.build_packets(Tick(0), single_data, vec![]),
+            Err(SerializationError::ChannelTooLargeForEmptyPacket { channel_id: 300 })
+        ));
+
+        let single_data = vec![(0, VecDeque::from(vec![message.clone(), message.clone()]))];
+        let packets = manager.build_packets(Tick(1), single_data, vec![])?;
+        assert_eq!(packets.len(), 2);
+        for packet in packets {
+            assert_eq!(packet.tick()?, Tick(1));
+            let contents = packet.parse_packet_payload()?;
+            assert_eq!(contents.get(&0).unwrap(), &vec![message.bytes.clone()]);
+        }
+        Ok(())
+    }
+
+    /// Channels whose id does not fit in an empty packet are detected before packing
+    #[test]
+    fn test_validate_channels() {
+        // room for a 1-byte channel id, the number of messages and an empty message (the footer
+        // takes the rest of the packet)
+        let mtu = HEADER_BYTES + 1 + 1 + MIN_SINGLE_DATA_BYTES;
+        let manager = PacketBuilder::new(1.5).with_reserved_footer_bytes(MAX_PACKET_SIZE - mtu);
+        let message = SingleData::new(None, Bytes::new());
+        let data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        assert!(manager.validate_channels(&data).is_ok());
+
+        // channel 300 is written with 2 bytes
+        let data = vec![(0, VecDeque::new()), (300, VecDeque::from(vec![message]))];
+        assert!(matches!(
+            manager.validate_channels(&data),
+            Err(SerializationError::ChannelTooLargeForEmptyPacket { channel_id: 300 })
+        ));
+    }
+
+    /// With the `trace` feature, an event is emitted with the reason why each packet was finished
+    #[cfg(feature = "trace")]
+    #[test]
+    fn test_finish_packet_trace_events() -> Result<(), PacketError> {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::Layer;
+
+        /// Records the `reason` and `payload_bytes` fields of the finish packet events
+        #[derive(Clone, Default)]
+        struct FinishEvents(Arc<Mutex<Vec<(String, u64)>>>);
+
+        #[derive(Default)]
+        struct FinishEventVisitor {
+            reason: String,
+            payload_bytes: u64,
+        }
+
+        impl Visit for FinishEventVisitor {
+            fn record_u64(&mut self, field: &Field, value: u64) {
+                if field.name() == "payload_bytes" {
+                    self.payload_bytes = value;
+                }
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "reason" {
+                    self.reason = format!("{value:?}");
+                }
+            }
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for FinishEvents {
+            fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+                if event.metadata().target() != FINISH_PACKET_TRACE_TARGET {
+                    return;
+                }
+                let mut visitor = FinishEventVisitor::default();
+                event.record(&mut visitor);
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((visitor.reason, visitor.payload_bytes));
+            }
+        }
+
+        let events = FinishEvents::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        let mut manager = PacketBuilder::new(1.5);
+        // the second message doesn't fit in the first packet
+        let message = SingleData::new(None, Bytes::from(vec![1u8; 700]));
+        let single_data = vec![(0, VecDeque::from(vec![message.clone(), message]))];
+        let packets = tracing::subscriber::with_default(subscriber, || {
+            manager.build_packets(Tick(0), single_data, vec![])
+        })?;
+        assert_eq!(packets.len(), 2);
+        let events = events.0.lock().unwrap();
+        assert_eq!(
+            *events,
+            vec![
+                (
+                    format!("{:?}", PacketFinishReason::MessageDoesNotFit),
+                    packets[0].payload.len() as u64
+                ),
+                (
+                    format!("{:?}", PacketFinishReason::NoMoreMessages),
+                    packets[1].payload.len() as u64
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    /// Writing more bytes than were reserved returns an error with the reserved and written sizes
+    #[test]
+    fn test_size_accounting_mismatch() {
+        let channel_dictionary = ChannelIdDictionary::default();
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let mut packet = Packet {
+            payload: vec![],
+            message_acks: vec![],
+            packet_id: PacketId(0),
+            mtu: MAX_PACKET_SIZE,
+            // only the channel id and number of messages were reserved, not the message
+            prewritten_size: 2,
+            written_messages: 0,
+        };
+        let res = PacketBuilder::write_single_messages(
+            &mut packet,
+            &mut VecDeque::from(vec![message.clone()]),
+            &mut 1,
+            0,
+            &channel_dictionary,
+            &mut ChannelEncryption::default(),
+            &mut None,
+            &mut None,
+            false,
+            false,
+        );
+        let Err(SerializationError::InChannel { source, .. }) = res else {
+            panic!("unexpected result {res:?}");
+        };
+        assert!(matches!(
+            *source,
+            SerializationError::SizeAccountingMismatch {
+                expected: 0,
+                actual
+            } if actual == message.len()
+        ));
+    }
+
+    /// An error while writing a message names the channel and the message that failed
+    #[test]
+    fn test_write_error_context() {
+        let channel_dictionary = ChannelIdDictionary::default();
+        let messages = (0..3)
+            .map(|i| SingleData::new(None, Bytes::from(vec![i; 10])))
+            .collect::<VecDeque<_>>();
+        let mut packet = Packet {
+            payload: vec![],
+            message_acks: vec![],
+            packet_id: PacketId(0),
+            mtu: MAX_PACKET_SIZE,
+            // the channel id, the number of messages and only the first message were reserved
+            prewritten_size: 2 + messages[0].len(),
+            written_messages: 0,
+        };
+        let res = PacketBuilder::write_single_messages(
+            &mut packet,
+            &mut messages.clone(),
+            &mut 2,
+            2,
+            &channel_dictionary,
+            &mut ChannelEncryption::default(),
+            &mut None,
+            &mut None,
+            false,
+            false,
+        );
+        let Err(error) = res else {
+            panic!("writing the second message should fail");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Could not write the message 1 of channel 2 in the packet"
+        );
+        assert!(matches!(
+            error,
+            SerializationError::InChannel {
+                channel_id: 2,
+                message_index: 1,
+                source,
+            } if matches!(*source, SerializationError::SizeAccountingMismatch { .. })
+        ));
+        // the channel is rolled back
+        assert!(packet.payload.is_empty());
+    }
+
+    /// A channel that is reserved in a packet but ends up without any message that fits is not
+    /// written in the packet
+    #[test]
+    fn test_pack_no_empty_channel() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        // 1186 bytes in the first packet: only 14 bytes are left, enough to reserve channel 1
+        // but not to write its message
+        let big_message = SingleData::new(None, Bytes::from(vec![1u8; 1170]));
+        let small_message = SingleData::new(None, Bytes::from(vec![2u8; 20]));
+        let single_data = vec![
+            (0, VecDeque::from(vec![big_message.clone()])),
+            (1, VecDeque::from(vec![small_message.clone()])),
+        ];
+        let packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(
+            packets[0].payload.len(),
+            HEADER_BYTES + 2 + big_message.len()
+        );
+
+        // read the raw channel entries of each packet: every channel has at least one message
+        let mut channels = vec![];
+        for packet in packets {
+            let mut reader = Reader::from(packet.payload);
+            reader.split_len(HEADER_BYTES);
+            while reader.has_remaining() {
+                let channel_id = reader.read_varint()?;
+                let num_messages = reader.read_varint()?;
+                assert_ne!(num_messages, 0);
+                for _ in 0..num_messages {
+                    SingleData::from_bytes(&mut reader)?;
+                }
+                channels.push(channel_id);
+            }
+        }
+        assert_eq!(channels, vec![0, 1]);
+        Ok(())
+    }
+
+    /// The remaining bytes of the packet kept open between two calls to `build_packets` go down
+    /// as messages are written in it, and no packet is in progress once it is finished
+    #[test]
+    fn test_current_remaining_bytes() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5)
+            .with_checksum(true)
+            .with_incremental_packets(true);
+        assert_eq!(manager.current_remaining_bytes(), None);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+
+        // room is kept for a header of any length until the open packet is finished
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        assert!(manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .is_empty());
+        let empty_packet_bytes = MAX_PACKET_SIZE - MAX_HEADER_BYTES - CHECKSUM_BYTES;
+        // channel id + number of messages + message
+        let first_remaining = empty_packet_bytes - 2 - message.len();
+        assert_eq!(manager.current_remaining_bytes(), Some(first_remaining));
+
+        // the next call writes a new channel section in the same packet
+        let single_data = vec![(1, VecDeque::from(vec![message.clone()]))];
+        assert!(manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .is_empty());
+        assert_eq!(
+            manager.current_remaining_bytes(),
+            Some(first_remaining - 2 - message.len())
+        );
+
+        // no packet is in progress once the open packet is finished
+        assert!(manager.flush_current()?.is_some());
+        assert_eq!(manager.current_remaining_bytes(), None);
+
+        // a new packet starts empty
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        assert!(manager
+            .build_packets(Tick(1), single_data, vec![])?
+            .is_empty());
+        assert_eq!(manager.current_remaining_bytes(), Some(first_remaining));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_build_inputs() -> Result<(), PacketError> {
+        let message = |i: u8| SingleData::new(None, Bytes::from(vec![i; 10]));
+        let inputs = vec![
+            vec![
+                (0, VecDeque::from(vec![message(0), message(1)])),
+                (1, VecDeque::from(vec![message(2)])),
+            ],
+            vec![(1, VecDeque::from(vec![message(3)]))],
+            vec![
+                (2, VecDeque::from(vec![message(4)])),
+                (0, VecDeque::from(vec![message(5)])),
+            ],
+        ];
+        let merged = PacketBuilder::merge_build_inputs(inputs);
+        assert_eq!(
+            merged,
+            vec![
+                (0, VecDeque::from(vec![message(0), message(1), message(5)])),
+                (1, VecDeque::from(vec![message(2), message(3)])),
+                (2, VecDeque::from(vec![message(4)])),
+            ]
+        );
+
+        // everything is packed in a single packet
+        let mut manager = PacketBuilder::new(1.5);
+        let packets = build_packets(&mut manager, Tick(0), merged, vec![])?;
+        assert_eq!(packets.len(), 1);
+        Ok(())
+    }
+
+    /// A bunch of small messages that all fit in the same packet
+    #[test]
+    fn test_pack_many_small_messages() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+        let channel_kind3 = ChannelKind::of::<Channel3>();
+        let channel_id3 = channel_registry.get_net_from_kind(&channel_kind3).unwrap();
+
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+
+        let single_data = vec![
+            (
+                *channel_id1,
+                VecDeque::from(vec![small_message.clone(); 200]),
+            ),
+            (
+                *channel_id2,
+                VecDeque::from(vec![small_message.clone(); 200]),
+            ),
+            (
+                *channel_id3,
+                VecDeque::from(vec![small_message.clone(); 200]),
+            ),
+        ];
+        let fragment_data = vec![];
+        let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+        assert_eq!(packets.len(), 7);
+        Ok(())
+    }
+
+    /// The number of messages of a channel is written as a varint, so it can go over 63
+    #[test]
+    fn test_pack_many_messages_in_channel() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let small_bytes = Bytes::from(vec![7u8; 2]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+        let single_data = vec![(299, VecDeque::from(vec![small_message.clone(); 100]))];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        let contents = packets.pop().unwrap().parse_packet_payload()?;
+        assert_eq!(contents.get(&299).unwrap(), &vec![small_bytes; 100]);
+        Ok(())
+    }
+
+    /// A single message that is too big for a packet returns an error instead of looping forever
+    #[test]
+    fn test_pack_oversized_single_message() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let max_len = manager.max_single_message_len(0);
+        // the biggest message that fits
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 1000]));
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 1000 + max_len - message.len()]));
+        assert_eq!(message.len(), max_len);
+        let single_data = vec![(0, VecDeque::from(vec![message]))];
+        let packets = manager.build_packets(Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].payload.len(), MAX_PACKET_SIZE);
+
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let oversized_message = SingleData::new(None, Bytes::from(vec![7u8; MAX_PACKET_SIZE]));
+        let single_data = vec![(0, VecDeque::from(vec![small_message, oversized_message]))];
+        assert!(matches!(
+            manager.build_packets(Tick(0), single_data, vec![]),
+            Err(SerializationError::MessageShouldBeFragmented {
+                size: MAX_PACKET_SIZE
+            })
+        ));
+        Ok(())
+    }
+
+    /// With incremental packets, the messages of two calls for the same tick share a packet
+    #[test]
+    fn test_incremental_packets() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_incremental_packets(true);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        assert!(manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .is_empty());
+        let single_data = vec![
+            (0, VecDeque::from(vec![message.clone()])),
+            (1, VecDeque::from(vec![message.clone()])),
+        ];
+        assert!(manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .is_empty());
+
+        let packet = manager.flush_current()?.unwrap();
+        assert!(manager.flush_current()?.is_none());
+        assert_eq!(packet.tick()?, Tick(0));
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(
+            contents.get(&0).unwrap(),
+            &vec![message.bytes.clone(), message.bytes.clone()]
+        );
+        assert_eq!(contents.get(&1).unwrap(), &vec![message.bytes.clone()]);
+
+        // the open packet is finished when the tick changes
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        assert!(manager
+            .build_packets(Tick(1), single_data, vec![])?
+            .is_empty());
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        let packets = manager.build_packets(Tick(2), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].tick()?, Tick(1));
+        assert_eq!(manager.flush_current()?.unwrap().tick()?, Tick(2));
+        Ok(())
+    }
+
+    /// The packet kept open takes its packet id and its acks when it is finished, not when it
+    /// was started
+    #[test]
+    fn test_incremental_packet_header() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_incremental_packets(true);
+        let mut remote = PacketBuilder::new(1.5);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        assert!(manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .is_empty());
+        assert!(manager.header_manager.sent_packets_not_acked().is_empty());
+
+        // a packet is received from the remote peer while the packet is open
+        let remote_packet = remote.build_ack_packet(Tick(0))?;
+        manager
+            .header_manager
+            .process_recv_header(&Packet::peek_header(&remote_packet.payload)?);
+        // and another packet is sent before the open packet is finished
+        manager.build_keepalive(Tick(0))?;
+
+        let packet = manager.flush_current()?.unwrap();
+        assert_eq!(packet.packet_id, PacketId(1));
+        assert!(manager
+            .header_manager
+            .sent_packets_not_acked()
+            .contains_key(&PacketId(1)));
+        let header = Packet::peek_header(&packet.payload)?;
+        assert_eq!(header.packet_id, PacketId(1));
+        assert!(header
+            .acked_packet_ids()
+            .any(|id| id == remote_packet.packet_id));
+        assert_eq!(header.tick, Tick(0));
+        assert_eq!(
+            packet.parse_packet_payload()?.get(&0).unwrap(),
+            &vec![message.bytes.clone()]
+        );
+        Ok(())
+    }
+
+    /// The packets built after the MTU is lowered respect the new MTU
+    #[test]
+    fn test_set_mtu() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_incremental_packets(true);
+        let single_data = || {
+            let messages = (0..40u8)
+                .map(|i| SingleData::new(None, Bytes::from(vec![i; 50])))
+                .collect::<VecDeque<_>>();
+            vec![(0, messages)]
+        };
+        let packets = manager.build_packets(Tick(0), single_data(), vec![])?;
+        assert!(packets.iter().any(|packet| packet.payload.len() > 500));
+
+        // an MTU that can't hold a fragment is rejected
+        assert!(matches!(
+            manager.set_mtu(min_mtu(0) - 1),
+            Err(PacketError::MtuTooSmall { .. })
+        ));
+        assert_eq!(manager.mtu(), MAX_PACKET_SIZE);
+
+        // the open packet is finished at the old MTU, and the next packets use the new one
+        manager.set_mtu(500)?;
+        let open_packet_len = manager.pending_packet.as_ref().unwrap().0.payload.len();
+        let packets = manager.build_packets(Tick(0), single_data(), vec![])?;
+        assert_eq!(packets[0].payload.len(), open_packet_len);
+        assert!(packets[1..]
+            .iter()
+            .chain(manager.flush_current()?.as_ref())
+            .all(|packet| packet.payload.len() <= 500));
+        assert!(packets.len() > 4);
+        Ok(())
+    }
+
+    /// `would_fit` tells if the next message still fits in the open packet
+    #[test]
+    fn test_would_fit() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_incremental_packets(true);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 100]));
+        // channel id, number of messages and message
+        let message_len = manager.channel_header_len(0) + varint_len(1) + message.len();
+        assert!(!manager.would_fit(message_len));
+
+        let mut num_messages = 0;
+        loop {
+            let fits = manager.would_fit(message_len);
+            let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+            let packets = manager.build_packets(Tick(0), single_data, vec![])?;
+            if !fits && num_messages > 0 {
+                // the message started a new packet
+                assert_eq!(packets.len(), 1);
+                assert_eq!(packets[0].written_messages, num_messages);
+                break;
+            }
+            assert!(packets.is_empty());
+            num_messages += 1;
+        }
+        assert!(num_messages > 5);
+        assert!(manager.would_fit(message_len));
+        manager.flush_current()?;
+        assert!(!manager.would_fit(message_len));
+        Ok(())
+    }
+
+    /// Building packets without messages does not finish the open packet
+    #[test]
+    fn test_empty_input_keeps_open_packet() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_incremental_packets(true);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        assert!(manager.build_packets(Tick(0), vec![], vec![])?.is_empty());
+        assert!(manager.flush_current()?.is_none());
+
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        assert!(manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .is_empty());
+        assert!(manager.build_packets(Tick(0), vec![], vec![])?.is_empty());
+        let empty_channels = vec![(0, VecDeque::new()), (1, VecDeque::new())];
+        assert!(manager
+            .build_packets(Tick(0), empty_channels, vec![(2, VecDeque::new())])?
+            .is_empty());
+
+        let packet = manager.flush_current()?.unwrap();
+        assert_eq!(packet.tick()?, Tick(0));
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents.get(&0).unwrap(), &vec![message.bytes.clone()]);
+        Ok(())
+    }
+
+    /// After a reset, the builder starts over like a new builder, for a new connection
+    #[test]
+    fn test_reset() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5)
+            .with_incremental_packets(true)
+            .with_metrics(true);
+        let message = SingleData::new(Some(MessageId(1)), Bytes::from(vec![7u8; 10]));
+        for tick in 0..3 {
+            let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+            manager.build_packets(Tick(tick), single_data, vec![])?;
+        }
+        // the packets of ticks 0 and 1 are finished, the one of tick 2 is still open (it only
+        // takes a packet id once it is finished)
+        assert_eq!(manager.header_manager.next_packet_id(), PacketId(2));
+        assert_eq!(manager.take_metrics().packets, 2);
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        manager.build_packets(Tick(3), single_data, vec![])?;
+
+        manager.reset();
+        assert!(manager.flush_current()?.is_none());
+        assert_eq!(manager.take_metrics(), PacketBuilderMetrics::default());
+        assert_eq!(manager.header_manager.next_packet_id(), PacketId(0));
+        assert!(manager.header_manager.sent_packets_not_acked().is_empty());
+        // the message acks of the dropped packet are kept for the next packets
+        assert_eq!(manager.ack_buffers.len(), 1);
+
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        manager.build_packets(Tick(0), single_data, vec![])?;
+        let packet = manager.flush_current()?.unwrap();
+        assert_eq!(packet.packet_id, PacketId(0));
+        assert_eq!(packet.tick()?, Tick(0));
+        assert!(manager.ack_buffers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_stats() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let fragments = FragmentSender::default()
+            .build_fragments(
+                MessageId(3),
+                None,
+                Bytes::from(vec![1u8; 2 * FRAGMENT_BYTES]),
+            )
+            .unwrap();
+        let mut packets = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(0, VecDeque::from(vec![message.clone()]))],
+            vec![(1, fragments.into())],
+        )?;
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        packets.extend(build_packets(&mut manager, Tick(1), single_data, vec![])?);
+        assert_eq!(packets.len(), 4);
+
+        let stats = manager.stats();
+        assert_eq!(stats.packets_sent, 4);
+        assert_eq!(
+            stats.bytes_sent,
+            packets
+                .iter()
+                .map(|packet| packet.payload.len() as u64)
+                .sum::<u64>()
+        );
+        assert_eq!(stats.fragments_sent, 2);
+        assert_eq!(stats.packets_acked, 0);
+
+        // the remote receives the first two packets and acks them
+        let mut remote = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS)?;
+        for packet in &packets[..2] {
+            remote.process_recv_header(&Packet::peek_header(&packet.payload)?);
+        }
+        let ack_header = remote.prepare_send_packet_header(PacketType::Data);
+        manager.header_manager.process_recv_acks(&ack_header);
+        // acks that were already processed are not counted again
+        manager.header_manager.process_recv_acks(&ack_header);
+        assert_eq!(manager.stats().packets_acked, 2);
+
+        manager.reset();
+        assert_eq!(manager.stats(), ConnectionStats::default());
+        Ok(())
+    }
+
+    /// A packet left in progress for an earlier tick is finished with its own tick instead of
+    /// receiving the messages of the current tick
+    #[test]
+    fn test_lingering_packet_tick_changed() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        let packets = manager.build_packets(Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].tick()?, Tick(0));
+
+        // a packet for tick 0 is still in progress when packets are built for tick 1
+        manager.build_new_single_packet(Tick(0))?;
+        let packet = manager.current_packet.as_mut().unwrap();
+        assert!(PacketBuilder::reserve_channel(
+            packet,
+            true,
+            0,
+            &ChannelIdDictionary::default(),
+            &ChannelEncryption::default()
+        )?);
+        packet.prewritten_size += message.len();
+        PacketBuilder::write_single_messages(
+            packet,
+            &mut VecDeque::from(vec![message.clone()]),
+            &mut 1,
+            0,
+            &ChannelIdDictionary::default(),
+            &mut ChannelEncryption::default(),
+            &mut None,
+            &mut None,
+            false,
+            false,
+        )?;
+
+        let single_data = vec![(1, VecDeque::from(vec![message.clone()]))];
+        let packets = manager.build_packets(Tick(1), single_data, vec![])?;
+        assert!(manager.current_packet.is_none());
+        assert_eq!(packets.len(), 2);
+        for (packet, (tick, channel_id)) in packets.into_iter().zip([(Tick(0), 0), (Tick(1), 1)]) {
+            assert_eq!(packet.tick()?, tick);
+            let contents = packet.parse_packet_payload()?;
+            assert_eq!(contents.len(), 1);
+            assert_eq!(
+                contents.get(&channel_id).unwrap(),
+                &vec![message.bytes.clone()]
+            );
+        }
+        Ok(())
+    }
+
+    /// Small packets are kept open until they reach `min_packet_bytes`, or until the tick changes
+    #[test]
+    fn test_min_packet_bytes() -> Result<(), PacketError> {
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = |channel_id| vec![(channel_id, VecDeque::from(vec![message.clone()]))];
+
+        // without the option, each call sends a tiny packet
+        let mut manager = PacketBuilder::new(1.5);
+        assert_eq!(
+            manager
+                .build_packets(Tick(0), single_data(0), vec![])?
+                .len(),
+            1
+        );
+        assert_eq!(
+            manager
+                .build_packets(Tick(0), single_data(1), vec![])?
+                .len(),
+            1
+        );
+
+        // a packet with one message has 25 bytes, two messages fit in one packet
+        let mut manager = PacketBuilder::new(1.5).with_min_packet_bytes(30);
+        assert!(manager
+            .build_packets(Tick(0), single_data(0), vec![])?
+            .is_empty());
+        let packets = manager.build_packets(Tick(0), single_data(1), vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0].payload.len(),
+            HEADER_BYTES + 2 * (2 + message.len())
+        );
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        assert_eq!(contents.len(), 2);
+        assert!(manager.flush_current()?.is_none());
+
+        // the packet is still finished when the tick changes
+        assert!(manager
+            .build_packets(Tick(1), single_data(0), vec![])?
+            .is_empty());
+        let packets = manager.build_packets(Tick(2), vec![], vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].tick()?, Tick(1));
+        Ok(())
+    }
+
+    /// The size of a message used to decide if it fits includes its flags, message id and length
+    /// varint, so a message whose body alone would fit does not overflow the packet
+    #[test]
+    fn test_single_message_prefix_at_mtu_boundary() -> Result<(), PacketError> {
+        let first = SingleData::new(None, Bytes::from(vec![1u8; 10]));
+        // header, channel id, number of messages, first message
+        let remaining = MAX_PACKET_SIZE - HEADER_BYTES - 1 - 1 - first.len();
+        // flags + 2 bytes of length varint, and 2 more bytes for the message id
+        for (id, prefix) in [(None, 3), (Some(MessageId(4)), 5)] {
+            for (body_len, num_packets) in [(remaining - prefix, 1), (remaining - prefix + 1, 2)] {
+                let second = SingleData::new(id, Bytes::from(vec![2u8; body_len]));
+                assert_eq!(second.len(), body_len + prefix);
+                let mut manager = PacketBuilder::new(1.5);
+                let packets = build_packets(
+                    &mut manager,
+                    Tick(0),
+                    vec![(0, VecDeque::from(vec![first.clone(), second]))],
+                    vec![],
+                )?;
+                assert_eq!(packets.len(), num_packets);
+                assert!(packets
+                    .iter()
+                    .all(|packet| packet.payload.len() <= MAX_PACKET_SIZE));
+                if num_packets == 1 {
+                    assert_eq!(packets[0].payload.len(), MAX_PACKET_SIZE);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_back_pressure() -> Result<(), PacketError> {
+        let message = SingleData::new(None, Bytes::from(vec![1u8; 1000]));
+        let single_data =
+            |num_messages| vec![(0, VecDeque::from(vec![message.clone(); num_messages]))];
+        let mut manager = PacketBuilder::new(1.5);
+        build_packets(&mut manager, Tick(0), single_data(2), vec![])?;
+        assert_eq!(manager.back_pressure(), None);
+
+        let mut manager = PacketBuilder::new(1.5).with_target_packets_per_tick(Some(1));
+        let packets = build_packets(&mut manager, Tick(0), single_data(2), vec![])?;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(
+            manager.back_pressure(),
+            Some(BackPressure {
+                produced_packets: 2,
+                over_budget: true,
+            })
+        );
+
+        build_packets(&mut manager, Tick(1), single_data(1), vec![])?;
+        assert_eq!(
+            manager.back_pressure(),
+            Some(BackPressure {
+                produced_packets: 1,
+                over_budget: false,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_packets_for_ticks() -> Result<(), PacketError> {
+        // with incremental packets, the last packet of a tick would stay open for the next call
+        let mut manager = PacketBuilder::new(1.5).with_incremental_packets(true);
+        let packets_per_tick = manager.build_packets_for_ticks(Tick(10)..Tick(13), |tick| {
+            let message = SingleData::new(None, Bytes::from(vec![tick.0 as u8; 10]));
+            (vec![(0, VecDeque::from(vec![message]))], vec![])
+        })?;
+        assert_eq!(
+            packets_per_tick
+                .iter()
+                .map(|(tick, _)| *tick)
+                .collect::<Vec<_>>(),
+            vec![Tick(10), Tick(11), Tick(12)]
+        );
+        for (tick, packets) in packets_per_tick {
+            assert_eq!(packets.len(), 1);
+            let packet = packets.into_iter().next().unwrap();
+            assert_eq!(packet.tick()?, tick);
+            let contents = packet.parse_packet_payload()?;
+            assert_eq!(contents[&0], vec![Bytes::from(vec![tick.0 as u8; 10])]);
+        }
+        assert!(manager.flush_current()?.is_none());
+        Ok(())
+    }
+
+    /// No end-of-packet marker is written after the last channel, so the channel with id 0 is a
+    /// regular channel that only costs its channel id and message count
+    #[test]
+    fn test_no_end_of_packet_marker() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![(0, VecDeque::from(vec![message.clone()]))];
+        let packet = manager
+            .build_packets(Tick(0), single_data, vec![])?
+            .pop()
+            .unwrap();
+        assert_eq!(
+            packet.payload.len(),
+            HEADER_BYTES + manager.channel_dictionary.len(0) + 1 + message.len()
+        );
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents.get(&0).unwrap(), &vec![message.bytes]);
+        Ok(())
+    }
+
+    /// The checksum is written at the end of the packet, and is accounted for when packing messages
+    #[test]
+    fn test_pack_checksum() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_checksum(true);
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![(0, VecDeque::from(vec![small_message.clone(); 200]))];
+        let packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 3);
+        for packet in packets.iter() {
+            assert!(packet.payload.len() <= MAX_PACKET_SIZE);
+            assert_eq!(packet.prewritten_size, 0);
+            assert!(packet.verify_checksum());
+        }
+
+        // flipping a byte is detected
+        let mut packet = packets.into_iter().next().unwrap();
+        packet.payload[20] ^= 1;
+        assert!(!packet.verify_checksum());
+        Ok(())
+    }
+
+    /// Under-full data packets of the same tick are merged, but fragment packets are not
+    #[test]
+    fn test_coalesce_packets() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5)
+            .with_checksum(true)
+            .with_invariant_checks(true);
+        let message = SingleData::new(Some(MessageId(3)), Bytes::from(vec![7u8; 10]));
+        let mut packets = vec![];
+        for channel_id in 0..3 {
+            let single_data = vec![(channel_id, VecDeque::from(vec![message.clone()]))];
+            packets.extend(manager.build_packets(Tick(0), single_data, vec![])?);
+        }
+        let mut fragment_sender = FragmentSender::default();
+        fragment_sender.fragment_size = manager.packet_limits()?.fragment_size;
+        let fragments =
+            fragment_sender.build_fragments(MessageId(0), None, Bytes::from(vec![1; 1500]))?;
+        let fragment_packets =
+            manager.build_packets(Tick(0), vec![], vec![(3, fragments.into())])?;
+        assert_eq!(fragment_packets.len(), 2);
+        packets.extend(fragment_packets);
+        assert_eq!(packets.len(), 5);
+        let first_packet_id = packets[0].packet_id;
+
+        let mut packets = manager.coalesce(packets).into_iter();
+        let mut packet = packets.next().unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packet.packet_id, first_packet_id);
+        assert_eq!(packet.written_messages, 3);
+        assert_eq!(
+            packet
+                .message_acks
+                .iter()
+                .map(|(channel_id, _)| *channel_id)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert!(packet.verify_checksum());
+        packet
+            .payload
+            .truncate(packet.payload.len() - CHECKSUM_BYTES);
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(contents.len(), 3);
+        for channel_id in 0..3 {
+            assert_eq!(
+                contents.get(&channel_id).unwrap(),
+                &vec![message.bytes.clone()]
+            );
+        }
+        Ok(())
+    }
+
+    /// The reserved footer bytes are never used by the packet content (including the checksum)
+    #[test]
+    fn test_pack_reserved_footer_bytes() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5)
+            .with_invariant_checks(true)
+            .with_checksum(true)
+            .with_reserved_footer_bytes(16);
+        let mut fragment_sender = FragmentSender::default();
+        fragment_sender.fragment_size = fragment_size(MAX_PACKET_SIZE - 16, manager.reserved_len());
+        let fragments =
+            fragment_sender.build_fragments(MessageId(0), None, Bytes::from(vec![1; 3000]))?;
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![(1, VecDeque::from(vec![small_message.clone(); 300]))];
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            single_data,
+            vec![(0, fragments.clone().into())],
+        )?;
+        assert!(packets
+            .iter()
+            .all(|packet| packet.payload.len() <= MAX_PACKET_SIZE - 16));
+        // the packets are still filled up to the reduced limit
+        assert!(packets
+            .iter()
+            .any(|packet| packet.payload.len() > MAX_PACKET_SIZE - 16 - small_message.len()));
+
+        let mut num_small_messages = 0;
+        let mut num_fragments = 0;
+        for mut packet in packets {
+            assert!(packet.verify_checksum());
+            packet
+                .payload
+                .truncate(packet.payload.len() - CHECKSUM_BYTES);
+            let contents = packet.parse_packet_payload()?;
+            num_small_messages += contents.get(&1).map_or(0, Vec::len);
+            num_fragments += contents.get(&0).map_or(0, Vec::len);
+        }
+        assert_eq!(num_small_messages, 300);
+        assert_eq!(num_fragments, fragments.len());
+        Ok(())
+    }
+
+    /// A channel that fails to be written is rolled back, and the packet in progress is finished
+    /// with the channels that were written before the error
+    #[test]
+    fn test_pack_error_rolls_back_channel() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings::default())
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                encrypted: true,
+                ..default()
+            })
+            .unwrap();
+        // the cipher adds more bytes than were reserved for it, so writing channel 1 fails
+        let mut manager = PacketBuilder::new(1.5)
+            .with_invariant_checks(true)
+            .with_channel_registry(&channel_registry)
+            .with_channel_encryption(4, |_, block| block.extend_from_slice(&[0; 5]));
+        let messages = |channel_id: ChannelId| {
+            let messages = (0..3)
+                .map(|i| SingleData::new(Some(MessageId(i)), Bytes::from(vec![i as u8; 10])))
+                .collect::<VecDeque<_>>();
+            (channel_id, messages)
+        };
+        let res = manager.build_packets(Tick(0), vec![messages(0), messages(1)], vec![]);
+        assert!(matches!(
+            res,
+            Err(SerializationError::SizeAccountingMismatch { .. })
+        ));
+
+        // the packet in progress only contains channel 0
+        let packet = manager.current_packet.as_ref().unwrap();
+        assert_eq!(packet.written_messages, 3);
+        assert_eq!(packet.prewritten_size, 0);
+        assert!(packet
+            .message_acks
+            .iter()
+            .all(|(channel_id, _)| *channel_id == 0));
+        assert_eq!(packet.message_acks.len(), 3);
+
+        // the next call finishes it (the invariant checks validate it) before sending its messages
+        let packets = manager.build_packets(Tick(0), vec![messages(0)], vec![])?;
+        assert_eq!(packets.len(), 2);
+        for packet in packets {
+            let contents = packet.parse_packet_payload()?;
+            assert_eq!(contents.len(), 1);
+            assert_eq!(contents[&0].len(), 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_encrypted_channel() -> Result<(), PacketError> {
+        const KEY: u8 = 0x5a;
+        const TAG: &[u8] = b"tag!";
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                encrypted: true,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings::default())
+            .unwrap();
+        let encrypted_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let plain_message = SingleData::new(None, Bytes::from(vec![8u8; 10]));
+        let single_data = || {
+            vec![
+                (0, VecDeque::from(vec![encrypted_message.clone(); 200])),
+                (1, VecDeque::from(vec![plain_message.clone(); 200])),
+            ]
+        };
+
+        // a mock cipher: xor every byte, and append a tag
+        let mut manager = PacketBuilder::new(1.5)
+            .with_invariant_checks(true)
+            .with_channel_registry(&channel_registry)
+            .with_channel_encryption(TAG.len(), |_, block| {
+                block.iter_mut().for_each(|byte| *byte ^= KEY);
+                block.extend_from_slice(TAG);
+            });
+        let packets = build_packets(&mut manager, Tick(0), single_data(), vec![])?;
+        assert!(packets.len() > 1);
+        assert!(packets
+            .iter()
+            .all(|packet| packet.payload.len() <= MAX_PACKET_SIZE));
+        // the encrypted messages are not written in the clear, the other ones are
+        assert!(packets
+            .iter()
+            .all(|packet| !packet.payload.windows(10).any(|w| w == [7u8; 10])));
+        assert!(packets
+            .iter()
+            .any(|packet| packet.payload.windows(10).any(|w| w == [8u8; 10])));
+
+        let mut parser =
+            PacketParser::new(ChannelIdDictionary::default()).with_decryption(|_, block| {
+                let Some(len) = block.len().checked_sub(TAG.len()) else {
+                    return false;
+                };
+                if &block[len..] != TAG {
+                    return false;
+                }
+                block.truncate(len);
+                block.iter_mut().for_each(|byte| *byte ^= KEY);
+                true
+            });
+        let mut contents = HashMap::default();
+        let mut received: HashMap<ChannelId, Vec<Bytes>> = HashMap::default();
+        for packet in packets {
+            parser.parse_into(packet, &mut contents)?;
+            for (channel_id, messages) in contents.iter() {
+                received
+                    .entry(*channel_id)
+                    .or_default()
+                    .extend_from_slice(messages);
+            }
+        }
+        assert_eq!(received[&0], vec![encrypted_message.bytes.clone(); 200]);
+        assert_eq!(received[&1], vec![plain_message.bytes.clone(); 200]);
+
+        // the encrypted sections cannot be read without the cipher
+        let mut packets = manager.build_packets(Tick(1), single_data(), vec![])?;
+        assert!(matches!(
+            packets.remove(0).parse_packet_payload(),
+            Err(PacketError::Serialization(SerializationError::InvalidValue))
+        ));
+        // and are not written without it
+        let mut manager = PacketBuilder::new(1.5).with_channel_registry(&channel_registry);
+        assert!(matches!(
+            manager.build_packets(Tick(0), single_data(), vec![]),
+            Err(SerializationError::MissingCipher { channel_id: 0 })
+        ));
+        Ok(())
+    }
+
+    /// With `pad_to`, every packet has exactly `pad_to` bytes, and the padding is stripped
+    /// when reading the packet back
+    #[test]
+    fn test_pack_pad_to() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5)
+            .with_invariant_checks(true)
+            .with_mtu(600)?
+            .with_checksum(true)
+            .with_pad_to(Some(600));
+        let mut fragment_sender = FragmentSender::default();
+        fragment_sender.fragment_size = fragment_size(600, manager.reserved_len());
+        let fragments =
+            fragment_sender.build_fragments(MessageId(0), None, Bytes::from(vec![1; 1500]))?;
+        let messages: VecDeque<_> = (0..100)
+            .map(|i| SingleData::new(None, Bytes::from(vec![2; i % 30])))
+            .collect();
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(1, messages.clone())],
+            vec![(0, fragments.clone().into())],
+        )?;
+        assert!(packets.len() > 3);
+        let mut single = vec![];
+        let mut fragment = vec![];
+        for mut packet in packets {
+            assert_eq!(packet.payload.len(), 600);
+            assert!(packet.verify_checksum());
+            packet.payload.truncate(600 - CHECKSUM_BYTES);
+            let mut contents = packet.without_padding()?.parse_packet_payload()?;
+            single.extend(contents.remove(&1).unwrap_or_default());
+            fragment.extend(contents.remove(&0).unwrap_or_default());
+        }
+        let mut expected: Vec<_> = messages.into_iter().map(|m| m.bytes).collect();
+        expected.sort_by_key(Bytes::len);
+        single.sort_by_key(Bytes::len);
+        assert_eq!(single, expected);
+        assert_eq!(
+            fragment,
+            fragments.into_iter().map(|f| f.bytes).collect::<Vec<_>>()
+        );
+
+        // header-only packets are padded too
+        let keepalive = manager.build_keepalive(Tick(1))?;
+        assert_eq!(keepalive.payload.len(), 600);
+        Ok(())
+    }
+
+    /// The packing invariants hold for a workload mixing fragments, message ids, several channels,
+    /// byte caps, a checksum and a channel dictionary
+    #[test]
+    fn test_pack_invariant_checks() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5)
+            .with_invariant_checks(true)
+            .with_checksum(true)
+            .with_channel_byte_cap(2, 3000)
+            .with_channel_dictionary(ChannelIdDictionary::new([300, 2]));
+        let mut fragment_sender = FragmentSender::default();
+        fragment_sender.fragment_size = manager.packet_limits()?.fragment_size;
+        for tick in 0..3 {
+            let mut fragment_data = vec![];
+            for (i, channel_id) in [0, 300].into_iter().enumerate() {
+                let fragments = fragment_sender.build_fragments(
+                    MessageId(i as u16),
+                    None,
+                    Bytes::from(vec![1; 2500 + 100 * i]),
+                )?;
+                fragment_data.push((channel_id, fragments.into()));
+            }
+            let single_data = (1..5)
+                .map(|channel_id| {
+                    let messages = (0..200)
+                        .map(|i| {
+                            let id = (i % 2 == 0).then_some(MessageId(i));
+                            SingleData::new(
+                                id,
+                                Bytes::from(vec![2; (i * channel_id) as usize % 70]),
+                            )
+                        })
+                        .collect();
+                    (channel_id, messages)
+                })
+                .collect();
+            build_packets(&mut manager, Tick(tick), single_data, fragment_data)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate message ack")]
+    fn test_pack_invariant_checks_duplicate_ack() {
+        let mut manager = PacketBuilder::new(1.5).with_invariant_checks(true);
+        manager.build_new_single_packet(Tick(0)).unwrap();
+        let ack = MessageAck {
+            message_id: MessageId(0),
+            fragment_id: None,
+        };
+        let packet = manager.current_packet.as_mut().unwrap();
+        packet.message_acks.push((0, ack));
+        packet.message_acks.push((0, ack));
+        manager.finish_packet();
+    }
+
+    /// Fragments that leave some headroom in their packet carry small messages, even when
+    /// they are not the last fragment of their message
+    #[test]
+    fn test_pack_fragment_headroom() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let mut fragment_sender = FragmentSender::default();
+        fragment_sender.fragment_size = FRAGMENT_SIZE - 100;
+        let fragments =
+            fragment_sender.build_fragments(MessageId(0), None, Bytes::from(vec![1; 3000]))?;
+        assert_eq!(fragments.len(), 3);
+        let small_message = SingleData::new(None, Bytes::from(vec![2; 10]));
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(1, VecDeque::from(vec![small_message.clone(); 20]))],
+            vec![(0, fragments.into())],
+        )?;
+        assert_eq!(packets.len(), 3);
+        let contents = packets
+            .into_iter()
+            .map(|packet| packet.parse_packet_payload())
+            .collect::<Result<Vec<_>, PacketError>>()?;
+        // the middle fragment packet also carries small messages
+        assert!(!contents[1].get(&1).unwrap().is_empty());
+        assert_eq!(
+            contents
+                .iter()
+                .map(|c| c.get(&1).map_or(0, Vec::len))
+                .sum::<usize>(),
+            20
+        );
+        Ok(())
+    }
+
+    /// Messages of the same size are written in the order in which they were queued
+    #[test]
+    fn test_pack_equal_size_messages_in_order() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let messages = (0..500u16)
+            .map(|i| SingleData::new(None, Bytes::copy_from_slice(&i.to_be_bytes())))
+            .collect::<VecDeque<_>>();
+        let packets = build_packets(&mut manager, Tick(0), vec![(0, messages.clone())], vec![])?;
+        assert!(packets.len() > 1);
+        let received = packets
+            .into_iter()
+            .map(|packet| Ok(packet.parse_packet_payload()?.remove(&0).unwrap()))
+            .collect::<Result<Vec<_>, PacketError>>()?
+            .concat();
+        assert_eq!(
+            received,
+            messages
+                .into_iter()
+                .map(|message| message.bytes)
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    /// Packets are finished at each tick boundary, and stamped with the tick of their messages
+    #[test]
+    fn test_pack_per_tick() -> Result<(), PacketError> {
+        use crate::packet::header::PacketHeader;
+
+        let mut manager = PacketBuilder::new(1.5);
+        let message = |tick: u8| SingleData::new(None, Bytes::from(vec![tick; 10]));
+        let packets = manager.build_packets_per_tick(vec![
+            (
+                Tick(1),
+                vec![(0, VecDeque::from(vec![message(1); 150]))],
+                vec![],
+            ),
+            (
+                Tick(2),
+                vec![(0, VecDeque::from(vec![message(2); 10]))],
+                vec![],
+            ),
+        ])?;
+        // the second packet of tick 1 is not full, but is not shared with tick 2
+        assert_eq!(packets.len(), 3);
+        let contents = packets
+            .into_iter()
+            .map(|packet| {
+                let header = PacketHeader::from_bytes(&mut packet.payload.clone().into())?;
+                Ok((
+                    header.tick,
+                    packet.parse_packet_payload()?.remove(&0).unwrap(),
+                ))
+            })
+            .collect::<Result<Vec<_>, PacketError>>()?;
+        assert_eq!(contents[0].0, Tick(1));
+        assert_eq!(contents[1].0, Tick(1));
+        assert_eq!(contents[0].1.len() + contents[1].1.len(), 150);
+        assert!(contents[1].1.iter().all(|bytes| bytes[0] == 1));
+        assert_eq!(contents[2].0, Tick(2));
+        assert_eq!(contents[2].1, vec![message(2).bytes; 10]);
+        Ok(())
+    }
+
+    /// Many small messages mixed with a few messages that are slightly smaller than the default MTU
+    #[test]
+    fn test_suggest_mtu() {
+        let mut sizes = vec![40; 2000];
+        sizes.extend(vec![1100; 10]);
+        let candidates = [300, 576, 1200, 1500, 9000];
+        // smaller MTUs fragment the large messages, larger MTUs leave more room unused
+        assert_eq!(suggest_mtu(&sizes, &candidates), 1200);
+        // a few small messages fit in a single small packet
+        assert_eq!(suggest_mtu(&[20; 10], &candidates), 300);
+        // a candidate that is too small to fit a fragment is never picked
+        assert_eq!(suggest_mtu(&sizes, &[10, 9000]), 9000);
+    }
+
+    /// The messages of an ordered channel are packed in queue order, the other channels follow
+    /// the packing strategy
+    #[test]
+    fn test_pack_ordered_channel_queue_order() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+                ..default()
+            })
+            .unwrap();
+        let unordered_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let ordered_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let mut manager = PacketBuilder::new(1.5).with_channel_registry(&channel_registry);
+        // sizes that are not sorted, and big enough to need several packets
+        let messages: VecDeque<_> = (0..20u16)
+            .map(|i| {
+                let size = [400, 20, 250, 5, 90][i as usize % 5];
+                SingleData::with_id(MessageId(i), Bytes::from(vec![i as u8; size]))
+            })
+            .collect();
+        let single_data = vec![
+            (unordered_id, messages.clone()),
+            (ordered_id, messages.clone()),
+        ];
+        let packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert!(packets.len() > 2);
+
+        let mut ordered_ids = vec![];
+        let mut unordered_sizes = vec![];
+        for packet in packets {
+            assert!(packet.payload.len() <= MAX_PACKET_SIZE);
+            for (channel_id, ack) in packet.message_acks {
+                if channel_id == ordered_id {
+                    ordered_ids.push(ack.message_id);
+                } else {
+                    unordered_sizes.push(messages[ack.message_id.0 as usize].bytes.len());
+                }
+            }
+        }
+        // the ordered channel keeps its queue order, the other channel is packed smallest first
+        assert_eq!(ordered_ids, (0..20).map(MessageId).collect::<Vec<_>>());
+        assert!(unordered_sizes.is_sorted());
+
+        // the strategy applies to the channels that are not ordered
+        let mut manager = manager.with_packing_strategy(PackingStrategy::LargestFirst);
+        let single_data = vec![(unordered_id, messages.clone())];
+        let candidate = SingleData::new(None, Bytes::from(vec![0; 300]));
+        // the candidate is packed after the 400 byte messages
+        assert_eq!(
+            manager.preview_placement(&single_data, &[], unordered_id, &candidate),
+            Placement::Packet {
+                packet_index: 1,
+                channel_id: unordered_id
+            }
+        );
+        let packets = build_packets(&mut manager, Tick(1), single_data, vec![])?;
+        let sizes: Vec<_> = packets
+            .iter()
+            .flat_map(|packet| &packet.message_acks)
+            .map(|(_, ack)| messages[ack.message_id.0 as usize].bytes.len())
+            .collect();
+        assert!(sizes.windows(2).all(|w| w[0] >= w[1]));
+        Ok(())
+    }
+
+    /// The header of a packet tells if the packer reordered its messages
+    #[test]
+    fn test_pack_sorted_flag() -> Result<(), PacketError> {
+        use crate::packet::header::PacketHeader;
+
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+                ..default()
+            })
+            .unwrap();
+        let unordered_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let ordered_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let messages = |sizes: &[usize]| {
+            sizes
+                .iter()
+                .map(|&size| SingleData::new(None, Bytes::from(vec![0; size])))
+                .collect::<VecDeque<_>>()
+        };
+        let sorted = |manager: &mut PacketBuilder,
+                      tick: u16,
+                      single_data: Vec<(ChannelId, VecDeque<SingleData>)>|
+         -> Result<bool, PacketError> {
+            let packets = build_packets(manager, Tick(tick), single_data, vec![])?;
+            assert_eq!(packets.len(), 1);
+            let header = PacketHeader::from_bytes(&mut packets[0].payload.clone().into())?;
+            assert_eq!(header.get_packet_type(), PacketType::Data);
+            Ok(header.sorted)
+        };
+        let mut manager = PacketBuilder::new(1.5).with_channel_registry(&channel_registry);
+
+        // the messages are already smallest first: the queue order is kept
+        assert!(!sorted(
+            &mut manager,
+            0,
+            vec![(unordered_id, messages(&[5, 10, 20]))]
+        )?);
+        // the messages are sorted
+        assert!(sorted(
+            &mut manager,
+            1,
+            vec![(unordered_id, messages(&[20, 5, 10]))]
+        )?);
+        // an ordered channel is never sorted, but the flag covers every channel of the packet
+        assert!(!sorted(
+            &mut manager,
+            2,
+            vec![(ordered_id, messages(&[20, 5, 10]))]
+        )?);
+        assert!(sorted(
+            &mut manager,
+            3,
+            vec![
+                (ordered_id, messages(&[20, 5, 10])),
+                (unordered_id, messages(&[20, 5, 10]))
+            ]
+        )?);
+
+        // no channel is sorted with the queue order strategy
+        let mut manager = manager.with_packing_strategy(PackingStrategy::QueueOrder);
+        assert!(!sorted(
+            &mut manager,
+            4,
+            vec![(unordered_id, messages(&[20, 5, 10]))]
+        )?);
+        Ok(())
+    }
+
+    /// The space left after the last fragment is only filled with the messages allowed by the
+    /// tail packing policy
+    #[test]
+    fn test_pack_fragment_tail_packing() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::UnorderedReliable(ReliableSettings::default()),
+                ..default()
+            })
+            .unwrap();
+        let unreliable_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let reliable_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let fragments = FragmentSender::default()
+            .build_fragments(
+                MessageId(0),
+                None,
+                Bytes::from(vec![1u8; (1.5 * FRAGMENT_SIZE as f32) as usize]),
+            )
+            .unwrap();
 
-    use bevy::prelude::{default, TypePath};
-    use bytes::Bytes;
+        for (tail_packing, num_packets, tail_channels) in [
+            (FragmentTailPacking::Never, 3, vec![(reliable_id, 1)]),
+            (
+                FragmentTailPacking::UnreliableOnly,
+                3,
+                vec![(reliable_id, 1), (unreliable_id, 1)],
+            ),
+            (
+                FragmentTailPacking::Always,
+                2,
+                vec![(reliable_id, 2), (unreliable_id, 1)],
+            ),
+        ] {
+            let mut manager = PacketBuilder::new(1.5)
+                .with_channel_registry(&channel_registry)
+                .with_fragment_tail_packing(tail_packing);
+            let single_data = vec![
+                (unreliable_id, VecDeque::from(vec![small_message.clone()])),
+                (reliable_id, VecDeque::from(vec![small_message.clone()])),
+            ];
+            let fragment_data = vec![(reliable_id, fragments.clone().into())];
+            let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+            assert_eq!(packets.len(), num_packets, "{tail_packing:?}");
+            // the packet of the last fragment
+            let tail = packets.into_iter().nth(1).unwrap();
+            assert_eq!(tail.packet_type()?, PacketType::DataFragment);
+            let mut contents: Vec<(ChannelId, usize)> = tail
+                .parse_packet_payload()?
+                .into_iter()
+                .map(|(channel_id, messages)| (channel_id, messages.len()))
+                .collect();
+            contents.sort_by_key(|(channel_id, _)| *channel_id != reliable_id);
+            assert_eq!(contents, tail_channels, "{tail_packing:?}");
+        }
+        Ok(())
+    }
 
-    use lightyear_macros::ChannelInternal;
+    /// With the reliability boundary, reliable and unreliable messages never share a packet
+    #[test]
+    fn test_pack_reliability_boundary() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::UnorderedReliable(ReliableSettings::default()),
+                ..default()
+            })
+            .unwrap();
+        let unreliable_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let reliable_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let small_message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = || {
+            vec![
+                (
+                    unreliable_id,
+                    VecDeque::from(vec![small_message.clone(); 150]),
+                ),
+                (
+                    reliable_id,
+                    VecDeque::from(vec![small_message.clone(); 150]),
+                ),
+            ]
+        };
+        let fragment_data = || {
+            vec![(
+                reliable_id,
+                VecDeque::from(vec![FragmentData {
+                    message_id: MessageId(0),
+                    fragment_id: 0,
+                    num_fragments: 1,
+                    offset: 0,
+                    bytes: Bytes::from(vec![0u8; 10]),
+                }]),
+            )]
+        };
 
-    use crate::channel::senders::fragment_sender::FragmentSender;
-    use crate::packet::message::MessageId;
-    use crate::prelude::*;
+        // without the boundary, a packet contains both channels
+        let mut manager = PacketBuilder::new(1.5).with_channel_registry(&channel_registry);
+        let packets = build_packets(&mut manager, Tick(0), single_data(), fragment_data())?;
+        let mut shared_packet = false;
+        for packet in packets {
+            let contents = packet.parse_packet_payload()?;
+            shared_packet |=
+                contents.contains_key(&unreliable_id) && contents.contains_key(&reliable_id);
+        }
+        assert!(shared_packet);
 
-    use super::*;
+        let mut manager = PacketBuilder::new(1.5)
+            .with_channel_registry(&channel_registry)
+            .with_reliability_boundary(true);
+        let packets = build_packets(&mut manager, Tick(0), single_data(), fragment_data())?;
+        let mut num_messages = 0;
+        for packet in packets {
+            let contents = packet.parse_packet_payload()?;
+            assert!(
+                !(contents.contains_key(&unreliable_id) && contents.contains_key(&reliable_id))
+            );
+            num_messages += contents.values().map(Vec::len).sum::<usize>();
+        }
+        assert_eq!(num_messages, 301);
+        Ok(())
+    }
 
-    #[derive(ChannelInternal, TypePath)]
-    struct Channel1;
+    /// With channel isolation, each packet contains the messages of a single channel
+    #[test]
+    fn test_pack_isolate_channels() -> Result<(), PacketError> {
+        let single_data = || {
+            (0..3)
+                .map(|channel_id| {
+                    let message = SingleData::new(None, Bytes::from(vec![channel_id as u8; 10]));
+                    (channel_id, VecDeque::from(vec![message; 3]))
+                })
+                .collect::<Vec<_>>()
+        };
+        for schedule_mode in [ScheduleMode::Grouped, ScheduleMode::RoundRobin] {
+            // all the messages fit in one packet
+            let mut manager = PacketBuilder::new(1.5).with_schedule_mode(schedule_mode);
+            let packets = build_packets(&mut manager, Tick(0), single_data(), vec![])?;
+            assert_eq!(packets.len(), 1);
 
-    #[derive(ChannelInternal, TypePath)]
-    struct Channel2;
+            manager.set_isolate_channels(true);
+            let packets = build_packets(&mut manager, Tick(1), single_data(), vec![])?;
+            assert_eq!(packets.len(), 3);
+            let mut channels = vec![];
+            for packet in packets {
+                let contents = packet.parse_packet_payload()?;
+                assert_eq!(contents.len(), 1);
+                let (channel_id, messages) = contents.into_iter().next().unwrap();
+                assert_eq!(messages.len(), 3);
+                channels.push(channel_id);
+            }
+            assert_eq!(channels, vec![0, 1, 2]);
+        }
+        Ok(())
+    }
 
-    #[derive(ChannelInternal, TypePath)]
-    struct Channel3;
+    /// The bytes recorded for each message add up to the packet payloads, without the headers
+    #[test]
+    fn test_pack_stats_message_bytes() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_pack_stats(true);
+        let single_data = (0..3)
+            .map(|channel_id| {
+                let messages = (0..100)
+                    .map(|i| {
+                        SingleData::new(Some(MessageId(i)), Bytes::from(vec![0u8; i as usize]))
+                    })
+                    .collect();
+                (channel_id, messages)
+            })
+            .collect();
+        let packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert!(packets.len() > 1);
+        let message_bytes = &manager.pack_stats().unwrap().message_bytes;
+        assert_eq!(message_bytes.len(), 300);
+        assert_eq!(
+            message_bytes.values().sum::<usize>(),
+            packets
+                .iter()
+                .map(|packet| packet.payload.len() - HEADER_BYTES)
+                .sum::<usize>()
+        );
+        Ok(())
+    }
 
-    fn get_channel_registry() -> ChannelRegistry {
-        let settings = ChannelSettings {
-            mode: ChannelMode::UnorderedUnreliable,
-            ..default()
+    /// The serialized size of each message is reported back, with and without fixed-length blocks
+    #[test]
+    fn test_pack_stats_serialized_bytes() -> Result<(), PacketError> {
+        let messages: VecDeque<_> = (0..10)
+            .map(|i| SingleData::new(Some(MessageId(i)), Bytes::from(vec![0u8; 5 + i as usize])))
+            .collect();
+        let mut manager = PacketBuilder::new(1.5).with_pack_stats(true);
+        build_packets(&mut manager, Tick(0), vec![(0, messages.clone())], vec![])?;
+        let serialized_bytes = &manager.pack_stats().unwrap().serialized_bytes;
+        assert_eq!(serialized_bytes.len(), 10);
+        for message in &messages {
+            assert_eq!(serialized_bytes[&(0, message.id.unwrap())], message.len());
+        }
+
+        // in a fixed-length block, the messages after the first one do not write their length
+        let message = |i| SingleData::new(Some(MessageId(i)), Bytes::from(vec![0u8; 5]));
+        let mut manager = PacketBuilder::new(1.5)
+            .with_pack_stats(true)
+            .with_fixed_length_blocks(true);
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(0, (0..3).map(message).collect())],
+            vec![],
+        )?;
+        let serialized_bytes = &manager.pack_stats().unwrap().serialized_bytes;
+        assert_eq!(serialized_bytes[&(0, MessageId(0))], message(0).len());
+        assert_eq!(serialized_bytes[&(0, MessageId(1))], message(1).len() - 1);
+        // the messages take the whole packet, after the channel id and the number of messages
+        assert_eq!(
+            serialized_bytes.values().sum::<usize>(),
+            packets[0].payload.len() - HEADER_BYTES - 2
+        );
+        Ok(())
+    }
+
+    /// The same workload packed by two builders with the same seed gives the same bytes, and the
+    /// seed changes the random order of the channels
+    #[test]
+    fn test_pack_same_seed() -> Result<(), PacketError> {
+        let build = |seed| -> Result<Vec<Payload>, SerializationError> {
+            let mut manager = PacketBuilder::new(1.5)
+                .with_seed(seed)
+                .with_tie_break(TieBreak::Random);
+            let single_data = (0..5)
+                .map(|channel_id| {
+                    let message = SingleData::new(None, Bytes::from(vec![channel_id as u8; 100]));
+                    (
+                        channel_id,
+                        VecDeque::from(vec![message; 10 + channel_id as usize]),
+                    )
+                })
+                .collect();
+            let fragment_data = vec![(
+                5,
+                VecDeque::from(vec![FragmentData {
+                    message_id: MessageId(0),
+                    fragment_id: 0,
+                    num_fragments: 1,
+                    offset: 0,
+                    bytes: Bytes::from(vec![5u8; FRAGMENT_SIZE]),
+                }]),
+            )];
+            Ok(
+                build_packets(&mut manager, Tick(0), single_data, fragment_data)?
+                    .into_iter()
+                    .map(|packet| packet.payload)
+                    .collect(),
+            )
         };
-        let mut c = ChannelRegistry::default();
-        c.add_channel::<Channel1>(settings.clone());
-        c.add_channel::<Channel2>(settings.clone());
-        c.add_channel::<Channel3>(settings.clone());
-        c
+        let packets = build(42)?;
+        assert!(packets.len() > 1);
+        assert_eq!(packets, build(42)?);
+        // the order of the channels depends on the seed
+        assert!((0..10).any(|seed| build(seed).is_ok_and(|other| other != packets)));
+        Ok(())
     }
 
-    /// A bunch of small messages that all fit in the same packet
+    /// Random workloads, with the randomized policies enabled, are packed into the same bytes by
+    /// two builders with the same [`PackerConfig`]
     #[test]
-    fn test_pack_small_messages() -> Result<(), PacketError> {
-        let channel_registry = get_channel_registry();
-        let mut manager = PacketBuilder::new(1.5);
-        let channel_kind1 = ChannelKind::of::<Channel1>();
-        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
-        let channel_kind2 = ChannelKind::of::<Channel2>();
-        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
-        let channel_kind3 = ChannelKind::of::<Channel3>();
-        let channel_id3 = channel_registry.get_net_from_kind(&channel_kind3).unwrap();
+    fn test_pack_same_seed_random_workloads() -> Result<(), PacketError> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let workload: Vec<Vec<(ChannelId, VecDeque<SingleData>)>> = (0..3)
+                .map(|_| {
+                    (0..rng.gen_range(1..6))
+                        .map(|channel_id| {
+                            let messages = (0..rng.gen_range(0..30))
+                                .map(|i| {
+                                    let len = rng.gen_range(0..400);
+                                    let id = rng.gen_bool(0.5).then_some(MessageId(i));
+                                    SingleData::new(id, Bytes::from(vec![i as u8; len]))
+                                })
+                                .collect();
+                            (channel_id, messages)
+                        })
+                        .collect()
+                })
+                .collect();
+            let weights: Vec<f32> = (0..5).map(|_| rng.gen_range(1..3) as f32).collect();
+            let build = || -> Result<Vec<Payload>, SerializationError> {
+                let mut manager = weights.iter().enumerate().fold(
+                    PacketBuilder::new(1.5)
+                        .with_packer_config(PackerConfig { seed })
+                        .with_tie_break(TieBreak::Random),
+                    |manager, (channel_id, weight)| {
+                        manager.with_channel_weight(channel_id as ChannelId, *weight)
+                    },
+                );
+                let mut payloads = vec![];
+                for (tick, single_data) in workload.iter().enumerate() {
+                    payloads.extend(
+                        build_packets(
+                            &mut manager,
+                            Tick(tick as u16),
+                            single_data.clone(),
+                            vec![],
+                        )?
+                        .into_iter()
+                        .map(|packet| packet.payload),
+                    );
+                }
+                Ok(payloads)
+            };
+            assert_eq!(build()?, build()?, "seed {seed}");
+        }
+        Ok(())
+    }
+
+    /// A packet that compresses well is compressed, and the header indicates it
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_pack_packet_compression() -> Result<(), PacketError> {
+        use crate::packet::header::PacketHeader;
 
+        let mut manager = PacketBuilder::new(1.5).with_packet_compression(true);
         let small_bytes = Bytes::from(vec![7u8; 10]);
         let small_message = SingleData::new(None, small_bytes.clone());
+        let single_data = vec![(0, VecDeque::from(vec![small_message.clone(); 50]))];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        let packet = packets.pop().unwrap();
+        assert!(packet.payload.len() < 11 + 2 + 50 * small_message.len());
+        let header = PacketHeader::from_bytes(&mut packet.payload.clone().into())?;
+        assert!(header.compressed);
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(contents.get(&0).unwrap(), &vec![small_bytes; 50]);
+
+        // random bytes don't compress, so the packet is kept as is
+        let random_bytes = Bytes::from((0..200).map(|_| rand::random::<u8>()).collect::<Vec<_>>());
+        let single_data = vec![(
+            0,
+            VecDeque::from(vec![SingleData::new(None, random_bytes.clone())]),
+        )];
+        let packet = build_packets(&mut manager, Tick(0), single_data, vec![])?
+            .pop()
+            .unwrap();
+        let header = PacketHeader::from_bytes(&mut packet.payload.clone().into())?;
+        assert!(!header.compressed);
+        assert_eq!(
+            packet.parse_packet_payload()?.get(&0).unwrap(),
+            &vec![random_bytes]
+        );
+        Ok(())
+    }
+
+    /// A compressed packet that announces a decompressed size bigger than the MTU is rejected
+    /// before that size is allocated
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_pack_compression_size_limit() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_packet_compression(true);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let single_data = vec![(0, VecDeque::from(vec![message; 50]))];
+        let mut packet = build_packets(&mut manager, Tick(0), single_data, vec![])?
+            .pop()
+            .unwrap();
+        // the compressed bytes start with the decompressed size
+        let header_len = Packet::peek_header(&packet.payload)?.len();
+        packet.payload[header_len..header_len + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            packet.parse_packet_payload(),
+            Err(PacketError::Serialization(
+                SerializationError::DecompressedPacketTooLarge {
+                    len,
+                    max: MAX_PACKET_SIZE,
+                }
+            )) if len == u32::MAX as usize
+        ));
+        Ok(())
+    }
+
+    /// Without the `lz4` feature, a compressed packet cannot be read
+    #[cfg(not(feature = "lz4"))]
+    #[test]
+    fn test_pack_compression_unsupported() -> Result<(), PacketError> {
+        use crate::packet::header::PacketHeader;
+
+        let mut manager = PacketBuilder::new(1.5);
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 10]));
+        let mut packet = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(0, vec![message].into())],
+            vec![],
+        )?
+        .pop()
+        .unwrap();
+        PacketHeader::set_compressed_flag(&mut packet.payload);
+        assert!(matches!(
+            packet.parse_packet_payload(),
+            Err(PacketError::Serialization(
+                SerializationError::CompressionUnsupported
+            ))
+        ));
+        Ok(())
+    }
+
+    /// A dictionary of similar payloads compresses small packets much better than the packet alone
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_pack_compression_dictionary() -> Result<(), PacketError> {
+        use crate::packet::packet::CompressionDictionary;
+        use crate::packet::packet_parser::PacketParser;
+
+        let state = |entity: u32| {
+            Bytes::from(format!(
+                r#"{{"entity":{entity},"position":[1.5,2.5,3.0],"rotation":[0.0,0.0,0.0,1.0],"health":100}}"#
+            ))
+        };
+        let samples = (0..4).map(state).collect::<Vec<_>>();
+        let dictionary =
+            CompressionDictionary::from_samples(3, samples.iter().map(|sample| sample.as_ref()));
+        let build = |manager: &mut PacketBuilder| -> Result<Packet, PacketError> {
+            let single_data = vec![(0, VecDeque::from(vec![SingleData::new(None, state(7))]))];
+            Ok(build_packets(manager, Tick(0), single_data, vec![])?
+                .pop()
+                .unwrap())
+        };
+        let without_dictionary = build(&mut PacketBuilder::new(1.5).with_packet_compression(true))?;
+        let mut manager = PacketBuilder::new(1.5)
+            .with_packet_compression(true)
+            .with_compression_dictionary(dictionary.clone());
+        let with_dictionary = build(&mut manager)?;
+        assert!(
+            with_dictionary.payload.len() * 2 < without_dictionary.payload.len(),
+            "{} vs {}",
+            with_dictionary.payload.len(),
+            without_dictionary.payload.len()
+        );
+        let header = Packet::peek_header(&with_dictionary.payload)?;
+        assert!(header.compressed);
+        assert_eq!(header.compression_dictionary, Some(3));
+
+        // the receiver needs the same dictionary
+        let mut out = HashMap::default();
+        assert!(matches!(
+            PacketParser::new(ChannelIdDictionary::default())
+                .parse_into(build(&mut manager)?, &mut out),
+            Err(SerializationError::UnknownCompressionDictionary { id: 3 })
+        ));
+        PacketParser::new(ChannelIdDictionary::default())
+            .with_compression_dictionary(dictionary)
+            .parse_into(with_dictionary, &mut out)?;
+        assert_eq!(out[&0], vec![state(7)]);
+        Ok(())
+    }
+
+    /// The message acks of a packet can be grouped by channel
+    #[test]
+    fn test_pack_grouped_acks() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let message = |id: u16| SingleData::new(Some(MessageId(id)), Bytes::from(vec![7u8; 10]));
+        let ack = |id: u16| MessageAck {
+            message_id: MessageId(id),
+            fragment_id: None,
+        };
+        let single_data = vec![
+            (0, VecDeque::from(vec![message(0), message(1)])),
+            (1, VecDeque::from(vec![message(2), message(3), message(4)])),
+        ];
+        let packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].message_acks.len(), 5);
+        assert_eq!(
+            packets[0].grouped_acks(),
+            vec![(0, vec![ack(0), ack(1)]), (1, vec![ack(2), ack(3), ack(4)]),]
+        );
+        Ok(())
+    }
+
+    /// Extracting the acks of several packets into the same vector accumulates them in order, and
+    /// moves them out of the packets
+    #[test]
+    fn test_extract_acks_into() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let message = |id: u16| SingleData::new(Some(MessageId(id)), Bytes::from(vec![7u8; 500]));
+        let single_data = vec![
+            (0, (0..3).map(message).collect()),
+            (1, (3..6).map(message).collect()),
+        ];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert!(packets.len() > 1);
+
+        let mut acks = vec![];
+        let mut expected = vec![];
+        for packet in packets.iter_mut() {
+            let packet_acks = packet.message_acks.clone();
+            let capacity = packet.message_acks.capacity();
+            packet.extract_acks_into(&mut acks);
+            expected.extend(packet_acks);
+            assert_eq!(acks, expected);
+            // the packet keeps its buffer for recycling
+            assert!(packet.message_acks.is_empty());
+            assert_eq!(packet.message_acks.capacity(), capacity);
+        }
+        assert_eq!(acks.len(), 6);
+
+        // extracting again adds nothing
+        packets[0].extract_acks_into(&mut acks);
+        assert_eq!(acks, expected);
+        Ok(())
+    }
+
+    /// The acks of each packet are the messages with an id and the fragments that it contains
+    #[test]
+    fn test_message_acks_by_packet() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let messages: VecDeque<_> = (0..10)
+            .map(|i| {
+                let id = (i % 2 == 0).then_some(MessageId(i));
+                SingleData::new(id, Bytes::from(vec![7u8; 300]))
+            })
+            .collect();
+        let packets = build_packets(&mut manager, Tick(0), vec![(1, messages)], vec![])?;
+        assert!(packets.len() > 1);
+        let acks = PacketBuilder::message_acks_by_packet(&packets);
+        let tracked_packets: Vec<_> = packets.iter().filter(|p| !p.is_empty()).collect();
+        assert_eq!(acks.len(), tracked_packets.len());
+        for ((packet_id, acks), packet) in acks.iter().zip(tracked_packets) {
+            assert_eq!(*packet_id, packet.packet_id);
+            assert_eq!(acks, &packet.message_acks);
+        }
+        let mut acked_ids: Vec<_> = acks
+            .into_iter()
+            .flat_map(|(_, acks)| acks)
+            .map(|(channel_id, ack)| {
+                assert_eq!(channel_id, 1);
+                assert_eq!(ack.fragment_id, None);
+                ack.message_id
+            })
+            .collect();
+        acked_ids.sort();
+        assert_eq!(
+            acked_ids,
+            (0..10).step_by(2).map(MessageId).collect::<Vec<_>>()
+        );
+
+        // a packet whose messages have no id is not tracked
+        let messages = VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![7u8; 10]))]);
+        let packets = build_packets(&mut manager, Tick(1), vec![(1, messages)], vec![])?;
+        assert!(PacketBuilder::message_acks_by_packet(&packets).is_empty());
+        Ok(())
+    }
+
+    /// An immediate packet contains only its message, and doesn't change the packing of the
+    /// other messages
+    #[test]
+    fn test_build_immediate() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_invariant_checks(true);
+        let message = SingleData::with_id(MessageId(3), Bytes::from(vec![7u8; 10]));
+        let packet = manager.build_immediate(2, message.clone(), Tick(5))?;
+        assert_eq!(packet.tick()?, Tick(5));
+        assert_eq!(packet.packet_id, PacketId(0));
+        assert_eq!(
+            packet.message_acks,
+            vec![(
+                2,
+                MessageAck {
+                    message_id: MessageId(3),
+                    fragment_id: None
+                }
+            )]
+        );
+        assert_eq!(
+            packet.payload.len(),
+            HEADER_BYTES + manager.channel_dictionary.len(2) + 1 + message.len()
+        );
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(contents, HashMap::from_iter([(2, vec![message.bytes])]));
+
+        // the next packets continue the sequence of packet ids
+        let small_message = SingleData::new(None, Bytes::from(vec![1u8; 10]));
+        let packets = build_packets(
+            &mut manager,
+            Tick(5),
+            vec![(0, VecDeque::from(vec![small_message]))],
+            vec![],
+        )?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].packet_id, PacketId(1));
+
+        // a message that needs to be fragmented is rejected
+        let big_message = SingleData::new(None, Bytes::from(vec![1u8; MAX_PACKET_SIZE]));
+        assert!(matches!(
+            manager.build_immediate(0, big_message, Tick(6)),
+            Err(SerializationError::MessageShouldBeFragmented { .. })
+        ));
+        assert_eq!(manager.header_manager.next_packet_id(), PacketId(2));
+        Ok(())
+    }
+
+    /// An immediate packet that fails to be written leaves nothing behind
+    #[test]
+    fn test_build_immediate_error() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                encrypted: true,
+                ..default()
+            })
+            .unwrap();
+        // the cipher adds more bytes than were reserved for it, so writing the message fails
+        let mut manager = PacketBuilder::new(1.5)
+            .with_channel_registry(&channel_registry)
+            .with_channel_encryption(4, |_, block| block.extend_from_slice(&[0; 5]));
+        let message = SingleData::with_id(MessageId(3), Bytes::from(vec![7u8; 10]));
+        assert!(manager
+            .build_immediate(0, message.clone(), Tick(0))
+            .is_err());
+        assert!(manager.current_packet.is_none());
+        assert_eq!(manager.header_manager.next_packet_id(), PacketId(0));
+        assert!(manager.header_manager.sent_packets_not_acked().is_empty());
+        assert_eq!(manager.payload_buffers.len(), 1);
+        // the next packets are not affected
+        let packet = manager.build_immediate(1, message, Tick(0))?;
+        assert_eq!(packet.packet_id, PacketId(0));
+        Ok(())
+    }
+
+    /// Fixed-size items are packed until the packet is full, the rest is returned in order
+    #[test]
+    fn test_pack_items() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_invariant_checks(true);
+        let serialize = |item: &u32| {
+            let mut bytes = item.to_be_bytes().to_vec();
+            bytes.resize(50, 0);
+            Bytes::from(bytes)
+        };
+        // each item is written with its length
+        let item_len = SingleData::new(None, serialize(&0)).len();
+        let channel_len = HEADER_BYTES + manager.channel_dictionary.len(2);
+        let items_per_packet = (1..)
+            .take_while(|n| channel_len + varint_len(*n as u64) + item_len * n <= manager.mtu())
+            .last()
+            .unwrap();
+        assert!(items_per_packet < 100);
+
+        let mut items: Vec<u32> = (0..100).collect();
+        let mut next_item = 0;
+        while !items.is_empty() {
+            let (packet, num_items, leftover) = manager.pack_items(2, items, serialize, Tick(0))?;
+            let packet = packet.unwrap();
+            // only the last packet is not full
+            assert_eq!(num_items, items_per_packet.min(100 - next_item as usize));
+            assert_eq!(leftover.len() + num_items + next_item as usize, 100);
+            if let Some(first) = leftover.first() {
+                assert_eq!(*first, next_item + num_items as u32);
+            }
+            let contents = packet.parse_packet_payload()?;
+            let expected: Vec<Bytes> = (next_item..next_item + num_items as u32)
+                .map(|item| serialize(&item))
+                .collect();
+            assert_eq!(contents, HashMap::from_iter([(2, expected)]));
+            next_item += num_items as u32;
+            items = leftover;
+        }
+        assert_eq!(next_item, 100);
+
+        // no items, no packet
+        let (packet, num_items, leftover) =
+            manager.pack_items(2, Vec::<u32>::new(), serialize, Tick(0))?;
+        assert!(packet.is_none());
+        assert_eq!((num_items, leftover), (0, vec![]));
+
+        // an item that doesn't fit in an empty packet
+        let next_packet_id = manager.header_manager.next_packet_id();
+        assert!(matches!(
+            manager.pack_items(
+                2,
+                [0u32],
+                |_| Bytes::from(vec![0; MAX_PACKET_SIZE]),
+                Tick(1)
+            ),
+            Err(SerializationError::MessageShouldBeFragmented { .. })
+        ));
+        // no packet was started
+        assert!(manager.current_packet.is_none());
+        assert_eq!(manager.header_manager.next_packet_id(), next_packet_id);
+
+        // a packet that fails to be written is dropped
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                encrypted: true,
+                ..default()
+            })
+            .unwrap();
+        let mut manager = PacketBuilder::new(1.5)
+            .with_channel_registry(&channel_registry)
+            .with_channel_encryption(4, |_, block| block.extend_from_slice(&[0; 5]));
+        assert!(manager
+            .pack_items(0, [0u32, 1], serialize, Tick(0))
+            .is_err());
+        assert!(manager.current_packet.is_none());
+        assert_eq!(manager.header_manager.next_packet_id(), PacketId(0));
+        assert!(manager.header_manager.sent_packets_not_acked().is_empty());
+        assert_eq!(manager.payload_buffers.len(), 1);
+        Ok(())
+    }
+
+    /// Two messages of a channel with the same id are written, rejected or deduplicated
+    #[test]
+    fn test_pack_duplicate_message_ids() -> Result<(), PacketError> {
+        let message =
+            |id: u16, byte: u8| SingleData::new(Some(MessageId(id)), vec![byte; 10].into());
+        let single_data = || {
+            vec![
+                (
+                    0,
+                    VecDeque::from(vec![message(5, 1), message(6, 2), message(5, 3)]),
+                ),
+                // the ids are only compared within a channel
+                (1, VecDeque::from(vec![message(5, 4)])),
+            ]
+        };
+        let acked_ids = |packet: &Packet| {
+            packet
+                .message_acks
+                .iter()
+                .map(|(channel_id, ack)| (*channel_id, ack.message_id.0))
+                .collect::<Vec<_>>()
+        };
+
+        let mut manager = PacketBuilder::new(1.5);
+        let packets = build_packets(&mut manager, Tick(0), single_data(), vec![])?;
+        assert_eq!(acked_ids(&packets[0]), vec![(0, 5), (0, 6), (0, 5), (1, 5)]);
+
+        let mut manager =
+            PacketBuilder::new(1.5).with_duplicate_message_ids(DuplicateMessageIds::Error);
+        assert!(matches!(
+            manager.build_packets(Tick(0), single_data(), vec![]),
+            Err(SerializationError::DuplicateMessageId {
+                channel_id: 0,
+                message_id: 5
+            })
+        ));
+        assert_eq!(manager.stats().packets_sent, 0);
+
+        let mut manager =
+            PacketBuilder::new(1.5).with_duplicate_message_ids(DuplicateMessageIds::Drop);
+        let mut packets = build_packets(&mut manager, Tick(0), single_data(), vec![])?;
+        assert_eq!(acked_ids(&packets[0]), vec![(0, 5), (0, 6), (1, 5)]);
+        let contents = packets.pop().unwrap().parse_packet_payload()?;
+        assert_eq!(contents[&0], vec![message(5, 1).bytes, message(6, 2).bytes]);
+        Ok(())
+    }
+
+    /// Identical consecutive messages without id are sent once, but messages with ids are kept
+    #[test]
+    fn test_pack_message_coalescing() -> Result<(), PacketError> {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::UnorderedReliable(ReliableSettings::default()),
+                ..default()
+            })
+            .unwrap();
+        let unreliable_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let reliable_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let mut manager = PacketBuilder::new(1.5)
+            .with_channel_registry(&channel_registry)
+            .with_message_coalescing(true);
+        let bytes = Bytes::from(vec![1u8; 10]);
+        let id_less = SingleData::new(None, bytes.clone());
+        let single_data = vec![
+            (
+                unreliable_id,
+                VecDeque::from(vec![
+                    id_less.clone(),
+                    id_less.clone(),
+                    id_less.clone(),
+                    SingleData::new(Some(MessageId(1)), bytes.clone()),
+                    SingleData::new(Some(MessageId(2)), bytes.clone()),
+                ]),
+            ),
+            // the messages of reliable channels are never merged
+            (reliable_id, VecDeque::from(vec![id_less.clone(); 2])),
+        ];
+        let packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].num_messages(), 2);
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        assert_eq!(contents[&unreliable_id], vec![bytes.clone(); 3]);
+        assert_eq!(contents[&reliable_id], vec![bytes.clone(); 2]);
+
+        // without coalescing, every message is sent
+        let mut manager = PacketBuilder::new(1.5);
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(0, VecDeque::from(vec![id_less; 3]))],
+            vec![],
+        )?;
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        assert_eq!(contents[&0], vec![bytes; 3]);
+        Ok(())
+    }
+
+    /// Messages of several sub-channels share the same channel id, and are demuxed on reception
+    #[test]
+    fn test_pack_sub_channels() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let messages = vec![
+            SingleData::new(None, Bytes::from_static(b"a0")),
+            SingleData::new(Some(MessageId(1)), Bytes::from_static(b"b1")).with_sub_channel(1),
+            SingleData::new(None, Bytes::from_static(b"c0")),
+            SingleData::new(None, Bytes::from_static(b"d1")).with_sub_channel(1),
+        ];
+        // sub-channel 0 does not take any space
+        assert_eq!(messages[0].len() + 1, messages[3].len());
+        let packet = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(0, VecDeque::from(messages))],
+            vec![],
+        )?
+        .pop()
+        .unwrap();
+
+        let mut reader = Reader::from(Bytes::from(packet.payload.clone()));
+        crate::packet::header::PacketHeader::from_bytes(&mut reader)?;
+        assert_eq!(ChannelIdDictionary::default().read(&mut reader)?, 0);
+        let num_messages = reader.read_varint()?;
+        let mut sub_channels: HashMap<u8, Vec<Bytes>> = HashMap::new();
+        for _ in 0..num_messages {
+            let single_data = SingleData::from_bytes(&mut reader)?;
+            sub_channels
+                .entry(single_data.sub_channel)
+                .or_default()
+                .push(single_data.bytes);
+        }
+        assert!(!reader.has_remaining());
+        assert_eq!(
+            sub_channels,
+            HashMap::from_iter([
+                (
+                    0,
+                    vec![Bytes::from_static(b"a0"), Bytes::from_static(b"c0")]
+                ),
+                (
+                    1,
+                    vec![Bytes::from_static(b"b1"), Bytes::from_static(b"d1")]
+                ),
+            ])
+        );
+        // the zero-copy reader skips the sub-channel
+        let channel_dictionary = ChannelIdDictionary::default();
+        let read = PacketReader::new(&packet.payload, &channel_dictionary)?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(read.len(), 4);
+        Ok(())
+    }
+
+    /// Messages of the same length are written with a single length, and decoded correctly
+    #[test]
+    fn test_pack_fixed_length_blocks() -> Result<(), PacketError> {
+        let messages = (0..10u8)
+            .map(|i| {
+                let message = SingleData::new(None, Bytes::from(vec![i; 200]));
+                if i % 2 == 0 {
+                    message
+                } else {
+                    SingleData::new(Some(MessageId(i as u16)), message.bytes)
+                }
+            })
+            .collect::<VecDeque<_>>();
+        let single_data = || {
+            vec![
+                (0, messages.clone()),
+                // messages of different lengths are written as usual
+                (
+                    1,
+                    VecDeque::from(vec![
+                        SingleData::new(None, Bytes::from(vec![1; 3])),
+                        SingleData::new(None, Bytes::from(vec![1; 4])),
+                    ]),
+                ),
+            ]
+        };
+        let mut manager = PacketBuilder::new(1.5).with_mtu(10_000)?;
+        let packet = build_packets(&mut manager, Tick(0), single_data(), vec![])?
+            .pop()
+            .unwrap();
+        let mut block_manager = PacketBuilder::new(1.5)
+            .with_mtu(10_000)?
+            .with_fixed_length_blocks(true)
+            .with_invariant_checks(true);
+        let block_packet = build_packets(&mut block_manager, Tick(0), single_data(), vec![])?
+            .pop()
+            .unwrap();
+        // the length of the 9 last messages of channel 0 is not written
+        assert_eq!(
+            block_packet.payload.len(),
+            packet.payload.len() - 9 * varint_len(200)
+        );
+        assert_eq!(block_packet.message_acks, packet.message_acks);
+
+        let channel_dictionary = ChannelIdDictionary::default();
+        let read = PacketReader::new(&block_packet.payload, &channel_dictionary)?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(read.len(), 12);
+        assert!(read[..10]
+            .iter()
+            .zip(messages.iter())
+            .all(|((channel_id, bytes), message)| *channel_id == 0
+                && *bytes == message.bytes.as_ref()));
+        let expected = packet.parse_packet_payload()?;
+        assert_eq!(block_packet.parse_packet_payload()?, expected);
+        Ok(())
+    }
+
+    /// The estimate accounts for the lengths that the fixed-length blocks do not write
+    #[test]
+    fn test_estimate_fixed_length_blocks() -> Result<(), PacketError> {
+        let mut num_packets = 0;
+        let mut num_block_packets = 0;
+        for n in 1..40 {
+            let single_data = || {
+                vec![
+                    (
+                        0,
+                        VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![0; 100])); n]),
+                    ),
+                    (
+                        1,
+                        VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; 70])); n]),
+                    ),
+                    // messages of different lengths are not written in a block
+                    (
+                        2,
+                        (0..n)
+                            .map(|i| SingleData::new(None, Bytes::from(vec![2; 60 + i % 3])))
+                            .collect(),
+                    ),
+                ]
+            };
+            let mut manager = PacketBuilder::new(1.5);
+            num_packets += build_packets(&mut manager, Tick(0), single_data(), vec![])?.len();
+            let mut block_manager = PacketBuilder::new(1.5).with_fixed_length_blocks(true);
+            num_block_packets +=
+                build_packets(&mut block_manager, Tick(0), single_data(), vec![])?.len();
+            // the messages written after a fragment
+            let fragment_data = vec![(
+                3,
+                VecDeque::from(vec![FragmentData {
+                    message_id: MessageId(0),
+                    fragment_id: 0,
+                    num_fragments: 1,
+                    offset: 0,
+                    bytes: Bytes::from(vec![3; 500]),
+                }]),
+            )];
+            build_packets(&mut block_manager, Tick(1), single_data(), fragment_data)?;
+        }
+        // the saved bytes leave room for more messages
+        assert!(num_block_packets < num_packets);
+        Ok(())
+    }
+
+    /// The packet limits describe the biggest message that the builder accepts
+    #[test]
+    fn test_packet_limits() -> Result<(), PacketError> {
+        for wire_config in [
+            WireConfig::default(),
+            WireConfig::default().with_mtu(600)?.with_checksum(true),
+            WireConfig::default().with_pad_to(Some(1000)),
+        ] {
+            let mut manager = PacketBuilder::new(1.5).with_wire_config(wire_config)?;
+            let limits = manager.packet_limits()?;
+            assert_eq!(limits.mtu, wire_config.mtu);
+            let max_bytes = limits.max_single_message_bytes();
+            assert!(max_bytes >= limits.max_message_bytes_before_fragmentation());
+            let message = |len| {
+                vec![(
+                    0,
+                    VecDeque::from(vec![SingleData::new(None, Bytes::from(vec![1; len]))]),
+                )]
+            };
+            let packets = build_packets(&mut manager, Tick(0), message(max_bytes), vec![])?;
+            assert_eq!(packets.len(), 1);
+            // the message fills the packet
+            assert_eq!(packets[0].payload.len(), limits.mtu);
+            assert!(matches!(
+                manager.build_packets(Tick(0), message(max_bytes + 1), vec![]),
+                Err(SerializationError::MessageShouldBeFragmented { .. })
+            ));
+        }
+        Ok(())
+    }
+
+    /// A data packet that ends up without any message is dropped instead of being sent
+    #[test]
+    fn test_no_packet_without_messages() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5).with_metrics(true);
+        // a packet received from the remote peer, to ack in the header of the next packet
+        manager.header_manager.track_pending_acks();
+        let mut remote = PacketHeaderManager::new(1.5, DEFAULT_ACK_WINDOW_BITS)?;
+        manager
+            .header_manager
+            .process_recv_packet_header(&remote.prepare_send_packet_header(PacketType::Data));
+        assert!(manager.header_manager.has_pending_acks());
+        let message = SingleData::new(None, Bytes::from(vec![1u8; 10]));
+        // every message is kept for a later call
+        let (packets, remainder) = manager.build_packets_limited(
+            Tick(0),
+            vec![(0, VecDeque::from(vec![message.clone(); 3]))],
+            vec![],
+            0,
+        )?;
+        assert!(packets.is_empty());
+        assert_eq!(remainder[&0].0.len(), 3);
+        // channels without messages do not start a packet
+        let packets = build_packets(&mut manager, Tick(0), vec![(0, VecDeque::new())], vec![])?;
+        assert!(packets.is_empty());
+
+        // a packet that was started but did not receive any message is dropped
+        manager.build_new_single_packet(Tick(0))?;
+        let mut emitted = vec![];
+        let mut num_packets = 0;
+        manager.emit_packet(
+            PacketFinishReason::NoMoreMessages,
+            &mut |packet| {
+                emitted.push(packet);
+                Ok::<_, PacketError>(())
+            },
+            &mut num_packets,
+        )?;
+        assert!(emitted.is_empty());
+        assert_eq!(num_packets, 0);
+        assert!(manager.current_packet.is_none());
+        let metrics = manager.take_metrics();
+        assert_eq!((metrics.packets, metrics.single_packets), (0, 0));
+        // nothing is left of the dropped packet: its id is not waiting for an ack (so it is never
+        // reported as lost) and is given to the next packet, and its ack is still pending
+        let header_manager = &manager.header_manager;
+        assert_eq!(header_manager.next_packet_id(), PacketId(0));
+        assert!(header_manager.sent_packets_not_acked().is_empty());
+        assert!(header_manager.has_pending_acks());
+        // its payload buffer is reused by the next packet
+        let buffer = manager.payload_buffers[0].as_ptr();
+        let packets = build_packets(
+            &mut manager,
+            Tick(0),
+            vec![(0, VecDeque::from([message]))],
+            vec![],
+        )?;
+        assert_eq!(packets[0].packet_id, PacketId(0));
+        assert_eq!(packets[0].payload.as_ptr(), buffer);
+        assert!(!manager.header_manager.has_pending_acks());
+        Ok(())
+    }
+
+    /// Packets emitted through a sink are the same as the packets returned by `build_packets`
+    #[test]
+    fn test_build_packets_with_sink() -> Result<(), PacketError> {
+        let data = || {
+            let fragments = FragmentSender::default()
+                .build_fragments(MessageId(0), None, Bytes::from(vec![1; 2500]))
+                .unwrap();
+            let single_data = (0..3)
+                .map(|channel_id| {
+                    let messages = (0..100)
+                        .map(|i| SingleData::new(None, Bytes::from(vec![2; i % 50])))
+                        .collect();
+                    (channel_id, messages)
+                })
+                .collect::<Vec<_>>();
+            (single_data, vec![(3, VecDeque::from(fragments))])
+        };
+        let mut manager = PacketBuilder::new(1.5);
+        let (single_data, fragment_data) = data();
+        let packets = manager.build_packets(Tick(0), single_data, fragment_data)?;
+        assert!(packets.len() > 1);
 
-        let single_data = vec![
-            (*channel_id1, VecDeque::from(vec![small_message.clone()])),
-            (
-                *channel_id2,
-                VecDeque::from(vec![small_message.clone(), small_message.clone()]),
-            ),
-            (*channel_id3, VecDeque::from(vec![small_message.clone()])),
-        ];
-        let fragment_data = vec![];
-        let mut packets = manager.build_packets(Tick(0), single_data, fragment_data)?;
-        assert_eq!(packets.len(), 1);
-        let packet = packets.pop().unwrap();
-        assert_eq!(packet.message_acks, vec![]);
-        let contents = packet.parse_packet_payload()?;
+        let mut streaming_manager = PacketBuilder::new(1.5);
+        let (single_data, fragment_data) = data();
+        let mut streamed = vec![];
+        streaming_manager.build_packets_with(Tick(0), single_data, fragment_data, |packet| {
+            streamed.push(packet);
+            Ok::<_, PacketError>(())
+        })?;
         assert_eq!(
-            contents.get(channel_id1).unwrap(),
-            &vec![small_bytes.clone()]
-        );
-        assert_eq!(
-            contents.get(channel_id2).unwrap(),
-            &vec![small_bytes.clone(), small_bytes.clone()]
-        );
-        assert_eq!(
-            contents.get(channel_id3).unwrap(),
-            &vec![small_bytes.clone()]
+            streamed.iter().map(|p| &p.payload).collect::<Vec<_>>(),
+            packets.iter().map(|p| &p.payload).collect::<Vec<_>>()
         );
+
+        // an error of the sink stops the packing
+        let mut num_emitted = 0;
+        let (single_data, fragment_data) = data();
+        let result =
+            streaming_manager.build_packets_with(Tick(1), single_data, fragment_data, |_| {
+                num_emitted += 1;
+                Err(PacketError::ChannelNotFound)
+            });
+        assert!(matches!(result, Err(PacketError::ChannelNotFound)));
+        assert_eq!(num_emitted, 1);
         Ok(())
     }
 
-    /// We cannot write the channel id of the next channel in the packet, so we need to finish the current
-    /// packet and start a new one.
-    /// We have 1200 -11 (header) -1 (channel_id) - 1(num_message) = 1184 bytes per message
-    ///
-    /// Test both with different channels and same channels
+    /// The message acks buffers of the sent packets are reused by the next fragment packets
     #[test]
-    fn test_pack_cannot_write_channel_id() -> Result<(), PacketError> {
-        let channel_registry = get_channel_registry();
+    fn test_recycle_acks() -> Result<(), PacketError> {
         let mut manager = PacketBuilder::new(1.5);
-        let channel_kind1 = ChannelKind::of::<Channel1>();
-        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
-        let channel_kind2 = ChannelKind::of::<Channel2>();
-        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
-
-        let small_bytes = Bytes::from(vec![7u8; 1184]);
-        let small_message = SingleData::new(None, small_bytes.clone());
+        let fragment_sender = FragmentSender::default();
+        let fragments = |id: u16| -> Result<Vec<(ChannelId, VecDeque<FragmentData>)>, PacketError> {
+            let fragments = fragment_sender.build_fragments(
+                MessageId(id),
+                None,
+                Bytes::from(vec![1; 3 * FRAGMENT_BYTES]),
+            )?;
+            Ok(vec![(0, fragments.into())])
+        };
+        let packets = build_packets(&mut manager, Tick(0), vec![], fragments(0)?)?;
+        assert_eq!(packets.len(), 3);
+        let mut recycled: Vec<_> = packets
+            .into_iter()
+            .map(|packet| {
+                let ptr = packet.message_acks.as_ptr();
+                manager.recycle_acks(packet.message_acks);
+                ptr
+            })
+            .collect();
 
-        {
-            let single_data = vec![(
-                *channel_id1,
-                VecDeque::from(vec![small_message.clone(), small_message.clone()]),
-            )];
-            let fragment_data = vec![];
-            let packets = manager.build_packets(Tick(0), single_data, fragment_data)?;
-            assert_eq!(packets.len(), 2);
-        }
-        {
-            let single_data = vec![
-                (*channel_id1, VecDeque::from(vec![small_message.clone()])),
-                (*channel_id2, VecDeque::from(vec![small_message.clone()])),
-            ];
-            let fragment_data = vec![];
-            let packets = manager.build_packets(Tick(0), single_data, fragment_data)?;
-            assert_eq!(packets.len(), 2);
+        let packets = build_packets(&mut manager, Tick(1), vec![], fragments(1)?)?;
+        assert_eq!(packets.len(), 3);
+        // the pool is a stack: the last recycled buffer is reused first
+        recycled.reverse();
+        for (packet, ptr) in packets.iter().zip(recycled) {
+            assert_eq!(packet.message_acks.as_ptr(), ptr);
+            assert_eq!(packet.message_acks.len(), 1);
+            assert_eq!(packet.message_acks[0].1.message_id, MessageId(1));
         }
+        assert!(manager.ack_buffers.is_empty());
         Ok(())
     }
 
-    /// A bunch of small messages that all fit in the same packet
     #[test]
-    fn test_pack_many_small_messages() -> Result<(), PacketError> {
-        let channel_registry = get_channel_registry();
+    fn test_buffer_sizing() {
+        // by default the buffers can hold a full packet
         let mut manager = PacketBuilder::new(1.5);
-        let channel_kind1 = ChannelKind::of::<Channel1>();
-        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
-        let channel_kind2 = ChannelKind::of::<Channel2>();
-        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
-        let channel_kind3 = ChannelKind::of::<Channel3>();
-        let channel_id3 = channel_registry.get_net_from_kind(&channel_kind3).unwrap();
+        assert_eq!(manager.get_new_buffer().capacity(), MAX_PACKET_SIZE);
 
-        let small_bytes = Bytes::from(vec![7u8; 10]);
-        let small_message = SingleData::new(None, small_bytes.clone());
+        // a quiet connection reserves less
+        let mut manager = PacketBuilder::new(1.5).with_buffer_sizing(BufferSizing {
+            initial: 64,
+            max: 1,
+        });
+        assert_eq!(manager.get_new_buffer().capacity(), 64);
+        // the initial capacity never exceeds the mtu
+        let mut manager = manager.with_mtu(32).unwrap();
+        assert_eq!(manager.get_new_buffer().capacity(), 32);
 
-        let single_data = vec![
-            (
-                *channel_id1,
-                VecDeque::from(vec![small_message.clone(); 200]),
-            ),
-            (
-                *channel_id2,
-                VecDeque::from(vec![small_message.clone(); 200]),
-            ),
-            (
-                *channel_id3,
-                VecDeque::from(vec![small_message.clone(); 200]),
-            ),
-        ];
-        let fragment_data = vec![];
-        let packets = manager.build_packets(Tick(0), single_data, fragment_data)?;
-        assert_eq!(packets.len(), 7);
-        Ok(())
+        // only `max` buffers are kept in the pool
+        let mut manager = manager.with_mtu(MAX_PACKET_SIZE).unwrap();
+        manager.recycle_acks(Vec::with_capacity(4));
+        manager.recycle_acks(Vec::with_capacity(4));
+        assert_eq!(manager.ack_buffers.len(), 1);
     }
 
     /// A bunch of small messages that fit in multiple packets
@@ -592,7 +7370,7 @@ mod tests {
             (*channel_id3, VecDeque::from(vec![small_message.clone()])),
         ];
         let fragment_data = vec![];
-        let packets = manager.build_packets(Tick(0), single_data, fragment_data)?;
+        let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
         assert_eq!(packets.len(), 2);
         Ok(())
     }
@@ -615,7 +7393,7 @@ mod tests {
 
         let num_big_bytes = (1.5 * FRAGMENT_SIZE as f32) as usize;
         let big_bytes = Bytes::from(vec![1u8; num_big_bytes]);
-        let fragmenter = FragmentSender::new();
+        let fragmenter = FragmentSender::default();
         let fragments = fragmenter
             .build_fragments(MessageId(3), None, big_bytes.clone())
             .unwrap();
@@ -632,7 +7410,7 @@ mod tests {
             (*channel_id3, VecDeque::from(vec![small_message.clone()])),
         ];
         let fragment_data = vec![(*channel_id2, fragments.clone().into())];
-        let packets = manager.build_packets(Tick(0), single_data, fragment_data)?;
+        let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
         assert_eq!(packets.len(), 2);
 
         let mut packets_queue: VecDeque<_> = packets.into();
@@ -686,5 +7464,382 @@ mod tests {
         Ok(())
     }
 
+    /// A message split in 3 fragments, along with small messages on 2 channels.
+    ///
+    /// The 2 full fragments get their own packets, and the small messages are all packed with the last fragment.
+    #[test]
+    fn test_pack_big_messages_three_fragments() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+
+        let num_big_bytes = (2.5 * FRAGMENT_SIZE as f32) as usize;
+        let big_bytes = Bytes::from(vec![1u8; num_big_bytes]);
+        let fragments = FragmentSender::default()
+            .build_fragments(MessageId(0), None, big_bytes)
+            .unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+        let single_data = vec![
+            (*channel_id1, VecDeque::from(vec![small_message.clone()])),
+            (
+                *channel_id2,
+                VecDeque::from(vec![small_message.clone(), small_message]),
+            ),
+        ];
+        let fragment_data = vec![(*channel_id1, fragments.clone().into())];
+        let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+        assert_eq!(packets.len(), 3);
+
+        for (fragment_id, packet) in packets.into_iter().enumerate() {
+            assert_eq!(
+                packet.message_acks,
+                vec![(
+                    *channel_id1,
+                    MessageAck {
+                        message_id: MessageId(0),
+                        fragment_id: Some(fragment_id as FragmentIndex),
+                    }
+                )]
+            );
+            let contents = packet.parse_packet_payload()?;
+            if fragment_id < 2 {
+                // the full fragments leave no room for other messages
+                assert_eq!(contents.len(), 1);
+                assert_eq!(
+                    contents.get(channel_id1).unwrap(),
+                    &vec![fragments[fragment_id].bytes.clone()]
+                );
+            } else {
+                assert_eq!(
+                    contents.get(channel_id1).unwrap(),
+                    &vec![fragments[2].bytes.clone(), small_bytes.clone()]
+                );
+                assert_eq!(
+                    contents.get(channel_id2).unwrap(),
+                    &vec![small_bytes.clone(), small_bytes.clone()]
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Two big messages in the same channel, each with a small last fragment.
+    ///
+    /// We should get 3 packets: 1 for each full fragment, and 1 with the last fragments of
+    /// both messages.
+    #[test]
+    fn test_pack_big_messages_shared_fragment_packet() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let num_big_bytes = FRAGMENT_SIZE + 100;
+        let fragments_0 = FragmentSender::default()
+            .build_fragments(MessageId(0), None, Bytes::from(vec![0u8; num_big_bytes]))
+            .unwrap();
+        let fragments_1 = FragmentSender::default()
+            .build_fragments(MessageId(1), None, Bytes::from(vec![1u8; num_big_bytes]))
+            .unwrap();
+        let fragment_data = vec![(
+            0,
+            fragments_0
+                .iter()
+                .chain(fragments_1.iter())
+                .cloned()
+                .collect(),
+        )];
+        let packets = build_packets(&mut manager, Tick(0), vec![], fragment_data)?;
+        assert_eq!(packets.len(), 3);
+
+        let ack = |message_id, fragment_id| {
+            (
+                0,
+                MessageAck {
+                    message_id: MessageId(message_id),
+                    fragment_id: Some(fragment_id),
+                },
+            )
+        };
+        // the packet with the last fragments is kept open while the full fragments are written
+        assert_eq!(packets[0].message_acks, vec![ack(0, 0)]);
+        assert_eq!(packets[1].message_acks, vec![ack(1, 0)]);
+        assert_eq!(packets[2].message_acks, vec![ack(0, 1), ack(1, 1)]);
+        assert!(packets
+            .iter()
+            .all(|packet| packet.payload.len() <= MAX_PACKET_SIZE));
+
+        let contents = packets.into_iter().nth(2).unwrap().parse_packet_payload()?;
+        assert_eq!(
+            contents.get(&0).unwrap(),
+            &vec![fragments_0[1].bytes.clone(), fragments_1[1].bytes.clone()]
+        );
+        Ok(())
+    }
+
+    /// With many fragmented messages, the last fragments of all the messages share packets, and
+    /// the fragments of each message are still sent in order
+    #[test]
+    fn test_pack_many_shared_fragment_packets() -> Result<(), PacketError> {
+        let mut manager = PacketBuilder::new(1.5);
+        let fragment_sender = FragmentSender::default();
+        let mut fragments = VecDeque::new();
+        for i in 0..50 {
+            fragments.extend(fragment_sender.build_fragments(
+                MessageId(i),
+                None,
+                Bytes::from(vec![i as u8; 2 * FRAGMENT_SIZE + 5]),
+            )?);
+        }
+        let packets = build_packets(&mut manager, Tick(0), vec![], vec![(0, fragments)])?;
+        // 1 packet for each full fragment, and 1 packet for all the last fragments
+        assert_eq!(packets.len(), 101);
+        assert_eq!(packets[100].message_acks.len(), 50);
+
+        let mut last_packet = HashMap::new();
+        for (packet_idx, packet) in packets.iter().enumerate() {
+            for (_, ack) in &packet.message_acks {
+                let fragment_id = ack.fragment_id.unwrap();
+                let previous = last_packet.insert(ack.message_id, (packet_idx, fragment_id));
+                assert!(
+                    previous.map_or(fragment_id == 0, |(previous_idx, previous_id)| {
+                        previous_idx < packet_idx && previous_id + 1 == fragment_id
+                    })
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The first channel's message doesn't fit in the room left by the last fragment, but the
+    /// messages of the next channel do, so they are packed with the fragment.
+    ///
+    /// We should get 3 packets: 1 with the 1st big fragment, 1 with the small fragment and the
+    /// messages from channel 2, and 1 with the message from channel 1.
+    /// (Without packing channel 2 with the fragment, channel 2 would need a 4th packet.)
+    #[test]
+    fn test_pack_big_messages_skip_channel() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+
+        let num_big_bytes = (1.5 * FRAGMENT_SIZE as f32) as usize;
+        let big_bytes = Bytes::from(vec![1u8; num_big_bytes]);
+        let fragmenter = FragmentSender::default();
+        let fragments = fragmenter
+            .build_fragments(MessageId(3), None, big_bytes.clone())
+            .unwrap();
+
+        let medium_bytes = Bytes::from(vec![5u8; FRAGMENT_SIZE]);
+        let medium_message = SingleData::new(None, medium_bytes.clone());
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+
+        let single_data = vec![
+            (*channel_id1, VecDeque::from(vec![medium_message])),
+            (
+                *channel_id2,
+                VecDeque::from(vec![small_message.clone(), small_message]),
+            ),
+        ];
+        let fragment_data = vec![(*channel_id1, fragments.clone().into())];
+        let packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+        assert_eq!(packets.len(), 3);
+        let mut packets_queue: VecDeque<_> = packets.into();
+
+        let contents = packets_queue.pop_front().unwrap().parse_packet_payload()?;
+        assert_eq!(
+            contents.get(channel_id1).unwrap(),
+            &vec![fragments[0].bytes.clone()]
+        );
+        assert!(contents.get(channel_id2).is_none());
+
+        let contents = packets_queue.pop_front().unwrap().parse_packet_payload()?;
+        assert_eq!(
+            contents.get(channel_id1).unwrap(),
+            &vec![fragments[1].bytes.clone()]
+        );
+        assert_eq!(
+            contents.get(channel_id2).unwrap(),
+            &vec![small_bytes.clone(), small_bytes]
+        );
+
+        let contents = packets_queue.pop_front().unwrap().parse_packet_payload()?;
+        assert_eq!(contents.get(channel_id1).unwrap(), &vec![medium_bytes]);
+        assert!(contents.get(channel_id2).is_none());
+        Ok(())
+    }
+
+    /// The last fragment of a message leaves some room in the packet, which gets filled with
+    /// single messages. The packet must then track both the fragment ack and the single message ack.
+    #[test]
+    fn test_pack_fragment_with_single_message_acks() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let mut manager = PacketBuilder::new(1.5);
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+
+        let num_big_bytes = (1.5 * FRAGMENT_SIZE as f32) as usize;
+        let big_bytes = Bytes::from(vec![1u8; num_big_bytes]);
+        let fragments = FragmentSender::default()
+            .build_fragments(MessageId(3), None, big_bytes.clone())
+            .unwrap();
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(Some(MessageId(5)), small_bytes.clone());
+
+        let single_data = vec![(*channel_id1, VecDeque::from(vec![small_message]))];
+        let fragment_data = vec![(*channel_id2, fragments.clone().into())];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, fragment_data)?;
+        assert_eq!(packets.len(), 2);
+
+        // the last fragment packet contains both kinds of acks
+        let packet = packets.pop().unwrap();
+        let (fragment_acks, single_acks): (Vec<_>, Vec<_>) = packet
+            .message_acks
+            .iter()
+            .copied()
+            .partition(|(_, ack)| ack.fragment_id.is_some());
+        assert_eq!(
+            fragment_acks,
+            vec![(
+                *channel_id2,
+                MessageAck {
+                    message_id: MessageId(3),
+                    fragment_id: Some(1),
+                }
+            )]
+        );
+        assert_eq!(
+            single_acks,
+            vec![(
+                *channel_id1,
+                MessageAck {
+                    message_id: MessageId(5),
+                    fragment_id: None,
+                }
+            )]
+        );
+
+        let contents = packet.parse_packet_payload()?;
+        assert_eq!(
+            contents.get(channel_id2).unwrap(),
+            &vec![fragments[1].bytes.clone()]
+        );
+        assert_eq!(contents.get(channel_id1).unwrap(), &vec![small_bytes]);
+        Ok(())
+    }
+
+    /// A channel with a byte cap only writes up to the cap; the remaining messages
+    /// are deferred to the next call
+    #[test]
+    fn test_pack_channel_byte_cap() -> Result<(), PacketError> {
+        let channel_registry = get_channel_registry();
+        let channel_kind1 = ChannelKind::of::<Channel1>();
+        let channel_id1 = channel_registry.get_net_from_kind(&channel_kind1).unwrap();
+        let channel_kind2 = ChannelKind::of::<Channel2>();
+        let channel_id2 = channel_registry.get_net_from_kind(&channel_kind2).unwrap();
+        let mut manager = PacketBuilder::new(1.5).with_channel_byte_cap(*channel_id1, 200);
+
+        // each message takes 52 bytes (1 for the id, 1 for the length, 50 for the data)
+        let message = SingleData::new(None, Bytes::from(vec![7u8; 50]));
+        let single_data = vec![
+            (*channel_id1, VecDeque::from(vec![message.clone(); 10])),
+            (*channel_id2, VecDeque::from(vec![message.clone(); 10])),
+        ];
+        let (packets, deferred) = manager.build_capped_packets(Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        // we stop pulling messages before going over 200 bytes
+        assert_eq!(contents.get(channel_id1).unwrap().len(), 3);
+        // the uncapped channel sends all of its messages
+        assert_eq!(contents.get(channel_id2).unwrap().len(), 10);
+        assert_eq!(deferred, vec![(*channel_id1, 7)]);
+
+        // the deferred messages are sent on the next calls, still respecting the cap
+        for (tick, num_deferred) in [(1, 4), (2, 1)] {
+            let (packets, deferred) = manager.build_capped_packets(Tick(tick), vec![], vec![])?;
+            let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+            assert_eq!(contents.get(channel_id1).unwrap().len(), 3);
+            assert_eq!(deferred, vec![(*channel_id1, num_deferred)]);
+        }
+        let (packets, deferred) = manager.build_capped_packets(Tick(3), vec![], vec![])?;
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        assert_eq!(contents.get(channel_id1).unwrap().len(), 1);
+        assert!(deferred.is_empty());
+        assert!(!manager.has_deferred_messages());
+
+        // a message bigger than the cap is still sent, on its own
+        let big_message = SingleData::new(None, Bytes::from(vec![7u8; 300]));
+        let single_data = vec![(
+            *channel_id1,
+            VecDeque::from(vec![big_message, message.clone()]),
+        )];
+        let (packets, deferred) = manager.build_capped_packets(Tick(4), single_data, vec![])?;
+        let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+        assert_eq!(contents.get(channel_id1).unwrap().len(), 1);
+        assert_eq!(deferred, vec![(*channel_id1, 1)]);
+        Ok(())
+    }
+
+    /// The deferred messages stay in the builder: when the reliable sender resends them before
+    /// they could be written, the resends are dropped instead of being queued behind the cap a
+    /// second time
+    #[test]
+    fn test_pack_channel_byte_cap_resends() -> Result<(), PacketError> {
+        let channel_id = 0;
+        let mut manager = PacketBuilder::new(1.5).with_channel_byte_cap(channel_id, 200);
+        // each message takes 52 bytes (1 for the id, 1 for the length, 50 for the data)
+        let messages = (0..10)
+            .map(|i| SingleData::new(Some(MessageId(i)), Bytes::from(vec![7u8; 50])))
+            .collect::<VecDeque<_>>();
+        let num_written = |packets: Vec<Packet>| -> Result<usize, PacketError> {
+            let contents = packets.into_iter().next().unwrap().parse_packet_payload()?;
+            Ok(contents[&channel_id].len())
+        };
+
+        let (packets, deferred) =
+            manager.build_capped_packets(Tick(0), vec![(channel_id, messages.clone())], vec![])?;
+        assert_eq!(num_written(packets)?, 3);
+        assert_eq!(deferred, vec![(channel_id, 7)]);
+
+        // the resend delay elapses before any ack: every message is resent, including the ones
+        // that the builder still holds
+        let (packets, deferred) =
+            manager.build_capped_packets(Tick(1), vec![(channel_id, messages)], vec![])?;
+        assert_eq!(num_written(packets)?, 3);
+        // only the resends of the 3 written messages are added behind the deferred messages
+        assert_eq!(deferred, vec![(channel_id, 7)]);
+        Ok(())
+    }
+
+    /// A large channel id that is in the channel dictionary is written as a 1-byte token
+    #[test]
+    fn test_pack_channel_dictionary() -> Result<(), PacketError> {
+        let channel_dictionary = ChannelIdDictionary::new([300]);
+        let mut manager =
+            PacketBuilder::new(1.5).with_channel_dictionary(channel_dictionary.clone());
+
+        let small_bytes = Bytes::from(vec![7u8; 10]);
+        let small_message = SingleData::new(None, small_bytes.clone());
+        let single_data = vec![(300, VecDeque::from(vec![small_message.clone()]))];
+        let mut packets = build_packets(&mut manager, Tick(0), single_data, vec![])?;
+        assert_eq!(packets.len(), 1);
+        let packet = packets.pop().unwrap();
+        // header + 1 byte for the channel token + 1 byte for the number of messages + message
+        assert_eq!(packet.payload.len(), 11 + 1 + 1 + small_message.len());
+        assert_eq!(packet.payload[11], 0);
+
+        let contents = packet.parse_packet_payload_with_dictionary(&channel_dictionary)?;
+        assert_eq!(contents.get(&300).unwrap(), &vec![small_bytes]);
+        Ok(())
+    }
+
     // TODO: ADD MORE TESTS
 }
@@ -0,0 +1,176 @@
+//! Parse many packets in a row, reusing the allocations of the previous parses
+use bevy::utils::HashMap;
+use bytes::Bytes;
+
+use crate::packet::channel_dictionary::ChannelIdDictionary;
+use crate::packet::encryption::{ChannelDecryption, DecryptFn};
+use crate::packet::packet::{CompressionDictionary, Packet, DEFAULT_MAX_MESSAGES_PER_PACKET};
+use crate::protocol::channel::ChannelId;
+use crate::serialize::SerializationError;
+
+/// Parses packets into a map from channel to the messages (or fragments) of that channel.
+///
+/// The messages are slices of the packet payload, so they are never copied. The map passed to
+/// [`Self::parse_into`] is cleared and reused, and the parser keeps the per-channel buffers of
+/// the previous parses around, so that parsing packets in a loop does not allocate once the
+/// buffers are large enough.
+#[derive(Debug)]
+pub(crate) struct PacketParser {
+    channel_dictionary: ChannelIdDictionary,
+    max_messages: usize,
+    /// Decrypts the sections of the encrypted channels (see [`Self::with_decryption`])
+    decryption: Option<ChannelDecryption>,
+    /// Dictionary of the packets that were compressed with one
+    compression_dictionary: Option<CompressionDictionary>,
+    /// Empty buffers, kept from the previous parses, to hold the messages of a channel
+    spare: Vec<Vec<Bytes>>,
+}
+
+impl PacketParser {
+    /// Create a parser for packets that were written with `channel_dictionary`
+    pub(crate) fn new(channel_dictionary: ChannelIdDictionary) -> Self {
+        Self {
+            channel_dictionary,
+            max_messages: DEFAULT_MAX_MESSAGES_PER_PACKET,
+            decryption: None,
+            compression_dictionary: None,
+            spare: Vec::new(),
+        }
+    }
+
+    /// Reject packets that contain more than `max_messages` messages
+    pub(crate) fn with_max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// Decrypt the sections of the encrypted channels with `decrypt`, which is called with the
+    /// block of messages of a channel, decrypts it in place and returns false if the block could
+    /// not be decrypted. Without it, packets that contain encrypted channels are rejected.
+    pub(crate) fn with_decryption(
+        mut self,
+        decrypt: impl FnMut(ChannelId, &mut Vec<u8>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.decryption = Some(ChannelDecryption(Box::new(decrypt) as DecryptFn));
+        self
+    }
+
+    /// Decompress the packets that were compressed with `compression_dictionary`
+    pub(crate) fn with_compression_dictionary(
+        mut self,
+        compression_dictionary: CompressionDictionary,
+    ) -> Self {
+        self.compression_dictionary = Some(compression_dictionary);
+        self
+    }
+
+    /// Parse the messages of `packet` into `out`, replacing the previous contents of `out`.
+    ///
+    /// Channels that have no message in `packet` are not present in `out`. If the packet is
+    /// invalid, `out` only contains the messages read before the error.
+    pub(crate) fn parse_into(
+        &mut self,
+        packet: Packet,
+        out: &mut HashMap<ChannelId, Vec<Bytes>>,
+    ) -> Result<(), SerializationError> {
+        // keep the buffers of the previous parse, the map itself keeps its capacity
+        self.spare.extend(out.drain().map(|(_, mut messages)| {
+            messages.clear();
+            messages
+        }));
+        let spare = &mut self.spare;
+        packet.read_payload(
+            &self.channel_dictionary,
+            self.max_messages,
+            self.decryption.as_mut(),
+            self.compression_dictionary.as_ref(),
+            |channel_id, bytes| {
+                out.entry(channel_id)
+                    .or_insert_with(|| spare.pop().unwrap_or_default())
+                    .push(bytes);
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::packet::message::SingleData;
+    use crate::packet::packet_builder::PacketBuilder;
+    use crate::shared::tick_manager::Tick;
+
+    use super::*;
+
+    fn build_packet(builder: &mut PacketBuilder, tick: Tick, channels: &[ChannelId]) -> Packet {
+        let single_data = channels
+            .iter()
+            .map(|&channel_id| {
+                let messages = (0..4)
+                    .map(|i| SingleData::new(None, Bytes::from(vec![channel_id as u8; 10 + i])))
+                    .collect::<VecDeque<_>>();
+                (channel_id, messages)
+            })
+            .collect();
+        let mut packets = builder.build_packets(tick, single_data, vec![]).unwrap();
+        assert_eq!(packets.len(), 1);
+        packets.pop().unwrap()
+    }
+
+    #[test]
+    fn test_parse_into_reuses_allocations() -> Result<(), SerializationError> {
+        let mut builder = PacketBuilder::new(1.5);
+        let mut parser = PacketParser::new(ChannelIdDictionary::default());
+        let mut out = HashMap::default();
+
+        parser.parse_into(build_packet(&mut builder, Tick(0), &[0, 1]), &mut out)?;
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[&0].len(), 4);
+        assert_eq!(out[&1][3], Bytes::from(vec![1; 13]));
+        let buffers: Vec<*const Bytes> = out.values().map(|messages| messages.as_ptr()).collect();
+        let map_capacity = out.capacity();
+
+        // the same channels: every buffer is reused, nothing is allocated
+        for tick in 1..10 {
+            parser.parse_into(build_packet(&mut builder, Tick(tick), &[1, 0]), &mut out)?;
+            assert_eq!(out.len(), 2);
+            assert_eq!(out[&0][0], Bytes::from(vec![0; 10]));
+            assert_eq!(out.capacity(), map_capacity);
+            for messages in out.values() {
+                assert_eq!(messages.len(), 4);
+                assert!(buffers.contains(&messages.as_ptr()));
+            }
+        }
+
+        // a different channel gets the buffer of a channel that is not in the packet anymore
+        parser.parse_into(build_packet(&mut builder, Tick(10), &[2]), &mut out)?;
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[&2].len(), 4);
+        assert!(buffers.contains(&out[&2].as_ptr()));
+        assert_eq!(parser.spare.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_into_matches_parse_packet_payload() -> Result<(), SerializationError> {
+        let mut builder = PacketBuilder::new(1.5);
+        let mut parser = PacketParser::new(ChannelIdDictionary::default()).with_max_messages(4);
+        let mut out = HashMap::default();
+
+        // two builders in the same state write the same packet
+        let expected = build_packet(&mut PacketBuilder::new(1.5), Tick(0), &[0])
+            .parse_packet_payload()
+            .unwrap();
+        parser.parse_into(build_packet(&mut builder, Tick(0), &[0]), &mut out)?;
+        assert_eq!(out, expected);
+
+        // more messages than allowed
+        let packet = build_packet(&mut builder, Tick(1), &[0, 1]);
+        assert!(matches!(
+            parser.parse_into(packet, &mut out),
+            Err(SerializationError::TooManyMessages { .. })
+        ));
+        Ok(())
+    }
+}
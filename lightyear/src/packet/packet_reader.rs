@@ -0,0 +1,219 @@
+//! Read the single messages of a packet as slices of the packet payload, without copying them
+use byteorder::{NetworkEndian, ReadBytesExt};
+
+use crate::packet::channel_dictionary::ChannelIdDictionary;
+use crate::packet::message::{FIXED_LENGTH_FLAG, MESSAGE_ID_FLAG, SUB_CHANNEL_FLAG};
+use crate::packet::packet::{Packet, MIN_SINGLE_DATA_BYTES};
+use crate::packet::packet_type::PacketType;
+use crate::protocol::channel::ChannelId;
+use crate::serialize::varint::read_varint;
+use crate::serialize::{SerializationError, ToBytes};
+use crate::shared::tick_manager::Tick;
+
+/// Iterator over the messages of a [`PacketType::Data`] packet, that yields each message as a
+/// slice of the payload; the caller decides if the message needs to be copied.
+///
+/// The framing is validated while iterating: the first error is returned and ends the iteration.
+#[derive(Debug)]
+pub(crate) struct PacketReader<'a> {
+    /// Part of the payload that has not been read yet
+    remaining: &'a [u8],
+    channel_dictionary: &'a ChannelIdDictionary,
+    /// Channel of the messages being read, and how many of its messages are left to read
+    current_channel: Option<(ChannelId, u64)>,
+    /// Length of every message of the current channel, if they were written as a block of
+    /// messages of the same length
+    block_len: Option<u64>,
+    /// Tick written in the header of the packet
+    tick: Tick,
+}
+
+impl<'a> PacketReader<'a> {
+    /// Start reading a packet. `payload` must not include the checksum.
+    ///
+    /// Only uncompressed [`PacketType::Data`] packets can be read this way.
+    pub(crate) fn new(
+        payload: &'a [u8],
+        channel_dictionary: &'a ChannelIdDictionary,
+    ) -> Result<Self, SerializationError> {
+        let header = Packet::peek_header(payload)?;
+        if header.compressed || header.get_packet_type() != PacketType::Data {
+            return Err(SerializationError::InvalidPacketType);
+        }
+        Ok(Self::from_channel_sections(
+            &payload[header.len()..],
+            channel_dictionary,
+            header.tick,
+        ))
+    }
+
+    /// Read the channel sections of a packet (everything after the header, or after the
+    /// fragments of a [`PacketType::DataFragment`] packet), that was sent at `tick`
+    pub(crate) fn from_channel_sections(
+        sections: &'a [u8],
+        channel_dictionary: &'a ChannelIdDictionary,
+        tick: Tick,
+    ) -> Self {
+        Self {
+            remaining: sections,
+            channel_dictionary,
+            current_channel: None,
+            block_len: None,
+            tick,
+        }
+    }
+
+    /// Read the messages of a channel block returned by [`ChannelBlocks`]
+    pub(crate) fn from_channel_block(
+        channel_id: ChannelId,
+        num_messages: usize,
+        block: &'a [u8],
+        channel_dictionary: &'a ChannelIdDictionary,
+        tick: Tick,
+    ) -> Self {
+        Self {
+            remaining: block,
+            channel_dictionary,
+            current_channel: Some((channel_id, num_messages as u64)),
+            block_len: None,
+            tick,
+        }
+    }
+
+    /// Iterate over the channel blocks of the rest of the packet instead of its messages
+    pub(crate) fn channel_blocks(self) -> ChannelBlocks<'a> {
+        ChannelBlocks { reader: self }
+    }
+
+    /// Tick at which the remote peer sent the packet. Every message of the packet was sent at
+    /// this tick, so the receiver can compute the transit delay as `current_tick - tick`.
+    pub(crate) fn tick(&self) -> Tick {
+        self.tick
+    }
+
+    /// Yield each message along with the tick of the packet (see [`Self::tick`])
+    pub(crate) fn with_tick(
+        self,
+    ) -> impl Iterator<Item = Result<(ChannelId, Tick, &'a [u8]), SerializationError>> {
+        let tick = self.tick;
+        self.map(move |message| message.map(|(channel_id, bytes)| (channel_id, tick, bytes)))
+    }
+
+    fn read_message(&mut self) -> Result<Option<(ChannelId, &'a [u8])>, SerializationError> {
+        let (channel_id, num_messages) = match self.current_channel {
+            Some((channel_id, num_messages)) if num_messages > 0 => (channel_id, num_messages),
+            _ => {
+                if self.remaining.is_empty() {
+                    return Ok(None);
+                }
+                let channel_id = self.channel_dictionary.read(&mut self.remaining)?;
+                let count = read_varint(&mut self.remaining)?;
+                let remaining = self.remaining.len();
+                if count == 0 {
+                    return Err(SerializationError::InvalidValue);
+                }
+                if count > (remaining / MIN_SINGLE_DATA_BYTES) as u64 {
+                    return Err(SerializationError::CountExceedsBuffer { count, remaining });
+                }
+                self.block_len = None;
+                (channel_id, count)
+            }
+        };
+        // the message id and the sub-channel are not needed to read the message
+        let flags = self.remaining.read_u8()?;
+        if flags & !(MESSAGE_ID_FLAG | SUB_CHANNEL_FLAG | FIXED_LENGTH_FLAG) != 0 {
+            return Err(SerializationError::InvalidValue);
+        }
+        if flags & MESSAGE_ID_FLAG != 0 {
+            self.remaining.read_u16::<NetworkEndian>()?;
+        }
+        if flags & SUB_CHANNEL_FLAG != 0 {
+            self.remaining.read_u8()?;
+        }
+        let len = match self.block_len {
+            Some(_) if flags & FIXED_LENGTH_FLAG != 0 => {
+                return Err(SerializationError::InvalidValue)
+            }
+            Some(len) => len,
+            None => {
+                let len = read_varint(&mut self.remaining)?;
+                if flags & FIXED_LENGTH_FLAG != 0 {
+                    self.block_len = Some(len);
+                }
+                len
+            }
+        };
+        let remaining = self.remaining.len();
+        if len > remaining as u64 {
+            return Err(SerializationError::TruncatedMessage {
+                len: len as usize,
+                remaining,
+            });
+        }
+        let (message, rest) = self.remaining.split_at(len as usize);
+        self.remaining = rest;
+        self.current_channel = Some((channel_id, num_messages - 1));
+        Ok(Some((channel_id, message)))
+    }
+}
+
+impl<'a> Iterator for PacketReader<'a> {
+    type Item = Result<(ChannelId, &'a [u8]), SerializationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_message() {
+            Ok(message) => message.map(Ok),
+            Err(e) => {
+                // stop at the first error
+                self.remaining = &[];
+                self.current_channel = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterator over the channel blocks of a packet: the channel id, the number of messages and the
+/// bytes of the messages, so that the messages of a channel are only read if the caller needs
+/// them (with [`PacketReader::from_channel_block`]).
+///
+/// The framing of the messages is still validated, to find where each block ends.
+#[derive(Debug)]
+pub(crate) struct ChannelBlocks<'a> {
+    reader: PacketReader<'a>,
+}
+
+impl<'a> ChannelBlocks<'a> {
+    fn read_block(&mut self) -> Result<Option<(ChannelId, usize, &'a [u8])>, SerializationError> {
+        let mut block = self.reader.remaining;
+        if block.is_empty() {
+            return Ok(None);
+        }
+        let channel_id = self.reader.channel_dictionary.read(&mut block)?;
+        let num_messages = read_varint(&mut block)?;
+        // read the messages of the channel to find the end of the block
+        loop {
+            self.reader.read_message()?;
+            if matches!(self.reader.current_channel, Some((_, 0))) {
+                break;
+            }
+        }
+        let block = &block[..block.len() - self.reader.remaining.len()];
+        Ok(Some((channel_id, num_messages as usize, block)))
+    }
+}
+
+impl<'a> Iterator for ChannelBlocks<'a> {
+    type Item = Result<(ChannelId, usize, &'a [u8]), SerializationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_block() {
+            Ok(block) => block.map(Ok),
+            Err(e) => {
+                // stop at the first error
+                self.reader.remaining = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
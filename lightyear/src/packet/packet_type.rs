@@ -1,4 +1,49 @@
-#[repr(u8)]
+//! Type of a packet, and layout of the first byte of the packet header.
+//!
+//! The first byte of every header holds the packet type along with some flags:
+//!
+//! | bits | meaning                                                                         |
+//! |------|---------------------------------------------------------------------------------|
+//! | 0-2  | packet type: 0 to 6 (see [`PacketType`]), or 7 if the type is extended           |
+//! | 3    | the messages were sorted ([`SORTED_PACKET_FLAG`], data packets only)             |
+//! | 4    | compressed with a dictionary ([`DICTIONARY_PACKET_FLAG`], compressed only)      |
+//! | 5-6  | width of the ack bitfield, or compact header ([`ACK_WINDOW_MASK`])              |
+//! | 7    | the rest of the packet is compressed ([`COMPRESSED_PACKET_FLAG`])               |
+//!
+//! The type bits never overlap the flags. An extended type ([`EXTENDED_PACKET_TYPE`]) is written
+//! in a byte after the tick: it holds the values from 7 to 127 (unknown types, for types added in
+//! newer versions) and the [`PacketType::User`] types. A flag is ignored by the packet types it
+//! does not apply to.
+
+/// Bits of the first header byte that hold the packet type
+pub(crate) const PACKET_TYPE_MASK: u8 = 0b0000_0111;
+
+/// Value of the packet type bits when the packet type is written in a byte after the tick,
+/// for the packet types that don't fit in the packet type bits
+pub(crate) const EXTENDED_PACKET_TYPE: u8 = 0b0000_0111;
+
+/// Bit of the first header byte that is set when the messages of a data packet are not in the
+/// order in which they were sent, because the packer sorted them by size. It is only read for
+/// the [`PacketType::Data`] and [`PacketType::DataFragment`] packets.
+pub(crate) const SORTED_PACKET_FLAG: u8 = 0b0000_1000;
+
+/// Bit of the first header byte that is set when the rest of the packet was compressed with a
+/// [`CompressionDictionary`](crate::packet::packet::CompressionDictionary), whose id is written
+/// at the end of the header. It is only read when the packet is compressed.
+pub(crate) const DICTIONARY_PACKET_FLAG: u8 = 0b0001_0000;
+
+/// Bits of the first header byte that encode the width of the ack bitfield:
+/// 0 for 32 bits, 1 for 64 bits, 2 for 128 bits, 3 for a compact header
+pub(crate) const ACK_WINDOW_MASK: u8 = 0b0110_0000;
+pub(crate) const ACK_WINDOW_SHIFT: u8 = 5;
+
+/// Bit of the first header byte that is set when the rest of the packet (after the header) is
+/// compressed
+pub(crate) const COMPRESSED_PACKET_FLAG: u8 = 0b1000_0000;
+
+/// First value of the range of packet types (`128..=255`) reserved for [`PacketType::User`]
+pub const FIRST_USER_PACKET_TYPE: u8 = 128;
+
 #[derive(Copy, Debug, Clone, Eq, PartialEq)]
 pub enum PacketType {
     /// A packet containing actual data
@@ -12,25 +57,52 @@ pub enum PacketType {
     /// - channel_id_2
     /// - num messages
     /// - ...
-    /// - channel_id = 0 = indication of end of packet
-    Data = 0,
-    DataFragment = 1,
+    ///
+    /// There is no end-of-packet marker: the channels are read until the end of the packet
+    /// (before the checksum, if any), so channel id 0 is a regular channel.
+    Data,
+    DataFragment,
+    /// A packet that only contains a header, sent to carry acks that did not fit in the headers
+    /// of the data packets. The receiver doesn't ack it back.
+    Ack,
+    /// A packet sent when the connection is established, that contains the sender's
+    /// [`ChannelSchema`](crate::protocol::channel::ChannelSchema)
+    Handshake,
+    /// A packet that only contains a header, sent to keep an idle connection alive.
+    /// It carries the acks of the header, but no messages.
+    KeepAlive,
+    /// A packet type that this version does not know about (for example one that was added in
+    /// a newer version), from 5 to 127. The rest of the packet is not read.
+    Unknown(u8),
+    /// A packet type defined by the transport or the app (for example a disconnect packet), in
+    /// the range starting at [`FIRST_USER_PACKET_TYPE`]. It goes through the same framing and
+    /// acks as the other packets, but its payload is not read as messages.
+    User(u8),
 }
 
 impl From<PacketType> for u8 {
     fn from(packet_type: PacketType) -> u8 {
-        packet_type as u8
+        match packet_type {
+            PacketType::Data => 0,
+            PacketType::DataFragment => 1,
+            PacketType::Ack => 2,
+            PacketType::Handshake => 3,
+            PacketType::KeepAlive => 4,
+            PacketType::Unknown(value) | PacketType::User(value) => value,
+        }
     }
 }
 
-impl TryFrom<u8> for PacketType {
-    type Error = crate::serialize::SerializationError;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+impl From<u8> for PacketType {
+    fn from(value: u8) -> Self {
         match value {
-            0 => Ok(PacketType::Data),
-            1 => Ok(PacketType::DataFragment),
-            _ => Err(crate::serialize::SerializationError::InvalidPacketType),
+            0 => PacketType::Data,
+            1 => PacketType::DataFragment,
+            2 => PacketType::Ack,
+            3 => PacketType::Handshake,
+            4 => PacketType::KeepAlive,
+            FIRST_USER_PACKET_TYPE..=u8::MAX => PacketType::User(value),
+            _ => PacketType::Unknown(value),
         }
     }
 }
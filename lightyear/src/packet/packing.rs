@@ -0,0 +1,144 @@
+//! Size accounting used to pack messages into a packet.
+//!
+//! This module only depends on `core`: the [`Packet`](crate::packet::packet::Packet) written by the
+//! [`PacketBuilder`](crate::packet::packet_builder::PacketBuilder) uses it with a `Vec<u8>`, but the
+//! same arithmetic works with any buffer implementing [`PacketSink`] (for example a fixed-size
+//! array on targets without a global allocator).
+
+/// Buffer that the bytes of a packet are written into
+pub trait PacketSink {
+    /// Number of bytes already written in the buffer
+    fn written_len(&self) -> usize;
+
+    /// Append `bytes` to the buffer.
+    ///
+    /// Returns false, without writing anything, if the buffer cannot hold them.
+    fn write_bytes(&mut self, bytes: &[u8]) -> bool;
+}
+
+/// Space left in a packet of at most `capacity` bytes, once the bytes already written in the
+/// [`PacketSink`] and the bytes that are `reserved` (but not written yet) are accounted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackingBudget {
+    /// Maximum number of bytes in the packet
+    pub capacity: usize,
+    /// Number of bytes that we know we are going to write, but haven't written yet
+    pub reserved: usize,
+}
+
+impl PackingBudget {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            reserved: 0,
+        }
+    }
+
+    /// Check that `size` more bytes fit in the packet
+    pub fn can_fit<S: PacketSink + ?Sized>(&self, sink: &S, size: usize) -> bool {
+        sink.written_len() + size + self.reserved <= self.capacity
+    }
+
+    /// Number of bytes that can still be written once the reserved bytes are written
+    pub fn remaining_bytes<S: PacketSink + ?Sized>(&self, sink: &S) -> usize {
+        self.capacity
+            .saturating_sub(sink.written_len() + self.reserved)
+    }
+
+    /// Reserve `size` bytes if they fit in the packet
+    pub fn reserve<S: PacketSink + ?Sized>(&mut self, sink: &S, size: usize) -> bool {
+        let can_fit = self.can_fit(sink, size);
+        if can_fit {
+            self.reserved += size;
+        }
+        can_fit
+    }
+
+    /// Check if we can write a channel id (that takes `channel_id_len` bytes) and the number of
+    /// messages of the channel. If we can, reserve the space for them
+    pub fn can_fit_channel<S: PacketSink + ?Sized>(
+        &mut self,
+        sink: &S,
+        channel_id_len: usize,
+    ) -> bool {
+        // 1 byte for the number of messages
+        self.reserve(sink, channel_id_len + 1)
+    }
+
+    /// Write `bytes` that were reserved earlier, releasing their reservation.
+    ///
+    /// Returns false, without writing anything, if the sink cannot hold them.
+    pub fn write_reserved<S: PacketSink + ?Sized>(&mut self, sink: &mut S, bytes: &[u8]) -> bool {
+        debug_assert!(bytes.len() <= self.reserved);
+        if !sink.write_bytes(bytes) {
+            return false;
+        }
+        self.reserved = self.reserved.saturating_sub(bytes.len());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sink writing into a fixed-size array, without allocating
+    struct ArraySink<const N: usize> {
+        buffer: [u8; N],
+        len: usize,
+    }
+
+    impl<const N: usize> PacketSink for ArraySink<N> {
+        fn written_len(&self) -> usize {
+            self.len
+        }
+
+        fn write_bytes(&mut self, bytes: &[u8]) -> bool {
+            let Some(end) = self.len.checked_add(bytes.len()).filter(|end| *end <= N) else {
+                return false;
+            };
+            self.buffer[self.len..end].copy_from_slice(bytes);
+            self.len = end;
+            true
+        }
+    }
+
+    /// Reserve a channel then as many 10-byte messages as possible, and write them
+    fn pack<S: PacketSink>(sink: &mut S, capacity: usize) -> usize {
+        let mut budget = PackingBudget::new(capacity);
+        let channel = [0u8, 0];
+        assert!(budget.can_fit_channel(sink, 1));
+        let message = [7u8; 10];
+        let mut num_messages = 0;
+        while budget.reserve(sink, message.len()) {
+            num_messages += 1;
+        }
+        assert!(budget.write_reserved(sink, &channel));
+        for _ in 0..num_messages {
+            assert!(budget.write_reserved(sink, &message));
+        }
+        assert_eq!(budget.reserved, 0);
+        assert_eq!(budget.remaining_bytes(sink), capacity - sink.written_len());
+        num_messages
+    }
+
+    #[test]
+    fn test_pack_fixed_size_sink() {
+        let mut sink = ArraySink::<64> {
+            buffer: [0; 64],
+            len: 0,
+        };
+        let num_messages = pack(&mut sink, 64);
+        assert_eq!(num_messages, 6);
+        assert_eq!(sink.written_len(), 62);
+        assert_eq!(&sink.buffer[2..12], &[7u8; 10]);
+        // the array is never written past its end
+        assert!(!sink.write_bytes(&[0; 3]));
+        assert_eq!(sink.written_len(), 62);
+
+        // the Vec sink packs the same way
+        let mut vec_sink = Vec::new();
+        assert_eq!(pack(&mut vec_sink, 64), num_messages);
+        assert_eq!(vec_sink.as_slice(), &sink.buffer[..sink.len]);
+    }
+}
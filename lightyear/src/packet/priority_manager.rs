@@ -23,6 +23,58 @@ pub struct BufferedMessage {
     data: MessageData,
 }
 
+/// Reason why a message was dropped instead of being sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DropReason {
+    /// The bandwidth quota was reached for this tick
+    BandwidthQuota,
+    /// The message is bigger than the maximum burst allowed by the bandwidth quota,
+    /// so it can never be sent
+    TooBigForBandwidthQuota,
+    /// The reliable message was not acked before its
+    /// [`ticks_to_live`](crate::packet::message::SingleData::ticks_to_live) elapsed
+    Expired,
+    /// The reliable message was still not acked after
+    /// [`ReliableSettings::max_retries`](crate::channel::builder::ReliableSettings::max_retries)
+    /// resends
+    MaxRetries,
+}
+
+/// List of the messages that were dropped during a send, along with the reason why
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DropReport {
+    pub dropped: Vec<(ChannelId, Option<MessageId>, DropReason)>,
+}
+
+impl DropReport {
+    pub fn is_empty(&self) -> bool {
+        self.dropped.is_empty()
+    }
+
+    fn add(&mut self, buffered_message: &BufferedMessage, reason: DropReason) {
+        self.dropped.push((
+            buffered_message.channel_net_id,
+            buffered_message.data.message_id(),
+            reason,
+        ));
+    }
+
+    /// Add the messages that a channel dropped by itself (see
+    /// [`ChannelSend::take_dropped_messages`](crate::channel::senders::ChannelSend::take_dropped_messages))
+    pub(crate) fn add_channel_drops(
+        &mut self,
+        channel_id: ChannelId,
+        drops: impl IntoIterator<Item = (MessageId, DropReason)>,
+    ) {
+        self.dropped.extend(
+            drops
+                .into_iter()
+                .map(|(message_id, reason)| (channel_id, Some(message_id), reason)),
+        );
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PriorityConfig {
     /// Number of bytes per second that can be sent to each client
@@ -93,16 +145,60 @@ impl PriorityManager {
         receiver
     }
 
+    /// Add a message that passed the priority filter to the list of messages to send
+    fn add_message(
+        &self,
+        buffered_message: BufferedMessage,
+        channel_registry: &ChannelRegistry,
+        single_data: &mut HashMap<ChannelId, VecDeque<SingleData>>,
+        fragment_data: &mut HashMap<ChannelId, VecDeque<FragmentData>>,
+    ) {
+        // notify the replication sender that the message was actually sent
+        if channel_registry.is_replication_update_channel(buffered_message.channel_net_id) {
+            // SAFETY: we are guaranteed in this situation to have a message id (because we use the unreliable with acks sender)
+            let message_id = buffered_message.data.message_id().unwrap();
+            for sender in self.replication_update_senders.iter() {
+                trace!(
+                    ?message_id,
+                    "notifying replication sender that a message was actually sent."
+                );
+                let _ = sender.send(message_id).map_err(|e| {
+                    error!(
+                        "error notifying replication sender that a message was actually sent: {:?}",
+                        e
+                    )
+                });
+            }
+        }
+
+        // the message is allowed, add it to the list of messages to send
+        match buffered_message.data {
+            MessageData::Single(single) => {
+                single_data
+                    .entry(buffered_message.channel_net_id)
+                    .or_default()
+                    .push_back(single);
+            }
+            MessageData::Fragment(fragment) => {
+                fragment_data
+                    .entry(buffered_message.channel_net_id)
+                    .or_default()
+                    .push_back(fragment);
+            }
+        }
+    }
+
     // TODO: maybe accumulate the used_bytes in the priority_manager instead of returning here?
     /// Filter the messages by priority and bandwidth quota
     /// Returns the list of messages that we can send, along with the amount of bytes we used
-    /// in the rate limiter.
+    /// in the rate limiter. The messages that are not sent are added to `drop_report`.
     #[cfg_attr(feature = "trace", instrument(level = Level::INFO, skip_all))]
     pub(crate) fn priority_filter(
         &mut self,
         data: Vec<(ChannelId, (VecDeque<SendMessage>, VecDeque<SendMessage>))>,
         channel_registry: &ChannelRegistry,
         tick: Tick,
+        drop_report: &mut DropReport,
     ) -> (
         Vec<(ChannelId, VecDeque<SingleData>)>,
         Vec<(ChannelId, VecDeque<FragmentData>)>,
@@ -178,10 +274,49 @@ impl PriorityManager {
             all_messages
         );
 
-        // select the top messages with the rate limiter
         let mut single_data: HashMap<ChannelId, VecDeque<SingleData>> = HashMap::new();
         let mut fragment_data: HashMap<ChannelId, VecDeque<FragmentData>> = HashMap::new();
         let mut bytes_used = 0;
+
+        // first send the messages that fit in the minimum share of bandwidth reserved for each channel
+        let mut floor_bytes: HashMap<ChannelId, usize> = channel_registry
+            .settings()
+            .filter_map(|(net_id, settings)| Some((net_id, settings.min_bytes_per_tick?)))
+            .collect();
+        if !floor_bytes.is_empty() {
+            let (floor_messages, other_messages): (Vec<_>, Vec<_>) = all_messages
+                .into_iter()
+                .rev()
+                .partition(|buffered_message| {
+                    let Some(floor) = floor_bytes.get_mut(&buffered_message.channel_net_id) else {
+                        return false;
+                    };
+                    let Some(remaining) = floor.checked_sub(buffered_message.data.len()) else {
+                        return false;
+                    };
+                    *floor = remaining;
+                    true
+                });
+            all_messages = other_messages;
+            all_messages.reverse();
+            for buffered_message in floor_messages {
+                // the reserved bytes are also taken from the bandwidth quota, if there is some left
+                let message_bytes = buffered_message.data.len() as u32;
+                if let Some(message_bytes) = NonZeroU32::new(message_bytes) {
+                    let _ = self.limiter.check_n(message_bytes);
+                }
+                trace!(channel=?buffered_message.channel_net_id, "Sending message in the channel's reserved bandwidth");
+                bytes_used += message_bytes;
+                self.add_message(
+                    buffered_message,
+                    channel_registry,
+                    &mut single_data,
+                    &mut fragment_data,
+                );
+            }
+        }
+
+        // select the top messages with the rate limiter
         while let Some(buffered_message) = all_messages.pop() {
             // we don't use the exact size of the message, but the size of the bytes
             // we will adjust for this later
@@ -189,6 +324,7 @@ impl PriorityManager {
             let nonzero_message_bytes = NonZeroU32::try_from(message_bytes).unwrap();
             let Ok(result) = self.limiter.check_n(nonzero_message_bytes) else {
                 error!("the bandwidth does not have enough capacity for a message of this size!");
+                drop_report.add(&buffered_message, DropReason::TooBigForBandwidthQuota);
                 break;
             };
 
@@ -196,6 +332,7 @@ impl PriorityManager {
             if buffered_message.priority < BYPASS_QUOTA_PRIORITY {
                 let Ok(()) = result else {
                     debug!("Bandwidth quota reached, no more messages can be sent this tick");
+                    drop_report.add(&buffered_message, DropReason::BandwidthQuota);
                     break;
                 };
             }
@@ -204,36 +341,12 @@ impl PriorityManager {
             // keep track of the bytes we added to the rate limiter
             bytes_used += message_bytes;
 
-            // notify the replication sender that the message was actually sent
-            if channel_registry.is_replication_update_channel(buffered_message.channel_net_id) {
-                // SAFETY: we are guaranteed in this situation to have a message id (because we use the unreliable with acks sender)
-                let message_id = buffered_message.data.message_id().unwrap();
-                for sender in self.replication_update_senders.iter() {
-                    trace!(
-                        ?message_id,
-                        "notifying replication sender that a message was actually sent."
-                    );
-                    let _ = sender.send(message_id).map_err(|e| {
-                        error!("error notifying replication sender that a message was actually sent: {:?}", e)
-                    });
-                }
-            }
-
-            // the message is allowed, add it to the list of messages to send
-            match buffered_message.data {
-                MessageData::Single(single) => {
-                    single_data
-                        .entry(buffered_message.channel_net_id)
-                        .or_default()
-                        .push_back(single);
-                }
-                MessageData::Fragment(fragment) => {
-                    fragment_data
-                        .entry(buffered_message.channel_net_id)
-                        .or_default()
-                        .push_back(fragment);
-                }
-            }
+            self.add_message(
+                buffered_message,
+                channel_registry,
+                &mut single_data,
+                &mut fragment_data,
+            );
         }
 
         // all the other messages that don't make the cut, we just drop
@@ -243,6 +356,9 @@ impl PriorityManager {
         //   - PROBLEM: we could have the entity action not get sent (bandwidth), and then the priority still drops because the entity update
         //     was sent right after...
         // - reliable entity actions:
+        for buffered_message in all_messages.iter() {
+            drop_report.add(buffered_message, DropReason::BandwidthQuota);
+        }
         let num_messages_sent = single_data.values().map(|data| data.len()).sum::<usize>()
             + fragment_data.values().map(|data| data.len()).sum::<usize>();
         debug!(
@@ -258,3 +374,129 @@ impl PriorityManager {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::default;
+    use bytes::Bytes;
+
+    use crate::prelude::*;
+    use crate::tests::protocol::*;
+
+    use super::*;
+
+    /// A channel with a minimum bandwidth share still sends messages even if a higher-priority
+    /// channel would use all the bandwidth
+    #[test]
+    fn test_priority_floor() {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                priority: 10.0,
+                ..default()
+            })
+            .unwrap();
+        channel_registry
+            .add_channel::<Channel2>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                priority: 1.0,
+                min_bytes_per_tick: Some(100),
+                ..default()
+            })
+            .unwrap();
+        let channel_id1 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let channel_id2 = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel2>())
+            .unwrap();
+        let mut manager = PriorityManager::new(PriorityConfig {
+            bandwidth_quota: Quota::per_second(nonzero!(1000u32)),
+            enabled: true,
+        });
+
+        let messages = |num_bytes: usize, num_messages: usize| {
+            (0..num_messages)
+                .map(|_| SendMessage {
+                    data: SingleData::new(None, Bytes::from(vec![0; num_bytes])).into(),
+                    priority: 1.0,
+                })
+                .collect::<VecDeque<_>>()
+        };
+        // messages of 100 bytes and 50 bytes, once the length and id are included
+        let data = vec![
+            (channel_id1, (messages(97, 20), VecDeque::new())),
+            (channel_id2, (messages(48, 3), VecDeque::new())),
+        ];
+        let (single_data, _, bytes_used) =
+            manager.priority_filter(data, &channel_registry, Tick(0), &mut DropReport::default());
+        let num_sent = |channel_id| {
+            single_data
+                .iter()
+                .find(|(id, _)| *id == channel_id)
+                .map_or(0, |(_, messages)| messages.len())
+        };
+        // the low-priority channel sends the messages that fit in its reserved 100 bytes
+        assert_eq!(num_sent(channel_id2), 2);
+        // the high-priority channel uses the rest of the bandwidth
+        assert_eq!(num_sent(channel_id1), 9);
+        assert_eq!(bytes_used, 1000);
+    }
+
+    /// Messages that don't fit in the bandwidth quota are reported as dropped
+    #[test]
+    fn test_drop_report() {
+        let mut channel_registry = ChannelRegistry::default();
+        channel_registry
+            .add_channel::<Channel1>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        let channel_id = *channel_registry
+            .get_net_from_kind(&ChannelKind::of::<Channel1>())
+            .unwrap();
+        let mut manager = PriorityManager::new(PriorityConfig {
+            bandwidth_quota: Quota::per_second(nonzero!(1000u32)),
+            enabled: true,
+        });
+        let message = |id: u16, num_bytes: usize, priority: f32| SendMessage {
+            data: SingleData::new(Some(MessageId(id)), Bytes::from(vec![0; num_bytes])).into(),
+            priority,
+        };
+
+        // the second message does not fit in the remaining quota
+        let data = vec![(
+            channel_id,
+            (
+                VecDeque::from(vec![message(0, 600, 2.0), message(1, 600, 1.0)]),
+                VecDeque::new(),
+            ),
+        )];
+        let mut drop_report = DropReport::default();
+        let (single_data, _, _) =
+            manager.priority_filter(data, &channel_registry, Tick(0), &mut drop_report);
+        assert_eq!(single_data[0].1.len(), 1);
+        assert_eq!(
+            drop_report.dropped,
+            vec![(channel_id, Some(MessageId(1)), DropReason::BandwidthQuota)]
+        );
+
+        // a message bigger than the quota can never be sent
+        let data = vec![(
+            channel_id,
+            (VecDeque::from(vec![message(2, 2000, 1.0)]), VecDeque::new()),
+        )];
+        let mut drop_report = DropReport::default();
+        manager.priority_filter(data, &channel_registry, Tick(0), &mut drop_report);
+        assert_eq!(
+            drop_report.dropped,
+            vec![(
+                channel_id,
+                Some(MessageId(2)),
+                DropReason::TooBigForBandwidthQuota
+            )]
+        );
+    }
+}
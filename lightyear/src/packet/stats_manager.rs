@@ -108,6 +108,12 @@ pub(crate) mod packet {
             self.current_stats.num_sent_packets += 1;
         }
 
+        /// Notify that a packet counted as sent was merged into another packet instead
+        pub(crate) fn sent_packet_cancelled(&mut self) {
+            self.current_stats.num_sent_packets =
+                self.current_stats.num_sent_packets.saturating_sub(1);
+        }
+
         /// Notify that a packet we sent got lost (we did not receive an ack for it)
         pub(crate) fn sent_packet_lost(&mut self) {
             #[cfg(feature = "metrics")]
@@ -0,0 +1,160 @@
+//! Frame packets on transports that carry a continuous byte stream (TCP, WebTransport streams),
+//! where the boundaries of the packets are not preserved.
+//!
+//! With [`PacketBuilder::with_stream_framing`](crate::packet::packet_builder::PacketBuilder::with_stream_framing),
+//! each finished packet starts with its length, written as a varint:
+//!
+//! `length | packet`
+use bytes::{Buf, BytesMut};
+
+use crate::packet::packet::Packet;
+use crate::packet::packet_builder::Payload;
+use crate::serialize::varint::{varint_len, varint_parse_len, write_varint};
+use crate::serialize::SerializationError;
+
+/// Prepend the length of the packet to `payload`
+pub(crate) fn frame_packet(payload: &mut Payload) {
+    let len = payload.len();
+    let mut framed = Vec::with_capacity(varint_len(len as u64) + len);
+    write_varint(&mut framed, len as u64).expect("a packet is smaller than the maximum varint");
+    framed.append(payload);
+    *payload = framed;
+}
+
+/// Buffers the bytes received on a stream, and returns the packets once all of their bytes
+/// were received
+#[derive(Debug)]
+pub(crate) struct StreamPacketReader {
+    buffer: BytesMut,
+    /// Biggest packet that the remote peer can send (its mtu)
+    max_len: usize,
+}
+
+impl StreamPacketReader {
+    pub(crate) fn new(max_len: usize) -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            max_len,
+        }
+    }
+
+    /// Add the next bytes received on the stream
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes received that are not part of a returned packet yet
+    pub(crate) fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Return the next packet if all of its bytes were received, without its length prefix.
+    ///
+    /// Returns an error if the length of the next packet is bigger than the maximum length: the
+    /// stream is probably corrupted, and cannot be read anymore.
+    pub(crate) fn poll(&mut self) -> Result<Option<Packet>, SerializationError> {
+        let Some(first) = self.buffer.first() else {
+            return Ok(None);
+        };
+        let prefix_len = varint_parse_len(*first);
+        if self.buffer.len() < prefix_len {
+            return Ok(None);
+        }
+        let len = self.buffer[1..prefix_len]
+            .iter()
+            .fold(u64::from(first & 0x3f), |len, byte| {
+                len << 8 | u64::from(*byte)
+            });
+        if len > self.max_len as u64 {
+            return Err(SerializationError::StreamPacketTooLarge {
+                len,
+                max: self.max_len,
+            });
+        }
+        let len = len as usize;
+        if self.buffer.len() < prefix_len + len {
+            return Ok(None);
+        }
+        self.buffer.advance(prefix_len);
+        let payload = self.buffer.split_to(len).to_vec();
+        let packet_id = Packet::peek_header(&payload)?.packet_id;
+        Ok(Some(Packet {
+            payload,
+            message_acks: vec![],
+            packet_id,
+            mtu: self.max_len,
+            prewritten_size: 0,
+            written_messages: 0,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use bytes::Bytes;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::connection::netcode::MAX_PACKET_SIZE;
+    use crate::packet::message::SingleData;
+    use crate::packet::packet_builder::PacketBuilder;
+    use crate::shared::tick_manager::Tick;
+
+    use super::*;
+
+    /// The packets are recovered whatever the sizes of the chunks in which the stream arrives
+    #[test]
+    fn test_stream_reader_chunks() -> Result<(), SerializationError> {
+        let mut builder = PacketBuilder::new(1.5)
+            .with_stream_framing(true)
+            .with_checksum(true);
+        let mut expected = vec![];
+        let mut stream = vec![];
+        for tick in 0..5 {
+            let single_data = vec![(
+                0,
+                (0..40)
+                    .map(|i| SingleData::new(None, Bytes::from(vec![tick as u8; 10 + i])))
+                    .collect::<VecDeque<_>>(),
+            )];
+            for packet in builder.build_packets(Tick(tick), single_data, vec![])? {
+                // the packet starts with its length
+                let prefix_len = varint_parse_len(packet.payload[0]);
+                assert!(packet.payload.len() <= MAX_PACKET_SIZE);
+                expected.push(packet.payload[prefix_len..].to_vec());
+                stream.extend_from_slice(&packet.payload);
+            }
+        }
+        assert!(expected.len() > 5);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for max_chunk in [1, 7, 300, stream.len()] {
+            let mut reader = StreamPacketReader::new(MAX_PACKET_SIZE);
+            let mut received = vec![];
+            let mut remaining = &stream[..];
+            while !remaining.is_empty() {
+                let (chunk, rest) =
+                    remaining.split_at(rng.gen_range(1..=max_chunk.min(remaining.len())));
+                reader.push(chunk);
+                remaining = rest;
+                while let Some(packet) = reader.poll()? {
+                    assert!(packet.verify_checksum());
+                    received.push(packet.payload);
+                }
+            }
+            assert_eq!(received, expected);
+            assert_eq!(reader.buffered_len(), 0);
+        }
+
+        // a length that is bigger than any packet
+        let mut reader = StreamPacketReader::new(100);
+        reader.push(&stream);
+        assert!(matches!(
+            reader.poll(),
+            Err(SerializationError::StreamPacketTooLarge { max: 100, .. })
+        ));
+        Ok(())
+    }
+}
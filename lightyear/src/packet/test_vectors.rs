@@ -0,0 +1,256 @@
+//! Canonical packets written for a fixed set of inputs, so that other implementations of the
+//! wire format can check that they read (and write) the same bytes.
+//!
+//! Each [`TestVector`] contains the messages given to the packet builder and the packets that it
+//! wrote, as hexadecimal strings annotated with their id, tick and length. The vectors are
+//! exported as JSON with [`to_json`], and [`check`] parses the packets of vectors read back with
+//! [`from_json`] and compares their messages with the inputs.
+//!
+//! The vectors are built with a new builder each, with the default [`WireConfig`] and the
+//! messages packed in queue order.
+use std::collections::VecDeque;
+
+use bevy::utils::HashMap;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::channel_dictionary::ChannelIdDictionary;
+use crate::packet::message::{MessageId, SingleData};
+use crate::packet::packet::{Packet, PacketId};
+use crate::packet::packet_builder::{PacketBuilder, PackingStrategy};
+use crate::packet::packet_parser::PacketParser;
+use crate::packet::wire_config::WireConfig;
+use crate::protocol::channel::ChannelId;
+use crate::serialize::SerializationError;
+use crate::shared::tick_manager::Tick;
+
+/// A message given to the packet builder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VectorMessage {
+    pub channel_id: ChannelId,
+    pub message_id: Option<u16>,
+    /// Bytes of the message, in hexadecimal
+    pub bytes: String,
+}
+
+/// A packet written by the packet builder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VectorPacket {
+    pub packet_id: u32,
+    pub tick: u16,
+    pub len: usize,
+    /// Bytes of the packet, in hexadecimal
+    pub bytes: String,
+}
+
+/// The packets written for a set of messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TestVector {
+    pub name: String,
+    /// Tick passed to `build_packets`, written in the header of every packet
+    pub tick: u16,
+    pub messages: Vec<VectorMessage>,
+    pub packets: Vec<VectorPacket>,
+}
+
+/// A [`TestVector`] that does not match the wire format of this implementation
+#[derive(thiserror::Error, Debug)]
+pub enum TestVectorError {
+    #[error("vector {name}: invalid hexadecimal string")]
+    InvalidHex { name: String },
+    #[error("vector {name}: the packets could not be parsed: {error}")]
+    Parse {
+        name: String,
+        error: SerializationError,
+    },
+    #[error("vector {name}: the messages of channel {channel_id} do not match the packets")]
+    MessageMismatch { name: String, channel_id: ChannelId },
+    #[error(transparent)]
+    Serialization(#[from] SerializationError),
+}
+
+/// Inputs of the vectors: name, tick, and messages (channel id, message id, bytes)
+fn inputs() -> Vec<(&'static str, u16, Vec<(ChannelId, Option<u16>, Vec<u8>)>)> {
+    vec![
+        ("single_message", 0, vec![(0, None, b"hello".to_vec())]),
+        (
+            "message_ids",
+            7,
+            (0..3).map(|i| (1, Some(i), vec![i as u8; 4])).collect(),
+        ),
+        (
+            "multiple_channels",
+            300,
+            vec![
+                (0, None, vec![0xaa; 3]),
+                (2, Some(10), vec![0xbb; 130]),
+                (5, None, vec![]),
+                (2, Some(11), vec![0xcc; 1]),
+            ],
+        ),
+        (
+            "multiple_packets",
+            65535,
+            (0..30).map(|i| (3, None, vec![i as u8; 100])).collect(),
+        ),
+    ]
+}
+
+/// Build the packets of every vector
+pub fn generate() -> Result<Vec<TestVector>, SerializationError> {
+    inputs()
+        .into_iter()
+        .map(|(name, tick, messages)| {
+            let mut single_data: Vec<(ChannelId, VecDeque<SingleData>)> = vec![];
+            for (channel_id, message_id, bytes) in &messages {
+                let message = SingleData::new(message_id.map(MessageId), bytes.clone().into());
+                match single_data.iter_mut().find(|(id, _)| id == channel_id) {
+                    Some((_, channel_messages)) => channel_messages.push_back(message),
+                    None => single_data.push((*channel_id, VecDeque::from([message]))),
+                }
+            }
+            let mut builder = PacketBuilder::new(1.5)
+                .with_wire_config(WireConfig::default())
+                .expect("the default wire config is valid")
+                .with_packing_strategy(PackingStrategy::QueueOrder);
+            let packets = builder.build_packets(Tick(tick), single_data, vec![])?;
+            Ok(TestVector {
+                name: name.to_string(),
+                tick,
+                messages: messages
+                    .into_iter()
+                    .map(|(channel_id, message_id, bytes)| VectorMessage {
+                        channel_id,
+                        message_id,
+                        bytes: to_hex(&bytes),
+                    })
+                    .collect(),
+                packets: packets
+                    .iter()
+                    .map(|packet| {
+                        Ok(VectorPacket {
+                            packet_id: packet.packet_id.0,
+                            tick: packet.tick()?.0,
+                            len: packet.payload.len(),
+                            bytes: to_hex(&packet.payload),
+                        })
+                    })
+                    .collect::<Result<_, SerializationError>>()?,
+            })
+        })
+        .collect()
+}
+
+/// Parse the packets of every vector, and check that each channel contains the messages of the
+/// vector, in the same order
+pub fn check(vectors: &[TestVector]) -> Result<(), TestVectorError> {
+    let mut parser = PacketParser::new(ChannelIdDictionary::default());
+    let mut contents = HashMap::default();
+    for vector in vectors {
+        let invalid_hex = || TestVectorError::InvalidHex {
+            name: vector.name.clone(),
+        };
+        let mut received: HashMap<ChannelId, Vec<Bytes>> = HashMap::default();
+        for vector_packet in &vector.packets {
+            let payload = from_hex(&vector_packet.bytes).ok_or_else(invalid_hex)?;
+            let packet = Packet {
+                mtu: payload.len(),
+                payload,
+                message_acks: vec![],
+                packet_id: PacketId(vector_packet.packet_id),
+                prewritten_size: 0,
+                written_messages: 0,
+            };
+            parser
+                .parse_into(packet, &mut contents)
+                .map_err(|error| TestVectorError::Parse {
+                    name: vector.name.clone(),
+                    error,
+                })?;
+            for (channel_id, messages) in contents.iter() {
+                received
+                    .entry(*channel_id)
+                    .or_default()
+                    .extend(messages.iter().cloned());
+            }
+        }
+        let mut expected: HashMap<ChannelId, Vec<Bytes>> = HashMap::default();
+        for message in &vector.messages {
+            let bytes = from_hex(&message.bytes).ok_or_else(invalid_hex)?;
+            expected
+                .entry(message.channel_id)
+                .or_default()
+                .push(bytes.into());
+        }
+        if let Some(channel_id) = expected
+            .keys()
+            .chain(received.keys())
+            .find(|channel_id| expected.get(*channel_id) != received.get(*channel_id))
+        {
+            return Err(TestVectorError::MessageMismatch {
+                name: vector.name.clone(),
+                channel_id: *channel_id,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Write the vectors as a JSON array
+pub fn to_json(vectors: &[TestVector]) -> Result<String, SerializationError> {
+    Ok(serde_json::to_string_pretty(vectors)?)
+}
+
+/// Read vectors written with [`to_json`]
+pub fn from_json(json: &str) -> Result<Vec<TestVector>, SerializationError> {
+    Ok(serde_json::from_str(json)?)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The packet of the `single_message` vector: the header, then the block of channel 0 with
+    /// the message `hello`
+    const SINGLE_MESSAGE_PACKET: &str = "000000ffff0000000000000001000568656c6c6f";
+
+    #[test]
+    fn test_vectors_round_trip() -> Result<(), TestVectorError> {
+        let vectors = generate()?;
+        assert_eq!(vectors.len(), 4);
+        assert!(vectors[3].packets.len() > 1);
+        check(&vectors)?;
+        // the vectors are the same once written and read back
+        let read = from_json(&to_json(&vectors)?)?;
+        assert_eq!(read, vectors);
+        check(&read)?;
+
+        // the wire format of the simplest packet does not change
+        assert_eq!(vectors[0].packets[0].bytes, SINGLE_MESSAGE_PACKET);
+
+        // a modified packet is detected
+        let mut modified = vectors.clone();
+        let packet = &mut modified[1].packets[0].bytes;
+        let last = packet.len() - 2;
+        packet.replace_range(last.., "ff");
+        assert!(matches!(
+            check(&modified),
+            Err(TestVectorError::MessageMismatch { channel_id: 1, .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! Send and receive packets directly over a [`UdpSocket`], without the connection layer.
+//!
+//! This is internal to the crate: the [`PacketBuilder`] that it wraps is not public, so it is
+//! only used to exchange packets in tests and tools, not by users of the crate.
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+use bevy::utils::HashMap;
+use bytes::Bytes;
+
+use crate::packet::header::PacketRecvOutcome;
+use crate::packet::message::SingleData;
+use crate::packet::packet::{strip_padding, verify_checksum, Packet, PacketId, CHECKSUM_BYTES};
+use crate::packet::packet_builder::PacketBuilder;
+use crate::packet::packet_parser::PacketParser;
+use crate::packet::packet_type::PacketType;
+use crate::protocol::channel::ChannelId;
+use crate::serialize::reader::Reader;
+use crate::serialize::{SerializationError, ToBytes};
+use crate::shared::tick_manager::Tick;
+
+/// A packet received by a [`UdpTransport`]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ParsedPacket {
+    /// Id of the packet, as written in its header
+    pub packet_id: PacketId,
+    pub packet_type: PacketType,
+    pub tick: Tick,
+    /// Packets that we sent, and that the remote peer acked for the first time in this packet
+    pub acked_packets: Vec<PacketId>,
+    /// Messages (or fragments) of each channel of the packet
+    pub messages: HashMap<ChannelId, Vec<Bytes>>,
+}
+
+/// Binds a [`PacketBuilder`] to a [`UdpSocket`]: the messages are packed and each packet is sent
+/// as a datagram, and the received datagrams are parsed and their acks are given back to the
+/// builder.
+///
+/// The received packets are read with the wire settings of the builder: its channel dictionary,
+/// checksum, padding and compression dictionary. The sections of encrypted channels are only read
+/// if a decryption function is given with [`Self::with_decryption`].
+///
+/// The socket should be blocking: [`Self::recv`] waits for a datagram until the read timeout of
+/// the socket (see [`UdpSocket::set_read_timeout`]).
+pub(crate) struct UdpTransport {
+    socket: UdpSocket,
+    builder: PacketBuilder,
+    parser: PacketParser,
+}
+
+impl UdpTransport {
+    /// Returns an error if the builder uses stream framing: each packet is already sent as its
+    /// own datagram, and the peer would read the length prefix as the header.
+    pub(crate) fn new(
+        socket: UdpSocket,
+        builder: PacketBuilder,
+    ) -> Result<Self, SerializationError> {
+        if builder.stream_framing {
+            return Err(SerializationError::UnsupportedTransportOption {
+                option: "stream_framing",
+            });
+        }
+        let mut parser = PacketParser::new(builder.channel_dictionary.clone())
+            .with_max_messages(builder.max_messages_per_packet());
+        if let Some(compression_dictionary) = &builder.compression_dictionary {
+            parser = parser.with_compression_dictionary(compression_dictionary.clone());
+        }
+        Ok(Self {
+            socket,
+            builder,
+            parser,
+        })
+    }
+
+    /// Decrypt the sections of the encrypted channels of the received packets with `decrypt`
+    /// (see [`PacketParser::with_decryption`])
+    pub(crate) fn with_decryption(
+        mut self,
+        decrypt: impl FnMut(ChannelId, &mut Vec<u8>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.parser = self.parser.with_decryption(decrypt);
+        self
+    }
+
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Pack `data` and send each packet to `addr`, including the packet that the builder would
+    /// otherwise keep open (see [`PacketBuilder::with_incremental_packets`]).
+    ///
+    /// Returns the number of datagrams sent.
+    pub(crate) fn send_batch(
+        &mut self,
+        addr: SocketAddr,
+        data: Vec<(ChannelId, VecDeque<SingleData>)>,
+        current_tick: Tick,
+    ) -> Result<usize, SerializationError> {
+        let socket = &self.socket;
+        let mut num_sent = 0;
+        let mut send = |packet: Packet| {
+            socket.send_to(&packet.payload, addr)?;
+            num_sent += 1;
+            Ok::<_, SerializationError>(())
+        };
+        self.builder
+            .build_packets_with(current_tick, data, vec![], &mut send)?;
+        if let Some(packet) = self.builder.flush_current()? {
+            send(packet)?;
+        }
+        Ok(num_sent)
+    }
+
+    /// Wait for the next datagram, receive it in `buf` and parse it.
+    ///
+    /// Returns `None` if no datagram arrived before the read timeout of the socket (or right away
+    /// if the socket is non-blocking and has nothing to read). Packets that were already received
+    /// are skipped.
+    pub(crate) fn recv(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<Option<(SocketAddr, ParsedPacket)>, SerializationError> {
+        loop {
+            let (len, addr) = match self.socket.recv_from(buf) {
+                Ok(received) => received,
+                // the error kind of a read timeout depends on the platform
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let mut payload = buf[..len].to_vec();
+            if self.builder.checksum {
+                if !verify_checksum(&payload) {
+                    return Err(SerializationError::ChecksumMismatch);
+                }
+                payload.truncate(len - CHECKSUM_BYTES);
+            }
+            let header = Packet::peek_header(&payload)?;
+            if self.builder.pad_to.is_some() {
+                let padded = Reader::from(payload.split_off(header.len()));
+                payload.extend_from_slice(&strip_padding(padded)?.consume());
+            }
+            let header_manager = &mut self.builder.header_manager;
+            if header_manager.recv_outcome(&header) != PacketRecvOutcome::New {
+                header_manager.process_recv_header(&header);
+                continue;
+            }
+            let packet = Packet {
+                mtu: self.builder.mtu(),
+                payload,
+                message_acks: vec![],
+                packet_id: header.packet_id,
+                prewritten_size: 0,
+                written_messages: 0,
+            };
+            let mut messages = HashMap::default();
+            self.parser.parse_into(packet, &mut messages)?;
+            // the packet is only acked once it could be read
+            let header_manager = &mut self.builder.header_manager;
+            header_manager.process_recv_header(&header);
+            let acked_packets = header_manager.process_recv_acks(&header);
+            return Ok(Some((
+                addr,
+                ParsedPacket {
+                    packet_id: header.packet_id,
+                    packet_type: header.get_packet_type(),
+                    tick: header.tick,
+                    acked_packets,
+                    messages,
+                },
+            )));
+        }
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[cfg(test)]
+mod tests {
+    use bevy::utils::Duration;
+
+    use super::*;
+
+    fn transport_with(builder: PacketBuilder) -> UdpTransport {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        UdpTransport::new(socket, builder).unwrap()
+    }
+
+    fn transport() -> UdpTransport {
+        transport_with(PacketBuilder::new(1.5))
+    }
+
+    /// Receive the next packet, which must arrive before the read timeout
+    fn recv_packet(
+        transport: &mut UdpTransport,
+        buf: &mut [u8],
+    ) -> Result<(SocketAddr, ParsedPacket), SerializationError> {
+        Ok(transport
+            .recv(buf)?
+            .expect("expected to receive a packet before the read timeout"))
+    }
+
+    fn small_messages(channel_id: ChannelId) -> (ChannelId, VecDeque<SingleData>) {
+        let messages = (0..5)
+            .map(|i| SingleData::new(None, Bytes::from(vec![i; 10 + i as usize])))
+            .collect::<VecDeque<_>>();
+        (channel_id, messages)
+    }
+
+    fn message_bytes(channel_id: ChannelId) -> Vec<Bytes> {
+        Vec::from(small_messages(channel_id).1)
+            .into_iter()
+            .map(|message| message.bytes)
+            .collect()
+    }
+
+    #[test]
+    fn test_udp_transport_loopback() -> Result<(), SerializationError> {
+        let mut client = transport();
+        let mut server = transport();
+        let client_addr = client.socket().local_addr()?;
+        let server_addr = server.socket().local_addr()?;
+        let mut buf = [0; 1500];
+
+        let sent = client.send_batch(
+            server_addr,
+            vec![small_messages(0), small_messages(1)],
+            Tick(3),
+        )?;
+        assert_eq!(sent, 1);
+
+        let (addr, packet) = recv_packet(&mut server, &mut buf)?;
+        assert_eq!(addr, client_addr);
+        assert_eq!(packet.packet_id, PacketId(0));
+        assert_eq!(packet.packet_type, PacketType::Data);
+        assert_eq!(packet.tick, Tick(3));
+        assert!(packet.acked_packets.is_empty());
+        assert_eq!(packet.messages.len(), 2);
+        assert_eq!(packet.messages[&1], message_bytes(1));
+        // nothing else arrives before the timeout
+        server
+            .socket()
+            .set_read_timeout(Some(Duration::from_millis(10)))?;
+        assert!(server.recv(&mut buf)?.is_none());
+
+        // the answer acks the packet of the client
+        server.send_batch(client_addr, vec![small_messages(0)], Tick(4))?;
+        let (addr, packet) = recv_packet(&mut client, &mut buf)?;
+        assert_eq!(addr, server_addr);
+        assert_eq!(packet.acked_packets, vec![PacketId(0)]);
+        assert!(client
+            .builder
+            .header_manager
+            .sent_packets_not_acked()
+            .is_empty());
+        Ok(())
+    }
+
+    /// The padding of the received packets is removed before they are parsed
+    #[test]
+    fn test_udp_transport_padding() -> Result<(), SerializationError> {
+        let builder = || PacketBuilder::new(1.5).with_pad_to(Some(256));
+        let mut client = transport_with(builder());
+        let mut server = transport_with(builder());
+        let server_addr = server.socket().local_addr()?;
+        let mut buf = [0; 1500];
+
+        client.send_batch(server_addr, vec![small_messages(2)], Tick(0))?;
+        let (_, packet) = recv_packet(&mut server, &mut buf)?;
+        assert_eq!(packet.messages.len(), 1);
+        assert_eq!(packet.messages[&2], message_bytes(2));
+        Ok(())
+    }
+
+    /// A builder that frames its packets for a stream cannot be used to send datagrams
+    #[test]
+    fn test_udp_transport_rejects_stream_framing() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let builder = PacketBuilder::new(1.5).with_stream_framing(true);
+        assert!(matches!(
+            UdpTransport::new(socket, builder),
+            Err(SerializationError::UnsupportedTransportOption {
+                option: "stream_framing"
+            })
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+use tracing::error;
+
+use crate::connection::netcode::MAX_PACKET_SIZE;
+use crate::packet::error::PacketError;
+use crate::packet::packet::{
+    fragment_size, min_mtu, CHECKSUM_BYTES, DEFAULT_MAX_MESSAGES_PER_PACKET, HEADER_BYTES,
+};
+use crate::serialize::varint::{varint_len, VARINT_MAX};
+
+/// Options that change how packets are written on the wire.
+///
+/// The same [`WireConfig`] is used to build the packets and to read the received packets, and
+/// both peers must use the same [`WireConfig`]: use [`WireConfig::check_remote`] to detect a
+/// peer that was configured differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WireConfig {
+    /// Maximum number of bytes in a packet
+    pub mtu: usize,
+    /// Compress the packets (requires the `lz4` feature)
+    pub packet_compression: bool,
+    /// Write a checksum at the end of each packet, and check it on each received packet
+    pub checksum: bool,
+    /// Maximum number of messages in a packet. Received packets that claim to contain more
+    /// messages are rejected before reading them. Must be at least 1.
+    pub max_messages_per_packet: usize,
+    /// If set, every packet is padded with zeros up to this number of bytes (at most the MTU),
+    /// so that the size of the packets does not reveal their content.
+    /// Packets whose content is bigger are sent without padding.
+    pub pad_to: Option<usize>,
+}
+
+impl Default for WireConfig {
+    fn default() -> Self {
+        Self {
+            mtu: MAX_PACKET_SIZE,
+            packet_compression: false,
+            checksum: false,
+            max_messages_per_packet: DEFAULT_MAX_MESSAGES_PER_PACKET,
+            pad_to: None,
+        }
+    }
+}
+
+impl WireConfig {
+    /// Returns an error if the packets are too small to hold a fragment with the options that
+    /// are already set (see [`Self::validate`])
+    pub fn with_mtu(mut self, mtu: usize) -> Result<Self, PacketError> {
+        self.mtu = mtu;
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn with_packet_compression(mut self, packet_compression: bool) -> Self {
+        self.packet_compression = packet_compression;
+        self
+    }
+
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn with_max_messages_per_packet(mut self, max_messages_per_packet: usize) -> Self {
+        self.max_messages_per_packet = max_messages_per_packet;
+        self
+    }
+
+    pub fn with_pad_to(mut self, pad_to: Option<usize>) -> Self {
+        self.pad_to = pad_to;
+        self
+    }
+
+    /// Check that the packets can hold a fragment once the checksum and the padding are reserved
+    pub fn validate(&self) -> Result<(), PacketError> {
+        let min = min_mtu(self.reserved_bytes());
+        if self.mtu < min {
+            return Err(PacketError::MtuTooSmall { mtu: self.mtu, min });
+        }
+        Ok(())
+    }
+
+    /// This config if it is valid, otherwise the default config (the error is logged), for the
+    /// connections that are built from a user config and can't fail
+    pub(crate) fn or_default_if_invalid(self) -> Self {
+        match self.validate() {
+            Ok(()) => self,
+            Err(e) => {
+                error!("Invalid wire config, the default one is used instead: {e}");
+                Self::default()
+            }
+        }
+    }
+
+    /// Size limits of the packets written with this config, or an error if the config is not
+    /// valid (see [`Self::validate`])
+    pub fn packet_limits(&self) -> Result<PacketLimits, PacketError> {
+        self.validate()?;
+        let reserved_bytes = self.reserved_bytes();
+        Ok(PacketLimits {
+            mtu: self.mtu,
+            max_packet_size: MAX_PACKET_SIZE,
+            header_bytes: HEADER_BYTES,
+            reserved_bytes,
+            fragment_size: fragment_size(self.mtu, reserved_bytes),
+        })
+    }
+
+    /// Number of bytes reserved in every packet for the checksum and the padding
+    fn reserved_bytes(&self) -> usize {
+        let padding_bytes = self
+            .pad_to
+            .map_or(0, |_| varint_len(self.mtu.min(VARINT_MAX as usize) as u64));
+        let checksum_bytes = if self.checksum { CHECKSUM_BYTES } else { 0 };
+        checksum_bytes + padding_bytes
+    }
+
+    /// Returns an error if the remote peer's config is not the same as ours
+    pub fn check_remote(&self, remote: &WireConfig) -> Result<(), PacketError> {
+        if self != remote {
+            return Err(PacketError::WireConfigMismatch {
+                local: *self,
+                remote: *remote,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Size limits of the packets, to plan how much data fits in a packet
+/// (see [`WireConfig::packet_limits`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketLimits {
+    /// Maximum number of bytes in a packet
+    pub mtu: usize,
+    /// Maximum number of bytes in a packet for the netcode transport
+    pub max_packet_size: usize,
+    /// Number of bytes of the header of a packet, with the default ack window
+    pub header_bytes: usize,
+    /// Number of bytes reserved in every packet (for the checksum and the padding)
+    pub reserved_bytes: usize,
+    /// Maximum number of bytes of a fragment of a message
+    pub fragment_size: usize,
+}
+
+impl PacketLimits {
+    /// Size of the largest message without [`MessageId`](crate::packet::message::MessageId) that
+    /// fits alone in a packet, on a channel whose id is written in 1 byte
+    pub fn max_single_message_bytes(&self) -> usize {
+        // channel id + number of messages + message id flag
+        let available = self
+            .mtu
+            .saturating_sub(self.header_bytes + self.reserved_bytes + 3);
+        // the length of the message is written before it
+        [1, 2, 4, 8]
+            .into_iter()
+            .map(|len_bytes| available.saturating_sub(len_bytes))
+            .find(|bytes| varint_len(*bytes as u64) <= available - bytes)
+            .unwrap_or_default()
+    }
+
+    /// Messages bigger than this are split into fragments
+    pub fn max_message_bytes_before_fragmentation(&self) -> usize {
+        self.fragment_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::packet::FRAGMENT_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn test_default_packet_limits() -> Result<(), PacketError> {
+        let limits = WireConfig::default().packet_limits()?;
+        assert_eq!(limits.mtu, MAX_PACKET_SIZE);
+        assert_eq!(limits.max_packet_size, MAX_PACKET_SIZE);
+        assert_eq!(limits.header_bytes, HEADER_BYTES);
+        assert_eq!(limits.reserved_bytes, 0);
+        assert_eq!(limits.fragment_size, FRAGMENT_SIZE);
+        assert_eq!(
+            limits.max_message_bytes_before_fragmentation(),
+            FRAGMENT_SIZE
+        );
+        // header + channel id + number of messages + message id flag + 2 bytes of length
+        assert_eq!(
+            limits.max_single_message_bytes(),
+            MAX_PACKET_SIZE - HEADER_BYTES - 5
+        );
+
+        // the checksum only makes the fragments smaller when it is enabled
+        let limits = WireConfig::default().with_checksum(true).packet_limits()?;
+        assert_eq!(limits.reserved_bytes, CHECKSUM_BYTES);
+        assert_eq!(limits.fragment_size, FRAGMENT_SIZE - CHECKSUM_BYTES);
+        Ok(())
+    }
+
+    /// The packets must be able to hold a fragment of 1 byte
+    #[test]
+    fn test_mtu_too_small() -> Result<(), PacketError> {
+        let min = min_mtu(0);
+        // 1 byte of data, and its offset in the message
+        let limits = WireConfig::default().with_mtu(min)?.packet_limits()?;
+        assert_eq!(limits.fragment_size, 1 + 4);
+        assert!(matches!(
+            WireConfig::default().with_mtu(min - 1),
+            Err(PacketError::MtuTooSmall { mtu, min: m }) if mtu == min - 1 && m == min
+        ));
+
+        // the checksum must also fit
+        let config = WireConfig::default().with_mtu(min)?.with_checksum(true);
+        assert!(matches!(
+            config.packet_limits(),
+            Err(PacketError::MtuTooSmall { min: m, .. }) if m == min + CHECKSUM_BYTES
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_remote() -> Result<(), PacketError> {
+        let config = WireConfig::default().with_mtu(600)?.with_checksum(true);
+        assert!(config.check_remote(&config).is_ok());
+
+        let remote = config.with_checksum(false);
+        assert!(matches!(
+            config.check_remote(&remote),
+            Err(PacketError::WireConfigMismatch { local, remote: r }) if local == config && r == remote
+        ));
+        Ok(())
+    }
+}
@@ -5,13 +5,15 @@ use std::any::TypeId;
 use std::collections::HashMap;
 
 use crate::channel::builder::{
-    AuthorityChannel, Channel, ChannelBuilder, ChannelSettings, PongChannel,
+    AuthorityChannel, Channel, ChannelBuilder, ChannelConfigError, ChannelSettings, PongChannel,
 };
 use crate::channel::builder::{
     ChannelContainer, EntityActionsChannel, EntityUpdatesChannel, InputChannel, PingChannel,
 };
+use crate::packet::error::PacketError;
 use crate::prelude::{ChannelMode, ReliableSettings};
 use crate::protocol::registry::{NetId, TypeKind, TypeMapper};
+use lightyear_macros::ToBytesInternal;
 
 // TODO: derive Reflect once we reach bevy 0.14
 /// ChannelKind - internal wrapper around the type of the channel
@@ -34,6 +36,23 @@ impl From<TypeId> for ChannelKind {
     }
 }
 
+/// Description of a registered channel, sent to the remote peer in the handshake
+#[derive(Debug, Clone, PartialEq, ToBytesInternal)]
+pub struct ChannelSchemaEntry {
+    pub channel_id: ChannelId,
+    pub name: String,
+    /// See [`ChannelMode::kind_id`]
+    pub mode: u8,
+}
+
+/// Description of all the channels of a [`ChannelRegistry`], used to check that both peers
+/// registered the same channels with the same ids
+#[derive(Debug, Clone, PartialEq, ToBytesInternal)]
+pub struct ChannelSchema {
+    /// Sorted by channel id
+    pub channels: Vec<ChannelSchemaEntry>,
+}
+
 /// Registry to store metadata about the various [`Channels`](Channel) to use to send messages.
 ///
 /// ### Adding channels
@@ -53,11 +72,17 @@ impl From<TypeId> for ChannelKind {
 ///    app.add_channel::<MyChannel>(ChannelSettings {
 ///      mode: ChannelMode::UnorderedUnreliable,
 ///      ..default()
-///    });
+///    })
+///    .unwrap();
 /// # }
 /// ```
 ///
+/// A configured registry can be cloned to create the channels of several connections: the clones
+/// keep the same [`ChannelId`] for each channel.
 ///
+/// By default the [`ChannelId`]s are assigned in registration order, so both peers must register
+/// their channels in the same order. With [`ChannelRegistry::with_stable_net_ids`] they are derived
+/// from a hash of the type path of the channel instead.
 #[derive(Resource, Default, Clone, Debug, PartialEq, TypePath)]
 pub struct ChannelRegistry {
     // we only store the ChannelBuilder because we might want to create multiple instances of the same channel
@@ -65,6 +90,11 @@ pub struct ChannelRegistry {
     pub(in crate::protocol) kind_map: TypeMapper<ChannelKind>,
     pub(in crate::protocol) name_map: HashMap<ChannelKind, String>,
     built: bool,
+    /// Settings of the channels added with [`ChannelRegistry::add_default_channel`]
+    default_settings: ChannelSettings,
+    /// Full type path of each channel, used to derive stable net ids
+    type_path_map: HashMap<ChannelKind, &'static str>,
+    stable_net_ids: bool,
 }
 
 impl ChannelRegistry {
@@ -74,8 +104,11 @@ impl ChannelRegistry {
             kind_map: TypeMapper::new(),
             name_map: HashMap::new(),
             built: false,
+            default_settings: ChannelSettings::default(),
+            type_path_map: HashMap::new(),
+            stable_net_ids: false,
         };
-        registry.add_channel::<EntityUpdatesChannel>(ChannelSettings {
+        registry.insert_channel::<EntityUpdatesChannel>(ChannelSettings {
             mode: ChannelMode::UnorderedUnreliableWithAcks,
             // we do not send the send_frequency to `replication_interval` here
             // because we want to make sure that the entity updates for tick T
@@ -83,8 +116,9 @@ impl ChannelRegistry {
             // directly on the replication_sender
             send_frequency: Duration::default(),
             priority: 1.0,
+            ..Default::default()
         });
-        registry.add_channel::<EntityActionsChannel>(ChannelSettings {
+        registry.insert_channel::<EntityActionsChannel>(ChannelSettings {
             mode: ChannelMode::UnorderedReliable(ReliableSettings::default()),
             // we do not send the send_frequency to `replication_interval` here
             // because we want to make sure that the entity updates for tick T
@@ -93,30 +127,35 @@ impl ChannelRegistry {
             send_frequency: Duration::default(),
             // we want to send the entity actions as soon as possible
             priority: 10.0,
+            ..Default::default()
         });
-        registry.add_channel::<PingChannel>(ChannelSettings {
+        registry.insert_channel::<PingChannel>(ChannelSettings {
             mode: ChannelMode::SequencedUnreliable,
             send_frequency: Duration::default(),
             // we always want to include the ping in the packet
             priority: f32::INFINITY,
+            ..Default::default()
         });
-        registry.add_channel::<PongChannel>(ChannelSettings {
+        registry.insert_channel::<PongChannel>(ChannelSettings {
             mode: ChannelMode::SequencedUnreliable,
             send_frequency: Duration::default(),
             // we always want to include the pong in the packet
             priority: f32::INFINITY,
+            ..Default::default()
         });
-        registry.add_channel::<InputChannel>(ChannelSettings {
+        registry.insert_channel::<InputChannel>(ChannelSettings {
             mode: ChannelMode::UnorderedUnreliable,
             send_frequency: input_send_interval,
             // we always want to include the inputs in the packet
             priority: f32::INFINITY,
+            ..Default::default()
         });
-        registry.add_channel::<AuthorityChannel>(ChannelSettings {
+        registry.insert_channel::<AuthorityChannel>(ChannelSettings {
             mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
             send_frequency: Duration::default(),
             // we want to send the authority transfers as soon as possible
             priority: 10.0,
+            ..Default::default()
         });
         registry
     }
@@ -150,11 +189,80 @@ impl ChannelRegistry {
     }
 
     /// Register a new type
-    pub fn add_channel<C: Channel>(&mut self, settings: ChannelSettings) {
-        let kind = self.kind_map.add::<C>();
+    ///
+    /// Returns an error if the settings are contradictory (see [`ChannelSettings::validate`])
+    pub fn add_channel<C: Channel>(
+        &mut self,
+        settings: ChannelSettings,
+    ) -> Result<(), ChannelConfigError> {
+        settings.validate()?;
+        if self.stable_net_ids {
+            let net_id = stable_net_id(std::any::type_name::<C>());
+            if let Some(other) = self
+                .kind_map
+                .kind(net_id)
+                .filter(|other| **other != ChannelKind::of::<C>())
+            {
+                return Err(ChannelConfigError::NetIdCollision {
+                    net_id,
+                    channel: C::name().to_string(),
+                    other: self.name(other).unwrap_or_default().to_string(),
+                });
+            }
+        }
+        self.insert_channel::<C>(settings);
+        Ok(())
+    }
+
+    fn insert_channel<C: Channel>(&mut self, settings: ChannelSettings) {
+        let kind = if self.stable_net_ids {
+            let kind = ChannelKind::of::<C>();
+            self.kind_map
+                .insert(kind, stable_net_id(std::any::type_name::<C>()));
+            kind
+        } else {
+            self.kind_map.add::<C>()
+        };
         self.builder_map.insert(kind, C::get_builder(settings));
         let name = C::name();
         self.name_map.insert(kind, name.to_string());
+        self.type_path_map.insert(kind, std::any::type_name::<C>());
+    }
+
+    /// Derive the [`ChannelId`] of each channel from a hash of its type path, instead of using the
+    /// registration order, so that peers agree on the ids even if they register the channels in
+    /// a different order.
+    ///
+    /// The ids of the channels that are already registered are re-assigned.
+    /// Stable ids take 2 bytes on the wire instead of 1.
+    ///
+    /// Panics if two of the already registered channels have the same id; channels added afterwards
+    /// return [`ChannelConfigError::NetIdCollision`] instead.
+    pub fn with_stable_net_ids(mut self) -> Self {
+        self.stable_net_ids = true;
+        self.kind_map = TypeMapper::new();
+        for (kind, type_path) in self.type_path_map.iter() {
+            let net_id = stable_net_id(type_path);
+            if let Some(other) = self.kind_map.kind(net_id) {
+                panic!(
+                    "channels {} and {} have the same stable net id {net_id}",
+                    type_path, self.type_path_map[other]
+                );
+            }
+            self.kind_map.insert(*kind, net_id);
+        }
+        self
+    }
+
+    /// Use `settings` for the channels added with [`Self::add_default_channel`]
+    pub fn with_defaults(mut self, settings: ChannelSettings) -> Self {
+        self.default_settings = settings;
+        self
+    }
+
+    /// Register a new type, with the default settings of the registry (see [`Self::with_defaults`])
+    pub fn add_default_channel<C: Channel>(&mut self) -> Result<(), ChannelConfigError> {
+        self.add_channel::<C>(self.default_settings.clone())
     }
 
     /// get the registered object for a given type
@@ -179,21 +287,96 @@ impl ChannelRegistry {
         self.get_builder_from_kind(channel_kind)
     }
 
+    /// Iterate through the [`ChannelSettings`] of every registered channel, along with its [`ChannelId`]
+    pub(crate) fn settings(&self) -> impl Iterator<Item = (ChannelId, &ChannelSettings)> {
+        self.kind_map.kind_map.iter().filter_map(|(kind, net_id)| {
+            self.builder_map
+                .get(kind)
+                .map(|builder| (*net_id, &builder.settings))
+        })
+    }
+
+    /// Describe the registered channels, to send them to the remote peer
+    pub fn schema(&self) -> ChannelSchema {
+        let mut channels = self
+            .settings()
+            .map(|(channel_id, settings)| ChannelSchemaEntry {
+                channel_id,
+                name: self
+                    .get_kind_from_net_id(channel_id)
+                    .and_then(|kind| self.name(kind))
+                    .unwrap_or_default()
+                    .to_string(),
+                mode: settings.mode.kind_id(),
+            })
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|entry| entry.channel_id);
+        ChannelSchema { channels }
+    }
+
+    /// Check that the remote peer registered the same channels as this registry.
+    ///
+    /// Returns an error describing the first channel that differs.
+    pub fn check_schema(&self, remote: &ChannelSchema) -> Result<(), PacketError> {
+        let local = self.schema();
+        let mut local_channels = local.channels.into_iter().peekable();
+        let mut remote_channels = remote.channels.iter().cloned().peekable();
+        loop {
+            let (local, remote) = match (local_channels.peek(), remote_channels.peek()) {
+                (None, None) => return Ok(()),
+                (Some(local), Some(remote)) if local.channel_id == remote.channel_id => {
+                    (local_channels.next(), remote_channels.next())
+                }
+                // the channel with the lowest id is missing on the other peer
+                (Some(local), Some(remote)) if local.channel_id < remote.channel_id => {
+                    (local_channels.next(), None)
+                }
+                (Some(_), Some(_)) | (None, Some(_)) => (None, remote_channels.next()),
+                (Some(_), None) => (local_channels.next(), None),
+            };
+            if local != remote {
+                let channel_id = local.as_ref().or(remote.as_ref()).unwrap().channel_id;
+                return Err(PacketError::ChannelSchemaMismatch {
+                    channel_id,
+                    local,
+                    remote,
+                });
+            }
+        }
+    }
+
     #[cfg(test)]
     fn len(&self) -> usize {
         self.kind_map.len()
     }
 }
 
+/// Hash the type path of a channel (FNV-1a) into an id that fits in a 2-byte varint
+fn stable_net_id(type_path: &str) -> ChannelId {
+    let hash = type_path.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    (hash % (1 << 14)) as ChannelId
+}
+
 /// Add a message to the list of messages that can be sent
 pub trait AppChannelExt {
-    fn add_channel<C: Channel>(&mut self, settings: ChannelSettings);
+    /// Register a channel in the [`ChannelRegistry`].
+    ///
+    /// Returns an error if the settings are contradictory (see [`ChannelSettings::validate`])
+    fn add_channel<C: Channel>(
+        &mut self,
+        settings: ChannelSettings,
+    ) -> Result<(), ChannelConfigError>;
 }
 
 impl AppChannelExt for App {
-    fn add_channel<C: Channel>(&mut self, settings: ChannelSettings) {
+    fn add_channel<C: Channel>(
+        &mut self,
+        settings: ChannelSettings,
+    ) -> Result<(), ChannelConfigError> {
         let mut registry = self.world_mut().resource_mut::<ChannelRegistry>();
-        registry.add_channel::<C>(settings);
+        registry.add_channel::<C>(settings)
     }
 }
 
@@ -203,12 +386,16 @@ mod tests {
     use lightyear_macros::ChannelInternal;
 
     use crate::channel::builder::{ChannelMode, ChannelSettings};
+    use crate::packet::packet::MIN_FRAGMENT_MTU;
 
     use super::*;
 
     #[derive(ChannelInternal, TypePath)]
     pub struct MyChannel;
 
+    #[derive(ChannelInternal, TypePath)]
+    pub struct MyOtherChannel;
+
     #[test]
     fn test_channel_registry() {
         let mut registry = ChannelRegistry::default();
@@ -217,7 +404,7 @@ mod tests {
             mode: ChannelMode::UnorderedUnreliable,
             ..default()
         };
-        registry.add_channel::<MyChannel>(settings.clone());
+        registry.add_channel::<MyChannel>(settings.clone()).unwrap();
         assert_eq!(registry.len(), 1);
 
         let builder = registry.get_builder_from_net_id(0).unwrap();
@@ -227,4 +414,230 @@ mod tests {
             ChannelMode::UnorderedUnreliable
         );
     }
+
+    #[test]
+    fn test_add_channel_valid_settings() {
+        let mut registry = ChannelRegistry::default();
+        let settings = ChannelSettings {
+            mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+            priority: f32::INFINITY,
+            max_bytes_per_tick: Some(1000),
+            min_bytes_per_tick: Some(1000),
+            fragment_mtu: Some(300),
+            ..default()
+        };
+        assert_eq!(registry.add_channel::<MyChannel>(settings), Ok(()));
+        assert_eq!(registry.len(), 1);
+    }
+
+    /// Channels added without settings use the defaults of the registry, and a cloned registry
+    /// keeps the same channel ids
+    #[test]
+    fn test_channel_registry_defaults_and_clone() {
+        let defaults = ChannelSettings {
+            mode: ChannelMode::SequencedUnreliable,
+            priority: 2.0,
+            ..default()
+        };
+        let mut registry = ChannelRegistry::default().with_defaults(defaults.clone());
+        registry
+            .add_channel::<MyChannel>(ChannelSettings {
+                mode: ChannelMode::UnorderedUnreliable,
+                ..default()
+            })
+            .unwrap();
+        registry.add_default_channel::<MyOtherChannel>().unwrap();
+        let other_kind = ChannelKind::of::<MyOtherChannel>();
+        assert_eq!(
+            registry
+                .get_builder_from_kind(&other_kind)
+                .unwrap()
+                .settings,
+            defaults
+        );
+
+        let cloned = registry.clone();
+        assert_eq!(cloned, registry);
+        for kind in [ChannelKind::of::<MyChannel>(), other_kind] {
+            assert_eq!(
+                cloned.get_net_from_kind(&kind),
+                registry.get_net_from_kind(&kind)
+            );
+        }
+        assert_eq!(cloned.schema(), registry.schema());
+    }
+
+    #[test]
+    fn test_add_channel_invalid_settings() {
+        let reliable = ChannelMode::OrderedReliable(ReliableSettings::default());
+        let cases = [
+            (
+                ChannelSettings {
+                    priority: -1.0,
+                    ..default()
+                },
+                ChannelConfigError::InvalidPriority(-1.0),
+            ),
+            (
+                ChannelSettings {
+                    weight: 0.0,
+                    ..default()
+                },
+                ChannelConfigError::InvalidWeight(0.0),
+            ),
+            (
+                ChannelSettings {
+                    mode: reliable.clone(),
+                    max_bytes_per_tick: Some(0),
+                    ..default()
+                },
+                ChannelConfigError::ZeroMaxBytesPerTick,
+            ),
+            (
+                ChannelSettings {
+                    max_bytes_per_tick: Some(100),
+                    min_bytes_per_tick: Some(200),
+                    ..default()
+                },
+                ChannelConfigError::MinBytesAboveMax {
+                    min_bytes_per_tick: 200,
+                    max_bytes_per_tick: 100,
+                },
+            ),
+            (
+                ChannelSettings {
+                    fragment_mtu: Some(10),
+                    ..default()
+                },
+                ChannelConfigError::FragmentMtuTooSmall {
+                    fragment_mtu: 10,
+                    min: MIN_FRAGMENT_MTU,
+                },
+            ),
+            (
+                ChannelSettings {
+                    mode: ChannelMode::UnorderedReliable(ReliableSettings {
+                        rtt_resend_factor: 0.0,
+                        ..default()
+                    }),
+                    ..default()
+                },
+                ChannelConfigError::InvalidResendFactor(0.0),
+            ),
+        ];
+        for (settings, error) in cases {
+            let mut registry = ChannelRegistry::default();
+            assert_eq!(registry.add_channel::<MyChannel>(settings), Err(error));
+            // the channel is not registered
+            assert_eq!(registry.len(), 0);
+        }
+
+        // a NaN priority is also rejected
+        let settings = ChannelSettings {
+            priority: f32::NAN,
+            ..default()
+        };
+        assert!(matches!(
+            settings.validate(),
+            Err(ChannelConfigError::InvalidPriority(_))
+        ));
+    }
+
+    #[test]
+    fn test_app_add_channel_invalid_settings() {
+        let mut app = App::new();
+        app.init_resource::<ChannelRegistry>();
+        let settings = ChannelSettings {
+            max_bytes_per_tick: Some(0),
+            ..default()
+        };
+        assert_eq!(
+            app.add_channel::<MyChannel>(settings),
+            Err(ChannelConfigError::ZeroMaxBytesPerTick)
+        );
+        assert_eq!(app.world().resource::<ChannelRegistry>().len(), 0);
+    }
+
+    #[test]
+    fn test_stable_net_ids_independent_of_order() {
+        let mut registry = ChannelRegistry::new(Duration::default()).with_stable_net_ids();
+        registry
+            .add_channel::<MyChannel>(ChannelSettings::default())
+            .unwrap();
+        registry
+            .add_channel::<MyOtherChannel>(ChannelSettings::default())
+            .unwrap();
+
+        let mut other_registry = ChannelRegistry::default();
+        other_registry
+            .add_channel::<MyOtherChannel>(ChannelSettings::default())
+            .unwrap();
+        other_registry
+            .add_channel::<MyChannel>(ChannelSettings::default())
+            .unwrap();
+        // the ids of the channels registered before are re-assigned
+        let other_registry = other_registry.with_stable_net_ids();
+
+        for kind in [
+            ChannelKind::of::<MyChannel>(),
+            ChannelKind::of::<MyOtherChannel>(),
+        ] {
+            assert_eq!(
+                registry.get_net_from_kind(&kind),
+                other_registry.get_net_from_kind(&kind)
+            );
+        }
+        let net_id = *registry
+            .get_net_from_kind(&ChannelKind::of::<MyChannel>())
+            .unwrap();
+        assert_eq!(
+            registry.get_kind_from_net_id(net_id),
+            Some(&ChannelKind::of::<MyChannel>())
+        );
+        assert_ne!(
+            registry.get_net_from_kind(&ChannelKind::of::<MyChannel>()),
+            registry.get_net_from_kind(&ChannelKind::of::<MyOtherChannel>())
+        );
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let mut registry = ChannelRegistry::default();
+        registry
+            .add_channel::<MyChannel>(ChannelSettings::default())
+            .unwrap();
+        let schema = registry.schema();
+        assert_eq!(
+            schema.channels,
+            vec![ChannelSchemaEntry {
+                channel_id: 0,
+                name: "MyChannel".to_string(),
+                mode: ChannelMode::UnorderedUnreliable.kind_id(),
+            }]
+        );
+        assert!(registry.check_schema(&schema).is_ok());
+
+        // the remote has a channel that is not registered locally
+        let mut remote_registry = registry.clone();
+        remote_registry
+            .add_channel::<MyOtherChannel>(ChannelSettings::default())
+            .unwrap();
+        assert!(matches!(
+            registry.check_schema(&remote_registry.schema()),
+            Err(PacketError::ChannelSchemaMismatch {
+                channel_id: 1,
+                local: None,
+                remote: Some(_),
+            })
+        ));
+        // the local registry has a channel that the remote does not have
+        assert!(matches!(
+            remote_registry.check_schema(&schema),
+            Err(PacketError::ChannelSchemaMismatch {
+                channel_id: 1,
+                local: Some(_),
+                remote: None,
+            })
+        ));
+    }
 }
@@ -14,6 +14,10 @@ impl ToBytes for NetId {
         varint_len(*self as u64)
     }
 
+    fn max_len() -> Option<usize> {
+        Some(varint_len(NetId::MAX as u64))
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         buffer.write_varint(*self as u64)?;
         Ok(())
@@ -23,7 +27,7 @@ impl ToBytes for NetId {
     where
         Self: Sized,
     {
-        Ok(buffer.read_varint()? as NetId)
+        NetId::try_from(buffer.read_varint()?).map_err(|_| SerializationError::InvalidValue)
     }
 }
 
@@ -65,6 +69,18 @@ impl<K: TypeKind> TypeMapper<K> {
         kind
     }
 
+    /// Register a type with a [`NetId`] chosen by the caller, who must make sure that it is unused
+    pub(crate) fn insert(&mut self, kind: K, net_id: NetId) {
+        if self.kind_map.contains_key(&kind) {
+            panic!(
+                "Type already registered with net id {:?}",
+                self.kind_map[&kind]
+            );
+        }
+        self.kind_map.insert(kind, net_id);
+        self.id_map.insert(net_id, kind);
+    }
+
     pub fn kind(&self, net_id: NetId) -> Option<&K> {
         self.id_map.get(&net_id)
     }
@@ -1,9 +1,13 @@
 use crate::prelude::{ComponentRegistry, Message, MessageRegistry};
+#[cfg(feature = "json")]
+use crate::serialize::ToBytes;
 use crate::serialize::{reader::Reader, writer::Writer, SerializationError};
 use crate::shared::replication::entity_map::{EntityMap, ReceiveEntityMap, SendEntityMap};
 use bevy::app::App;
 use bevy::ecs::entity::MapEntities;
 use bevy::ptr::{Ptr, PtrMut};
+#[cfg(feature = "json")]
+use bytes::Bytes;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::any::TypeId;
@@ -86,21 +90,68 @@ unsafe fn erased_serialize_fn<M: Message>(
     }
 }
 
-/// Default serialize function using bincode
-fn default_serialize<M: Message + Serialize>(
-    message: &M,
-    buffer: &mut Writer,
-) -> Result<(), SerializationError> {
-    let _ = bincode::serde::encode_into_std_write(message, buffer, bincode::config::standard())?;
-    Ok(())
+/// Encoding used for the body of a message or component.
+///
+/// The packet framing (headers, channel ids, number of messages) does not depend on the backend.
+/// Types registered without custom serialization functions use [`Bincode`]; another backend can
+/// be used with [`SerializeFns::from_backend`]. The backend is picked per type when it is
+/// registered: the [`PacketBuilder`](crate::packet::packet_builder::PacketBuilder) only handles
+/// the encoded bodies, so it is not generic over the backend.
+///
+/// Several bodies can be written one after the other, so `deserialize` must read exactly the
+/// bytes written by `serialize`.
+pub trait SerializeBackend {
+    fn serialize<M: Serialize>(message: &M, writer: &mut Writer) -> Result<(), SerializationError>;
+
+    fn deserialize<M: DeserializeOwned>(reader: &mut Reader) -> Result<M, SerializationError>;
 }
 
-/// Default deserialize function using bincode
-fn default_deserialize<M: Message + DeserializeOwned>(
-    buffer: &mut Reader,
-) -> Result<M, SerializationError> {
-    let data = bincode::serde::decode_from_std_read(buffer, bincode::config::standard())?;
-    Ok(data)
+/// Default [`SerializeBackend`], using bincode
+pub struct Bincode;
+
+impl SerializeBackend for Bincode {
+    fn serialize<M: Serialize>(message: &M, writer: &mut Writer) -> Result<(), SerializationError> {
+        let _ =
+            bincode::serde::encode_into_std_write(message, writer, bincode::config::standard())?;
+        Ok(())
+    }
+
+    fn deserialize<M: DeserializeOwned>(reader: &mut Reader) -> Result<M, SerializationError> {
+        let data = bincode::serde::decode_from_std_read(reader, bincode::config::standard())?;
+        Ok(data)
+    }
+}
+
+/// [`SerializeBackend`] that writes the messages as JSON, which is bigger but can be read by
+/// external tools.
+///
+/// The JSON text is prefixed with its length, as the end of some JSON values (like numbers) can
+/// only be found by reading the next byte.
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl SerializeBackend for Json {
+    fn serialize<M: Serialize>(message: &M, writer: &mut Writer) -> Result<(), SerializationError> {
+        let json = Bytes::from(serde_json::to_vec(message)?);
+        json.to_bytes(writer)
+    }
+
+    fn deserialize<M: DeserializeOwned>(reader: &mut Reader) -> Result<M, SerializationError> {
+        let json = Bytes::from_bytes(reader)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+impl<M: Serialize + DeserializeOwned> SerializeFns<M> {
+    /// Serialization functions that encode the type with the [`SerializeBackend`] `B`
+    pub fn from_backend<B: SerializeBackend>() -> Self {
+        Self {
+            serialize: B::serialize::<M>,
+            deserialize: B::deserialize::<M>,
+            serialize_map_entities: None,
+        }
+    }
 }
 
 pub(crate) fn serialize_map_entities<M>(
@@ -151,11 +202,7 @@ unsafe fn erased_receive_map_entities<M: MapEntities + 'static>(
 
 impl ErasedSerializeFns {
     pub(crate) fn new<M: Message + Serialize + DeserializeOwned>() -> Self {
-        let serialize_fns = SerializeFns {
-            serialize: default_serialize::<M>,
-            deserialize: default_deserialize::<M>,
-            serialize_map_entities: None,
-        };
+        let serialize_fns = SerializeFns::<M>::from_backend::<Bincode>();
         Self {
             type_id: TypeId::of::<M>(),
             type_name: std::any::type_name::<M>(),
@@ -294,7 +341,11 @@ impl AppSerializeExt for App {
 
 #[cfg(test)]
 mod tests {
-    use crate::protocol::serialize::{erased_serialize_fn, ErasedSerializeFns};
+    #[cfg(feature = "json")]
+    use crate::protocol::serialize::Json;
+    use crate::protocol::serialize::{
+        erased_serialize_fn, Bincode, ErasedSerializeFns, SerializeBackend, SerializeFns,
+    };
     use crate::serialize::reader::Reader;
     use crate::serialize::writer::Writer;
     use crate::shared::replication::authority::AuthorityChange;
@@ -302,6 +353,67 @@ mod tests {
     use bevy::prelude::Entity;
     use bevy::ptr::Ptr;
 
+    fn test_backend_round_trip<B: SerializeBackend>() {
+        let registry = ErasedSerializeFns::new_custom_serde::<AuthorityChange>(
+            SerializeFns::from_backend::<B>(),
+        );
+        let messages = [
+            AuthorityChange {
+                entity: Entity::from_raw(1),
+                gain_authority: true,
+            },
+            AuthorityChange {
+                entity: Entity::from_raw(2),
+                gain_authority: false,
+            },
+        ];
+        // the messages are written one after the other
+        let mut writer = Writer::default();
+        for message in messages.iter() {
+            unsafe { registry.serialize(message, &mut writer, None) }.unwrap();
+        }
+
+        let mut reader = Reader::from(writer.to_bytes());
+        for message in messages.iter() {
+            let new_message = unsafe {
+                registry
+                    .deserialize::<AuthorityChange>(&mut reader, &mut ReceiveEntityMap::default())
+            }
+            .unwrap();
+            assert_eq!(&new_message, message);
+        }
+    }
+
+    #[test]
+    fn test_bincode_backend() {
+        test_backend_round_trip::<Bincode>();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_backend() {
+        test_backend_round_trip::<Json>();
+    }
+
+    /// A JSON number is only complete once the next byte is read, which must not be taken from
+    /// the next message
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_backend_number() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Score(u32);
+
+        let fns = SerializeFns::<Score>::from_backend::<Json>();
+        let mut writer = Writer::default();
+        (fns.serialize)(&Score(12), &mut writer).unwrap();
+        (fns.serialize)(&Score(7), &mut writer).unwrap();
+
+        let mut reader = Reader::from(writer.to_bytes());
+        assert_eq!((fns.deserialize)(&mut reader).unwrap(), Score(12));
+        assert_eq!((fns.deserialize)(&mut reader).unwrap(), Score(7));
+        assert!(!reader.has_remaining());
+    }
+
     #[test]
     fn test_erased_serde() {
         let mut registry = ErasedSerializeFns::new::<AuthorityChange>();
@@ -1,14 +1,20 @@
 //! Serialization and deserialization of types
 
+use crate::protocol::channel::ChannelId;
 use crate::serialize::reader::Reader;
 use crate::serialize::varint::{varint_len, VarIntReadExt, VarIntWriteExt};
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use byteorder::{NetworkEndian, ReadBytesExt};
 use bytes::Bytes;
 use hashbrown::HashMap;
 use std::hash::{BuildHasher, Hash};
 
+/// Re-exported so that [`ToBytes`] can be implemented (or derived) without depending on `byteorder`
+pub use byteorder::WriteBytesExt;
+/// Derive [`ToBytes`] for a struct whose fields all implement [`ToBytes`]
+pub use lightyear_macros::ToBytes;
+
 pub mod reader;
-pub(crate) mod varint;
+pub mod varint;
 pub mod writer;
 
 pub type RawData = Vec<u8>;
@@ -22,19 +28,101 @@ pub enum SerializationError {
     InvalidPacketType,
     #[error("Invalid value")]
     InvalidValue,
+    #[deprecated(note = "use `SerializationError::SizeAccountingMismatch` instead")]
     #[error("Substraction overflow")]
     SubstractionOverflow,
+    #[error("Wrote {actual} bytes in the packet, but only {expected} bytes were reserved")]
+    SizeAccountingMismatch { expected: usize, actual: usize },
     #[error(transparent)]
     BincodeEncode(#[from] bincode::error::EncodeError),
     #[error(transparent)]
     BincodeDecode(#[from] bincode::error::DecodeError),
-    #[error("The message is too big ({0} bytes) to be sent. We can split a message only up to 256 fragments.")]
-    MessageTooBig(usize),
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("The message is too big ({size} bytes) to be sent. We can split a message only up to {max_fragments} fragments.")]
+    MessageTooLarge { size: usize, max_fragments: usize },
+    #[error("The message ({size} bytes) does not fit in an empty packet, it should have been fragmented")]
+    MessageShouldBeFragmented { size: usize },
+    #[error("The message of channel {channel_id} ({size} bytes) must be fragmented, but it has no message id")]
+    MissingFragmentMessageId { channel_id: ChannelId, size: usize },
+    #[error("The fragmentation policy returned an invalid fragment (offset {offset}, {len} bytes) for a message of {size} bytes split into fragments of at most {fragment_size} bytes")]
+    InvalidFragment {
+        size: usize,
+        fragment_size: usize,
+        offset: usize,
+        len: usize,
+    },
+    #[error("The channel id {channel_id} does not fit in an empty packet")]
+    ChannelTooLargeForEmptyPacket { channel_id: ChannelId },
+    #[error("The checksum of the packet does not match its content")]
+    ChecksumMismatch,
+    #[error("The message length ({len} bytes) is bigger than the rest of the buffer ({remaining} bytes)")]
+    TruncatedMessage { len: usize, remaining: usize },
+    #[error("{count} messages cannot fit in the rest of the buffer ({remaining} bytes)")]
+    CountExceedsBuffer { count: u64, remaining: usize },
+    #[error("The packet claims to contain {claimed} messages, but at most {max} messages are allowed in a packet")]
+    TooManyMessages { claimed: u64, max: usize },
+    #[error("The channel id {0} is not valid")]
+    UnknownChannel(u64),
+    #[error("{in_flight} message ids are waiting for an ack, no message id can be allocated")]
+    MessageIdsExhausted { in_flight: usize },
+    #[error("Channel {channel_id} is encrypted, but no cipher was provided")]
+    MissingCipher { channel_id: ChannelId },
+    #[error("The messages of channel {channel_id} could not be decrypted")]
+    DecryptionFailed { channel_id: ChannelId },
+    #[error("Two messages of channel {channel_id} have the message id {message_id}")]
+    DuplicateMessageId {
+        channel_id: ChannelId,
+        message_id: u16,
+    },
+    #[error("The stream announces a packet of {len} bytes, but packets are at most {max} bytes")]
+    StreamPacketTooLarge { len: u64, max: usize },
+    #[error("The packet was compressed with the dictionary {id}, which is not known")]
+    UnknownCompressionDictionary { id: u8 },
+    #[error("The packet decompresses to {len} bytes, but packets are at most {max} bytes")]
+    DecompressedPacketTooLarge { len: usize, max: usize },
+    #[error(
+        "The packet is compressed, but compression support (the `lz4` feature) is not enabled"
+    )]
+    CompressionUnsupported,
+    #[error("The transport cannot send the packets of a builder with the option {option}")]
+    UnsupportedTransportOption { option: &'static str },
+    #[error(
+        "The packet starts with its stream length prefix, its header cannot be edited in place"
+    )]
+    StreamFramedPacket,
+    #[error("Could not write the message {message_index} of channel {channel_id} in the packet")]
+    InChannel {
+        channel_id: ChannelId,
+        /// Index of the message among the messages of the channel written in the packet
+        message_index: usize,
+        #[source]
+        source: Box<SerializationError>,
+    },
+}
+
+impl SerializationError {
+    /// Attribute the error to the message `message_index` of the channel `channel_id`
+    pub(crate) fn in_channel(self, channel_id: ChannelId, message_index: usize) -> Self {
+        SerializationError::InChannel {
+            channel_id,
+            message_index,
+            source: Box::new(self),
+        }
+    }
 }
 
 #[allow(clippy::len_without_is_empty)]
 pub trait ToBytes {
     fn len(&self) -> usize;
+
+    /// Upper bound on [`ToBytes::len`] for any value of the type, so that space can be reserved
+    /// before the value is known. None if the serialized size is not bounded (for example for
+    /// a type that contains arbitrary bytes or a list).
+    fn max_len() -> Option<usize>
+    where
+        Self: Sized;
     fn to_bytes<T: byteorder::WriteBytesExt>(
         &self,
         buffer: &mut T,
@@ -53,6 +141,10 @@ impl<M: ToBytes> ToBytes for Option<M> {
         }
     }
 
+    fn max_len() -> Option<usize> {
+        M::max_len().map(|len| 1 + len)
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         match self {
             Some(value) => {
@@ -79,12 +171,150 @@ impl<M: ToBytes> ToBytes for Option<M> {
     }
 }
 
+impl ToBytes for u8 {
+    fn len(&self) -> usize {
+        1
+    }
+
+    fn max_len() -> Option<usize> {
+        Some(1)
+    }
+
+    fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
+        Ok(buffer.write_u8(*self)?)
+    }
+
+    fn from_bytes(buffer: &mut Reader) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        Ok(buffer.read_u8()?)
+    }
+}
+
+impl ToBytes for bool {
+    fn len(&self) -> usize {
+        1
+    }
+
+    fn max_len() -> Option<usize> {
+        Some(1)
+    }
+
+    fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
+        Ok(buffer.write_u8(*self as u8)?)
+    }
+
+    fn from_bytes(buffer: &mut Reader) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        match buffer.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SerializationError::InvalidValue),
+        }
+    }
+}
+
+impl ToBytes for u32 {
+    fn len(&self) -> usize {
+        varint_len(*self as u64)
+    }
+
+    fn max_len() -> Option<usize> {
+        Some(varint_len(u32::MAX as u64))
+    }
+
+    fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
+        buffer.write_varint(*self as u64)
+    }
+
+    fn from_bytes(buffer: &mut Reader) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        u32::try_from(buffer.read_varint()?).map_err(|_| SerializationError::InvalidValue)
+    }
+}
+
+/// u64 values are written with a fixed size, because a varint cannot hold the full range
+impl ToBytes for u64 {
+    fn len(&self) -> usize {
+        8
+    }
+
+    fn max_len() -> Option<usize> {
+        Some(8)
+    }
+
+    fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
+        Ok(buffer.write_u64::<NetworkEndian>(*self)?)
+    }
+
+    fn from_bytes(buffer: &mut Reader) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        Ok(buffer.read_u64::<NetworkEndian>()?)
+    }
+}
+
+impl ToBytes for f32 {
+    fn len(&self) -> usize {
+        4
+    }
+
+    fn max_len() -> Option<usize> {
+        Some(4)
+    }
+
+    fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
+        Ok(buffer.write_f32::<NetworkEndian>(*self)?)
+    }
+
+    fn from_bytes(buffer: &mut Reader) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        Ok(buffer.read_f32::<NetworkEndian>()?)
+    }
+}
+
+impl ToBytes for String {
+    fn len(&self) -> usize {
+        varint_len(self.len() as u64) + self.len()
+    }
+
+    fn max_len() -> Option<usize> {
+        None
+    }
+
+    fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
+        buffer.write_varint(self.len() as u64)?;
+        buffer.write_all(self.as_bytes())?;
+        Ok(())
+    }
+
+    fn from_bytes(buffer: &mut Reader) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        let bytes = Bytes::from_bytes(buffer)?;
+        String::from_utf8(bytes.into()).map_err(|_| SerializationError::InvalidValue)
+    }
+}
+
 /// When we read, instead of allocating we just create a new Bytes by slicing the buffer
 impl ToBytes for Bytes {
     fn len(&self) -> usize {
         varint_len(self.len() as u64) + self.len()
     }
 
+    fn max_len() -> Option<usize> {
+        None
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         buffer.write_varint(self.len() as u64)?;
         buffer.write_all(self.as_ref())?;
@@ -95,9 +325,15 @@ impl ToBytes for Bytes {
     where
         Self: Sized,
     {
-        let len = buffer.read_varint()? as usize;
-        let bytes = buffer.split_len(len);
-        Ok(bytes)
+        let len = buffer.read_varint()?;
+        let remaining = buffer.remaining();
+        if len > remaining as u64 {
+            return Err(SerializationError::TruncatedMessage {
+                len: len as usize,
+                remaining,
+            });
+        }
+        Ok(buffer.split_len(len as usize))
     }
 }
 
@@ -115,6 +351,10 @@ macro_rules! impl_tuple_query_data {
                 len
             }
 
+            fn max_len() -> Option<usize> {
+                Some(0 $(+ $name::max_len()?)*)
+            }
+
             fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
                 let ($($name,)*) = self;
                 $($name.to_bytes(buffer)?;)*
@@ -131,8 +371,13 @@ macro_rules! impl_tuple_query_data {
 bevy::utils::all_tuples!(impl_tuple_query_data, 1, 8, P);
 
 impl<M: ToBytes> ToBytes for Vec<M> {
+    // the number of items is written as a u64
     fn len(&self) -> usize {
-        varint_len(self.len() as u64) + self.iter().map(ToBytes::len).sum::<usize>()
+        8 + self.iter().map(ToBytes::len).sum::<usize>()
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -147,7 +392,8 @@ impl<M: ToBytes> ToBytes for Vec<M> {
     {
         let len = buffer.read_u64::<byteorder::NetworkEndian>()? as usize;
         // TODO: if we know the MIN_LEN we can preallocate
-        let mut vec = Vec::with_capacity(len);
+        // (the length comes from the remote peer, so don't preallocate more than the buffer can hold)
+        let mut vec = Vec::with_capacity(len.min(buffer.remaining()));
         for _ in 0..len {
             vec.push(M::from_bytes(buffer)?);
         }
@@ -156,8 +402,13 @@ impl<M: ToBytes> ToBytes for Vec<M> {
 }
 
 impl<K: ToBytes + Eq + Hash, V: ToBytes, S: Default + BuildHasher> ToBytes for HashMap<K, V, S> {
+    // the number of entries is written as a u64
     fn len(&self) -> usize {
-        varint_len(self.len() as u64) + self.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+        8 + self.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -190,6 +441,149 @@ impl<K: ToBytes + Eq + Hash, V: ToBytes, S: Default + BuildHasher> ToBytes for H
 mod tests {
     use super::*;
     use crate::serialize::writer::Writer;
+    use lightyear_macros::ToBytesInternal;
+
+    #[derive(ToBytesInternal, Debug, PartialEq)]
+    struct DerivedMessage {
+        id: u32,
+        flag: bool,
+        kind: u8,
+        timestamp: u64,
+        value: f32,
+        payload: Bytes,
+        extra: Option<u16>,
+    }
+
+    #[derive(ToBytesInternal, Debug, PartialEq)]
+    struct DerivedTuple(u16, Bytes);
+
+    #[test]
+    fn test_derive_to_bytes() {
+        let message = DerivedMessage {
+            id: 100_000,
+            flag: true,
+            kind: 3,
+            timestamp: u64::MAX,
+            value: 1.5,
+            payload: Bytes::from(vec![7; 70]),
+            extra: Some(300),
+        };
+        let mut writer = Writer::with_capacity(10);
+        message.to_bytes(&mut writer).unwrap();
+        let bytes = writer.to_bytes();
+        assert_eq!(bytes.len(), message.len());
+        let mut reader = Reader::from(bytes);
+        assert_eq!(DerivedMessage::from_bytes(&mut reader).unwrap(), message);
+        assert!(!reader.has_remaining());
+
+        let tuple = DerivedTuple(5, Bytes::from("hello"));
+        let mut writer = Writer::with_capacity(10);
+        tuple.to_bytes(&mut writer).unwrap();
+        let bytes = writer.to_bytes();
+        assert_eq!(bytes.len(), tuple.len());
+        let mut reader = Reader::from(bytes);
+        assert_eq!(DerivedTuple::from_bytes(&mut reader).unwrap(), tuple);
+    }
+
+    /// Serialize `value`, and check that it takes exactly `len()` bytes, and at most `max_len()`
+    pub(crate) fn assert_len_within_max_len<M: ToBytes>(value: &M) {
+        let mut writer = Writer::with_capacity(10);
+        value.to_bytes(&mut writer).unwrap();
+        let written = writer.to_bytes().len();
+        assert_eq!(written, value.len());
+        if let Some(max_len) = M::max_len() {
+            assert!(written <= max_len, "{written} > {max_len}");
+        }
+    }
+
+    #[test]
+    fn test_len_within_max_len() {
+        use crate::connection::id::ClientId;
+        use crate::packet::message::{FragmentData, MessageId, SingleData};
+        use crate::shared::ping::message::{Ping, Pong};
+        use crate::shared::ping::store::PingId;
+        use crate::shared::replication::components::ReplicationGroupId;
+        use crate::shared::replication::network_target::NetworkTarget;
+        use crate::shared::replication::SpawnAction;
+        use crate::shared::tick_manager::Tick;
+        use crate::shared::time_manager::WrappedTime;
+        use bevy::prelude::Entity;
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        use std::time::Duration;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        // values spread over all the varint lengths
+        let small_u32 = |rng: &mut StdRng| rng.gen::<u32>() >> rng.gen_range(0..32);
+        let bytes = |rng: &mut StdRng| Bytes::from(vec![0; rng.gen_range(0..300)]);
+        for _ in 0..1000 {
+            assert_len_within_max_len(&rng.gen::<u8>());
+            assert_len_within_max_len(&rng.gen::<bool>());
+            assert_len_within_max_len(&small_u32(&mut rng));
+            assert_len_within_max_len(&rng.gen::<u64>());
+            assert_len_within_max_len(&rng.gen::<f32>());
+            assert_len_within_max_len(&(rng.gen::<u16>() >> rng.gen_range(0..16)));
+            assert_len_within_max_len(&rng.gen_bool(0.5).then(|| small_u32(&mut rng)));
+            assert_len_within_max_len(&(rng.gen::<u8>(), small_u32(&mut rng), rng.gen::<u64>()));
+            assert_len_within_max_len(&bytes(&mut rng));
+            assert_len_within_max_len(&String::from("a").repeat(rng.gen_range(0..300)));
+            assert_len_within_max_len(
+                &(0..rng.gen_range(0..10))
+                    .map(|_| small_u32(&mut rng))
+                    .collect::<Vec<_>>(),
+            );
+            assert_len_within_max_len(
+                &(0..rng.gen_range(0..10))
+                    .map(|i| (i, small_u32(&mut rng)))
+                    .collect::<HashMap<u8, u32>>(),
+            );
+            assert_len_within_max_len(&Tick(rng.gen()));
+            assert_len_within_max_len(&Ping {
+                id: PingId(rng.gen()),
+            });
+            assert_len_within_max_len(&Pong {
+                ping_id: PingId(rng.gen()),
+                ping_received_time: WrappedTime::new(small_u32(&mut rng)),
+                pong_sent_time: WrappedTime {
+                    elapsed: Duration::from_millis(rng.gen()),
+                },
+            });
+            assert_len_within_max_len(&ReplicationGroupId(rng.gen()));
+            let entity = Entity::from_raw(small_u32(&mut rng) >> 1);
+            assert_len_within_max_len(&entity);
+            assert_len_within_max_len(&SpawnAction::Reuse(entity));
+            let client_id = ClientId::Netcode(rng.gen());
+            assert_len_within_max_len(&client_id);
+            assert_len_within_max_len(&NetworkTarget::Only(vec![client_id; rng.gen_range(0..5)]));
+            let id = rng.gen_bool(0.5).then(|| MessageId(rng.gen()));
+            assert_len_within_max_len(
+                &SingleData::new(id, bytes(&mut rng)).with_sub_channel(rng.gen()),
+            );
+            assert_len_within_max_len(&FragmentData {
+                message_id: MessageId(rng.gen()),
+                fragment_id: rng.gen(),
+                num_fragments: rng.gen(),
+                offset: small_u32(&mut rng),
+                bytes: bytes(&mut rng),
+            });
+            assert_len_within_max_len(&DerivedMessage {
+                id: small_u32(&mut rng),
+                flag: rng.gen(),
+                kind: rng.gen(),
+                timestamp: rng.gen(),
+                value: rng.gen(),
+                payload: bytes(&mut rng),
+                extra: rng.gen_bool(0.5).then(|| rng.gen()),
+            });
+        }
+        // the types that contain arbitrary bytes or lists are not bounded
+        assert_eq!(SingleData::max_len(), None);
+        assert_eq!(Vec::<u8>::max_len(), None);
+        assert_eq!(DerivedMessage::max_len(), None);
+        // a varint takes 1, 2, 4 or 8 bytes
+        assert_eq!(<(u8, u32, Option<u16>)>::max_len(), Some(1 + 8 + 1 + 4));
+        assert_eq!(Entity::max_len(), Some(8 + 4));
+    }
 
     #[test]
     fn test_serialize_bytes() {
@@ -51,6 +51,11 @@ impl Reader {
         bytes
     }
 
+    /// Returns the next byte without consuming it
+    pub(crate) fn peek_u8(&self) -> Option<u8> {
+        self.0.chunk().first().copied()
+    }
+
     pub(crate) fn has_remaining(&self) -> bool {
         self.0.has_remaining()
     }
@@ -1,6 +1,13 @@
+//! Variable-length integers, using the same encoding as QUIC.
+//!
+//! The two most significant bits of the first byte give the length of the integer (1, 2, 4 or 8
+//! bytes), so the biggest value that can be encoded is [`VARINT_MAX`].
 use crate::serialize::SerializationError;
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
-use std::io::Seek;
+use std::io::{Read, Seek, Write};
+
+/// Biggest value that can be encoded as a variable-length integer
+pub const VARINT_MAX: u64 = 4_611_686_018_427_387_903;
 
 /// Returns how many bytes it would take to encode `v` as a variable-length
 /// integer.
@@ -13,7 +20,7 @@ pub const fn varint_len(v: u64) -> usize {
         2
     } else if v <= 1_073_741_823 {
         4
-    } else if v <= 4_611_686_018_427_387_903 {
+    } else if v <= VARINT_MAX {
         8
     } else {
         // NOTE: cannot use a value that is close to u64::MAX
@@ -32,28 +39,37 @@ pub const fn varint_parse_len(first: u8) -> usize {
     }
 }
 
+/// Write `value` as a variable-length integer, in network byte order.
+///
+/// Writes exactly [`varint_len`] bytes, or returns an error if `value` is bigger than [`VARINT_MAX`].
+pub fn write_varint(buf: &mut (impl Write + ?Sized), value: u64) -> Result<(), SerializationError> {
+    if value > VARINT_MAX {
+        return Err(std::io::Error::other("value is too large for varint").into());
+    }
+    match varint_len(value) {
+        1 => buf.write_u8(value as u8)?,
+        2 => buf.write_u16::<NetworkEndian>((value as u16) | 0x4000)?,
+        4 => buf.write_u32::<NetworkEndian>((value as u32) | 0x8000_0000)?,
+        _ => buf.write_u64::<NetworkEndian>(value | 0xc000_0000_0000_0000)?,
+    };
+    Ok(())
+}
+
+/// Read a variable-length integer written by [`write_varint`]
+pub fn read_varint(reader: &mut impl Read) -> Result<u64, SerializationError> {
+    let first = reader.read_u8()?;
+    let len = varint_parse_len(first);
+    let mut value = u64::from(first & 0x3f);
+    for _ in 1..len {
+        value = (value << 8) | u64::from(reader.read_u8()?);
+    }
+    Ok(value)
+}
+
 pub trait VarIntWriteExt: WriteBytesExt {
     /// Write a variable length integer to the writer, in network byte order
     fn write_varint(&mut self, value: u64) -> Result<(), SerializationError> {
-        let len = varint_len(value);
-        match len {
-            1 => self.write_u8(value as u8)?,
-            2 => {
-                let val = (value as u16) | 0x4000;
-                self.write_u16::<NetworkEndian>(val)?;
-            }
-            4 => {
-                let val = (value as u32) | 0x8000_0000;
-                self.write_u32::<NetworkEndian>(val)?;
-            }
-            8 => {
-                let val = value | 0xc0_0000_0000_0000;
-                self.write_u64::<NetworkEndian>(val)?;
-            }
-            _ => return Err(std::io::Error::other("value is too large for varint").into()),
-        };
-
-        Ok(())
+        write_varint(self, value)
     }
 }
 
@@ -92,9 +108,40 @@ impl<T: ReadBytesExt + Seek> VarIntReadExt for T {}
 
 #[cfg(test)]
 mod tests {
-    use crate::serialize::varint::{VarIntReadExt, VarIntWriteExt};
+    use crate::serialize::varint::{
+        read_varint, varint_len, write_varint, VarIntReadExt, VarIntWriteExt, VARINT_MAX,
+    };
     use std::io::Cursor;
 
+    #[test]
+    fn test_varint_boundaries() {
+        for value in [
+            0,
+            63,
+            64,
+            (1 << 14) - 1,
+            1 << 14,
+            (1 << 30) - 1,
+            1 << 30,
+            VARINT_MAX,
+        ] {
+            let mut writer = vec![];
+            write_varint(&mut writer, value).unwrap();
+            assert_eq!(writer.len(), varint_len(value), "value: {value}");
+
+            assert_eq!(read_varint(&mut writer.as_slice()).unwrap(), value);
+            // the seeking reader agrees with the free function
+            assert_eq!(Cursor::new(writer).read_varint().unwrap(), value);
+        }
+
+        // bigger values cannot be encoded
+        for value in [VARINT_MAX + 1, u64::MAX] {
+            let mut writer = vec![];
+            assert!(write_varint(&mut writer, value).is_err());
+            assert!(writer.is_empty());
+        }
+    }
+
     #[test]
     fn test_varint_len_1() {
         // TEST WITH 1
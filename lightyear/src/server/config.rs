@@ -8,6 +8,8 @@ use crate::connection::netcode::{Key, PRIVATE_KEY_BYTES};
 use crate::connection::server::{
     ConnectionRequestHandler, DefaultConnectionRequestHandler, NetConfig,
 };
+use crate::packet::error::PacketError;
+use crate::packet::wire_config::WireConfig;
 use crate::prelude::ReplicationConfig;
 use crate::shared::config::SharedConfig;
 use crate::shared::ping::manager::PingConfig;
@@ -67,6 +69,9 @@ pub struct PacketConfig {
     pub per_client_send_bandwidth_cap: Quota,
     /// If false, there is no bandwidth cap and all messages are sent as soon as possible
     pub bandwidth_cap_enabled: bool,
+    /// How the packets are written on the wire (MTU, checksum, compression, padding).
+    /// The client and the server must use the same config.
+    pub wire_config: WireConfig,
 }
 
 impl Default for PacketConfig {
@@ -76,6 +81,7 @@ impl Default for PacketConfig {
             // 56 KB/s bandwidth cap
             per_client_send_bandwidth_cap: Quota::per_second(nonzero!(56000u32)),
             bandwidth_cap_enabled: false,
+            wire_config: WireConfig::default(),
         }
     }
 }
@@ -96,6 +102,17 @@ impl PacketConfig {
         self.bandwidth_cap_enabled = true;
         self
     }
+
+    /// Write the packets with this [`WireConfig`].
+    ///
+    /// Returns an error if the config is invalid (for example an MTU that is too small to hold a
+    /// fragment). An invalid config that is set directly on the field is replaced by the default
+    /// config when the connection is created.
+    pub fn with_wire_config(mut self, wire_config: WireConfig) -> Result<Self, PacketError> {
+        wire_config.validate()?;
+        self.wire_config = wire_config;
+        Ok(self)
+    }
 }
 
 /// Configuration for the server plugin.
@@ -171,4 +188,63 @@ mod tests {
             &NetworkingState::Disconnected
         );
     }
+
+    /// The wire config of the packet config is used by the connections of both peers
+    #[test]
+    fn test_wire_config() -> Result<(), PacketError> {
+        let mut stepper = BevyStepper::default();
+        stepper.stop();
+
+        let wire_config = WireConfig::default().with_checksum(true);
+        assert!(matches!(
+            PacketConfig::default().with_wire_config(WireConfig {
+                mtu: 10,
+                ..wire_config
+            }),
+            Err(PacketError::MtuTooSmall { .. })
+        ));
+        stepper
+            .server_app
+            .world_mut()
+            .resource_mut::<ServerConfig>()
+            .packet
+            .wire_config = wire_config;
+        stepper
+            .client_app
+            .world_mut()
+            .resource_mut::<crate::prelude::client::ClientConfig>()
+            .packet
+            .wire_config = wire_config;
+        stepper.start();
+
+        assert_eq!(
+            stepper
+                .client_app
+                .world()
+                .resource::<State<NetworkingState>>()
+                .get(),
+            &NetworkingState::Connected
+        );
+        assert_eq!(
+            stepper
+                .client_app
+                .world()
+                .resource::<crate::prelude::client::ConnectionManager>()
+                .message_manager
+                .wire_config(),
+            wire_config
+        );
+        assert_eq!(
+            stepper
+                .server_app
+                .world()
+                .resource::<crate::prelude::server::ConnectionManager>()
+                .connection(ClientId::Netcode(TEST_CLIENT_ID))
+                .unwrap()
+                .message_manager
+                .wire_config(),
+            wire_config
+        );
+        Ok(())
+    }
 }
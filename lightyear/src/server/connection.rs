@@ -495,7 +495,10 @@ impl Connection {
             channel_registry,
             packet_config.nack_rtt_multiple,
             packet_config.into(),
-        );
+        )
+        .with_wire_config(packet_config.wire_config.or_default_if_invalid())
+        .expect("the wire config is valid")
+        .with_handshake(true);
         // get notified about acks/nacks for replication-update messages
         let entity_updates_sender = &mut message_manager
             .channels
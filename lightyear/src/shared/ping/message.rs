@@ -17,6 +17,10 @@ impl ToBytes for Ping {
         2
     }
 
+    fn max_len() -> Option<usize> {
+        Some(2)
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         self.id.to_bytes(buffer)
     }
@@ -47,6 +51,10 @@ impl ToBytes for Pong {
         10
     }
 
+    fn max_len() -> Option<usize> {
+        Some(10)
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         self.ping_id.to_bytes(buffer)?;
         self.ping_received_time.to_bytes(buffer)?;
@@ -288,6 +288,10 @@ impl ToBytes for ReplicationGroupId {
         8
     }
 
+    fn max_len() -> Option<usize> {
+        Some(8)
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         buffer.write_u64::<NetworkEndian>(self.0)?;
         Ok(())
@@ -125,12 +125,14 @@ impl DeltaManager {
     /// We remove every tick that is too old (which means we cannot do delta compression and
     /// we will be sending a full component value)
     pub(crate) fn tick_cleanup(&mut self, current_tick: Tick) {
-        let delta = (u16::MAX / 3) as i16;
+        // keep the ticks that are less than `max_age` ticks before the current tick
+        // (using the wrapping distance, so that ticks just before a wrap-around are kept)
+        let max_age = i32::from(u16::MAX / 3);
         self.acks.values_mut().for_each(|group_data| {
-            group_data.retain(|k, _| *k - current_tick > delta);
+            group_data.retain(|k, _| current_tick.diff(*k) < max_age);
         });
         self.data.data.values_mut().for_each(|group_data| {
-            group_data.retain(|k, _| *k - current_tick > delta);
+            group_data.retain(|k, _| current_tick.diff(*k) < max_age);
         });
     }
 }
@@ -279,4 +281,60 @@ mod tests {
         let retrieved_component = unsafe { retrieved.deref::<ComponentDeltaCompression>() };
         assert_eq!(retrieved_component, &component);
     }
+
+    /// Ticks just before the wrap-around are older than the ticks just after it
+    #[test]
+    fn test_delete_old_data_tick_wrapping() {
+        let mut registry = ComponentRegistry::default();
+        registry.register_component::<ComponentDeltaCompression>();
+        registry.set_delta_compression::<ComponentDeltaCompression>();
+        let mut store = DeltaComponentStore::default();
+        let entity = Entity::from_raw(0);
+        let replication_group = ReplicationGroupId(0);
+        let component = ComponentDeltaCompression(vec![1, 2]);
+        let ptr = Ptr::from(&component);
+        let kind = ComponentKind::of::<ComponentDeltaCompression>();
+
+        for tick in [Tick(65534), Tick(65535), Tick(0), Tick(1)] {
+            store.store_component_value(entity, tick, kind, ptr, replication_group, &registry);
+        }
+        store.delete_old_data(Tick(0), replication_group, &registry);
+
+        for (tick, kept) in [
+            (Tick(65534), false),
+            (Tick(65535), false),
+            (Tick(0), true),
+            (Tick(1), true),
+        ] {
+            assert_eq!(
+                store
+                    .get_component_value(entity, tick, kind, replication_group)
+                    .is_some(),
+                kept,
+                "{tick:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tick_cleanup_tick_wrapping() {
+        let mut manager = DeltaManager::default();
+        let replication_group = ReplicationGroupId(0);
+        manager.acks.insert(
+            replication_group,
+            HashMap::from_iter([
+                // before the wrap-around, but recent
+                (Tick(65000), 1),
+                (Tick(100), 1),
+                // not acked yet, more recent than the current tick
+                (Tick(300), 1),
+                // older than u16::MAX / 3 ticks
+                (Tick(43000), 1),
+            ]),
+        );
+        manager.tick_cleanup(Tick(200));
+        let mut ticks: Vec<_> = manager.acks[&replication_group].keys().copied().collect();
+        ticks.sort();
+        assert_eq!(ticks, vec![Tick(65000), Tick(100), Tick(300)]);
+    }
 }
@@ -45,7 +45,11 @@ pub(crate) mod systems;
 /// TODO: optimize for the case where generation == 1, which should be most cases
 impl ToBytes for Entity {
     fn len(&self) -> usize {
-        varint_len(self.index() as u64) + varint_len(self.generation() as u64)
+        varint_len(self.index() as u64) + 4
+    }
+
+    fn max_len() -> Option<usize> {
+        Some(varint_len(u32::MAX as u64) + 4)
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -80,7 +84,14 @@ pub struct EntityActions {
 
 impl ToBytes for EntityActions {
     fn len(&self) -> usize {
-        self.spawn.len() + self.insert.len() + self.remove.len() + self.updates.len()
+        self.spawn.len()
+            + ToBytes::len(&self.insert)
+            + ToBytes::len(&self.remove)
+            + ToBytes::len(&self.updates)
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -123,6 +134,10 @@ impl ToBytes for SpawnAction {
         }
     }
 
+    fn max_len() -> Option<usize> {
+        Entity::max_len().map(|len| 1 + len)
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         match &self {
             SpawnAction::None => buffer.write_u8(0)?,
@@ -170,7 +185,11 @@ pub struct SendEntityActionsMessage {
 
 impl ToBytes for SendEntityActionsMessage {
     fn len(&self) -> usize {
-        self.sequence_id.len() + self.group_id.len() + self.actions.len()
+        self.sequence_id.len() + self.group_id.len() + ToBytes::len(&self.actions)
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -203,7 +222,11 @@ pub struct EntityActionsMessage {
 
 impl ToBytes for EntityActionsMessage {
     fn len(&self) -> usize {
-        self.sequence_id.len() + self.group_id.len() + self.actions.len()
+        self.sequence_id.len() + self.group_id.len() + ToBytes::len(&self.actions)
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -239,7 +262,11 @@ pub struct SendEntityUpdatesMessage {
 
 impl ToBytes for SendEntityUpdatesMessage {
     fn len(&self) -> usize {
-        self.group_id.len() + self.last_action_tick.len() + self.updates.len()
+        self.group_id.len() + self.last_action_tick.len() + ToBytes::len(&self.updates)
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -278,7 +305,11 @@ pub struct EntityUpdatesMessage {
 
 impl ToBytes for EntityUpdatesMessage {
     fn len(&self) -> usize {
-        self.group_id.len() + self.last_action_tick.len() + self.updates.len()
+        self.group_id.len() + self.last_action_tick.len() + ToBytes::len(&self.updates)
+    }
+
+    fn max_len() -> Option<usize> {
+        None
     }
 
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
@@ -29,13 +29,17 @@ impl ToBytes for NetworkTarget {
         match self {
             NetworkTarget::None => 1,
             NetworkTarget::AllExceptSingle(client_id) => 1 + client_id.len(),
-            NetworkTarget::AllExcept(client_ids) => 1 + client_ids.len(),
+            NetworkTarget::AllExcept(client_ids) => 1 + ToBytes::len(client_ids),
             NetworkTarget::All => 1,
-            NetworkTarget::Only(client_ids) => 1 + client_ids.len(),
+            NetworkTarget::Only(client_ids) => 1 + ToBytes::len(client_ids),
             NetworkTarget::Single(client_id) => 1 + client_id.len(),
         }
     }
 
+    fn max_len() -> Option<usize> {
+        None
+    }
+
     fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
         match self {
             NetworkTarget::None => {
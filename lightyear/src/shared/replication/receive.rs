@@ -214,7 +214,7 @@ impl ReplicationReceiver {
             debug!("Checking group channel: {:?}", group_channel);
             if let Some(latest_tick) = group_channel.latest_tick {
                 // delta = u16::MAX / 4
-                if tick - latest_tick > (i16::MAX / 2) {
+                if tick.diff(latest_tick) > i32::from(i16::MAX / 2) {
                     debug!(
                     ?tick,
                     ?latest_tick,
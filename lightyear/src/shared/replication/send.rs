@@ -259,13 +259,13 @@ impl ReplicationSender {
     /// Do some internal bookkeeping:
     /// - handle tick wrapping
     pub(crate) fn cleanup(&mut self, tick: Tick) {
-        let delta = (u16::MAX / 4) as i16;
+        let delta = i32::from(u16::MAX / 4);
         // if it's been enough time since we last any action for the group, we can set the last_action_tick to None
         // (meaning that there's no need when we receive the update to check if we have already received a previous action)
         for group_channel in self.group_channels.values_mut() {
             debug!("Checking group channel: {:?}", group_channel);
             if let Some(last_action_tick) = group_channel.last_action_tick {
-                if tick - last_action_tick > delta {
+                if tick.diff(last_action_tick) > delta {
                     debug!(
                     ?tick,
                     ?last_action_tick,
@@ -275,7 +275,7 @@ impl ReplicationSender {
                 }
             }
             if let Some(ack_tick) = group_channel.ack_tick {
-                if tick - ack_tick > delta {
+                if tick.diff(ack_tick) > delta {
                     group_channel.ack_tick = None;
                 }
             }
@@ -157,6 +157,10 @@ mod wrapped_time {
             4
         }
 
+        fn max_len() -> Option<usize> {
+            Some(4)
+        }
+
         // NOTE: we only encode the milliseconds up to u32, which is 46 days
         fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
             let millis: u32 = self.elapsed.as_millis().try_into().unwrap_or(u32::MAX);
@@ -256,10 +256,12 @@ impl Plugin for ProtocolPlugin {
         app.add_channel::<Channel1>(ChannelSettings {
             mode: ChannelMode::UnorderedUnreliable,
             ..default()
-        });
+        })
+        .unwrap();
         app.add_channel::<Channel2>(ChannelSettings {
             mode: ChannelMode::UnorderedUnreliableWithAcks,
             ..default()
-        });
+        })
+        .unwrap();
     }
 }
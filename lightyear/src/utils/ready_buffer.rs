@@ -241,6 +241,24 @@ mod tests {
         assert_eq!(buffer.len(), 1);
     }
 
+    /// Ticks after the wrap-around are more recent than the ticks before it
+    #[test]
+    fn test_pop_until_tick_wrapping() {
+        let mut buffer = ReadyBuffer::new();
+        buffer.push(Tick(1), 1);
+        buffer.push(Tick(65535), 65535);
+        buffer.push(Tick(65534), 65534);
+        buffer.push(Tick(0), 0);
+
+        assert!(buffer.has_item(&Tick(65534)));
+        assert_eq!(buffer.pop_until(&Tick(65535)), Some((Tick(65535), 65535)));
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.drain_until(&Tick(0)), vec![(Tick(0), 0)]);
+        assert!(!buffer.has_item(&Tick(0)));
+        assert_eq!(buffer.pop_until(&Tick(2)), Some((Tick(1), 1)));
+        assert!(buffer.is_empty());
+    }
+
     #[test]
     fn test_drain_until() {
         let mut buffer = ReadyBuffer::new();
@@ -39,6 +39,10 @@ macro_rules! wrapping_id {
                     2
                 }
 
+                fn max_len() -> Option<usize> {
+                    Some(2)
+                }
+
                 fn to_bytes<T: WriteBytesExt>(&self, buffer: &mut T) -> Result<(), SerializationError> {
                     Ok(buffer.write_u16::<NetworkEndian>(self.0)?)
                 }
@@ -82,6 +86,25 @@ macro_rules! wrapping_id {
             //     }
             // }
 
+            impl $struct_name {
+                /// Returns the id that comes `rhs` steps after this one, wrapping around at `u16::MAX`
+                pub fn wrapping_add(self, rhs: u16) -> Self {
+                    Self(self.0.wrapping_add(rhs))
+                }
+
+                /// Returns the id that comes `rhs` steps before this one, wrapping around at 0
+                pub fn wrapping_sub(self, rhs: u16) -> Self {
+                    Self(self.0.wrapping_sub(rhs))
+                }
+
+                /// Signed number of steps from `other` to `self`: positive if `self` is more recent.
+                ///
+                /// Ids that are more than half the range (32768) apart are considered to have wrapped around.
+                pub fn diff(self, other: Self) -> i32 {
+                    i32::from(wrapping_diff(other.0, self.0))
+                }
+            }
+
             impl WrappedId for $struct_name {
                  fn rem(&self, total: usize) -> usize {
                      (self.0 as usize) % total
@@ -224,6 +247,26 @@ mod sequence_compare_tests {
         assert!(Id(0) < Id(32767));
         assert!(Id(0) > Id(32768));
     }
+
+    #[test]
+    fn test_wrapping_arithmetic() {
+        assert_eq!(Id(65535).wrapping_add(1), Id(0));
+        assert_eq!(Id(65530).wrapping_add(10), Id(4));
+        assert_eq!(Id(0).wrapping_sub(1), Id(65535));
+        assert_eq!(Id(4).wrapping_sub(10), Id(65530));
+
+        assert_eq!(Id(5).diff(Id(2)), 3);
+        assert_eq!(Id(2).diff(Id(5)), -3);
+        // across the wrap boundary
+        assert_eq!(Id(0).diff(Id(65535)), 1);
+        assert_eq!(Id(65535).diff(Id(0)), -1);
+        assert_eq!(Id(4).diff(Id(65530)), 10);
+        assert_eq!(Id(65530).diff(Id(4)), -10);
+        // at half the range
+        assert_eq!(Id(32767).diff(Id(0)), 32767);
+        assert_eq!(Id(32768).diff(Id(0)), -32768);
+        assert_eq!(Id(0).diff(Id(32769)), 32767);
+    }
 }
 
 #[cfg(test)]
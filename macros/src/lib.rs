@@ -9,9 +9,11 @@ use quote::{format_ident, quote};
 use syn::{parse_macro_input, ItemEnum};
 
 use channel::channel_impl;
+use to_bytes::to_bytes_impl;
 
 mod channel;
 mod shared;
+mod to_bytes;
 
 // Channel
 #[doc(hidden)]
@@ -27,3 +29,20 @@ pub fn channel_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream
     let shared_crate_name = quote! { lightyear };
     channel_impl(input, shared_crate_name)
 }
+
+// ToBytes
+#[doc(hidden)]
+#[proc_macro_derive(ToBytesInternal)]
+pub fn to_bytes_derive_internal(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let shared_crate_name = quote! { crate };
+    to_bytes_impl(input, shared_crate_name)
+}
+
+/// Derives the ToBytes trait for a struct, by serializing each field in order.
+///
+/// All the fields must implement ToBytes.
+#[proc_macro_derive(ToBytes)]
+pub fn to_bytes_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let shared_crate_name = quote! { lightyear };
+    to_bytes_impl(input, shared_crate_name)
+}
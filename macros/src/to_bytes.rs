@@ -0,0 +1,90 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Index};
+
+pub fn to_bytes_impl(
+    input: proc_macro::TokenStream,
+    shared_crate_name: TokenStream,
+) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let Data::Struct(data_struct) = &input.data else {
+        panic!("Can only derive ToBytes on a struct");
+    };
+
+    // Names
+    let struct_name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = &input.generics.split_for_impl();
+    let serialize = quote! { #shared_crate_name::serialize };
+
+    let types = data_struct
+        .fields
+        .iter()
+        .map(|field| &field.ty)
+        .collect::<Vec<_>>();
+
+    // accessor of each field (`self.name` or `self.0`), and the expression used to build the struct
+    let (accessors, constructor) = match &data_struct.fields {
+        Fields::Named(fields) => {
+            let names = fields
+                .named
+                .iter()
+                .map(|field| field.ident.clone().unwrap())
+                .collect::<Vec<_>>();
+            let types = fields.named.iter().map(|field| &field.ty);
+            (
+                names
+                    .iter()
+                    .map(|name| quote! { #name })
+                    .collect::<Vec<_>>(),
+                quote! {
+                    Self {
+                        #(#names: <#types as #serialize::ToBytes>::from_bytes(buffer)?,)*
+                    }
+                },
+            )
+        }
+        Fields::Unnamed(fields) => {
+            let indices = (0..fields.unnamed.len()).map(Index::from);
+            let types = fields.unnamed.iter().map(|field| &field.ty);
+            (
+                indices.map(|index| quote! { #index }).collect::<Vec<_>>(),
+                quote! {
+                    Self(#(<#types as #serialize::ToBytes>::from_bytes(buffer)?,)*)
+                },
+            )
+        }
+        Fields::Unit => (vec![], quote! { Self }),
+    };
+
+    let gen = quote! {
+        impl #impl_generics #serialize::ToBytes for #struct_name #type_generics #where_clause {
+            fn len(&self) -> usize {
+                0 #(+ #serialize::ToBytes::len(&self.#accessors))*
+            }
+
+            fn max_len() -> Option<usize> {
+                Some(0 #(+ <#types as #serialize::ToBytes>::max_len()?)*)
+            }
+
+            fn to_bytes<T: #serialize::WriteBytesExt>(
+                &self,
+                buffer: &mut T,
+            ) -> Result<(), #serialize::SerializationError> {
+                #(#serialize::ToBytes::to_bytes(&self.#accessors, buffer)?;)*
+                Ok(())
+            }
+
+            fn from_bytes(
+                buffer: &mut #serialize::reader::Reader,
+            ) -> Result<Self, #serialize::SerializationError>
+            where
+                Self: Sized,
+            {
+                Ok(#constructor)
+            }
+        }
+    };
+
+    proc_macro::TokenStream::from(gen)
+}